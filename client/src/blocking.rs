@@ -0,0 +1,112 @@
+//! A synchronous client, for tests and tools without an async runtime.
+//!
+//! It must not be used from within an async runtime; use the async
+//! [`Client`](crate::Client) there.
+
+use std::time::Duration;
+
+use reqwest::blocking::Response;
+
+use crate::client::{list_params, wait_params, Deleted, Endpoint};
+use crate::expect::ExpectMessage;
+use crate::model::{Message, MessageId, MessageList};
+use crate::{Error, Result};
+
+/// The blocking counterpart of [`crate::Client`], with the same methods.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::blocking::Client,
+    endpoint: Endpoint,
+}
+
+impl Client {
+    /// Creates a client for the server at `base_url`, such as
+    /// `http://127.0.0.1:8025`. Requests go to the default mailbox.
+    pub fn new(base_url: &str) -> Result<Client> {
+        Ok(Client {
+            // Waiting for a message can take far longer than the default
+            // request timeout; the server bounds it instead.
+            http: reqwest::blocking::Client::builder().timeout(None).build()?,
+            endpoint: Endpoint::new(base_url)?,
+        })
+    }
+
+    /// A client for the same server that only sees mailbox `name`.
+    pub fn mailbox(&self, name: &str) -> Client {
+        Client {
+            http: self.http.clone(),
+            endpoint: self.endpoint.mailbox(name),
+        }
+    }
+
+    /// Returns the newest messages, up to 1000, newest first.
+    pub fn list(&self) -> Result<MessageList> {
+        self.fetch_list(None)
+    }
+
+    /// Returns the newest messages matching `query`, up to 1000.
+    pub fn search(&self, query: &str) -> Result<MessageList> {
+        self.fetch_list(Some(query))
+    }
+
+    fn fetch_list(&self, query: Option<&str>) -> Result<MessageList> {
+        let request = self
+            .http
+            .get(self.endpoint.url("/messages"))
+            .query(&list_params(query));
+        Ok(check(request.send()?)?.json()?)
+    }
+
+    pub fn get(&self, id: MessageId) -> Result<Message> {
+        let request = self.http.get(self.endpoint.url(&format!("/messages/{id}")));
+        Ok(check(request.send()?)?.json()?)
+    }
+
+    /// Returns the message exactly as it was received over SMTP.
+    pub fn raw(&self, id: MessageId) -> Result<Vec<u8>> {
+        let request = self
+            .http
+            .get(self.endpoint.url(&format!("/messages/{id}/raw")));
+        Ok(check(request.send()?)?.bytes()?.to_vec())
+    }
+
+    /// Waits up to `timeout` for a message matching `query`, which may be
+    /// empty to match any message. A message already stored counts.
+    pub fn wait_for(&self, query: &str, timeout: Duration) -> Result<Message> {
+        let request = self
+            .http
+            .get(self.endpoint.url("/messages/wait"))
+            .query(&wait_params(query, timeout));
+        Ok(check(request.send()?)?.json()?)
+    }
+
+    pub fn delete(&self, id: MessageId) -> Result<()> {
+        let request = self
+            .http
+            .delete(self.endpoint.url(&format!("/messages/{id}")));
+        check(request.send()?)?;
+        Ok(())
+    }
+
+    /// Deletes every message and returns how many there were.
+    pub fn delete_all(&self) -> Result<usize> {
+        let request = self.http.delete(self.endpoint.url("/messages"));
+        let deleted: Deleted = check(request.send()?)?.json()?;
+        Ok(deleted.deleted)
+    }
+
+    /// Starts describing a message that should arrive; call
+    /// [`ExpectMessage::wait`] to wait for it.
+    pub fn expect_message(&self) -> ExpectMessage<&Client> {
+        ExpectMessage::new(self)
+    }
+}
+
+fn check(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text()?;
+    Err(Error::from_response(status.as_u16(), &body))
+}
@@ -0,0 +1,166 @@
+//! The async client.
+
+use std::time::Duration;
+
+use reqwest::{Response, Url};
+
+use crate::expect::ExpectMessage;
+use crate::model::{Message, MessageId, MessageList};
+use crate::{Error, Result};
+
+/// Most messages the server returns per list request.
+pub(crate) const PAGE_LIMIT: usize = 1000;
+
+/// Where the API lives and which mailbox requests are scoped to.
+#[derive(Debug, Clone)]
+pub(crate) struct Endpoint {
+    /// Scheme, host and port, without a trailing slash.
+    origin: String,
+    /// `/api`, or `/api/mailboxes/{name}` once scoped to a mailbox.
+    scope: String,
+}
+
+impl Endpoint {
+    pub(crate) fn new(base_url: &str) -> Result<Endpoint> {
+        let url = Url::parse(base_url).map_err(|_| Error::InvalidUrl(base_url.to_string()))?;
+        if url.cannot_be_a_base() || !matches!(url.scheme(), "http" | "https") {
+            return Err(Error::InvalidUrl(base_url.to_string()));
+        }
+        Ok(Endpoint {
+            origin: url.origin().ascii_serialization(),
+            scope: "/api".to_string(),
+        })
+    }
+
+    pub(crate) fn mailbox(&self, name: &str) -> Endpoint {
+        Endpoint {
+            origin: self.origin.clone(),
+            scope: format!("/api/mailboxes/{name}"),
+        }
+    }
+
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("{}{}{path}", self.origin, self.scope)
+    }
+}
+
+/// Query parameters of a list request.
+pub(crate) fn list_params(query: Option<&str>) -> Vec<(&'static str, String)> {
+    let mut params = vec![("limit", PAGE_LIMIT.to_string())];
+    if let Some(query) = query {
+        params.push(("query", query.to_string()));
+    }
+    params
+}
+
+/// Query parameters of a wait request.
+pub(crate) fn wait_params(query: &str, timeout: Duration) -> [(&'static str, String); 2] {
+    [
+        ("query", query.to_string()),
+        ("timeout", timeout.as_secs_f64().to_string()),
+    ]
+}
+
+/// Talks to a RubberMail server. Cloning is cheap and clones share
+/// connections.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    endpoint: Endpoint,
+}
+
+impl Client {
+    /// Creates a client for the server at `base_url`, such as
+    /// `http://127.0.0.1:8025`. Requests go to the default mailbox.
+    pub fn new(base_url: &str) -> Result<Client> {
+        Ok(Client {
+            http: reqwest::Client::new(),
+            endpoint: Endpoint::new(base_url)?,
+        })
+    }
+
+    /// A client for the same server that only sees mailbox `name`.
+    pub fn mailbox(&self, name: &str) -> Client {
+        Client {
+            http: self.http.clone(),
+            endpoint: self.endpoint.mailbox(name),
+        }
+    }
+
+    /// Returns the newest messages, up to 1000, newest first.
+    pub async fn list(&self) -> Result<MessageList> {
+        self.fetch_list(None).await
+    }
+
+    /// Returns the newest messages matching `query`, in the server's search
+    /// language (e.g. `to:bob subject:"password reset"`), up to 1000.
+    pub async fn search(&self, query: &str) -> Result<MessageList> {
+        self.fetch_list(Some(query)).await
+    }
+
+    async fn fetch_list(&self, query: Option<&str>) -> Result<MessageList> {
+        let request = self
+            .http
+            .get(self.endpoint.url("/messages"))
+            .query(&list_params(query));
+        Ok(check(request.send().await?).await?.json().await?)
+    }
+
+    pub async fn get(&self, id: MessageId) -> Result<Message> {
+        let request = self.http.get(self.endpoint.url(&format!("/messages/{id}")));
+        Ok(check(request.send().await?).await?.json().await?)
+    }
+
+    /// Returns the message exactly as it was received over SMTP.
+    pub async fn raw(&self, id: MessageId) -> Result<Vec<u8>> {
+        let request = self
+            .http
+            .get(self.endpoint.url(&format!("/messages/{id}/raw")));
+        Ok(check(request.send().await?).await?.bytes().await?.to_vec())
+    }
+
+    /// Waits up to `timeout` for a message matching `query`, which may be
+    /// empty to match any message. A message already stored counts.
+    pub async fn wait_for(&self, query: &str, timeout: Duration) -> Result<Message> {
+        let request = self
+            .http
+            .get(self.endpoint.url("/messages/wait"))
+            .query(&wait_params(query, timeout));
+        Ok(check(request.send().await?).await?.json().await?)
+    }
+
+    pub async fn delete(&self, id: MessageId) -> Result<()> {
+        let request = self
+            .http
+            .delete(self.endpoint.url(&format!("/messages/{id}")));
+        check(request.send().await?).await?;
+        Ok(())
+    }
+
+    /// Deletes every message and returns how many there were.
+    pub async fn delete_all(&self) -> Result<usize> {
+        let request = self.http.delete(self.endpoint.url("/messages"));
+        let deleted: Deleted = check(request.send().await?).await?.json().await?;
+        Ok(deleted.deleted)
+    }
+
+    /// Starts describing a message that should arrive; awaiting the result
+    /// waits for it.
+    pub fn expect_message(&self) -> ExpectMessage<&Client> {
+        ExpectMessage::new(self)
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct Deleted {
+    pub(crate) deleted: usize,
+}
+
+async fn check(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await?;
+    Err(Error::from_response(status.as_u16(), &body))
+}
@@ -0,0 +1,58 @@
+use serde::Deserialize;
+
+/// Errors returned by the clients.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid base URL {0:?}")]
+    InvalidUrl(String),
+    /// The server could not be reached or sent an unreadable response.
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    /// The server answered with an error status.
+    #[error("HTTP {status}: {message}")]
+    Api { status: u16, message: String },
+    /// No matching message arrived in time.
+    #[error("{0}")]
+    Timeout(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// The HTTP status the server answered with, if it answered.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::Api { status, .. } => Some(*status),
+            Error::Timeout(_) => Some(404),
+            Error::Http(err) => err.status().map(|status| status.as_u16()),
+            Error::InvalidUrl(_) => None,
+        }
+    }
+
+    /// Whether the server reported the message or mailbox as missing.
+    pub fn is_not_found(&self) -> bool {
+        self.status() == Some(404)
+    }
+
+    /// Builds the error for a failed response from its `{"error": ...}`
+    /// body. A wait that ran out of time is a 404 marked `timed_out`.
+    pub(crate) fn from_response(status: u16, body: &str) -> Error {
+        #[derive(Deserialize)]
+        struct Body {
+            error: String,
+            #[serde(default)]
+            timed_out: bool,
+        }
+        match serde_json::from_str::<Body>(body) {
+            Ok(body) if body.timed_out => Error::Timeout(body.error),
+            Ok(body) => Error::Api {
+                status,
+                message: body.error,
+            },
+            Err(_) => Error::Api {
+                status,
+                message: body.trim().to_string(),
+            },
+        }
+    }
+}
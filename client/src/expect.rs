@@ -0,0 +1,159 @@
+//! Fluent expectations about mail that should arrive.
+
+use std::future::{Future, IntoFuture};
+use std::pin::Pin;
+use std::time::Duration;
+
+use crate::model::Message;
+use crate::{Client, Error, Result};
+
+/// How long an expectation waits unless [`ExpectMessage::within`] says
+/// otherwise.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A message that should arrive, built from [`Client::expect_message`].
+///
+/// Every criterion must hold. A message already stored counts, so an
+/// expectation can be checked after the code under test has run. Failing
+/// to arrive in time yields [`Error::Timeout`] describing what was
+/// expected.
+#[derive(Debug, Clone)]
+#[must_use = "an expectation does nothing until it is awaited or waited for"]
+pub struct ExpectMessage<C> {
+    client: C,
+    terms: Vec<String>,
+    criteria: Vec<String>,
+    timeout: Duration,
+}
+
+impl<C> ExpectMessage<C> {
+    pub(crate) fn new(client: C) -> ExpectMessage<C> {
+        ExpectMessage {
+            client,
+            terms: Vec::new(),
+            criteria: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    fn with(mut self, term: String, criterion: String) -> Self {
+        self.terms.push(term);
+        self.criteria.push(criterion);
+        self
+    }
+
+    /// The `From` header or envelope sender contains `address`.
+    pub fn from(self, address: &str) -> Self {
+        self.with(
+            format!("from:{}", quote(address)),
+            format!("from {address}"),
+        )
+    }
+
+    /// A recipient header or envelope recipient contains `address`.
+    pub fn to(self, address: &str) -> Self {
+        self.with(format!("to:{}", quote(address)), format!("to {address}"))
+    }
+
+    pub fn subject_contains(self, text: &str) -> Self {
+        self.with(
+            format!("subject:{}", quote(text)),
+            format!("with a subject containing {text:?}"),
+        )
+    }
+
+    /// The subject or the text or HTML body contains `text`.
+    pub fn contains(self, text: &str) -> Self {
+        self.with(quote(text), format!("containing {text:?}"))
+    }
+
+    pub fn with_attachment(self) -> Self {
+        self.with(
+            "has:attachment".to_string(),
+            "with an attachment".to_string(),
+        )
+    }
+
+    /// The message matches a query in the server's search language.
+    pub fn matching(self, query: &str) -> Self {
+        self.with(query.to_string(), format!("matching {query:?}"))
+    }
+
+    /// Waits up to `timeout` instead of 10 seconds.
+    pub fn within(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn query(&self) -> String {
+        self.terms.join(" ")
+    }
+
+    /// Rewrites a timeout so the failure says what was expected.
+    fn explain(&self, err: Error) -> Error {
+        match err {
+            Error::Timeout(_) => {
+                let mut expected = String::from("no message");
+                for criterion in &self.criteria {
+                    expected.push(' ');
+                    expected.push_str(criterion);
+                }
+                Error::Timeout(format!("{expected} arrived within {:?}", self.timeout))
+            }
+            err => err,
+        }
+    }
+}
+
+/// Search terms are grouped with double quotes, which cannot be escaped.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', ""))
+}
+
+impl<'a> IntoFuture for ExpectMessage<&'a Client> {
+    type Output = Result<Message>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Result<Message>> + Send + 'a>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(async move {
+            self.client
+                .wait_for(&self.query(), self.timeout)
+                .await
+                .map_err(|err| self.explain(err))
+        })
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl ExpectMessage<&crate::blocking::Client> {
+    /// Blocks until a matching message is stored and returns it.
+    pub fn wait(self) -> Result<Message> {
+        self.client
+            .wait_for(&self.query(), self.timeout)
+            .map_err(|err| self.explain(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_queries_and_failures() {
+        let expect = ExpectMessage::new(())
+            .to("bob@example.test")
+            .subject_contains("say \"hi\"")
+            .with_attachment()
+            .within(Duration::from_millis(1500));
+        assert_eq!(
+            expect.query(),
+            r#"to:"bob@example.test" subject:"say hi" has:attachment"#
+        );
+        assert_eq!(
+            expect
+                .explain(Error::Timeout("no matching message".into()))
+                .to_string(),
+            r#"no message to bob@example.test with a subject containing "say \"hi\"" with an attachment arrived within 1.5s"#
+        );
+    }
+}
@@ -0,0 +1,30 @@
+//! Typed client for the RubberMail HTTP API.
+//!
+//! [`Client`] is async; [`blocking::Client`] (the default `blocking` feature)
+//! offers the same calls for code without a runtime. Both can wait for mail
+//! with fluent expectations:
+//!
+//! ```no_run
+//! # async fn example() -> rubbermail_client::Result<()> {
+//! let client = rubbermail_client::Client::new("http://127.0.0.1:8025")?;
+//! let message = client
+//!     .expect_message()
+//!     .to("bob@example.test")
+//!     .subject_contains("password reset")
+//!     .await?;
+//! assert!(message.text.unwrap_or_default().contains("/reset?token="));
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod client;
+mod error;
+mod expect;
+pub mod model;
+
+pub use client::Client;
+pub use error::{Error, Result};
+pub use expect::ExpectMessage;
+pub use model::{Address, Message, MessageList, MessageSummary};
@@ -0,0 +1,138 @@
+//! The messages returned by the API, as documented in `docs/api.md`.
+//!
+//! Fields the server adds in later releases are ignored.
+
+use std::fmt;
+use std::net::SocketAddr;
+
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::Deserialize;
+
+/// Identifier the server assigned to a message.
+pub type MessageId = u64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Address {
+    pub name: Option<String>,
+    /// The `local@domain` part.
+    pub address: String,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{name} <{}>", self.address),
+            None => f.write_str(&self.address),
+        }
+    }
+}
+
+/// The SMTP envelope a message was submitted with.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Envelope {
+    /// Empty for the null sender `<>`.
+    pub mail_from: String,
+    pub rcpt_to: Vec<String>,
+}
+
+/// One entry of a message list.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageSummary {
+    pub id: MessageId,
+    #[serde(default)]
+    pub mailbox: String,
+    pub received_at: DateTime<Utc>,
+    pub envelope: Envelope,
+    pub from: Option<Address>,
+    pub to: Vec<Address>,
+    pub cc: Vec<Address>,
+    pub subject: Option<String>,
+    pub date: Option<DateTime<FixedOffset>>,
+    /// Size of the raw message in bytes.
+    pub size: usize,
+    pub attachment_count: usize,
+    pub snippet: String,
+    pub spam_score: Option<f64>,
+    /// IMAP flags such as `\Seen`, set by mail clients.
+    #[serde(default)]
+    pub flags: Vec<String>,
+    /// Pinned messages are never evicted by retention limits.
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+/// A page of messages, newest first.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageList {
+    /// Number of messages in the mailbox, or matching the search.
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub messages: Vec<MessageSummary>,
+}
+
+/// A fully parsed message.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Message {
+    #[serde(flatten)]
+    pub summary: MessageSummary,
+    pub message_id: Option<String>,
+    pub reply_to: Vec<Address>,
+    pub bcc: Vec<Address>,
+    pub session: Session,
+    pub headers: Vec<Header>,
+    pub text: Option<String>,
+    pub html: Option<String>,
+    pub attachments: Vec<Attachment>,
+    pub inlines: Vec<Attachment>,
+}
+
+impl Message {
+    /// The first header named `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    }
+}
+
+impl std::ops::Deref for Message {
+    type Target = MessageSummary;
+
+    fn deref(&self) -> &MessageSummary {
+        &self.summary
+    }
+}
+
+/// The SMTP session a message arrived on.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Session {
+    pub peer: SocketAddr,
+    pub helo: String,
+    pub tls: Option<Tls>,
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Tls {
+    pub version: String,
+    pub cipher: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Attachment {
+    /// IMAP-style section number, e.g. `"2.1"`.
+    pub part_id: String,
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub disposition: Option<String>,
+    pub content_id: Option<String>,
+    pub size: usize,
+}
@@ -0,0 +1,48 @@
+mod common;
+
+use std::time::Duration;
+
+use common::send;
+use rubbermail::TestServer;
+use rubbermail_client::blocking::Client;
+use rubbermail_client::Error;
+
+#[test]
+fn covers_the_api_without_a_runtime() {
+    let server = TestServer::start().unwrap();
+    let client = Client::new(&server.http_url()).unwrap();
+    send(
+        server.smtp_addr(),
+        "shop@example.test",
+        &["bob@example.test"],
+        "Subject: Your receipt\r\n\r\nTotal: 12.00\r\n",
+    );
+
+    let list = client.list().unwrap();
+    assert_eq!(list.total, 1);
+    assert_eq!(client.search("subject:invoice").unwrap().total, 0);
+    let message = client.get(list.messages[0].id).unwrap();
+    assert_eq!(message.text.as_deref(), Some("Total: 12.00\r\n"));
+    assert!(client.raw(1).unwrap().starts_with(b"Subject: Your receipt"));
+
+    let message = client
+        .expect_message()
+        .from("shop@example.test")
+        .to("bob")
+        .subject_contains("receipt")
+        .wait()
+        .unwrap();
+    assert_eq!(message.id, 1);
+    let err = client
+        .expect_message()
+        .with_attachment()
+        .within(Duration::from_millis(200))
+        .wait()
+        .unwrap_err();
+    assert!(matches!(err, Error::Timeout(_)));
+    assert_eq!(err.status(), Some(404));
+
+    assert_eq!(client.wait_for("", Duration::from_secs(1)).unwrap().id, 1);
+    client.delete(1).unwrap();
+    assert_eq!(client.delete_all().unwrap(), 0);
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::send;
+use rubbermail::mailbox::Mailboxes;
+use rubbermail::smtp::SmtpConfig;
+use rubbermail::TestServer;
+use rubbermail_client::{Client, Error};
+
+const RESET: &str = "From: Shop <shop@example.test>\r\n\
+    To: Bob <bob@example.test>\r\n\
+    Subject: Password reset\r\n\
+    Message-ID: <reset-1@example.test>\r\n\
+    \r\n\
+    Open https://shop.test/reset?token=abc\r\n";
+
+#[tokio::test]
+async fn lists_gets_and_deletes_messages() {
+    let server = TestServer::start().unwrap();
+    let client = Client::new(&server.http_url()).unwrap();
+    send(
+        server.smtp_addr(),
+        "shop@example.test",
+        &["bob@example.test"],
+        RESET,
+    );
+    send(
+        server.smtp_addr(),
+        "news@example.test",
+        &["carol@example.test"],
+        "Subject: Weekly news\r\n\r\nhello\r\n",
+    );
+
+    let list = client.list().await.unwrap();
+    assert_eq!(list.total, 2);
+    assert_eq!(list.messages[0].subject.as_deref(), Some("Weekly news"));
+    let found = client.search("to:bob").await.unwrap();
+    assert_eq!(found.total, 1);
+
+    let message = client.get(found.messages[0].id).await.unwrap();
+    assert_eq!(message.id, 1);
+    assert_eq!(
+        message.from.as_ref().unwrap().to_string(),
+        "Shop <shop@example.test>"
+    );
+    assert_eq!(message.envelope.rcpt_to, ["bob@example.test"]);
+    assert_eq!(message.message_id.as_deref(), Some("reset-1@example.test"));
+    assert_eq!(message.header("subject"), Some("Password reset"));
+    assert!(message.text.as_deref().unwrap().contains("token=abc"));
+    assert_eq!(message.session.helo, "client.test");
+    assert_eq!(client.raw(1).await.unwrap(), RESET.as_bytes());
+
+    client.delete(1).await.unwrap();
+    assert!(client.get(1).await.unwrap_err().is_not_found());
+    assert!(client.delete(1).await.unwrap_err().is_not_found());
+    assert_eq!(client.delete_all().await.unwrap(), 1);
+    assert_eq!(client.list().await.unwrap().total, 0);
+}
+
+#[tokio::test]
+async fn expectations_wait_for_matching_mail() {
+    let server = TestServer::start().unwrap();
+    let client = Client::new(&server.http_url()).unwrap();
+    let smtp = server.smtp_addr();
+    let sender = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        send(smtp, "shop@example.test", &["bob@example.test"], RESET);
+    });
+
+    let message = client
+        .expect_message()
+        .to("bob@example.test")
+        .subject_contains("password reset")
+        .contains("token=abc")
+        .await
+        .unwrap();
+    assert_eq!(message.subject.as_deref(), Some("Password reset"));
+    sender.join().unwrap();
+
+    let started = Instant::now();
+    let err = client
+        .expect_message()
+        .to("alice@example.test")
+        .within(Duration::from_millis(300))
+        .await
+        .unwrap_err();
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert!(matches!(err, Error::Timeout(_)));
+    assert_eq!(
+        err.to_string(),
+        "no message to alice@example.test arrived within 300ms"
+    );
+}
+
+#[tokio::test]
+async fn scopes_to_a_mailbox() {
+    let rules = vec!["team-a:rcpt=*@team-a.test".parse().unwrap()];
+    let server = TestServer::start_with(SmtpConfig {
+        mailboxes: Mailboxes::new(rules),
+        ..SmtpConfig::default()
+    })
+    .unwrap();
+    let client = Client::new(&server.http_url()).unwrap();
+    let team_a = client.mailbox("team-a");
+    send(
+        server.smtp_addr(),
+        "app@example.test",
+        &["x@team-a.test"],
+        "Subject: a\r\n\r\na\r\n",
+    );
+    send(
+        server.smtp_addr(),
+        "app@example.test",
+        &["x@example.test"],
+        "Subject: b\r\n\r\nb\r\n",
+    );
+
+    let message = team_a.expect_message().subject_contains("a").await.unwrap();
+    assert_eq!(message.mailbox, "team-a");
+    assert_eq!(team_a.list().await.unwrap().total, 1);
+    assert_eq!(client.list().await.unwrap().messages[0].id, 2);
+    assert!(client.get(1).await.unwrap_err().is_not_found());
+    let err = client.mailbox("nope").list().await.unwrap_err();
+    assert_eq!(err.to_string(), "HTTP 404: mailbox \"nope\" not found");
+}
+
+#[test]
+fn rejects_invalid_base_urls() {
+    assert!(matches!(
+        Client::new("localhost:8025"),
+        Err(Error::InvalidUrl(_))
+    ));
+    assert!(Client::new("http://127.0.0.1:8025/").is_ok());
+}
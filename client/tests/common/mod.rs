@@ -0,0 +1,34 @@
+//! Helpers shared by the client tests, which run against an in-process
+//! [`rubbermail::TestServer`].
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+
+/// Delivers `body` over SMTP, blocking until it is stored.
+pub fn send(smtp: SocketAddr, from: &str, to: &[&str], body: &str) {
+    let stream = TcpStream::connect(smtp).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut expect = |line: Option<&str>, code: &str| {
+        if let Some(line) = line {
+            writer.write_all(format!("{line}\r\n").as_bytes()).unwrap();
+        }
+        loop {
+            let mut reply = String::new();
+            reader.read_line(&mut reply).unwrap();
+            assert!(reply.starts_with(code), "expected {code}, got {reply:?}");
+            if reply.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+        }
+    };
+    expect(None, "220");
+    expect(Some("EHLO client.test"), "250");
+    expect(Some(&format!("MAIL FROM:<{from}>")), "250");
+    for rcpt in to {
+        expect(Some(&format!("RCPT TO:<{rcpt}>")), "250");
+    }
+    expect(Some("DATA"), "354");
+    expect(Some(&format!("{body}.")), "250");
+    expect(Some("QUIT"), "221");
+}
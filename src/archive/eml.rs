@@ -0,0 +1,24 @@
+//! A zip archive of `.eml` files, one per message, each modified at the
+//! time its message was received.
+
+use std::sync::Arc;
+
+use super::{add_file, zip_entries, zip_writer, ArchiveError, ArchivedMessage};
+use crate::store::CapturedMessage;
+
+pub(super) fn write(messages: &[Arc<CapturedMessage>]) -> Result<Vec<u8>, ArchiveError> {
+    let mut zip = zip_writer();
+    for message in messages {
+        let name = format!("{}.eml", message.id);
+        add_file(&mut zip, &name, message.received_at, &message.raw)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+pub(super) fn read(data: &[u8]) -> Result<Vec<ArchivedMessage>, ArchiveError> {
+    Ok(zip_entries(data)?
+        .into_iter()
+        .filter(|entry| entry.name.to_ascii_lowercase().ends_with(".eml"))
+        .map(|entry| ArchivedMessage::new(entry.data, entry.modified))
+        .collect())
+}
@@ -0,0 +1,118 @@
+//! Maildir, zipped: one file per message in `cur`, named
+//! `<unix time>.<unique>.<host>:2,<flags>`.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use zip::write::SimpleFileOptions;
+
+use super::{add_file, zip_entries, zip_writer, ArchiveError, ArchivedMessage};
+use crate::store::CapturedMessage;
+
+/// Maildir info letters and the IMAP flags they stand for, in the
+/// alphabetical order Maildir requires.
+const FLAGS: [(char, &str); 6] = [
+    ('D', "\\Draft"),
+    ('F', "\\Flagged"),
+    ('P', "$Forwarded"),
+    ('R', "\\Answered"),
+    ('S', "\\Seen"),
+    ('T', "\\Deleted"),
+];
+
+pub(super) fn write(messages: &[Arc<CapturedMessage>]) -> Result<Vec<u8>, ArchiveError> {
+    let mut zip = zip_writer();
+    for folder in ["cur/", "new/", "tmp/"] {
+        zip.add_directory(folder, SimpleFileOptions::default())?;
+    }
+    for message in messages {
+        let received_at = message.received_at;
+        let info: String = FLAGS
+            .iter()
+            .filter(|(_, flag)| message.flags.contains(*flag))
+            .map(|(letter, _)| letter)
+            .collect();
+        let name = format!(
+            "cur/{}.M{}I{}.rubbermail:2,{info}",
+            received_at.timestamp(),
+            received_at.timestamp_subsec_micros(),
+            message.id
+        );
+        add_file(&mut zip, &name, received_at, &message.raw)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+pub(super) fn read(data: &[u8]) -> Result<Vec<ArchivedMessage>, ArchiveError> {
+    let mut messages = Vec::new();
+    for entry in zip_entries(data)? {
+        let Some(file) = folder(&entry.name).and_then(|_| entry.name.rsplit('/').next()) else {
+            continue;
+        };
+        let (unique, info) = match file.rsplit_once(":2,").or_else(|| file.rsplit_once("!2,")) {
+            Some((unique, info)) => (unique, info),
+            None => (file, ""),
+        };
+        let received_at = delivery_time(unique).or(entry.modified);
+        let mut message = ArchivedMessage::new(entry.data, received_at);
+        message.flags = FLAGS
+            .iter()
+            .filter(|(letter, _)| info.contains(*letter))
+            .map(|(_, flag)| flag.to_string())
+            .collect();
+        messages.push((unique.to_string(), message));
+    }
+    // Maildir has no order of its own, so sort by delivery.
+    messages.sort_by(|(a, left), (b, right)| (left.received_at, a).cmp(&(right.received_at, b)));
+    Ok(messages.into_iter().map(|(_, message)| message).collect())
+}
+
+/// The Maildir folder a zip entry is in, if it is a message: `cur` or
+/// `new`, possibly below other directories.
+pub(super) fn folder(name: &str) -> Option<&str> {
+    let mut parts = name.rsplit('/');
+    let file = parts.next()?;
+    let folder = parts.next()?;
+    (!file.is_empty() && matches!(folder, "cur" | "new")).then_some(folder)
+}
+
+/// The delivery time at the start of a Maildir file name, with the
+/// microseconds of a `M<usec>` part if there is one.
+fn delivery_time(unique: &str) -> Option<DateTime<Utc>> {
+    let mut parts = unique.split('.');
+    let seconds = parts.next()?.parse().ok()?;
+    let micros = parts
+        .next()
+        .and_then(|part| part.strip_prefix('M'))
+        .and_then(|part| {
+            let digits = part
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(part.len());
+            part[..digits].parse::<u32>().ok()
+        })
+        .filter(|&micros| micros < 1_000_000)
+        .unwrap_or(0);
+    DateTime::from_timestamp(seconds, micros * 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_file_names() {
+        assert_eq!(folder("cur/1.2.host:2,S"), Some("cur"));
+        assert_eq!(folder("Maildir/new/1.2.host"), Some("new"));
+        assert_eq!(folder("tmp/1.2.host"), None);
+        assert_eq!(folder("cur/"), None);
+        assert_eq!(
+            delivery_time("1709641800.M250I7.rubbermail"),
+            DateTime::from_timestamp(1709641800, 250_000)
+        );
+        assert_eq!(
+            delivery_time("1709641800.P123Q4.host"),
+            DateTime::from_timestamp(1709641800, 0)
+        );
+        assert_eq!(delivery_time("message.eml"), None);
+    }
+}
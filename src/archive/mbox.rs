@@ -0,0 +1,131 @@
+//! mboxrd: messages separated by `From ` lines, with `From ` lines in the
+//! body quoted by one more `>` than they had.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use super::{ArchiveError, ArchivedMessage};
+use crate::store::CapturedMessage;
+
+/// The `From ` line date format, as written by `asctime`.
+const DATE_FORMAT: &str = "%a %b %e %H:%M:%S %Y";
+
+pub(super) fn write(messages: &[Arc<CapturedMessage>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for message in messages {
+        let sender = match message.envelope.mail_from.as_str() {
+            "" => "MAILER-DAEMON",
+            sender => sender,
+        };
+        let date = message.received_at.format(DATE_FORMAT);
+        out.extend_from_slice(format!("From {sender} {date}\n").as_bytes());
+        for line in message.raw.split_inclusive(|&b| b == b'\n') {
+            let content = line.strip_suffix(b"\n").unwrap_or(line);
+            let content = content.strip_suffix(b"\r").unwrap_or(content);
+            if is_from_line(content) {
+                out.push(b'>');
+            }
+            out.extend_from_slice(content);
+            out.push(b'\n');
+        }
+        // A blank line separates messages.
+        out.push(b'\n');
+    }
+    out
+}
+
+pub(super) fn read(data: &[u8]) -> Result<Vec<ArchivedMessage>, ArchiveError> {
+    if !data.starts_with(b"From ") {
+        return Err(ArchiveError::Invalid(
+            "not an mbox file: it must start with a \"From \" line".into(),
+        ));
+    }
+    let mut messages: Vec<ArchivedMessage> = Vec::new();
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        if let Some(envelope) = content.strip_prefix(b"From ") {
+            finish(messages.last_mut());
+            let (sender, received_at) = parse_envelope(&String::from_utf8_lossy(envelope));
+            messages.push(ArchivedMessage {
+                sender,
+                ..ArchivedMessage::new(Vec::new(), received_at)
+            });
+            continue;
+        }
+        let message = messages.last_mut().expect("data starts with a From line");
+        let content = match content.strip_prefix(b">") {
+            Some(unquoted) if is_from_line(unquoted) => unquoted,
+            _ => content,
+        };
+        message.raw.extend_from_slice(content);
+        message.raw.extend_from_slice(b"\r\n");
+    }
+    finish(messages.last_mut());
+    Ok(messages)
+}
+
+/// Drops the blank line that separates a message from the next.
+fn finish(message: Option<&mut ArchivedMessage>) {
+    if let Some(message) = message {
+        if message.raw.ends_with(b"\r\n\r\n") {
+            message.raw.truncate(message.raw.len() - 2);
+        }
+    }
+}
+
+/// Whether a line is `From ` after any number of `>`, and so needs quoting.
+fn is_from_line(line: &[u8]) -> bool {
+    let start = line.iter().take_while(|&&b| b == b'>').count();
+    line[start..].starts_with(b"From ")
+}
+
+/// Parses the rest of a `From ` line: the sender, then an `asctime` date.
+fn parse_envelope(envelope: &str) -> (Option<String>, Option<DateTime<Utc>>) {
+    let mut words = envelope.split_whitespace();
+    let sender = words
+        .next()
+        .filter(|sender| *sender != "MAILER-DAEMON")
+        .map(str::to_string);
+    let date = words.collect::<Vec<_>>().join(" ");
+    let received_at = NaiveDateTime::parse_from_str(&date, DATE_FORMAT)
+        .map(|date| date.and_utc())
+        .or_else(|_| {
+            DateTime::parse_from_str(&date, "%a %b %e %H:%M:%S %Y %z")
+                .map(|date| date.with_timezone(&Utc))
+        })
+        .ok();
+    (sender, received_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn quotes_from_lines() {
+        let data = b"From a@example.test Tue Mar  5 12:30:00 2024\n\
+Subject: x\n\n>From here\n>>From there\nfine\n\n\
+From MAILER-DAEMON Tue Mar  5 12:31:00 2024 +0100\nSubject: y\n\ny\n\n";
+        let messages = read(data).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].sender.as_deref(), Some("a@example.test"));
+        assert_eq!(
+            messages[0].received_at,
+            Some(Utc.with_ymd_and_hms(2024, 3, 5, 12, 30, 0).unwrap())
+        );
+        // The input is mboxrd, so both lines lose one `>`.
+        assert_eq!(
+            messages[0].raw,
+            b"Subject: x\r\n\r\nFrom here\r\n>From there\r\nfine\r\n"
+        );
+        assert_eq!(messages[1].sender, None);
+        assert_eq!(
+            messages[1].received_at,
+            Some(Utc.with_ymd_and_hms(2024, 3, 5, 11, 31, 0).unwrap())
+        );
+        assert!(read(b"Subject: x\n\nnot mbox\n").is_err());
+    }
+}
@@ -0,0 +1,343 @@
+//! Export and import of captured mail as mbox, Maildir or a zip of `.eml`
+//! files, so a test run's mail can be attached to a bug report and loaded
+//! back later.
+//!
+//! Exports keep each message's bytes and received time. mbox files use the
+//! mboxrd convention and Unix line endings; Maildir directories are shipped
+//! as zip archives and also keep the IMAP flags Maildir can express.
+
+mod eml;
+mod maildir;
+mod mbox;
+
+use std::collections::BTreeSet;
+use std::io::{self, Cursor, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Datelike, NaiveDate, Timelike, Utc};
+use serde::Deserialize;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::mime::{split_head_body, Headers};
+use crate::store::{CapturedMessage, Envelope, MessageId, MessageStore, NewMessage, SessionInfo};
+
+/// Largest total size of the files unpacked from one zip archive, so a small
+/// upload cannot expand without bound.
+const MAX_UNPACKED: u64 = 1 << 30;
+
+/// An archive format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// One mboxrd file.
+    Mbox,
+    /// A zip archive holding a Maildir's `cur`, `new` and `tmp` directories.
+    Maildir,
+    /// A zip archive with one `.eml` file per message.
+    Eml,
+}
+
+impl Format {
+    /// Guesses the format of an archive: zip archives are Maildirs if they
+    /// have a `cur` or `new` directory, and everything else is mbox.
+    pub fn detect(data: &[u8]) -> Format {
+        if !data.starts_with(b"PK\x03\x04") {
+            return Format::Mbox;
+        }
+        let maildir = ZipArchive::new(Cursor::new(data)).is_ok_and(|archive| {
+            archive
+                .file_names()
+                .any(|name| maildir::folder(name).is_some())
+        });
+        if maildir {
+            Format::Maildir
+        } else {
+            Format::Eml
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Mbox => "application/mbox",
+            Format::Maildir | Format::Eml => "application/zip",
+        }
+    }
+
+    /// A file name for an export of `name`.
+    pub fn file_name(self, name: &str) -> String {
+        match self {
+            Format::Mbox => format!("{name}.mbox"),
+            Format::Maildir => format!("{name}-maildir.zip"),
+            Format::Eml => format!("{name}-eml.zip"),
+        }
+    }
+}
+
+/// Why an archive could not be written or read.
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("invalid zip archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+/// A message read from an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchivedMessage {
+    pub raw: Vec<u8>,
+    /// When the message was received, if the archive records it.
+    pub received_at: Option<DateTime<Utc>>,
+    /// The envelope sender, if the archive records it.
+    pub sender: Option<String>,
+    /// IMAP flags, for formats that record them.
+    pub flags: BTreeSet<String>,
+}
+
+impl ArchivedMessage {
+    fn new(raw: Vec<u8>, received_at: Option<DateTime<Utc>>) -> ArchivedMessage {
+        ArchivedMessage {
+            raw,
+            received_at,
+            sender: None,
+            flags: BTreeSet::new(),
+        }
+    }
+}
+
+/// Writes `messages`, oldest first, as an archive in `format`.
+pub fn export(format: Format, messages: &[Arc<CapturedMessage>]) -> Result<Vec<u8>, ArchiveError> {
+    match format {
+        Format::Mbox => Ok(mbox::write(messages)),
+        Format::Maildir => maildir::write(messages),
+        Format::Eml => eml::write(messages),
+    }
+}
+
+/// Reads the messages of an archive in `format`.
+pub fn import(format: Format, data: &[u8]) -> Result<Vec<ArchivedMessage>, ArchiveError> {
+    match format {
+        Format::Mbox => mbox::read(data),
+        Format::Maildir => maildir::read(data),
+        Format::Eml => eml::read(data),
+    }
+}
+
+/// Stores imported messages in `mailbox`, in order, and returns their new
+/// ids. Messages without a received time get the current one.
+///
+/// Archives do not record the SMTP session, so imported messages have an
+/// unspecified peer address. The envelope sender comes from the archive or
+/// the `Return-Path` header, and there are no envelope recipients.
+///
+/// The messages are announced as [`StoreEvent::Imported`], so webhooks and
+/// spam checks leave them alone.
+///
+/// [`StoreEvent::Imported`]: crate::store::StoreEvent::Imported
+pub fn restore(
+    store: &MessageStore,
+    mailbox: &str,
+    messages: Vec<ArchivedMessage>,
+) -> crate::Result<Vec<MessageId>> {
+    let mut ids = Vec::with_capacity(messages.len());
+    for message in messages {
+        let mail_from = message
+            .sender
+            .or_else(|| return_path(&message.raw))
+            .unwrap_or_default();
+        let new = NewMessage {
+            envelope: Envelope {
+                mail_from,
+                rcpt_to: Vec::new(),
+            },
+            session: SessionInfo {
+                peer: SocketAddr::from(([0, 0, 0, 0], 0)),
+                helo: String::new(),
+                tls: None,
+                username: None,
+            },
+            raw: message.raw,
+            authentication: None,
+        };
+        let received_at = message.received_at.unwrap_or_else(Utc::now);
+        let stored = store.import_at(mailbox, new, received_at)?;
+        if !message.flags.is_empty() {
+            store.update(stored.id, |stored| stored.flags = message.flags)?;
+        }
+        ids.push(stored.id);
+    }
+    Ok(ids)
+}
+
+/// The address in the `Return-Path` header, if there is one.
+fn return_path(raw: &[u8]) -> Option<String> {
+    let (head, _) = split_head_body(raw);
+    let headers = Headers::parse(head);
+    let value = headers.get("Return-Path")?;
+    let address = value.trim().trim_start_matches('<').trim_end_matches('>');
+    (!address.is_empty()).then(|| address.to_string())
+}
+
+/// Starts a zip archive in memory.
+fn zip_writer() -> ZipWriter<Cursor<Vec<u8>>> {
+    ZipWriter::new(Cursor::new(Vec::new()))
+}
+
+/// Adds a compressed file modified at `modified` to a zip archive.
+fn add_file(
+    zip: &mut ZipWriter<Cursor<Vec<u8>>>,
+    name: &str,
+    modified: DateTime<Utc>,
+    data: &[u8],
+) -> Result<(), ArchiveError> {
+    let mut options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    if let Some(modified) = dos_time(modified) {
+        options = options.last_modified_time(modified);
+    }
+    zip.start_file(name, options)?;
+    zip.write_all(data)?;
+    Ok(())
+}
+
+/// A file read from a zip archive.
+struct ZipEntry {
+    name: String,
+    modified: Option<DateTime<Utc>>,
+    data: Vec<u8>,
+}
+
+/// Reads every file of a zip archive, skipping directories.
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry>, ArchiveError> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+    let mut budget = MAX_UNPACKED;
+    let mut entries = Vec::new();
+    for index in 0..archive.len() {
+        let file = archive.by_index(index)?;
+        if file.is_dir() {
+            continue;
+        }
+        let name = file.name().to_string();
+        let modified = file.last_modified().and_then(from_dos_time);
+        let mut data = Vec::new();
+        file.take(budget + 1).read_to_end(&mut data)?;
+        budget = budget
+            .checked_sub(data.len() as u64)
+            .ok_or_else(|| ArchiveError::Invalid("archive unpacks to more than 1 GiB".into()))?;
+        entries.push(ZipEntry {
+            name,
+            modified,
+            data,
+        });
+    }
+    Ok(entries)
+}
+
+/// Converts to the zip format's local time, which we take to be UTC. Zip
+/// times have a two-second resolution and start in 1980.
+fn dos_time(time: DateTime<Utc>) -> Option<zip::DateTime> {
+    zip::DateTime::from_date_and_time(
+        u16::try_from(time.year()).ok()?,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .ok()
+}
+
+fn from_dos_time(time: zip::DateTime) -> Option<DateTime<Utc>> {
+    let date = NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())?;
+    let time = date.and_hms_opt(
+        time.hour().into(),
+        time.minute().into(),
+        time.second().into(),
+    )?;
+    Some(time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn captured(id: MessageId, raw: &str, flags: &[&str]) -> Arc<CapturedMessage> {
+        let mut message = CapturedMessage::new(
+            id,
+            Utc.with_ymd_and_hms(2024, 3, 5, 12, 30, id as u32 * 2)
+                .unwrap(),
+            Envelope {
+                mail_from: "sender@example.test".into(),
+                rcpt_to: vec!["rcpt@example.test".into()],
+            },
+            SessionInfo {
+                peer: "127.0.0.1:1".parse().unwrap(),
+                helo: String::new(),
+                tls: None,
+                username: None,
+            },
+            raw.as_bytes().to_vec(),
+        );
+        message.flags = flags.iter().map(|flag| flag.to_string()).collect();
+        Arc::new(message)
+    }
+
+    fn messages() -> Vec<Arc<CapturedMessage>> {
+        vec![
+            captured(
+                1,
+                "Subject: one\r\n\r\nFrom the start\r\n>From quoted\r\n",
+                &["\\Seen", "\\Flagged"],
+            ),
+            captured(2, "Subject: two\r\n\r\nbody\r\n", &[]),
+        ]
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        let messages = messages();
+        for format in [Format::Mbox, Format::Maildir, Format::Eml] {
+            let data = export(format, &messages).unwrap();
+            assert_eq!(Format::detect(&data), format);
+            let imported = import(format, &data).unwrap();
+            assert_eq!(imported.len(), 2, "{format:?}");
+            for (original, imported) in messages.iter().zip(&imported) {
+                assert_eq!(imported.raw, original.raw, "{format:?}");
+                assert_eq!(imported.received_at, Some(original.received_at));
+            }
+            let flags: Vec<&str> = imported[0].flags.iter().map(String::as_str).collect();
+            match format {
+                Format::Maildir => assert_eq!(flags, ["\\Flagged", "\\Seen"]),
+                _ => assert!(flags.is_empty()),
+            }
+        }
+    }
+
+    #[test]
+    fn restores_into_a_mailbox() {
+        let store = MessageStore::new();
+        let imported = vec![
+            ArchivedMessage::new(
+                b"Return-Path: <bounce@example.test>\r\n\r\nx\r\n".to_vec(),
+                None,
+            ),
+            ArchivedMessage {
+                sender: Some("a@example.test".into()),
+                flags: BTreeSet::from(["\\Seen".to_string()]),
+                ..ArchivedMessage::new(b"Subject: y\r\n\r\ny\r\n".to_vec(), Some(Utc::now()))
+            },
+        ];
+        let ids = restore(&store, "ci", imported).unwrap();
+        assert_eq!(ids, [1, 2]);
+        let first = store.get(1).unwrap();
+        assert_eq!(first.mailbox, "ci");
+        assert_eq!(first.envelope.mail_from, "bounce@example.test");
+        let second = store.get(2).unwrap();
+        assert_eq!(second.envelope.mail_from, "a@example.test");
+        assert!(second.flags.contains("\\Seen"));
+    }
+}
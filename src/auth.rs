@@ -0,0 +1,178 @@
+//! Client authentication: the configured user list and the SASL mechanisms
+//! the mail listeners accept.
+//!
+//! RubberMail is a test double, so by default any credentials are accepted
+//! and merely recorded. Strict mode checks them against a user list.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use md5::Md5;
+
+/// A `name:password` pair from the configuration.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credential {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credential")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for Credential {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Credential, String> {
+        match value.split_once(':') {
+            Some((username, password)) if !username.is_empty() => Ok(Credential {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => Err("expected NAME:PASSWORD".to_string()),
+        }
+    }
+}
+
+/// Which credentials a listener accepts.
+#[derive(Clone, Default)]
+pub enum AuthPolicy {
+    /// Any username and password succeed.
+    #[default]
+    AcceptAny,
+    /// Only the listed users, with their passwords, succeed, and clients
+    /// must authenticate before sending.
+    Strict(Arc<HashMap<String, String>>),
+}
+
+impl fmt::Debug for AuthPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthPolicy::AcceptAny => f.write_str("AcceptAny"),
+            AuthPolicy::Strict(users) => write!(f, "Strict({} users)", users.len()),
+        }
+    }
+}
+
+impl AuthPolicy {
+    /// Strict mode if any users are given, otherwise accept-any.
+    pub fn from_users(users: &[Credential]) -> AuthPolicy {
+        if users.is_empty() {
+            return AuthPolicy::AcceptAny;
+        }
+        let users = users
+            .iter()
+            .map(|user| (user.username.clone(), user.password.clone()))
+            .collect();
+        AuthPolicy::Strict(Arc::new(users))
+    }
+
+    pub fn is_strict(&self) -> bool {
+        matches!(self, AuthPolicy::Strict(_))
+    }
+
+    /// Checks a plaintext password (PLAIN, LOGIN, POP3 `PASS`, IMAP `LOGIN`).
+    pub fn check_password(&self, username: &str, password: &str) -> bool {
+        match self {
+            AuthPolicy::AcceptAny => true,
+            AuthPolicy::Strict(users) => users.get(username).is_some_and(|p| p == password),
+        }
+    }
+
+    /// Checks a CRAM-MD5 response: the hex HMAC-MD5 of `challenge` keyed
+    /// with the user's password (RFC 2195).
+    pub fn check_cram_md5(&self, username: &str, challenge: &str, digest: &str) -> bool {
+        match self {
+            AuthPolicy::AcceptAny => true,
+            AuthPolicy::Strict(users) => users
+                .get(username)
+                .is_some_and(|password| cram_md5_digest(password, challenge) == digest),
+        }
+    }
+}
+
+/// Computes the lowercase hex CRAM-MD5 digest.
+pub fn cram_md5_digest(password: &str, challenge: &str) -> String {
+    let mut mac = Hmac::<Md5>::new_from_slice(password.as_bytes()).expect("HMAC takes any key");
+    mac.update(challenge.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Splits a decoded SASL PLAIN response (`authzid NUL authcid NUL passwd`,
+/// RFC 4616) into username and password.
+pub fn decode_plain(response: &[u8]) -> Option<(String, String)> {
+    let mut fields = response.split(|&b| b == 0);
+    let (_authzid, username, password) = (fields.next()?, fields.next()?, fields.next()?);
+    if fields.next().is_some() || username.is_empty() {
+        return None;
+    }
+    Some((
+        String::from_utf8(username.to_vec()).ok()?,
+        String::from_utf8(password.to_vec()).ok()?,
+    ))
+}
+
+/// Splits a decoded CRAM-MD5 response (`username SP digest`).
+pub fn decode_cram_md5(response: &[u8]) -> Option<(String, String)> {
+    let response = std::str::from_utf8(response).ok()?;
+    let (username, digest) = response.rsplit_once(' ')?;
+    if username.is_empty() {
+        return None;
+    }
+    Some((username.to_string(), digest.to_ascii_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_responses() {
+        assert_eq!(
+            decode_plain(b"\0alice\0secret"),
+            Some(("alice".into(), "secret".into()))
+        );
+        assert_eq!(
+            decode_plain(b"admin\0alice\0"),
+            Some(("alice".into(), "".into()))
+        );
+        assert_eq!(decode_plain(b"alice\0secret"), None);
+        assert_eq!(decode_plain(b"\0\0secret"), None);
+    }
+
+    #[test]
+    fn cram_md5_matches_rfc_2195_example() {
+        let challenge = "<1896.697170952@postoffice.reston.mci.net>";
+        assert_eq!(
+            cram_md5_digest("tanstaaftanstaaf", challenge),
+            "b913a602c7eda7a495b4e6e7334d3890"
+        );
+        let policy = AuthPolicy::from_users(&["tim:tanstaaftanstaaf".parse().unwrap()]);
+        let (user, digest) = decode_cram_md5(b"tim b913a602c7eda7a495b4e6e7334d3890").unwrap();
+        assert!(policy.check_cram_md5(&user, challenge, &digest));
+        assert!(!policy.check_cram_md5(&user, "<other@host>", &digest));
+    }
+
+    #[test]
+    fn policies() {
+        assert!(AuthPolicy::AcceptAny.check_password("anyone", "anything"));
+        let strict =
+            AuthPolicy::from_users(&["alice:secret".parse().unwrap(), "bob:a:b".parse().unwrap()]);
+        assert!(strict.check_password("alice", "secret"));
+        assert!(strict.check_password("bob", "a:b"));
+        assert!(!strict.check_password("alice", "wrong"));
+        assert!(!strict.check_password("carol", "secret"));
+        assert!("nopassword".parse::<Credential>().is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! HTML email client compatibility: which elements and CSS an HTML body
+//! uses, and how well each family of mail clients supports them.
+//!
+//! Support data is bundled in `data.json`, a curated subset modeled on
+//! caniemail.com with one verdict per client family. A family's score is
+//! the share of detected features it supports, counting partial support
+//! as half.
+
+mod usage;
+
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+pub use usage::Usage;
+
+/// How well a client family supports a feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Support {
+    Yes,
+    /// Works in some versions or with caveats, given in the notes.
+    Partial,
+    No,
+}
+
+impl Support {
+    fn weight(self) -> f64 {
+        match self {
+            Support::Yes => 1.0,
+            Support::Partial => 0.5,
+            Support::No => 0.0,
+        }
+    }
+}
+
+/// A family of mail clients, e.g. every Outlook.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Client {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Dataset {
+    clients: Vec<Client>,
+    features: Vec<Feature>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Feature {
+    id: String,
+    title: String,
+    #[serde(rename = "match")]
+    matcher: Matcher,
+    /// Keyed by client id.
+    support: BTreeMap<String, Support>,
+    #[serde(default)]
+    notes: BTreeMap<String, String>,
+}
+
+/// What makes a body use a feature.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Matcher {
+    Element(String),
+    Attribute(String),
+    /// A property, optionally only with a value containing this keyword.
+    Property {
+        name: String,
+        #[serde(default)]
+        value: Option<String>,
+    },
+    AtRule(String),
+    Selector(String),
+    Function(String),
+}
+
+impl Matcher {
+    /// How many times `usage` uses the feature.
+    fn count(&self, usage: &Usage) -> usize {
+        let get = |map: &BTreeMap<String, usize>, key: &str| map.get(key).copied().unwrap_or(0);
+        match self {
+            Matcher::Element(name) => get(&usage.elements, name),
+            Matcher::Attribute(name) => get(&usage.attributes, name),
+            Matcher::Property { name, value: None } => get(&usage.properties, name),
+            Matcher::Property {
+                name,
+                value: Some(keyword),
+            } => usage
+                .declarations
+                .iter()
+                .filter(|(property, value)| {
+                    property == name
+                        && value
+                            .split(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+                            .any(|word| word == keyword)
+                })
+                .count(),
+            Matcher::AtRule(name) => get(&usage.at_rules, name),
+            Matcher::Selector(name) => get(&usage.selectors, name),
+            Matcher::Function(name) => get(&usage.functions, name),
+        }
+    }
+}
+
+fn dataset() -> &'static Dataset {
+    static DATASET: OnceLock<Dataset> = OnceLock::new();
+    DATASET.get_or_init(|| {
+        serde_json::from_str(include_str!("data.json"))
+            .expect("bundled compatibility data is valid")
+    })
+}
+
+/// An element or CSS property and how often it occurs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Count {
+    pub name: String,
+    pub count: usize,
+}
+
+/// A feature from the dataset found in the body.
+#[derive(Debug, Clone, Serialize)]
+pub struct FeatureUse {
+    /// caniemail-style slug, e.g. `css-display-flex`.
+    pub id: String,
+    pub title: String,
+    pub count: usize,
+    /// Keyed by client id.
+    pub support: BTreeMap<String, Support>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub notes: BTreeMap<String, String>,
+}
+
+/// How well one client family will render the body.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientScore {
+    pub id: String,
+    pub name: String,
+    /// 0 to 100; 100 if no known feature is used.
+    pub score: u8,
+    pub supported: usize,
+    pub partial: usize,
+    pub unsupported: usize,
+}
+
+/// The compatibility report for one HTML body.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// Every element used, by name.
+    pub elements: Vec<Count>,
+    /// Every CSS property used, inline or in `<style>`, by name.
+    pub properties: Vec<Count>,
+    /// Features from the dataset that the body uses, worst supported first.
+    pub features: Vec<FeatureUse>,
+    /// One score per client family, in dataset order.
+    pub clients: Vec<ClientScore>,
+}
+
+impl Report {
+    pub fn of(html: &str) -> Report {
+        let usage = Usage::of(html);
+        let dataset = dataset();
+
+        let mut features: Vec<FeatureUse> = dataset
+            .features
+            .iter()
+            .filter_map(|feature| {
+                let count = feature.matcher.count(&usage);
+                (count > 0).then(|| FeatureUse {
+                    id: feature.id.clone(),
+                    title: feature.title.clone(),
+                    count,
+                    support: feature.support.clone(),
+                    notes: feature.notes.clone(),
+                })
+            })
+            .collect();
+        features.sort_by(|a, b| support_total(a).total_cmp(&support_total(b)));
+
+        let clients = dataset
+            .clients
+            .iter()
+            .map(|client| score(client, &features))
+            .collect();
+        let counts = |map: BTreeMap<String, usize>| {
+            map.into_iter()
+                .map(|(name, count)| Count { name, count })
+                .collect()
+        };
+        Report {
+            elements: counts(usage.elements),
+            properties: counts(usage.properties),
+            features,
+            clients,
+        }
+    }
+}
+
+/// Sum of support weights across clients, for ordering features.
+fn support_total(feature: &FeatureUse) -> f64 {
+    feature
+        .support
+        .values()
+        .map(|support| support.weight())
+        .sum()
+}
+
+fn score(client: &Client, features: &[FeatureUse]) -> ClientScore {
+    let mut result = ClientScore {
+        id: client.id.clone(),
+        name: client.name.clone(),
+        score: 100,
+        supported: 0,
+        partial: 0,
+        unsupported: 0,
+    };
+    let mut total = 0.0;
+    for feature in features {
+        // Missing data counts as unsupported, the safe assumption.
+        let support = feature
+            .support
+            .get(&client.id)
+            .copied()
+            .unwrap_or(Support::No);
+        match support {
+            Support::Yes => result.supported += 1,
+            Support::Partial => result.partial += 1,
+            Support::No => result.unsupported += 1,
+        }
+        total += support.weight();
+    }
+    if !features.is_empty() {
+        result.score = (100.0 * total / features.len() as f64).round() as u8;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dataset_covers_every_client() {
+        let dataset = dataset();
+        for feature in &dataset.features {
+            for client in &dataset.clients {
+                assert!(
+                    feature.support.contains_key(&client.id),
+                    "{} has no data for {}",
+                    feature.id,
+                    client.id
+                );
+            }
+            for client in feature.notes.keys() {
+                assert!(feature.support.contains_key(client), "{}", feature.id);
+            }
+        }
+    }
+
+    #[test]
+    fn scores_detected_features_per_client() {
+        let report = Report::of(
+            r#"<table><tr><td style="display: flex">a</td></tr></table><video src="a.mp4"></video>"#,
+        );
+        let ids: Vec<&str> = report.features.iter().map(|f| f.id.as_str()).collect();
+        assert_eq!(ids, ["html-video", "css-display-flex", "html-table"]);
+
+        let score = |id: &str| report.clients.iter().find(|c| c.id == id).unwrap().clone();
+        // Table yes, flex yes, video yes.
+        assert_eq!(score("apple-mail").score, 100);
+        // Table yes, flex partial, video no.
+        let outlook = score("outlook");
+        assert_eq!(
+            (
+                outlook.score,
+                outlook.supported,
+                outlook.partial,
+                outlook.unsupported
+            ),
+            (50, 1, 1, 1)
+        );
+        assert_eq!(
+            report.properties,
+            [Count {
+                name: "display".to_string(),
+                count: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn plain_markup_scores_full_marks() {
+        let report = Report::of("<p>Hello</p>");
+        assert!(report.features.is_empty());
+        assert!(report.clients.iter().all(|client| client.score == 100));
+    }
+}
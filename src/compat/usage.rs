@@ -0,0 +1,208 @@
+//! Counting the elements, attributes and CSS an HTML body uses.
+
+use std::collections::BTreeMap;
+
+use crate::html;
+
+/// What an HTML body uses, with occurrence counts. Names are lowercased.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub elements: BTreeMap<String, usize>,
+    pub attributes: BTreeMap<String, usize>,
+    pub properties: BTreeMap<String, usize>,
+    /// Every CSS declaration as `(property, value)`.
+    pub declarations: Vec<(String, String)>,
+    /// At-rules such as `media`, without the `@`.
+    pub at_rules: BTreeMap<String, usize>,
+    /// Pseudo-classes and pseudo-elements in selectors, e.g. `:hover`.
+    pub selectors: BTreeMap<String, usize>,
+    /// Functions called in CSS values, e.g. `calc`.
+    pub functions: BTreeMap<String, usize>,
+}
+
+impl Usage {
+    pub fn of(html: &str) -> Usage {
+        let mut usage = Usage::default();
+        for tag in html::tags(html) {
+            *usage.elements.entry(tag.name.clone()).or_default() += 1;
+            for (name, value) in &tag.attributes {
+                *usage.attributes.entry(name.clone()).or_default() += 1;
+                if name == "style" {
+                    for declaration in value.split(';') {
+                        usage.declaration(declaration);
+                    }
+                }
+            }
+            if let (Some(css), "style") = (tag.raw_text, tag.name.as_str()) {
+                usage.stylesheet(css);
+            }
+        }
+        usage
+    }
+
+    fn stylesheet(&mut self, css: &str) {
+        let css = strip_comments(css);
+        // One entry per open block: whether it holds declarations (a style
+        // rule) rather than nested rules (`@media`) or descriptors
+        // (`@font-face`).
+        let mut blocks: Vec<bool> = Vec::new();
+        let mut buffer = String::new();
+        for c in css.chars() {
+            match c {
+                '{' => {
+                    let prelude = buffer.trim();
+                    let is_rule = if let Some(at_rule) = prelude.strip_prefix('@') {
+                        let name = self.at_rule(at_rule);
+                        name == "page"
+                    } else {
+                        self.selector(prelude);
+                        true
+                    };
+                    blocks.push(is_rule);
+                    buffer.clear();
+                }
+                ';' | '}' => {
+                    let text = buffer.trim();
+                    if let Some(at_rule) = text.strip_prefix('@') {
+                        // A statement such as `@import url(...);`.
+                        self.at_rule(at_rule);
+                    } else if blocks.last() == Some(&true) {
+                        self.declaration(text);
+                    }
+                    if c == '}' {
+                        blocks.pop();
+                    }
+                    buffer.clear();
+                }
+                c => buffer.push(c),
+            }
+        }
+    }
+
+    /// Records an at-rule and returns its name.
+    fn at_rule(&mut self, text: &str) -> String {
+        let name: String = text
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+            .collect::<String>()
+            .to_ascii_lowercase();
+        if !name.is_empty() {
+            *self.at_rules.entry(name.clone()).or_default() += 1;
+        }
+        name
+    }
+
+    fn selector(&mut self, selector: &str) {
+        let mut rest = selector;
+        while let Some(start) = rest.find(':') {
+            let after = &rest[start + 1..];
+            let (prefix, after) = match after.strip_prefix(':') {
+                Some(after) => ("::", after),
+                None => (":", after),
+            };
+            let name: String = after
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+                .collect();
+            if !name.is_empty() {
+                let key = format!("{prefix}{}", name.to_ascii_lowercase());
+                *self.selectors.entry(key).or_default() += 1;
+            }
+            rest = &after[name.len()..];
+        }
+    }
+
+    fn declaration(&mut self, text: &str) {
+        let Some((property, value)) = text.split_once(':') else {
+            return;
+        };
+        let property = property.trim().to_ascii_lowercase();
+        let valid = property
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if property.is_empty() || !valid {
+            return;
+        }
+        let value = value.trim().to_ascii_lowercase();
+        let value = value.trim_end_matches("!important").trim_end();
+
+        for name in functions(value) {
+            *self.functions.entry(name.to_string()).or_default() += 1;
+        }
+        *self.properties.entry(property.clone()).or_default() += 1;
+        self.declarations.push((property, value.to_string()));
+    }
+}
+
+fn strip_comments(css: &str) -> String {
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        rest = rest[start + 2..]
+            .find("*/")
+            .map_or("", |end| &rest[start + 2 + end + 2..]);
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Names of the functions called in a CSS value, such as `calc` in
+/// `calc(100% - 2px)`.
+fn functions(value: &str) -> impl Iterator<Item = &str> {
+    value.match_indices('(').filter_map(|(open, _)| {
+        let start = value[..open]
+            .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+            .map_or(0, |i| i + 1);
+        let name = &value[start..open];
+        (!name.is_empty()).then_some(name)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_elements_attributes_and_inline_styles() {
+        let usage = Usage::of(
+            r#"<!-- <video> --><TABLE width=600><tr><td style="padding: 4px; DISPLAY:flex !important">
+            <img srcset='a.png 2x' alt="<b>">x</td></tr></table>"#,
+        );
+        assert_eq!(usage.elements["table"], 1);
+        assert_eq!(usage.elements["img"], 1);
+        assert!(!usage.elements.contains_key("video"));
+        assert!(!usage.elements.contains_key("b"));
+        assert_eq!(usage.attributes["srcset"], 1);
+        assert_eq!(usage.attributes["width"], 1);
+        assert_eq!(usage.properties["display"], 1);
+        assert!(usage
+            .declarations
+            .contains(&("display".to_string(), "flex".to_string())));
+    }
+
+    #[test]
+    fn reads_style_blocks() {
+        let usage = Usage::of(
+            "<style>/* a { color: red } */
+            @import url(fonts.css);
+            @font-face { font-family: Brand; src: url(brand.woff2) }
+            @media (max-width: 600px) { .col { width: calc(100% - 20px); } }
+            a:hover, p::before { color: var(--brand); }
+            </style><p>hi</p>",
+        );
+        assert_eq!(usage.elements["style"], 1);
+        assert_eq!(usage.elements["p"], 1);
+        for rule in ["import", "font-face", "media"] {
+            assert_eq!(usage.at_rules[rule], 1, "{rule}");
+        }
+        assert_eq!(usage.properties["width"], 1);
+        assert_eq!(usage.properties["color"], 1);
+        // Descriptors inside @font-face are not properties.
+        assert!(!usage.properties.contains_key("src"));
+        assert_eq!(usage.selectors[":hover"], 1);
+        assert_eq!(usage.selectors["::before"], 1);
+        assert_eq!(usage.functions["calc"], 1);
+        assert_eq!(usage.functions["var"], 1);
+    }
+}
@@ -0,0 +1,329 @@
+//! Command-line and environment configuration.
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+
+use crate::auth::{AuthPolicy, Credential};
+use crate::dns::Records;
+use crate::faults::{FaultSet, Faults};
+use crate::imap::ImapConfig;
+use crate::mailbox::MailboxRule;
+use crate::pop3::Pop3Config;
+use crate::release::{Relay, ReleaseConfig, UpstreamTls};
+use crate::retention::RetentionRule;
+use crate::smtp::SmtpConfig;
+use crate::spam::{self, Spamd, SpamdConfig};
+use crate::storage::StorageKind;
+use crate::tls::{self, Identity, TlsAcceptor, TlsMode};
+use crate::webhooks::{WebhookSet, Webhooks};
+use crate::{Error, Result};
+
+/// RubberMail - email testing tool for developers.
+///
+/// Accepts every message sent to the SMTP listener and keeps it for
+/// inspection instead of delivering it.
+#[derive(Debug, Clone, Parser)]
+#[command(name = "rubbermail", version, about)]
+pub struct Config {
+    /// Address the SMTP capture listener binds to. Repeat for more
+    /// listeners, e.g. to route mail into mailboxes by port.
+    #[arg(
+        long,
+        env = "RUBBERMAIL_SMTP_ADDR",
+        default_value = "0.0.0.0:1025",
+        value_delimiter = ','
+    )]
+    pub smtp_addr: Vec<SocketAddr>,
+
+    /// Address the HTTP API binds to.
+    #[arg(long, env = "RUBBERMAIL_HTTP_ADDR", default_value = "0.0.0.0:8025")]
+    pub http_addr: SocketAddr,
+
+    /// Host name announced in SMTP greetings.
+    #[arg(long, env = "RUBBERMAIL_HOSTNAME", default_value = "rubbermail")]
+    pub hostname: String,
+
+    /// Largest message accepted over SMTP, in bytes.
+    #[arg(long, env = "RUBBERMAIL_MAX_MESSAGE_SIZE", default_value_t = 25 * 1024 * 1024)]
+    pub max_message_size: usize,
+
+    /// Where captured messages are kept.
+    #[arg(long, env = "RUBBERMAIL_STORAGE", value_enum, default_value_t = StorageKind::Memory)]
+    pub storage: StorageKind,
+
+    /// Directory (eml) or database file (sqlite) for persistent storage.
+    /// Defaults to `rubbermail-mail` or `rubbermail.db` in the working
+    /// directory.
+    #[arg(long, env = "RUBBERMAIL_STORAGE_PATH")]
+    pub storage_path: Option<PathBuf>,
+
+    /// Seconds an SMTP client may stay idle before being disconnected.
+    #[arg(long, env = "RUBBERMAIL_SMTP_TIMEOUT", default_value_t = 300)]
+    pub smtp_timeout: u64,
+
+    /// Offer STARTTLS on the SMTP listener.
+    #[arg(long, env = "RUBBERMAIL_STARTTLS")]
+    pub starttls: bool,
+
+    /// Also listen for implicit-TLS SMTP (SMTPS) on this address, e.g.
+    /// `0.0.0.0:465`.
+    #[arg(long, env = "RUBBERMAIL_SMTPS_ADDR")]
+    pub smtps_addr: Option<SocketAddr>,
+
+    /// Serve captured mail over POP3 on this address, e.g. `0.0.0.0:1110`.
+    /// The listener always offers STLS.
+    #[arg(long, env = "RUBBERMAIL_POP3_ADDR")]
+    pub pop3_addr: Option<SocketAddr>,
+
+    /// Serve captured mail over IMAP on this address, e.g. `0.0.0.0:1143`.
+    /// The listener always offers STARTTLS.
+    #[arg(long, env = "RUBBERMAIL_IMAP_ADDR")]
+    pub imap_addr: Option<SocketAddr>,
+
+    /// PEM certificate chain for TLS. Without it a self-signed certificate
+    /// is generated at startup.
+    #[arg(long, env = "RUBBERMAIL_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key matching `--tls-cert`.
+    #[arg(long, env = "RUBBERMAIL_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// A `NAME:PASSWORD` account; repeat for more. Giving any switches
+    /// authentication to strict mode, where only these credentials are
+    /// accepted and clients must log in. Otherwise any credentials work.
+    /// Values are not split at commas, since passwords may contain them.
+    #[arg(
+        long = "auth-user",
+        env = "RUBBERMAIL_AUTH_USERS",
+        value_name = "NAME:PASSWORD"
+    )]
+    pub auth_users: Vec<Credential>,
+
+    /// JSON file with fault-injection rules to start with, in the format of
+    /// `PUT /api/faults`.
+    #[arg(long, env = "RUBBERMAIL_FAULTS")]
+    pub faults: Option<PathBuf>,
+
+    /// JSON file with webhooks to start with, in the format of
+    /// `PUT /api/webhooks`.
+    #[arg(long, env = "RUBBERMAIL_WEBHOOKS")]
+    pub webhooks: Option<PathBuf>,
+
+    /// Upstream SMTP server that captured messages can be released to.
+    /// Releasing is disabled without it.
+    #[arg(long, env = "RUBBERMAIL_RELEASE_HOST")]
+    pub release_host: Option<String>,
+
+    /// Port of the upstream SMTP server.
+    #[arg(long, env = "RUBBERMAIL_RELEASE_PORT", default_value_t = 587)]
+    pub release_port: u16,
+
+    /// How the connection to the upstream server is secured.
+    #[arg(long, env = "RUBBERMAIL_RELEASE_TLS", value_enum, default_value_t = UpstreamTls::Starttls)]
+    pub release_tls: UpstreamTls,
+
+    /// `NAME:PASSWORD` to log in to the upstream server with.
+    #[arg(long, env = "RUBBERMAIL_RELEASE_AUTH", value_name = "NAME:PASSWORD")]
+    pub release_auth: Option<Credential>,
+
+    /// Envelope sender for released messages, instead of the captured one.
+    #[arg(long, env = "RUBBERMAIL_RELEASE_FROM")]
+    pub release_from: Option<String>,
+
+    /// Recipients released messages go to when a release names none,
+    /// instead of the captured ones.
+    #[arg(long, env = "RUBBERMAIL_RELEASE_TO", value_delimiter = ',')]
+    pub release_to: Vec<String>,
+
+    /// PEM certificate to trust for the upstream server, in addition to
+    /// the public roots.
+    #[arg(long, env = "RUBBERMAIL_RELEASE_CA")]
+    pub release_ca: Option<PathBuf>,
+
+    /// spamd (SpamAssassin) server every captured message is scored with.
+    /// Scoring is disabled without it.
+    #[arg(long, env = "RUBBERMAIL_SPAMD_HOST")]
+    pub spamd_host: Option<String>,
+
+    /// Port of the spamd server.
+    #[arg(long, env = "RUBBERMAIL_SPAMD_PORT", default_value_t = spam::DEFAULT_PORT)]
+    pub spamd_port: u16,
+
+    /// User whose SpamAssassin preferences spamd should apply.
+    #[arg(long, env = "RUBBERMAIL_SPAMD_USER")]
+    pub spamd_user: Option<String>,
+
+    /// Let link checks request hosts on loopback and private networks, such
+    /// as a development server on `localhost`. Off by default, since any
+    /// captured message could otherwise probe the internal network.
+    #[arg(long, env = "RUBBERMAIL_CHECK_INTERNAL_LINKS")]
+    pub check_internal_links: bool,
+
+    /// Zone file, or JSON map with a `.json` extension, holding the DNS
+    /// records DKIM, SPF and DMARC checks use instead of live DNS.
+    #[arg(long, env = "RUBBERMAIL_DNS_RECORDS")]
+    pub dns_records: Option<PathBuf>,
+
+    /// Routes messages into a named mailbox: `NAME:rcpt=PATTERN` by
+    /// envelope recipient (`*` is a wildcard), `NAME:user=USER` by SMTP
+    /// AUTH user, or `NAME:port=PORT` by listener. Repeat for more.
+    /// Messages no rule matches go to the `default` mailbox.
+    #[arg(
+        long = "mailbox",
+        env = "RUBBERMAIL_MAILBOXES",
+        value_name = "NAME:KIND=VALUE",
+        value_delimiter = ','
+    )]
+    pub mailbox_rules: Vec<MailboxRule>,
+
+    /// Limits how much mail a mailbox keeps: `NAME:messages=COUNT`,
+    /// `NAME:age=AGE` (e.g. `7d`) or `NAME:bytes=SIZE` (e.g. `500M`), where
+    /// `NAME` may be `*` for every mailbox. Repeat for more. The oldest
+    /// unpinned messages are evicted first.
+    #[arg(
+        long = "retention",
+        env = "RUBBERMAIL_RETENTION",
+        value_name = "NAME:KIND=VALUE",
+        value_delimiter = ','
+    )]
+    pub retention_rules: Vec<RetentionRule>,
+}
+
+impl Config {
+    pub fn smtp(&self, tls: TlsMode) -> SmtpConfig {
+        SmtpConfig {
+            hostname: self.hostname.clone(),
+            max_message_size: self.max_message_size,
+            idle_timeout: Duration::from_secs(self.smtp_timeout),
+            tls,
+            auth: AuthPolicy::from_users(&self.auth_users),
+            ..SmtpConfig::default()
+        }
+    }
+
+    /// POP3 listener settings from the command line.
+    pub fn pop3(&self, tls: TlsMode) -> Pop3Config {
+        Pop3Config {
+            hostname: self.hostname.clone(),
+            tls,
+            auth: AuthPolicy::from_users(&self.auth_users),
+            ..Pop3Config::default()
+        }
+    }
+
+    /// IMAP listener settings from the command line.
+    pub fn imap(&self, tls: TlsMode) -> ImapConfig {
+        ImapConfig {
+            hostname: self.hostname.clone(),
+            max_message_size: self.max_message_size,
+            tls,
+            auth: AuthPolicy::from_users(&self.auth_users),
+            ..ImapConfig::default()
+        }
+    }
+
+    /// Loads the initial fault rules, if a file was given.
+    pub fn fault_rules(&self) -> Result<Faults> {
+        let Some(path) = &self.faults else {
+            return Ok(Faults::default());
+        };
+        let invalid = |err: String| Error::Config(format!("{}: {err}", path.display()));
+        let json = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+        let set: FaultSet = serde_json::from_str(&json).map_err(|err| invalid(err.to_string()))?;
+        Faults::new(set.rules).map_err(invalid)
+    }
+
+    /// Loads the initial webhooks, if a file was given.
+    pub fn webhooks(&self) -> Result<Webhooks> {
+        let Some(path) = &self.webhooks else {
+            return Ok(Webhooks::default());
+        };
+        let invalid = |err: String| Error::Config(format!("{}: {err}", path.display()));
+        let json = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+        let set: WebhookSet =
+            serde_json::from_str(&json).map_err(|err| invalid(err.to_string()))?;
+        Webhooks::new(set.webhooks).map_err(invalid)
+    }
+
+    /// Sets up releasing, if an upstream server was given.
+    pub fn relay(&self) -> Result<Option<Relay>> {
+        let Some(host) = &self.release_host else {
+            return Ok(None);
+        };
+        let ca_certs = match &self.release_ca {
+            Some(path) => tls::load_certs(path)?,
+            None => Vec::new(),
+        };
+        let config = ReleaseConfig {
+            tls: self.release_tls,
+            credentials: self.release_auth.clone(),
+            mail_from: self.release_from.clone(),
+            to: self.release_to.clone(),
+            hostname: self.hostname.clone(),
+            ca_certs,
+            ..ReleaseConfig::new(host.clone(), self.release_port)
+        };
+        Relay::new(config).map(Some)
+    }
+
+    /// Sets up spam scoring, if a spamd server was given.
+    pub fn spamd(&self) -> Option<Spamd> {
+        let host = self.spamd_host.as_ref()?;
+        Some(Spamd::new(SpamdConfig {
+            user: self.spamd_user.clone(),
+            ..SpamdConfig::new(host.clone(), self.spamd_port)
+        }))
+    }
+
+    /// Loads the DNS record set, if a file was given.
+    pub fn dns_records(&self) -> Result<Records> {
+        match &self.dns_records {
+            Some(path) => Records::load(path),
+            None => Ok(Records::default()),
+        }
+    }
+
+    /// Whether any listener needs a certificate.
+    pub fn tls_enabled(&self) -> bool {
+        self.starttls
+            || self.smtps_addr.is_some()
+            || self.pop3_addr.is_some()
+            || self.imap_addr.is_some()
+    }
+
+    /// Loads the configured certificate, or generates a self-signed one.
+    pub fn tls_acceptor(&self) -> Result<TlsAcceptor> {
+        let identity = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Identity::load(cert, key)?,
+            _ => Identity::self_signed(&self.hostname)?,
+        };
+        identity.acceptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_user_passwords_may_contain_commas() {
+        let config = Config::try_parse_from([
+            "rubbermail",
+            "--auth-user",
+            "alice:a,b",
+            "--auth-user",
+            "bob:c",
+        ])
+        .unwrap();
+        assert_eq!(config.auth_users.len(), 2);
+        let policy = AuthPolicy::from_users(&config.auth_users);
+        assert!(policy.check_password("alice", "a,b"));
+        assert!(policy.check_password("bob", "c"));
+        assert!(!policy.check_password("alice", "a"));
+    }
+}
@@ -0,0 +1,551 @@
+//! DKIM signature verification (RFC 6376), for `rsa-sha256` and
+//! `ed25519-sha256` (RFC 8463) signatures.
+//!
+//! Public keys are looked up in the offline [`Records`] rather than DNS, so
+//! signatures made with keys that are not published yet can be checked.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::Utc;
+use ed25519_dalek::{Signature, VerifyingKey};
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::traits::PublicKeyParts;
+use rsa::{Pkcs1v15Sign, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::dns::Records;
+
+/// Shortest RSA key accepted, as required by RFC 8301.
+const MIN_RSA_BITS: usize = 1024;
+
+/// The outcome of checking one signature, as in RFC 8601.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DkimStatus {
+    Pass,
+    /// The signature or body hash does not match, or it has expired.
+    Fail,
+    /// The signature cannot be checked: it is malformed, uses an
+    /// unsupported algorithm, or there is no usable key.
+    Permerror,
+}
+
+/// The result for one `DKIM-Signature` header.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DkimResult {
+    /// Signing domain, from `d=`.
+    pub domain: String,
+    /// Key selector, from `s=`.
+    pub selector: String,
+    pub algorithm: String,
+    /// The header fields covered, from `h=`.
+    pub signed_headers: Vec<String>,
+    pub status: DkimStatus,
+    /// Why the signature did not pass.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Checks every `DKIM-Signature` header of a raw message, in order.
+pub fn verify(raw: &[u8], records: &Records) -> Vec<DkimResult> {
+    let raw = crlf(raw);
+    let (fields, body) = split(&raw);
+    fields
+        .iter()
+        .filter(|field| field.name.eq_ignore_ascii_case("DKIM-Signature"))
+        .map(|signature| check(signature, &fields, body, records))
+        .collect()
+}
+
+/// A header field with its exact bytes, folding and final CRLF included.
+#[derive(Debug)]
+struct Field<'a> {
+    /// The field name, without any whitespace before the colon.
+    name: &'a str,
+    raw: &'a [u8],
+    colon: usize,
+}
+
+impl Field<'_> {
+    /// Everything after the colon.
+    fn value(&self) -> &[u8] {
+        &self.raw[self.colon + 1..]
+    }
+}
+
+/// Converts bare LFs to CRLF, which DKIM canonicalization assumes.
+fn crlf(raw: &[u8]) -> Cow<'_, [u8]> {
+    let bare_lf = raw
+        .iter()
+        .enumerate()
+        .any(|(i, &b)| b == b'\n' && (i == 0 || raw[i - 1] != b'\r'));
+    if !bare_lf {
+        return Cow::Borrowed(raw);
+    }
+    let mut out = Vec::with_capacity(raw.len() + raw.len() / 32);
+    for (i, &b) in raw.iter().enumerate() {
+        if b == b'\n' && (i == 0 || raw[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(b);
+    }
+    Cow::Owned(out)
+}
+
+/// Splits a message into its header fields and body.
+fn split(raw: &[u8]) -> (Vec<Field<'_>>, &[u8]) {
+    let mut fields = Vec::new();
+    let mut start = None;
+    let mut offset = 0;
+    let mut header_end = raw.len();
+    for line in raw.split_inclusive(|&b| b == b'\n') {
+        let line_start = offset;
+        offset += line.len();
+        if line == b"\r\n" {
+            header_end = line_start;
+            break;
+        }
+        if line[0] == b' ' || line[0] == b'\t' {
+            continue;
+        }
+        if let Some(field_start) = start.replace(line_start) {
+            fields.extend(field(&raw[field_start..line_start]));
+        }
+    }
+    if let Some(field_start) = start {
+        fields.extend(field(&raw[field_start..header_end]));
+    }
+    let body = if header_end < raw.len() {
+        &raw[header_end + 2..]
+    } else {
+        &raw[raw.len()..]
+    };
+    (fields, body)
+}
+
+fn field(raw: &[u8]) -> Option<Field<'_>> {
+    let colon = raw.iter().position(|&b| b == b':')?;
+    let name = std::str::from_utf8(&raw[..colon]).ok()?.trim_end();
+    (!name.is_empty() && !name.contains(|c: char| c.is_ascii_whitespace())).then_some(Field {
+        name,
+        raw,
+        colon,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+/// Parses a `tag=value; ...` list, as used by signatures and key records.
+/// Whitespace inside values is collapsed; base64 values drop it entirely.
+pub(crate) fn tags(text: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut tags = BTreeMap::new();
+    for spec in text.split(';') {
+        if spec.trim().is_empty() {
+            continue;
+        }
+        let (name, value) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("malformed tag {:?}", spec.trim()))?;
+        let value: String = value.split_whitespace().collect::<Vec<_>>().join(" ");
+        if tags.insert(name.trim().to_string(), value).is_some() {
+            return Err(format!("duplicate tag {}=", name.trim()));
+        }
+    }
+    Ok(tags)
+}
+
+fn base64_value(value: &str) -> Result<Vec<u8>, String> {
+    let compact: String = value.split_whitespace().collect();
+    BASE64.decode(compact).map_err(|err| err.to_string())
+}
+
+fn check(signature: &Field, fields: &[Field], body: &[u8], records: &Records) -> DkimResult {
+    let value = String::from_utf8_lossy(signature.value());
+    let tags = tags(&value).unwrap_or_default();
+    let tag = |name: &str| tags.get(name).cloned().unwrap_or_default();
+    let mut result = DkimResult {
+        domain: tag("d").to_ascii_lowercase(),
+        selector: tag("s"),
+        algorithm: tag("a"),
+        signed_headers: tag("h")
+            .split(':')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect(),
+        status: DkimStatus::Pass,
+        reason: None,
+    };
+    if let Err((status, reason)) = evaluate(signature, fields, body, records, &value) {
+        result.status = status;
+        result.reason = Some(reason);
+    }
+    result
+}
+
+type Failure = (DkimStatus, String);
+
+fn permerror(reason: impl Into<String>) -> Failure {
+    (DkimStatus::Permerror, reason.into())
+}
+
+fn fail(reason: impl Into<String>) -> Failure {
+    (DkimStatus::Fail, reason.into())
+}
+
+fn evaluate(
+    signature: &Field,
+    fields: &[Field],
+    body: &[u8],
+    records: &Records,
+    value: &str,
+) -> Result<(), Failure> {
+    let tags = tags(value).map_err(permerror)?;
+    let required = |name: &str| {
+        tags.get(name)
+            .map(String::as_str)
+            .ok_or_else(|| permerror(format!("missing {name}= tag")))
+    };
+    if required("v")? != "1" {
+        return Err(permerror("unsupported version"));
+    }
+    let algorithm = required("a")?;
+    let key_type = match algorithm {
+        "rsa-sha256" => "rsa",
+        "ed25519-sha256" => "ed25519",
+        other => return Err(permerror(format!("unsupported algorithm {other}"))),
+    };
+    let domain = required("d")?.to_ascii_lowercase();
+    let selector = required("s")?;
+    let signed: Vec<String> = required("h")?
+        .split(':')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+    if !signed.iter().any(|name| name == "from") {
+        return Err(permerror("the From header is not signed"));
+    }
+    let expected_body_hash = base64_value(required("bh")?)
+        .map_err(|err| permerror(format!("invalid bh= tag: {err}")))?;
+    let signature_bytes =
+        base64_value(required("b")?).map_err(|err| permerror(format!("invalid b= tag: {err}")))?;
+    if let Some(identity) = tags.get("i") {
+        let identity_domain = identity
+            .rsplit('@')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if identity_domain != domain && !identity_domain.ends_with(&format!(".{domain}")) {
+            return Err(permerror(format!("identity {identity} is not in {domain}")));
+        }
+    }
+    let (header_canon, body_canon) = match tags.get("c").map(String::as_str) {
+        None => (Canonicalization::Simple, Canonicalization::Simple),
+        Some(c) => {
+            let (header, body) = c.split_once('/').unwrap_or((c, "simple"));
+            let parse = |name: &str| match name {
+                "simple" => Ok(Canonicalization::Simple),
+                "relaxed" => Ok(Canonicalization::Relaxed),
+                other => Err(permerror(format!("unknown canonicalization {other}"))),
+            };
+            (parse(header)?, parse(body)?)
+        }
+    };
+    let length = tags
+        .get("l")
+        .map(|l| l.parse::<usize>().map_err(|_| permerror("invalid l= tag")))
+        .transpose()?;
+    if let Some(expires) = tags.get("x") {
+        let expires: i64 = expires.parse().map_err(|_| permerror("invalid x= tag"))?;
+        if expires < Utc::now().timestamp() {
+            return Err(fail(format!("signature expired at {expires}")));
+        }
+    }
+
+    let key = key(records, selector, &domain, key_type)?;
+
+    let mut canonical_body = canonical_body(body, body_canon);
+    if let Some(length) = length {
+        if length > canonical_body.len() {
+            return Err(fail("l= is longer than the body"));
+        }
+        canonical_body.truncate(length);
+    }
+    if Sha256::digest(&canonical_body).as_slice() != expected_body_hash {
+        return Err(fail(
+            "body hash does not match; the body was changed after signing",
+        ));
+    }
+
+    let mut hasher = Sha256::new();
+    let mut used = vec![false; fields.len()];
+    for name in &signed {
+        // Each name takes the last instance not used yet, bottom-up.
+        let found = (0..fields.len())
+            .rev()
+            .find(|&i| !used[i] && fields[i].name.eq_ignore_ascii_case(name));
+        if let Some(i) = found {
+            used[i] = true;
+            hasher.update(canonical_header(&fields[i], header_canon));
+        }
+    }
+    let unsigned = without_signature_value(signature);
+    let mut own = canonical_header(&unsigned_field(signature, &unsigned), header_canon);
+    // The signature's own header is hashed without its final CRLF.
+    own.truncate(own.len().saturating_sub(2));
+    hasher.update(own);
+    let header_hash = hasher.finalize();
+
+    let verified = match key {
+        Key::Rsa(key) => key
+            .verify(
+                Pkcs1v15Sign::new::<Sha256>(),
+                &header_hash,
+                &signature_bytes,
+            )
+            .is_ok(),
+        Key::Ed25519(key) => Signature::from_slice(&signature_bytes)
+            .is_ok_and(|signature| key.verify_strict(&header_hash, &signature).is_ok()),
+    };
+    if verified {
+        Ok(())
+    } else {
+        Err(fail(
+            "signature does not verify; the signed headers were changed or the key does not match",
+        ))
+    }
+}
+
+enum Key {
+    Rsa(RsaPublicKey),
+    Ed25519(VerifyingKey),
+}
+
+/// Looks up and decodes the public key at `selector._domainkey.domain`.
+fn key(records: &Records, selector: &str, domain: &str, key_type: &str) -> Result<Key, Failure> {
+    let name = format!("{selector}._domainkey.{domain}");
+    let record = records
+        .txt(&name)
+        .into_iter()
+        .next()
+        .ok_or_else(|| permerror(format!("no key record at {name}")))?;
+    let tags = tags(record).map_err(|err| permerror(format!("invalid key record: {err}")))?;
+    if tags.get("v").is_some_and(|v| v != "DKIM1") {
+        return Err(permerror("invalid key record: unsupported version"));
+    }
+    let k = tags.get("k").map_or("rsa", String::as_str);
+    if k != key_type {
+        return Err(permerror(format!("key is {k}, signature is {key_type}")));
+    }
+    if let Some(hashes) = tags.get("h") {
+        if !hashes.split(':').any(|hash| hash.trim() == "sha256") {
+            return Err(permerror("key does not allow sha256"));
+        }
+    }
+    let data = tags.get("p").map(String::as_str).unwrap_or_default();
+    if data.is_empty() {
+        return Err(permerror("key has been revoked"));
+    }
+    let data = base64_value(data).map_err(|err| permerror(format!("invalid key data: {err}")))?;
+    match key_type {
+        "rsa" => {
+            let key = RsaPublicKey::from_public_key_der(&data)
+                .or_else(|_| RsaPublicKey::from_pkcs1_der(&data))
+                .map_err(|_| permerror("invalid RSA key data"))?;
+            if key.size() * 8 < MIN_RSA_BITS {
+                return Err(permerror(format!(
+                    "RSA key is shorter than {MIN_RSA_BITS} bits"
+                )));
+            }
+            Ok(Key::Rsa(key))
+        }
+        _ => {
+            let bytes: [u8; 32] = data
+                .as_slice()
+                .try_into()
+                .map_err(|_| permerror("Ed25519 key is not 32 bytes"))?;
+            VerifyingKey::from_bytes(&bytes)
+                .map(Key::Ed25519)
+                .map_err(|_| permerror("invalid Ed25519 key data"))
+        }
+    }
+}
+
+/// The signature field's bytes with the value of its `b=` tag removed.
+fn without_signature_value(signature: &Field) -> Vec<u8> {
+    let raw = signature.raw;
+    let prefix = signature.colon + 1;
+    let mut out = raw[..prefix].to_vec();
+    let value = &raw[prefix..];
+    let mut first = true;
+    for spec in value.split(|&b| b == b';') {
+        if !first {
+            out.push(b';');
+        }
+        first = false;
+        let eq = spec.iter().position(|&b| b == b'=');
+        let is_b = eq.is_some_and(|eq| {
+            spec[..eq]
+                .iter()
+                .filter(|b| !b.is_ascii_whitespace())
+                .eq(b"b".iter())
+        });
+        match eq {
+            Some(eq) if is_b => {
+                out.extend_from_slice(&spec[..=eq]);
+                // Keep the line ending if `b=` is the last tag.
+                if spec.ends_with(b"\r\n") {
+                    out.extend_from_slice(b"\r\n");
+                }
+            }
+            _ => out.extend_from_slice(spec),
+        }
+    }
+    out
+}
+
+fn unsigned_field<'a>(signature: &Field<'a>, raw: &'a [u8]) -> Field<'a> {
+    Field {
+        name: signature.name,
+        raw,
+        colon: signature.colon,
+    }
+}
+
+fn canonical_header(field: &Field, canonicalization: Canonicalization) -> Vec<u8> {
+    match canonicalization {
+        Canonicalization::Simple => field.raw.to_vec(),
+        Canonicalization::Relaxed => {
+            let mut out = field.name.to_ascii_lowercase().into_bytes();
+            out.push(b':');
+            let mut value = Vec::new();
+            let mut space = false;
+            for &b in field.value() {
+                match b {
+                    b'\r' | b'\n' => {}
+                    b' ' | b'\t' => space = true,
+                    b => {
+                        if space && !value.is_empty() {
+                            value.push(b' ');
+                        }
+                        space = false;
+                        value.push(b);
+                    }
+                }
+            }
+            out.extend(value);
+            out.extend_from_slice(b"\r\n");
+            out
+        }
+    }
+}
+
+fn canonical_body(body: &[u8], canonicalization: Canonicalization) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len());
+    match canonicalization {
+        Canonicalization::Simple => out.extend_from_slice(body),
+        Canonicalization::Relaxed => {
+            for line in body.split_inclusive(|&b| b == b'\n') {
+                let content = line.strip_suffix(b"\r\n").unwrap_or(line);
+                let mut space = false;
+                let mut cooked = Vec::with_capacity(content.len());
+                for &b in content {
+                    if b == b' ' || b == b'\t' {
+                        space = true;
+                    } else {
+                        if space {
+                            cooked.push(b' ');
+                        }
+                        space = false;
+                        cooked.push(b);
+                    }
+                }
+                // Trailing whitespace is dropped by not flushing `space`.
+                out.extend(cooked);
+                if line.ends_with(b"\r\n") {
+                    out.extend_from_slice(b"\r\n");
+                }
+            }
+        }
+    }
+    // Both forms drop empty lines at the end of the body.
+    while out.ends_with(b"\r\n\r\n") {
+        out.truncate(out.len() - 2);
+    }
+    match canonicalization {
+        Canonicalization::Simple if out.is_empty() || !out.ends_with(b"\r\n") => {
+            out.extend_from_slice(b"\r\n");
+        }
+        Canonicalization::Relaxed if out == b"\r\n" => out.clear(),
+        Canonicalization::Relaxed if !out.is_empty() && !out.ends_with(b"\r\n") => {
+            out.extend_from_slice(b"\r\n");
+        }
+        _ => {}
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example from RFC 6376, section 3.4.5.
+    const EXAMPLE: &[u8] = b"A: X\r\nB : Y\t\r\n\tZ  \r\n\r\n C \r\nD \t E\r\n\r\n\r\n";
+
+    fn canonical(canonicalization: Canonicalization) -> Vec<u8> {
+        let (fields, body) = split(EXAMPLE);
+        let mut out = Vec::new();
+        for field in &fields {
+            out.extend(canonical_header(field, canonicalization));
+        }
+        out.extend_from_slice(b"\r\n");
+        out.extend(canonical_body(body, canonicalization));
+        out
+    }
+
+    #[test]
+    fn canonicalizes_like_the_rfc_example() {
+        assert_eq!(
+            canonical(Canonicalization::Relaxed),
+            b"a:X\r\nb:Y Z\r\n\r\n C\r\nD E\r\n"
+        );
+        assert_eq!(
+            canonical(Canonicalization::Simple),
+            b"A: X\r\nB : Y\t\r\n\tZ  \r\n\r\n C \r\nD \t E\r\n"
+        );
+    }
+
+    #[test]
+    fn canonicalizes_empty_bodies() {
+        assert_eq!(canonical_body(b"", Canonicalization::Simple), b"\r\n");
+        assert_eq!(
+            canonical_body(b"\r\n\r\n", Canonicalization::Simple),
+            b"\r\n"
+        );
+        assert_eq!(canonical_body(b"", Canonicalization::Relaxed), b"");
+        assert_eq!(canonical_body(b" \r\n\r\n", Canonicalization::Relaxed), b"");
+        assert_eq!(canonical_body(b"x", Canonicalization::Relaxed), b"x\r\n");
+    }
+
+    #[test]
+    fn removes_only_the_signature_value() {
+        let raw = b"DKIM-Signature: v=1; bh=abc=; b=sig\r\n nature;\r\n d=x\r\n";
+        let field = field(raw).unwrap();
+        assert_eq!(
+            without_signature_value(&field),
+            b"DKIM-Signature: v=1; bh=abc=; b=;\r\n d=x\r\n"
+        );
+        let raw = b"DKIM-Signature: v=1; b=sig\r\n";
+        assert_eq!(
+            without_signature_value(&super::field(raw).unwrap()),
+            b"DKIM-Signature: v=1; b=\r\n"
+        );
+    }
+}
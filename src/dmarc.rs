@@ -0,0 +1,396 @@
+//! DMARC evaluation (RFC 7489) of the header From domain, combining the DKIM
+//! and SPF results with the policy in the offline [`Records`].
+//!
+//! Without a public suffix list, the organizational domain is taken to be
+//! the last two labels of a name, so `mail.example.com` and `example.com`
+//! align but `a.example.co.uk` and `b.example.co.uk` do too.
+
+use serde::{Deserialize, Serialize};
+
+use crate::dkim::{self, DkimResult, DkimStatus};
+use crate::dns::Records;
+use crate::mime::{address, split_head_body, Address, Headers};
+use crate::spf::{self, SpfResult, SpfStatus};
+use crate::store::{Envelope, SessionInfo};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DmarcStatus {
+    /// The From domain publishes no policy.
+    None,
+    /// An aligned DKIM signature or SPF check passed.
+    Pass,
+    Fail,
+    /// The policy record or the From header is invalid.
+    Permerror,
+}
+
+/// What the domain asks receivers to do with failing mail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Policy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl Policy {
+    fn parse(value: &str) -> Option<Policy> {
+        match value.to_ascii_lowercase().as_str() {
+            "none" => Some(Policy::None),
+            "quarantine" => Some(Policy::Quarantine),
+            "reject" => Some(Policy::Reject),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Alignment {
+    /// The organizational domains must match.
+    Relaxed,
+    /// The domains must match exactly.
+    Strict,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DmarcResult {
+    pub status: DmarcStatus,
+    /// Domain of the header From address.
+    pub from_domain: Option<String>,
+    /// Where the policy record was found: the From domain or its
+    /// organizational domain.
+    pub record_domain: Option<String>,
+    /// The policy that applies, `sp=` for subdomains that inherit it.
+    pub policy: Option<Policy>,
+    /// Share of failing mail the policy applies to (`pct=`).
+    pub percent: Option<u8>,
+    pub dkim_alignment: Alignment,
+    pub spf_alignment: Alignment,
+    /// Whether a passing DKIM signature aligns with the From domain.
+    pub dkim_aligned: bool,
+    /// Whether a passing SPF check aligns with the From domain.
+    pub spf_aligned: bool,
+    /// What a receiver would do: the policy on failure, `none` otherwise.
+    pub disposition: Policy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// The DKIM, SPF and DMARC results for a message, worked out once when it is
+/// received, so they do not change as signatures expire or records are
+/// edited later.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Authentication {
+    /// One result per `DKIM-Signature` header.
+    pub dkim: Vec<DkimResult>,
+    pub spf: SpfResult,
+    pub dmarc: DmarcResult,
+}
+
+impl Authentication {
+    /// Checks a message received over SMTP against `records`.
+    pub fn check(
+        raw: &[u8],
+        envelope: &Envelope,
+        session: &SessionInfo,
+        records: &Records,
+    ) -> Authentication {
+        let dkim = dkim::verify(raw, records);
+        let spf = spf::check(
+            session.peer.ip(),
+            &envelope.mail_from,
+            &session.helo,
+            records,
+        );
+        let (head, _) = split_head_body(raw);
+        let from = Headers::parse(head)
+            .get_raw("From")
+            .map(address::parse_list)
+            .unwrap_or_default();
+        let dmarc = evaluate(&from, &dkim, &spf, records);
+        Authentication { dkim, spf, dmarc }
+    }
+}
+
+/// Evaluates the policy of the domain in `from`, the header From addresses.
+pub fn evaluate(
+    from: &[Address],
+    dkim: &[DkimResult],
+    spf: &SpfResult,
+    records: &Records,
+) -> DmarcResult {
+    let mut result = DmarcResult {
+        status: DmarcStatus::None,
+        from_domain: None,
+        record_domain: None,
+        policy: None,
+        percent: None,
+        dkim_alignment: Alignment::Relaxed,
+        spf_alignment: Alignment::Relaxed,
+        dkim_aligned: false,
+        spf_aligned: false,
+        disposition: Policy::None,
+        reason: None,
+    };
+    let mut domains: Vec<String> = from
+        .iter()
+        .filter_map(|address| address.email.rsplit_once('@'))
+        .map(|(_, domain)| domain.trim_end_matches('.').to_ascii_lowercase())
+        .collect();
+    domains.sort();
+    domains.dedup();
+    let from_domain = match domains.as_slice() {
+        [] => return permerror(result, "the message has no From address".into()),
+        [domain] => domain.clone(),
+        _ => return permerror(result, "the From addresses are in different domains".into()),
+    };
+    result.from_domain = Some(from_domain.clone());
+
+    let organizational = organizational_domain(&from_domain);
+    let (record_domain, record) = match discover(&from_domain, records) {
+        Ok(Some(record)) => (from_domain.clone(), record),
+        Ok(None) if organizational != from_domain => match discover(&organizational, records) {
+            Ok(Some(record)) => (organizational.clone(), record),
+            Ok(None) => return no_policy(result),
+            Err(reason) => return permerror(result, reason),
+        },
+        Ok(None) => return no_policy(result),
+        Err(reason) => return permerror(result, reason),
+    };
+    result.record_domain = Some(record_domain.clone());
+    let tags = match dkim::tags(record) {
+        Ok(tags) => tags,
+        Err(err) => return permerror(result, format!("invalid DMARC record: {err}")),
+    };
+    let Some(policy) = tags.get("p").and_then(|p| Policy::parse(p)) else {
+        return permerror(
+            result,
+            "invalid DMARC record: missing or unknown p= tag".into(),
+        );
+    };
+    let policy = match tags.get("sp") {
+        Some(sp) if record_domain != from_domain => match Policy::parse(sp) {
+            Some(sp) => sp,
+            None => return permerror(result, "invalid DMARC record: unknown sp= tag".into()),
+        },
+        _ => policy,
+    };
+    let alignment = |tag: &str| match tags.get(tag).map(String::as_str) {
+        None | Some("r") => Ok(Alignment::Relaxed),
+        Some("s") => Ok(Alignment::Strict),
+        Some(other) => Err(format!("invalid DMARC record: {tag}={other}")),
+    };
+    let (dkim_alignment, spf_alignment) = match (alignment("adkim"), alignment("aspf")) {
+        (Ok(dkim), Ok(spf)) => (dkim, spf),
+        (Err(reason), _) | (_, Err(reason)) => return permerror(result, reason),
+    };
+    let percent = match tags.get("pct") {
+        None => 100,
+        Some(pct) => match pct.parse::<u8>().ok().filter(|&pct| pct <= 100) {
+            Some(pct) => pct,
+            None => return permerror(result, format!("invalid DMARC record: pct={pct}")),
+        },
+    };
+
+    result.policy = Some(policy);
+    result.percent = Some(percent);
+    result.dkim_alignment = dkim_alignment;
+    result.spf_alignment = spf_alignment;
+    result.dkim_aligned = dkim.iter().any(|signature| {
+        signature.status == DkimStatus::Pass
+            && aligned(&signature.domain, &from_domain, dkim_alignment)
+    });
+    result.spf_aligned =
+        spf.status == SpfStatus::Pass && aligned(&spf.domain, &from_domain, spf_alignment);
+    if result.dkim_aligned || result.spf_aligned {
+        result.status = DmarcStatus::Pass;
+    } else {
+        result.status = DmarcStatus::Fail;
+        result.disposition = policy;
+        result.reason = Some(failure_reason(dkim, spf, &from_domain));
+    }
+    result
+}
+
+fn no_policy(mut result: DmarcResult) -> DmarcResult {
+    let domain = result.from_domain.as_deref().unwrap_or_default();
+    result.reason = Some(format!("no DMARC record for {domain}"));
+    result
+}
+
+fn permerror(mut result: DmarcResult, reason: String) -> DmarcResult {
+    result.status = DmarcStatus::Permerror;
+    result.reason = Some(reason);
+    result
+}
+
+/// The DMARC record at `_dmarc.{domain}`, if there is exactly one.
+fn discover<'a>(domain: &str, records: &'a Records) -> Result<Option<&'a str>, String> {
+    let name = format!("_dmarc.{domain}");
+    let found: Vec<&str> = records
+        .txt(&name)
+        .into_iter()
+        .filter(|text| {
+            let version = text.split(';').next().unwrap_or_default();
+            version.replace(' ', "") == "v=DMARC1"
+        })
+        .collect();
+    match found.as_slice() {
+        [record] => Ok(Some(record)),
+        [] => Ok(None),
+        _ => Err(format!("more than one DMARC record at {name}")),
+    }
+}
+
+fn failure_reason(dkim: &[DkimResult], spf: &SpfResult, from_domain: &str) -> String {
+    let passed: Vec<&str> = dkim
+        .iter()
+        .filter(|signature| signature.status == DkimStatus::Pass)
+        .map(|signature| signature.domain.as_str())
+        .collect();
+    let dkim = match passed.as_slice() {
+        [] => "no DKIM signature passed".to_string(),
+        domains => format!(
+            "DKIM passed only for {}, which does not align",
+            domains.join(", ")
+        ),
+    };
+    let spf = if spf.status == SpfStatus::Pass {
+        format!("SPF passed for {}, which does not align", spf.domain)
+    } else {
+        format!("SPF did not pass for {}", spf.domain)
+    };
+    format!("{dkim} with {from_domain}; {spf}")
+}
+
+fn aligned(domain: &str, from_domain: &str, alignment: Alignment) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    match alignment {
+        Alignment::Strict => domain == from_domain,
+        Alignment::Relaxed => organizational_domain(&domain) == organizational_domain(from_domain),
+    }
+}
+
+/// The last two labels of `domain`.
+fn organizational_domain(domain: &str) -> String {
+    let labels: Vec<&str> = domain.split('.').collect();
+    labels[labels.len().saturating_sub(2)..].join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::spf::SpfIdentity;
+
+    const RECORDS: &str = r#"{
+        "_dmarc.example.com": "v=DMARC1; p=reject; sp=quarantine; adkim=s",
+        "_dmarc.example.org": "v=DMARC1; p=none; pct=20"
+    }"#;
+
+    fn from(email: &str) -> Vec<Address> {
+        vec![Address {
+            name: None,
+            email: email.to_string(),
+        }]
+    }
+
+    fn signature(domain: &str, status: DkimStatus) -> DkimResult {
+        DkimResult {
+            domain: domain.to_string(),
+            selector: "mail".to_string(),
+            algorithm: "ed25519-sha256".to_string(),
+            signed_headers: vec!["from".to_string()],
+            status,
+            reason: None,
+        }
+    }
+
+    fn spf(domain: &str, status: SpfStatus) -> SpfResult {
+        SpfResult {
+            status,
+            identity: SpfIdentity::Mailfrom,
+            domain: domain.to_string(),
+            ip: Ipv4Addr::LOCALHOST.into(),
+            mechanism: None,
+            reason: None,
+        }
+    }
+
+    fn evaluate_with(from_address: &str, dkim: &[DkimResult], spf: &SpfResult) -> DmarcResult {
+        let records = Records::from_json(RECORDS).unwrap();
+        evaluate(&from(from_address), dkim, spf, &records)
+    }
+
+    #[test]
+    fn passes_with_an_aligned_signature() {
+        let none = spf("bounces.example.net", SpfStatus::Fail);
+        let result = evaluate_with(
+            "app@example.com",
+            &[
+                signature("example.com", DkimStatus::Fail),
+                signature("example.com", DkimStatus::Pass),
+            ],
+            &none,
+        );
+        assert_eq!(result.status, DmarcStatus::Pass);
+        assert!(result.dkim_aligned);
+        assert_eq!(result.policy, Some(Policy::Reject));
+        assert_eq!(result.disposition, Policy::None);
+
+        // adkim=s: a parent domain signature does not align.
+        let result = evaluate_with(
+            "app@mail.example.com",
+            &[signature("example.com", DkimStatus::Pass)],
+            &none,
+        );
+        assert_eq!(result.status, DmarcStatus::Fail);
+        assert_eq!(result.record_domain.as_deref(), Some("example.com"));
+        assert_eq!(result.policy, Some(Policy::Quarantine));
+        assert_eq!(result.disposition, Policy::Quarantine);
+        assert_eq!(
+            result.reason.as_deref(),
+            Some(
+                "DKIM passed only for example.com, which does not align with \
+                 mail.example.com; SPF did not pass for bounces.example.net"
+            )
+        );
+    }
+
+    #[test]
+    fn passes_with_relaxed_spf_alignment() {
+        let result = evaluate_with(
+            "app@example.org",
+            &[],
+            &spf("bounces.example.org", SpfStatus::Pass),
+        );
+        assert_eq!(result.status, DmarcStatus::Pass);
+        assert!(result.spf_aligned);
+        assert_eq!(result.percent, Some(20));
+
+        let result = evaluate_with("app@example.org", &[], &spf("example.net", SpfStatus::Pass));
+        assert_eq!(result.status, DmarcStatus::Fail);
+        assert_eq!(result.disposition, Policy::None);
+    }
+
+    #[test]
+    fn reports_missing_policies_and_bad_from_headers() {
+        let pass = spf("example.net", SpfStatus::Pass);
+        let result = evaluate_with("app@example.net", &[], &pass);
+        assert_eq!(result.status, DmarcStatus::None);
+        assert_eq!(
+            result.reason.as_deref(),
+            Some("no DMARC record for example.net")
+        );
+
+        let records = Records::from_json(RECORDS).unwrap();
+        let mut addresses = from("app@example.com");
+        addresses.extend(from("app@example.org"));
+        let result = evaluate(&addresses, &[], &pass, &records);
+        assert_eq!(result.status, DmarcStatus::Permerror);
+        assert_eq!(result.from_domain, None);
+    }
+}
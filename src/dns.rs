@@ -0,0 +1,397 @@
+//! An offline record set that stands in for DNS.
+//!
+//! Checks that would normally query DNS, such as DKIM key lookups, read
+//! records from here instead. They then work without network access and
+//! with keys and policies that are not published anywhere. Records come from
+//! a zone file or a JSON map given with `--dns-records`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::{Error, Result};
+
+/// One resource record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Mx {
+        preference: u16,
+        exchange: String,
+    },
+    /// The character-strings of a TXT record, joined.
+    Txt(String),
+}
+
+/// Records by owner name. Cloning is cheap; clones share the records.
+#[derive(Debug, Clone, Default)]
+pub struct Records {
+    names: Arc<BTreeMap<String, Vec<Record>>>,
+}
+
+impl Records {
+    /// Reads a JSON map if the file name ends in `.json`, a zone file
+    /// otherwise.
+    pub fn load(path: &Path) -> Result<Records> {
+        let invalid = |err: String| Error::Config(format!("{}: {err}", path.display()));
+        let text = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+        if path.extension().is_some_and(|ext| ext == "json") {
+            Records::from_json(&text).map_err(invalid)
+        } else {
+            Records::parse_zone(&text).map_err(invalid)
+        }
+    }
+
+    /// Parses a master file (RFC 1035) with A, AAAA, MX and TXT records.
+    /// `$ORIGIN`, `@`, relative names, parentheses and omitted owner names
+    /// work as in BIND; TTLs and classes are ignored, as are other types.
+    pub fn parse_zone(text: &str) -> Result<Records, String> {
+        let mut records = Records::default();
+        let mut origin = String::new();
+        let mut owner: Option<String> = None;
+        for (number, entry) in zone_entries(text) {
+            let fail = |err: String| format!("line {number}: {err}");
+            let Some(first) = entry.tokens.first() else {
+                continue;
+            };
+            if first.text.eq_ignore_ascii_case("$ORIGIN") {
+                let name = entry
+                    .tokens
+                    .get(1)
+                    .ok_or_else(|| fail("$ORIGIN needs a name".into()))?;
+                origin = absolute(&name.text, &origin);
+                continue;
+            }
+            if first.text.starts_with('$') {
+                continue;
+            }
+            let mut tokens = entry.tokens.iter().peekable();
+            if !entry.indented {
+                let name = tokens.next().expect("entry has a first token");
+                owner = Some(absolute(&name.text, &origin));
+            }
+            let name = owner
+                .clone()
+                .ok_or_else(|| fail("record without an owner name".into()))?;
+            // Optional TTL and class, in either order.
+            while let Some(token) = tokens.peek() {
+                let text = &token.text;
+                let ttl = !token.quoted
+                    && text.chars().all(|c| c.is_ascii_alphanumeric())
+                    && text.starts_with(|c: char| c.is_ascii_digit());
+                if ttl
+                    || ["IN", "CH", "HS"]
+                        .iter()
+                        .any(|class| text.eq_ignore_ascii_case(class))
+                {
+                    tokens.next();
+                } else {
+                    break;
+                }
+            }
+            let kind = tokens
+                .next()
+                .ok_or_else(|| fail("record without a type".into()))?;
+            let data: Vec<&Token> = tokens.collect();
+            if let Some(record) = parse_record(&kind.text, &data, &origin).map_err(fail)? {
+                records.insert(&name, record);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Parses a JSON object mapping names to records:
+    ///
+    /// ```json
+    /// {
+    ///   "mail._domainkey.example.com": "v=DKIM1; k=ed25519; p=...",
+    ///   "example.com": { "TXT": ["v=spf1 mx -all"], "MX": ["10 mx.example.com"] }
+    /// }
+    /// ```
+    ///
+    /// A string or a list of strings is a set of TXT records. An object maps
+    /// types to record data, as written in a zone file.
+    pub fn from_json(text: &str) -> Result<Records, String> {
+        let map: BTreeMap<String, Value> =
+            serde_json::from_str(text).map_err(|err| err.to_string())?;
+        let mut records = Records::default();
+        for (name, value) in map {
+            let name = absolute(&name, "");
+            let fail = |err: String| format!("{name}: {err}");
+            let by_type = match value {
+                Value::Object(types) => types.into_iter().collect(),
+                other => vec![("TXT".to_string(), other)],
+            };
+            for (kind, value) in by_type {
+                let values = match value {
+                    Value::String(value) => vec![value],
+                    Value::Array(values) => values
+                        .into_iter()
+                        .map(|value| match value {
+                            Value::String(value) => Ok(value),
+                            _ => Err(fail("record data must be strings".into())),
+                        })
+                        .collect::<Result<_, _>>()?,
+                    _ => return Err(fail("record data must be strings".into())),
+                };
+                for value in values {
+                    let record = if kind.eq_ignore_ascii_case("TXT") {
+                        // Taken as the joined text, without zone quoting.
+                        Some(Record::Txt(value))
+                    } else {
+                        let tokens = tokenize(&value);
+                        let data: Vec<&Token> = tokens.iter().collect();
+                        parse_record(&kind, &data, "").map_err(fail)?
+                    };
+                    match record {
+                        Some(record) => records.insert(&name, record),
+                        None => return Err(fail(format!("unsupported record type {kind}"))),
+                    }
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Adds a record.
+    pub fn insert(&mut self, name: &str, record: Record) {
+        Arc::make_mut(&mut self.names)
+            .entry(absolute(name, ""))
+            .or_default()
+            .push(record);
+    }
+
+    /// Every record at `name`, in the order given.
+    pub fn get(&self, name: &str) -> &[Record] {
+        self.names
+            .get(&absolute(name, ""))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    pub fn txt(&self, name: &str) -> Vec<&str> {
+        self.get(name)
+            .iter()
+            .filter_map(|record| match record {
+                Record::Txt(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Number of names with records.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+/// A name in canonical form: lowercase, without the trailing dot.
+/// Relative names get `origin` appended.
+fn absolute(name: &str, origin: &str) -> String {
+    let name = name.to_ascii_lowercase();
+    if name == "@" {
+        return origin.to_string();
+    }
+    match name.strip_suffix('.') {
+        Some(name) => name.to_string(),
+        None if origin.is_empty() => name,
+        None => format!("{name}.{origin}"),
+    }
+}
+
+fn parse_record(kind: &str, data: &[&Token], origin: &str) -> Result<Option<Record>, String> {
+    let single = || match data {
+        [token] => Ok(token.text.as_str()),
+        _ => Err(format!("{kind} record needs exactly one value")),
+    };
+    let record = match kind.to_ascii_uppercase().as_str() {
+        "A" => Record::A(single()?.parse().map_err(|_| "invalid IPv4 address")?),
+        "AAAA" => Record::Aaaa(single()?.parse().map_err(|_| "invalid IPv6 address")?),
+        "MX" => match data {
+            [preference, exchange] => Record::Mx {
+                preference: preference
+                    .text
+                    .parse()
+                    .map_err(|_| "invalid MX preference")?,
+                exchange: absolute(&exchange.text, origin),
+            },
+            _ => return Err("MX record needs a preference and an exchange".into()),
+        },
+        "TXT" if data.is_empty() => return Err("TXT record without text".into()),
+        "TXT" => Record::Txt(data.iter().map(|token| token.text.as_str()).collect()),
+        _ => return Ok(None),
+    };
+    Ok(Some(record))
+}
+
+#[derive(Debug)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// A logical zone file entry: one line, or several joined by parentheses.
+#[derive(Debug)]
+struct Entry {
+    /// Whether the entry starts with whitespace, meaning it has the
+    /// previous entry's owner name.
+    indented: bool,
+    tokens: Vec<Token>,
+}
+
+/// Splits a zone file into entries, numbered by their first line.
+fn zone_entries(text: &str) -> Vec<(usize, Entry)> {
+    let mut entries = Vec::new();
+    let mut pending: Option<(usize, Entry)> = None;
+    let mut depth = 0usize;
+    for (index, line) in text.lines().enumerate() {
+        let (tokens, change) = tokenize_line(line);
+        match pending.as_mut() {
+            Some((_, entry)) => entry.tokens.extend(tokens),
+            None => {
+                let entry = Entry {
+                    indented: line.starts_with([' ', '\t']),
+                    tokens,
+                };
+                pending = Some((index + 1, entry));
+            }
+        }
+        depth = depth.saturating_add_signed(change);
+        if depth == 0 {
+            entries.extend(pending.take());
+        }
+    }
+    entries.extend(pending);
+    entries
+}
+
+fn tokenize(text: &str) -> Vec<Token> {
+    tokenize_line(text).0
+}
+
+/// Splits a line into tokens, dropping comments and parentheses. Also
+/// returns by how much the line changes the parenthesis depth.
+fn tokenize_line(line: &str) -> (Vec<Token>, isize) {
+    let mut tokens = Vec::new();
+    let mut depth = 0;
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ';' => break,
+            '(' | ')' => {
+                depth += if c == '(' { 1 } else { -1 };
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => text.extend(chars.next()),
+                        c => text.push(c),
+                    }
+                }
+                tokens.push(Token { text, quoted: true });
+            }
+            _ => {
+                let mut text = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, ';' | '(' | ')' | '"') {
+                        break;
+                    }
+                    text.push(c);
+                    chars.next();
+                }
+                tokens.push(Token {
+                    text,
+                    quoted: false,
+                });
+            }
+        }
+    }
+    (tokens, depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_zone_files() {
+        let records = Records::parse_zone(
+            r#"$ORIGIN example.com.
+$TTL 3600
+@           IN  MX  10 mx1
+                MX  20 mx2.example.net.
+            300 IN  TXT "v=spf1 ip4:192.0.2.0/24 " "-all" ; comment
+mx1             A   192.0.2.10
+                AAAA 2001:db8::10
+brisbane._domainkey IN TXT ( "v=DKIM1; k=ed25519; "
+    "p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=" )
+_dmarc.Other.Test. TXT "v=DMARC1; p=reject"
+www CNAME example.com.
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            records.get("example.com"),
+            [
+                Record::Mx {
+                    preference: 10,
+                    exchange: "mx1.example.com".into()
+                },
+                Record::Mx {
+                    preference: 20,
+                    exchange: "mx2.example.net".into()
+                },
+                Record::Txt("v=spf1 ip4:192.0.2.0/24 -all".into()),
+            ]
+        );
+        assert_eq!(
+            records.get("MX1.example.com."),
+            [
+                Record::A("192.0.2.10".parse().unwrap()),
+                Record::Aaaa("2001:db8::10".parse().unwrap())
+            ]
+        );
+        assert_eq!(
+            records.txt("brisbane._domainkey.example.com"),
+            ["v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="]
+        );
+        assert_eq!(records.txt("_dmarc.other.test"), ["v=DMARC1; p=reject"]);
+        assert!(records.get("www.example.com").is_empty());
+    }
+
+    #[test]
+    fn reports_zone_errors_by_line() {
+        let err = Records::parse_zone("a.test. TXT \"x\"\nb.test. A 300.1.1.1\n").unwrap_err();
+        assert_eq!(err, "line 2: invalid IPv4 address");
+    }
+
+    #[test]
+    fn parses_json_maps() {
+        let records = Records::from_json(
+            r#"{
+                "s1._domainkey.example.com": "v=DKIM1; p=abc",
+                "example.com.": { "TXT": ["v=spf1 mx -all"], "MX": "10 mx.example.com", "A": ["192.0.2.1"] }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(records.txt("s1._domainkey.example.com"), ["v=DKIM1; p=abc"]);
+        assert_eq!(records.txt("example.com"), ["v=spf1 mx -all"]);
+        assert_eq!(records.get("example.com").len(), 3);
+        assert!(Records::from_json(r#"{"x": {"SRV": "1 2 3 x"}}"#).is_err());
+    }
+}
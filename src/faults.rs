@@ -0,0 +1,279 @@
+//! Fault injection: rules that make the SMTP listener misbehave on purpose,
+//! so retry and bounce handling in the application under test can be
+//! exercised.
+//!
+//! Rules are kept behind a shared [`Faults`] handle and can be replaced at
+//! any time, typically through `PUT /api/faults`. Sessions consult them at
+//! each stage of the conversation; the first matching rule that fires wins.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+/// Longest delay a rule may add, so a typo cannot wedge a test run.
+const MAX_DELAY_MS: u64 = 10 * 60 * 1000;
+
+/// A point in the SMTP conversation where a fault can be injected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Before the greeting.
+    Connect,
+    /// `HELO` or `EHLO`.
+    Helo,
+    /// `AUTH`.
+    Auth,
+    /// `MAIL FROM`.
+    Mail,
+    /// `RCPT TO`.
+    Rcpt,
+    /// The `DATA` command, before the client sends the message.
+    Data,
+    /// The end of the message data, before it is stored.
+    Message,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Connect => "connect",
+            Stage::Helo => "helo",
+            Stage::Auth => "auth",
+            Stage::Mail => "mail",
+            Stage::Rcpt => "rcpt",
+            Stage::Data => "data",
+            Stage::Message => "message",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What happens when a rule fires.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FaultAction {
+    /// Answer with this 4xx or 5xx reply instead of the normal one. At the
+    /// connect stage the connection is closed after the reply.
+    Reply {
+        code: u16,
+        /// Reply text; a generic one with an enhanced status code is used
+        /// if absent.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    /// Wait before handling the stage normally.
+    Delay { millis: u64 },
+    /// Close the connection without a reply. At the data stage the client
+    /// is first invited to send the message, so the drop happens mid-DATA.
+    Disconnect,
+    /// Stop responding and leave the connection open until the idle timeout,
+    /// so the client times out.
+    Timeout,
+}
+
+impl fmt::Display for FaultAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultAction::Reply { code, message } => {
+                write!(
+                    f,
+                    "reply {code} {}",
+                    FaultAction::reply_text(*code, message.as_deref())
+                )
+            }
+            FaultAction::Delay { millis } => write!(f, "delay {millis} ms"),
+            FaultAction::Disconnect => f.write_str("disconnect"),
+            FaultAction::Timeout => f.write_str("stop responding"),
+        }
+    }
+}
+
+impl FaultAction {
+    /// The text of a [`FaultAction::Reply`], or a default for its code.
+    pub fn reply_text(code: u16, message: Option<&str>) -> String {
+        match message {
+            Some(message) => message.to_string(),
+            None if code < 500 => "4.3.0 Temporary failure (injected)".to_string(),
+            None => "5.3.0 Permanent failure (injected)".to_string(),
+        }
+    }
+}
+
+/// One fault rule.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FaultRule {
+    pub stage: Stage,
+    pub action: FaultAction,
+    /// Chance, from 0 to 1, that the rule fires when it applies.
+    #[serde(default = "always")]
+    pub probability: f64,
+    /// Only apply when the command argument (the address for MAIL and RCPT,
+    /// the domain for HELO, the mechanism for AUTH) contains this text,
+    /// ignoring case.
+    #[serde(default, rename = "match", skip_serializing_if = "Option::is_none")]
+    pub matching: Option<String>,
+    /// Fire at most this many more times, then drop the rule.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub times: Option<u32>,
+}
+
+/// A complete rule set, as read from `--faults` and exchanged over the API.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FaultSet {
+    pub rules: Vec<FaultRule>,
+}
+
+fn always() -> f64 {
+    1.0
+}
+
+impl FaultRule {
+    fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.probability) {
+            return Err(format!(
+                "probability must be between 0 and 1, got {}",
+                self.probability
+            ));
+        }
+        if self.times == Some(0) {
+            return Err("times must be at least 1".to_string());
+        }
+        match &self.action {
+            FaultAction::Reply { code, .. } if !(400..=599).contains(code) => {
+                Err(format!("reply code must be 4xx or 5xx, got {code}"))
+            }
+            FaultAction::Delay { millis } if *millis > MAX_DELAY_MS => {
+                Err(format!("delay must be at most {MAX_DELAY_MS} ms"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn applies(&self, stage: Stage, argument: &str) -> bool {
+        self.stage == stage
+            && self.matching.as_ref().is_none_or(|needle| {
+                argument
+                    .to_lowercase()
+                    .contains(needle.to_lowercase().as_str())
+            })
+    }
+}
+
+/// The shared, runtime-changeable rule set. Cloning the handle is cheap.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    rules: Arc<Mutex<Vec<FaultRule>>>,
+}
+
+impl Faults {
+    pub fn new(rules: Vec<FaultRule>) -> Result<Faults, String> {
+        let faults = Faults::default();
+        faults.set(rules)?;
+        Ok(faults)
+    }
+
+    /// Replaces every rule, or leaves them untouched if one is invalid.
+    pub fn set(&self, rules: Vec<FaultRule>) -> Result<(), String> {
+        for (i, rule) in rules.iter().enumerate() {
+            rule.validate().map_err(|err| format!("rule {i}: {err}"))?;
+        }
+        *self.rules.lock().unwrap() = rules;
+        Ok(())
+    }
+
+    /// The current rules, with `times` showing what is left.
+    pub fn rules(&self) -> Vec<FaultRule> {
+        self.rules.lock().unwrap().clone()
+    }
+
+    /// Rolls the dice for the first rule that applies to `stage` and returns
+    /// its action if it fires.
+    pub fn check(&self, stage: Stage, argument: &str) -> Option<FaultAction> {
+        let mut rules = self.rules.lock().unwrap();
+        let index = rules.iter().position(|rule| {
+            rule.applies(stage, argument) && rand::random::<f64>() < rule.probability
+        })?;
+        let action = rules[index].action.clone();
+        if let Some(times) = &mut rules[index].times {
+            *times = times.saturating_sub(1);
+            if *times == 0 {
+                rules.remove(index);
+            }
+        }
+        Some(action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(json: &str) -> FaultRule {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn rules_fire_by_stage_match_and_count() {
+        let faults = Faults::new(vec![
+            rule(r#"{"stage": "rcpt", "match": "@Bounce.test", "action": {"type": "reply", "code": 550}}"#),
+            rule(r#"{"stage": "rcpt", "times": 2, "action": {"type": "disconnect"}}"#),
+        ])
+        .unwrap();
+        assert_eq!(faults.check(Stage::Mail, "a@bounce.test"), None);
+        assert_eq!(
+            faults.check(Stage::Rcpt, "x@bounce.test"),
+            Some(FaultAction::Reply {
+                code: 550,
+                message: None
+            })
+        );
+        assert_eq!(
+            faults.check(Stage::Rcpt, "b@example.test"),
+            Some(FaultAction::Disconnect)
+        );
+        assert_eq!(faults.rules()[1].times, Some(1));
+        assert_eq!(
+            faults.check(Stage::Rcpt, "b@example.test"),
+            Some(FaultAction::Disconnect)
+        );
+        assert_eq!(faults.check(Stage::Rcpt, "b@example.test"), None);
+        assert_eq!(faults.rules().len(), 1);
+    }
+
+    #[test]
+    fn probability_zero_never_fires() {
+        let faults = Faults::new(vec![rule(
+            r#"{"stage": "connect", "probability": 0, "action": {"type": "timeout"}}"#,
+        )])
+        .unwrap();
+        assert!((0..100).all(|_| faults.check(Stage::Connect, "").is_none()));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected_without_changing_the_set() {
+        let faults = Faults::new(vec![rule(
+            r#"{"stage": "mail", "action": {"type": "delay", "millis": 5}}"#,
+        )])
+        .unwrap();
+        let err = faults
+            .set(vec![rule(
+                r#"{"stage": "mail", "action": {"type": "reply", "code": 250}}"#,
+            )])
+            .unwrap_err();
+        assert_eq!(err, "rule 0: reply code must be 4xx or 5xx, got 250");
+        let err = faults
+            .set(vec![rule(
+                r#"{"stage": "mail", "probability": 1.5, "action": {"type": "disconnect"}}"#,
+            )])
+            .unwrap_err();
+        assert!(err.contains("probability"));
+        let err = faults
+            .set(vec![rule(
+                r#"{"stage": "mail", "times": 0, "action": {"type": "disconnect"}}"#,
+            )])
+            .unwrap_err();
+        assert_eq!(err, "rule 0: times must be at least 1");
+        assert_eq!(faults.rules().len(), 1);
+    }
+}
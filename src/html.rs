@@ -0,0 +1,215 @@
+//! A forgiving scanner for the start tags of an HTML body.
+//!
+//! It does not build a document tree: the analyses that use it only need
+//! to see each element with its attributes, and the text of `<style>`
+//! blocks. Comments, end tags and declarations are skipped.
+
+/// A start tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Tag<'a> {
+    /// Lowercased element name.
+    pub(crate) name: String,
+    /// Lowercased attribute names with their values, entities decoded.
+    pub(crate) attributes: Vec<(String, String)>,
+    /// The content of a `<style>` or `<script>` element, which is not
+    /// scanned for tags.
+    pub(crate) raw_text: Option<&'a str>,
+}
+
+impl Tag<'_> {
+    pub(crate) fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Iterates over the start tags of `html`, in document order.
+pub(crate) fn tags(html: &str) -> Tags<'_> {
+    Tags { rest: html }
+}
+
+pub(crate) struct Tags<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Tag<'a>;
+
+    fn next(&mut self) -> Option<Tag<'a>> {
+        loop {
+            let start = self.rest.find('<')?;
+            let rest = &self.rest[start..];
+            if let Some(after) = rest.strip_prefix("<!--") {
+                self.rest = after.find("-->").map_or("", |end| &after[end + 3..]);
+                continue;
+            }
+            if rest.starts_with("</") || rest.starts_with("<!") || rest.starts_with("<?") {
+                self.rest = rest.find('>').map_or("", |end| &rest[end + 1..]);
+                continue;
+            }
+            let Some((mut tag, len)) = parse_tag(&rest[1..]) else {
+                self.rest = &rest[1..];
+                continue;
+            };
+            self.rest = &rest[1 + len..];
+            if tag.name == "style" || tag.name == "script" {
+                // Raw text: runs to the matching end tag.
+                let end = find_ignore_case(self.rest, &format!("</{}", tag.name))
+                    .unwrap_or(self.rest.len());
+                tag.raw_text = Some(&self.rest[..end]);
+                self.rest = &self.rest[end..];
+            }
+            return Some(tag);
+        }
+    }
+}
+
+/// Parses a start tag from just after its `<`, returning it and how many
+/// bytes it spans.
+fn parse_tag(text: &str) -> Option<(Tag<'static>, usize)> {
+    let bytes = text.as_bytes();
+    if !bytes.first()?.is_ascii_alphabetic() {
+        return None;
+    }
+    let name_len = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == ':'))
+        .unwrap_or(text.len());
+    let mut tag = Tag {
+        name: text[..name_len].to_ascii_lowercase(),
+        attributes: Vec::new(),
+        raw_text: None,
+    };
+
+    let mut i = name_len;
+    loop {
+        while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b'/') {
+            i += 1;
+        }
+        match bytes.get(i) {
+            None => return Some((tag, text.len())),
+            Some(b'>') => return Some((tag, i + 1)),
+            Some(_) => {}
+        }
+        let start = i;
+        while i < bytes.len() && !b" \t\r\n=>/".contains(&bytes[i]) {
+            i += 1;
+        }
+        let name = text[start..i].to_ascii_lowercase();
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        let mut value = "";
+        if bytes.get(i) == Some(&b'=') {
+            i += 1;
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            match bytes.get(i) {
+                Some(&quote @ (b'"' | b'\'')) => {
+                    let end = text[i + 1..]
+                        .find(quote as char)
+                        .map_or(text.len(), |end| i + 1 + end);
+                    value = &text[i + 1..end];
+                    i = (end + 1).min(text.len());
+                }
+                _ => {
+                    let start = i;
+                    while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' {
+                        i += 1;
+                    }
+                    value = &text[start..i];
+                }
+            }
+        }
+        if !name.is_empty() {
+            tag.attributes.push((name, decode_entities(value)));
+        }
+    }
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+/// Decodes numeric character references and the named ones that turn up
+/// in attribute values. Unknown references are left alone.
+pub(crate) fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| {
+                let name = &rest[1..1 + end];
+                let c = match name {
+                    "amp" => Some('&'),
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    "nbsp" => Some('\u{a0}'),
+                    _ => {
+                        let code = if let Some(hex) =
+                            name.strip_prefix("#x").or(name.strip_prefix("#X"))
+                        {
+                            u32::from_str_radix(hex, 16).ok()
+                        } else {
+                            name.strip_prefix('#').and_then(|dec| dec.parse().ok())
+                        };
+                        code.and_then(char::from_u32)
+                    }
+                };
+                c.map(|c| (c, end + 2))
+            });
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scans_start_tags_and_attributes() {
+        let html = r#"<!-- <a href="x"> --><P Class=intro>Hi <A HREF='/a?b=1&amp;c=2' title="<b>">x</a>
+            <img src=logo.png alt><style>a { color: red } <p></style></p>"#;
+        let found: Vec<Tag> = tags(html).collect();
+        let names: Vec<&str> = found.iter().map(|tag| tag.name.as_str()).collect();
+        assert_eq!(names, ["p", "a", "img", "style"]);
+        assert_eq!(found[0].attribute("class"), Some("intro"));
+        assert_eq!(found[1].attribute("href"), Some("/a?b=1&c=2"));
+        assert_eq!(found[1].attribute("title"), Some("<b>"));
+        assert_eq!(found[2].attribute("alt"), Some(""));
+        assert_eq!(found[3].raw_text, Some("a { color: red } <p>"));
+    }
+
+    #[test]
+    fn decodes_entities() {
+        assert_eq!(
+            decode_entities("a&amp;b &#65;&#x42; &copy; & &bogus"),
+            "a&b AB &copy; & &bogus"
+        );
+    }
+}
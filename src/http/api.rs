@@ -0,0 +1,530 @@
+//! Handlers for the `/api/messages` endpoints, which every mailbox has its
+//! own copy of under `/api/mailboxes/{mailbox}`.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, FromRequestParts, Query, RawPathParams, State};
+use axum::http::request::Parts;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use serde::Deserialize;
+
+use super::error::ApiError;
+use super::model::{
+    Deleted, Imported, MailboxJson, MessageDetail, MessageList, MessageSummary, ReleaseStatus,
+    Released, Upstream,
+};
+use super::AppState;
+use crate::archive::{self, Format};
+use crate::compat;
+use crate::links::LinkReport;
+use crate::mailbox::DEFAULT_MAILBOX;
+use crate::release;
+use crate::search;
+use crate::spam::SpamReport;
+use crate::store::{CapturedMessage, MessageId};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Seconds `GET /api/messages/wait` blocks for when no timeout is given.
+const DEFAULT_WAIT_SECS: f64 = 10.0;
+/// Upper bound on the `timeout` parameter, so requests cannot hang forever.
+const MAX_WAIT_SECS: f64 = 300.0;
+
+/// Largest archive `POST /api/messages/import` accepts.
+const MAX_IMPORT_SIZE: usize = 512 * 1024 * 1024;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/mailboxes", get(list_mailboxes))
+        .route("/api/release", get(release_status))
+}
+
+/// Routes relative to a mailbox, mounted once for the default mailbox and
+/// once per named mailbox.
+pub(crate) fn mailbox_routes() -> Router<AppState> {
+    Router::new()
+        .route("/messages", get(list_messages).delete(delete_all))
+        .route("/messages/wait", get(wait_for_message))
+        .route("/messages/export", get(export_messages))
+        .route(
+            "/messages/import",
+            post(import_messages).layer(DefaultBodyLimit::max(MAX_IMPORT_SIZE)),
+        )
+        .route("/messages/{id}", get(get_message).delete(delete_message))
+        .route("/messages/{id}/raw", get(get_raw))
+        .route("/messages/{id}/parts/{part_id}", get(get_part))
+        .route("/messages/{id}/compatibility", get(get_compatibility))
+        .route("/messages/{id}/links", get(get_links))
+        .route("/messages/{id}/release", post(release_message))
+        .route("/messages/{id}/spam", post(score_message))
+        .route("/messages/{id}/pin", put(pin_message).delete(unpin_message))
+}
+
+/// Looks up a path parameter by name.
+async fn path_param(parts: &mut Parts, name: &str) -> Result<Option<String>, ApiError> {
+    let params = RawPathParams::from_request_parts(parts, &())
+        .await
+        .map_err(|err| ApiError::BadRequest(err.body_text()))?;
+    Ok(params
+        .iter()
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string()))
+}
+
+/// The mailbox a request is scoped to: the `{mailbox}` path segment, or the
+/// default mailbox for the unscoped routes. Unknown mailboxes are rejected.
+pub(crate) struct Mailbox(pub String);
+
+impl FromRequestParts<AppState> for Mailbox {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, ApiError> {
+        let name = path_param(parts, "mailbox")
+            .await?
+            .unwrap_or_else(|| DEFAULT_MAILBOX.to_string());
+        if state.mailboxes.contains(&name) {
+            Ok(Mailbox(name))
+        } else {
+            Err(ApiError::MailboxNotFound(name))
+        }
+    }
+}
+
+/// The `{id}` path segment, rejected with a JSON error if it is not a number.
+struct MessageIdPath(MessageId);
+
+impl<S: Send + Sync> FromRequestParts<S> for MessageIdPath {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, ApiError> {
+        let id = path_param(parts, "id").await?.unwrap_or_default();
+        parse_id(&id).map(MessageIdPath)
+    }
+}
+
+/// Returns message `id` if it is in `mailbox`. Messages of other mailboxes
+/// are reported as missing, as if they did not exist.
+fn find(state: &AppState, mailbox: &str, id: MessageId) -> Result<Arc<CapturedMessage>, ApiError> {
+    state
+        .store
+        .get(id)
+        .filter(|message| message.mailbox == mailbox)
+        .ok_or(ApiError::MessageNotFound(id))
+}
+
+fn parse_id(id: &str) -> Result<MessageId, ApiError> {
+    id.parse()
+        .map_err(|_| ApiError::BadRequest(format!("invalid message id {id:?}")))
+}
+
+#[derive(Debug, Deserialize)]
+struct Page {
+    offset: Option<usize>,
+    limit: Option<usize>,
+    /// A search in the language of [`search::Query`].
+    query: Option<String>,
+}
+
+fn parse_query(query: &str) -> Result<search::Query, ApiError> {
+    search::Query::parse(query).map_err(|err| ApiError::BadRequest(format!("invalid query: {err}")))
+}
+
+async fn list_mailboxes(State(state): State<AppState>) -> Json<Vec<MailboxJson>> {
+    let counts = state.store.mailbox_counts();
+    Json(
+        state
+            .mailboxes
+            .names()
+            .into_iter()
+            .map(|name| MailboxJson {
+                name: name.to_string(),
+                total: counts.get(name).copied().unwrap_or(0),
+                rules: state
+                    .mailboxes
+                    .rules()
+                    .iter()
+                    .filter(|rule| rule.mailbox == name)
+                    .map(|rule| rule.matcher.clone())
+                    .collect(),
+                retention: state.retention.limits(name),
+                evicted: state.retention.evicted(name),
+            })
+            .collect(),
+    )
+}
+
+async fn list_messages(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    Query(page): Query<Page>,
+) -> Result<Json<MessageList>, ApiError> {
+    let offset = page.offset.unwrap_or(0);
+    let limit = page.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let all = match page.query.as_deref().map(parse_query).transpose()? {
+        Some(query) if !query.is_empty() => state.store.search(&query.in_mailbox(&mailbox)),
+        _ => state.store.list_mailbox(&mailbox),
+    };
+    let messages = all
+        .iter()
+        .skip(offset)
+        .take(limit)
+        .map(|m| MessageSummary::from(m.as_ref()))
+        .collect();
+    Ok(Json(MessageList {
+        total: all.len(),
+        offset,
+        limit,
+        messages,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct WaitQuery {
+    #[serde(default)]
+    query: String,
+    /// Seconds to wait; fractions are allowed.
+    timeout: Option<f64>,
+}
+
+/// Long poll: responds with the newest message matching the query as soon as
+/// there is one, or with 404 once the timeout passes.
+async fn wait_for_message(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    Query(wait): Query<WaitQuery>,
+) -> Result<Json<MessageDetail>, ApiError> {
+    let query = parse_query(&wait.query)?.in_mailbox(&mailbox);
+    let secs = wait.timeout.unwrap_or(DEFAULT_WAIT_SECS);
+    if !(0.0..=MAX_WAIT_SECS).contains(&secs) {
+        return Err(ApiError::BadRequest(format!(
+            "timeout must be between 0 and {MAX_WAIT_SECS} seconds"
+        )));
+    }
+    let captured = state
+        .store
+        .wait_for(&query, Duration::from_secs_f64(secs))
+        .await
+        .ok_or_else(|| ApiError::Timeout(format!("no matching message arrived within {secs}s")))?;
+    Ok(Json(MessageDetail::new(&captured, &captured.parse())))
+}
+
+async fn get_message(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    MessageIdPath(id): MessageIdPath,
+) -> Result<Json<MessageDetail>, ApiError> {
+    let captured = find(&state, &mailbox, id)?;
+    Ok(Json(MessageDetail::new(&captured, &captured.parse())))
+}
+
+async fn get_raw(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    MessageIdPath(id): MessageIdPath,
+) -> Result<impl IntoResponse, ApiError> {
+    let captured = find(&state, &mailbox, id)?;
+    Ok((
+        [(header::CONTENT_TYPE, "message/rfc822")],
+        captured.raw.clone(),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    /// Defaults to mbox.
+    format: Option<Format>,
+    /// Exports only the messages matching this search.
+    query: Option<String>,
+}
+
+/// Downloads the mailbox, or the messages matching a search, as an archive.
+async fn export_messages(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    Query(export): Query<ExportQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let format = export.format.unwrap_or(Format::Mbox);
+    let mut messages = match export.query.as_deref().map(parse_query).transpose()? {
+        Some(query) if !query.is_empty() => state.store.search(&query.in_mailbox(&mailbox)),
+        _ => state.store.list_mailbox(&mailbox),
+    };
+    messages.reverse();
+    let archive = archive::export(format, &messages)
+        .map_err(|err| ApiError::Internal(io::Error::other(err).into()))?;
+    let file_name = format.file_name(&format!("rubbermail-{mailbox}"));
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                content_disposition("attachment", Some(&file_name)),
+            ),
+        ],
+        archive,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportQuery {
+    /// Detected from the archive if not given.
+    format: Option<Format>,
+}
+
+/// Loads the messages of an archive into the mailbox.
+async fn import_messages(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    Query(import): Query<ImportQuery>,
+    body: Bytes,
+) -> Result<Json<Imported>, ApiError> {
+    let format = import.format.unwrap_or_else(|| Format::detect(&body));
+    let messages = archive::import(format, &body)
+        .map_err(|err| ApiError::BadRequest(format!("invalid archive: {err}")))?;
+    let ids = archive::restore(&state.store, &mailbox, messages)?;
+    Ok(Json(Imported {
+        imported: ids.len(),
+        ids,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct PartQuery {
+    #[serde(default)]
+    download: bool,
+}
+
+/// Serves the decoded content of one MIME part, such as an attachment or an
+/// inline image.
+async fn get_part(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    MessageIdPath(id): MessageIdPath,
+    PartIdPath(part_id): PartIdPath,
+    Query(query): Query<PartQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let captured = find(&state, &mailbox, id)?;
+    let section = parse_part_id(&part_id)
+        .ok_or_else(|| ApiError::BadRequest(format!("invalid part id {part_id:?}")))?;
+    let message = captured.parse();
+    let part = message
+        .part(&section)
+        .ok_or_else(|| ApiError::PartNotFound(part_id.clone()))?;
+
+    let mut content_type = part.content_type.mime_type.clone();
+    if let Some(charset) = part.content_type.param("charset") {
+        content_type.push_str(&format!("; charset={charset}"));
+    }
+    let kind = if query.download {
+        "attachment"
+    } else {
+        "inline"
+    };
+    let disposition = content_disposition(kind, part.filename());
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        part.data().to_vec(),
+    ))
+}
+
+/// The `{part_id}` path segment.
+struct PartIdPath(String);
+
+impl<S: Send + Sync> FromRequestParts<S> for PartIdPath {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, ApiError> {
+        Ok(PartIdPath(
+            path_param(parts, "part_id").await?.unwrap_or_default(),
+        ))
+    }
+}
+
+fn parse_part_id(part_id: &str) -> Option<Vec<usize>> {
+    part_id.split('.').map(|n| n.parse().ok()).collect()
+}
+
+/// Builds a `Content-Disposition` value, using the RFC 6266 `filename*`
+/// form so non-ASCII names survive.
+fn content_disposition(kind: &str, filename: Option<&str>) -> String {
+    let Some(filename) = filename else {
+        return kind.to_string();
+    };
+    let fallback: String = filename
+        .chars()
+        .map(|c| {
+            if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let encoded: String = filename
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect();
+    format!("{kind}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+async fn delete_message(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    MessageIdPath(id): MessageIdPath,
+) -> Result<StatusCode, ApiError> {
+    find(&state, &mailbox, id)?;
+    if state.store.delete(id)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::MessageNotFound(id))
+    }
+}
+
+async fn delete_all(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+) -> Result<Json<Deleted>, ApiError> {
+    Ok(Json(Deleted {
+        deleted: state.store.clear_mailbox(&mailbox)?,
+    }))
+}
+
+/// Exempts a message from retention limits.
+async fn pin_message(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    MessageIdPath(id): MessageIdPath,
+) -> Result<Json<MessageSummary>, ApiError> {
+    set_pinned(&state, &mailbox, id, true)
+}
+
+async fn unpin_message(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    MessageIdPath(id): MessageIdPath,
+) -> Result<Json<MessageSummary>, ApiError> {
+    set_pinned(&state, &mailbox, id, false)
+}
+
+fn set_pinned(
+    state: &AppState,
+    mailbox: &str,
+    id: MessageId,
+    pinned: bool,
+) -> Result<Json<MessageSummary>, ApiError> {
+    find(state, mailbox, id)?;
+    let message = state
+        .store
+        .update(id, |message| message.pinned = pinned)?
+        .ok_or(ApiError::MessageNotFound(id))?;
+    Ok(Json(MessageSummary::from(&*message)))
+}
+
+/// Reports how well mail clients will render the HTML body.
+async fn get_compatibility(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    MessageIdPath(id): MessageIdPath,
+) -> Result<Json<compat::Report>, ApiError> {
+    let captured = find(&state, &mailbox, id)?;
+    let html = captured.parse().html.ok_or(ApiError::NoHtmlBody(id))?;
+    Ok(Json(compat::Report::of(&html)))
+}
+
+#[derive(Debug, Deserialize)]
+struct LinksQuery {
+    /// Also request every link to see whether it resolves.
+    #[serde(default)]
+    check: bool,
+}
+
+/// Lists the links in the message bodies with any problems found.
+async fn get_links(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    MessageIdPath(id): MessageIdPath,
+    Query(query): Query<LinksQuery>,
+) -> Result<Json<LinkReport>, ApiError> {
+    let captured = find(&state, &mailbox, id)?;
+    let parsed = captured.parse();
+    let mut report = LinkReport::of(parsed.html.as_deref(), parsed.text.as_deref());
+    if query.check {
+        report.check(state.check_internal_links).await;
+    }
+    Ok(Json(report))
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ReleaseRequest {
+    /// Recipients to send to instead of the default ones.
+    #[serde(default)]
+    to: Vec<String>,
+}
+
+/// Sends a captured message on to the configured upstream server.
+async fn release_message(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    MessageIdPath(id): MessageIdPath,
+    body: Option<Json<ReleaseRequest>>,
+) -> Result<Json<Released>, ApiError> {
+    let relay = state.release.as_ref().ok_or(ApiError::ReleaseDisabled)?;
+    let captured = find(&state, &mailbox, id)?;
+    let Json(request) = body.unwrap_or_default();
+    for address in &request.to {
+        release::validate_address(address).map_err(ApiError::BadRequest)?;
+    }
+    let to = relay.recipients(&captured, request.to);
+    let reply = relay
+        .release(&captured, &to)
+        .await
+        .map_err(|err| ApiError::Upstream(err.to_string()))?;
+    state.webhooks.released(&captured, &to, &reply);
+    Ok(Json(Released {
+        to,
+        reply: reply.to_string(),
+    }))
+}
+
+/// Scores a message with spamd now, replacing any earlier score.
+async fn score_message(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    MessageIdPath(id): MessageIdPath,
+) -> Result<Json<SpamReport>, ApiError> {
+    let spamd = state.spamd.as_ref().ok_or(ApiError::SpamdDisabled)?;
+    let captured = find(&state, &mailbox, id)?;
+    let report = spamd
+        .check(&captured.raw)
+        .await
+        .map_err(|err| ApiError::Upstream(err.to_string()))?;
+    state
+        .store
+        .update(id, |message| message.spam = Some(report.clone()))?
+        .ok_or(ApiError::MessageNotFound(id))?;
+    Ok(Json(report))
+}
+
+async fn release_status(State(state): State<AppState>) -> Json<ReleaseStatus> {
+    Json(ReleaseStatus {
+        upstream: state
+            .release
+            .as_ref()
+            .map(|relay| Upstream::from(relay.config())),
+    })
+}
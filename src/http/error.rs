@@ -0,0 +1,79 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use tracing::error;
+
+use crate::sessions::SessionId;
+use crate::store::MessageId;
+use crate::Error;
+
+/// Errors returned by API handlers, rendered as `{"error": "..."}`.
+#[derive(Debug)]
+pub enum ApiError {
+    MessageNotFound(MessageId),
+    MailboxNotFound(String),
+    PartNotFound(String),
+    /// The message exists but has nothing to analyze.
+    NoHtmlBody(MessageId),
+    SessionNotFound(SessionId),
+    BadRequest(String),
+    /// A wait ended without a result. Answered with 404 like a missing
+    /// message, plus `"timed_out": true` to tell the two apart.
+    Timeout(String),
+    /// Releasing was asked for but no upstream server is configured.
+    ReleaseDisabled,
+    /// Spam scoring was asked for but no spamd is configured.
+    SpamdDisabled,
+    /// The upstream server could not be reached or refused a message.
+    Upstream(String),
+    Internal(Error),
+}
+
+impl From<Error> for ApiError {
+    fn from(err: Error) -> Self {
+        ApiError::Internal(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::MessageNotFound(id) => {
+                (StatusCode::NOT_FOUND, format!("message {id} not found"))
+            }
+            ApiError::MailboxNotFound(name) => {
+                (StatusCode::NOT_FOUND, format!("mailbox {name:?} not found"))
+            }
+            ApiError::PartNotFound(part_id) => {
+                (StatusCode::NOT_FOUND, format!("part {part_id} not found"))
+            }
+            ApiError::NoHtmlBody(id) => (
+                StatusCode::NOT_FOUND,
+                format!("message {id} has no HTML body"),
+            ),
+            ApiError::SessionNotFound(id) => {
+                (StatusCode::NOT_FOUND, format!("session {id} not found"))
+            }
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            ApiError::Timeout(message) => {
+                let body = json!({ "error": message, "timed_out": true });
+                return (StatusCode::NOT_FOUND, Json(body)).into_response();
+            }
+            ApiError::ReleaseDisabled => (
+                StatusCode::CONFLICT,
+                "releasing is disabled; start with --release-host".to_string(),
+            ),
+            ApiError::SpamdDisabled => (
+                StatusCode::CONFLICT,
+                "spam scoring is disabled; start with --spamd-host".to_string(),
+            ),
+            ApiError::Upstream(message) => (StatusCode::BAD_GATEWAY, message),
+            ApiError::Internal(err) => {
+                error!("request failed: {err}");
+                (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+            }
+        };
+        (status, Json(json!({ "error": message }))).into_response()
+    }
+}
@@ -0,0 +1,86 @@
+//! Live push of store changes over Server-Sent Events and WebSocket.
+
+use std::convert::Infallible;
+use std::time::Duration;
+
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tracing::debug;
+
+use super::api::Mailbox;
+use super::model::Event;
+use super::AppState;
+use crate::store::StoreEvent;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Routes relative to a mailbox; each stream only carries that mailbox's
+/// events.
+pub(crate) fn mailbox_routes() -> Router<AppState> {
+    Router::new()
+        .route("/events", get(sse))
+        .route("/ws", get(websocket))
+}
+
+async fn sse(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    // Events missed by a lagging client are skipped rather than ending the stream.
+    let stream = BroadcastStream::new(state.store.subscribe()).filter_map(move |event| {
+        let event = event.ok().filter(|event| event.concerns(&mailbox))?;
+        let event = Event::from(&event);
+        let data = serde_json::to_string(&event).ok()?;
+        Some(Ok(SseEvent::default().event(event.name()).data(data)))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::new().interval(KEEP_ALIVE))
+}
+
+async fn websocket(
+    State(state): State<AppState>,
+    Mailbox(mailbox): Mailbox,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    // Subscribe before the handshake completes so nothing sent right after
+    // the client connects is missed.
+    let events = state.store.subscribe();
+    upgrade.on_upgrade(move |socket| push_events(socket, events, mailbox))
+}
+
+async fn push_events(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<StoreEvent>,
+    mailbox: String,
+) {
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) if event.concerns(&mailbox) => Event::from(&event),
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!(missed, "WebSocket client lagging, events dropped");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                let Ok(text) = serde_json::to_string(&event) else { continue };
+                if socket.send(WsMessage::Text(text.into())).await.is_err() {
+                    return;
+                }
+            }
+            incoming = socket.recv() => match incoming {
+                // Client messages are ignored; pings are answered by axum.
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
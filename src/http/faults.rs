@@ -0,0 +1,42 @@
+//! Handlers for `/api/faults`: viewing and changing fault-injection rules.
+
+use axum::extract::rejection::JsonRejection;
+use axum::extract::State;
+use axum::routing::get;
+use axum::{Json, Router};
+use tracing::info;
+
+use super::error::ApiError;
+use super::AppState;
+use crate::faults::FaultSet;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route(
+        "/api/faults",
+        get(get_faults).put(put_faults).delete(delete_faults),
+    )
+}
+
+async fn get_faults(State(state): State<AppState>) -> Json<FaultSet> {
+    Json(FaultSet {
+        rules: state.faults.rules(),
+    })
+}
+
+/// Replaces every rule. An invalid rule set is rejected as a whole.
+async fn put_faults(
+    State(state): State<AppState>,
+    body: Result<Json<FaultSet>, JsonRejection>,
+) -> Result<Json<FaultSet>, ApiError> {
+    let Json(set) = body.map_err(|err| ApiError::BadRequest(err.body_text()))?;
+    state.faults.set(set.rules).map_err(ApiError::BadRequest)?;
+    let rules = state.faults.rules();
+    info!(rules = rules.len(), "fault rules replaced");
+    Ok(Json(FaultSet { rules }))
+}
+
+async fn delete_faults(State(state): State<AppState>) -> Json<FaultSet> {
+    state.faults.set(Vec::new()).unwrap_or_default();
+    info!("fault rules cleared");
+    Json(FaultSet::default())
+}
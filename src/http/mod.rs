@@ -0,0 +1,81 @@
+//! The HTTP server: JSON API and web inbox for captured messages.
+
+mod api;
+mod error;
+mod events;
+mod faults;
+pub mod model;
+mod sessions;
+mod ui;
+mod webhooks;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::Router;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::faults::Faults;
+use crate::mailbox::Mailboxes;
+use crate::release::Relay;
+use crate::retention::Retention;
+use crate::sessions::SessionLog;
+use crate::spam::Spamd;
+use crate::store::MessageStore;
+use crate::webhooks::Webhooks;
+
+/// State available to every handler. The handles are shared with the mail
+/// listeners.
+#[derive(Debug, Clone, Default)]
+pub struct AppState {
+    pub store: MessageStore,
+    pub faults: Faults,
+    pub sessions: SessionLog,
+    /// Set if messages can be released to an upstream server.
+    pub release: Option<Arc<Relay>>,
+    /// Set if messages are scored by spamd.
+    pub spamd: Option<Arc<Spamd>>,
+    /// The configured mailboxes, for scoping requests.
+    pub mailboxes: Mailboxes,
+    /// Retention limits, reported with each mailbox.
+    pub retention: Retention,
+    /// Webhooks told about releases, and changed through the API.
+    pub webhooks: Webhooks,
+    /// Whether link checks may request loopback and private-network hosts.
+    pub check_internal_links: bool,
+}
+
+/// A bound HTTP listener that has not started serving yet.
+pub struct HttpServer {
+    listener: TcpListener,
+    router: Router,
+}
+
+impl HttpServer {
+    pub async fn bind(addr: SocketAddr, state: AppState) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let mailbox = api::mailbox_routes().merge(events::mailbox_routes());
+        let router = Router::new()
+            .nest("/api", mailbox.clone())
+            .nest("/api/mailboxes/{mailbox}", mailbox)
+            .merge(api::routes())
+            .merge(faults::routes())
+            .merge(sessions::routes())
+            .merge(ui::routes())
+            .merge(webhooks::routes())
+            .with_state(state);
+        Ok(HttpServer { listener, router })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves requests until the task is dropped.
+    pub async fn serve(self) -> io::Result<()> {
+        info!(addr = %self.listener.local_addr()?, "HTTP server ready");
+        axum::serve(self.listener, self.router).await
+    }
+}
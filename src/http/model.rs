@@ -0,0 +1,316 @@
+//! JSON representations returned by the HTTP API.
+//!
+//! These types are the public, documented schema (see `docs/api.md`). Fields
+//! may be added over time but are never renamed or removed.
+
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::Serialize;
+
+use crate::dkim::DkimResult;
+use crate::dmarc::{Authentication, DmarcResult};
+use crate::mailbox::Matcher;
+use crate::mime::{self, Address, Message};
+use crate::release::{ReleaseConfig, UpstreamTls};
+use crate::retention::Limits;
+use crate::spam::SpamReport;
+use crate::spf::SpfResult;
+use crate::store::{CapturedMessage, Envelope, MessageId, SessionInfo, StoreEvent};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AddressJson {
+    pub name: Option<String>,
+    pub address: String,
+}
+
+impl From<&Address> for AddressJson {
+    fn from(address: &Address) -> Self {
+        AddressJson {
+            name: address.name.clone(),
+            address: address.email.clone(),
+        }
+    }
+}
+
+fn addresses(list: &[Address]) -> Vec<AddressJson> {
+    list.iter().map(AddressJson::from).collect()
+}
+
+/// One entry of the message list.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageSummary {
+    pub id: MessageId,
+    pub mailbox: String,
+    pub received_at: DateTime<Utc>,
+    pub envelope: Envelope,
+    pub from: Option<AddressJson>,
+    pub to: Vec<AddressJson>,
+    pub cc: Vec<AddressJson>,
+    pub subject: Option<String>,
+    /// The `Date` header, if present and parseable.
+    pub date: Option<DateTime<FixedOffset>>,
+    /// Size of the raw message in bytes.
+    pub size: usize,
+    pub attachment_count: usize,
+    pub snippet: String,
+    /// SpamAssassin score, once the message has been scored.
+    pub spam_score: Option<f64>,
+    /// IMAP flags, such as `\Seen`, set by mail clients.
+    pub flags: Vec<String>,
+    /// Pinned messages are never evicted by retention limits.
+    pub pinned: bool,
+}
+
+impl From<&CapturedMessage> for MessageSummary {
+    fn from(message: &CapturedMessage) -> Self {
+        let summary = &message.summary;
+        MessageSummary {
+            id: message.id,
+            mailbox: message.mailbox.clone(),
+            received_at: message.received_at,
+            envelope: message.envelope.clone(),
+            from: summary.from.as_ref().map(AddressJson::from),
+            to: addresses(&summary.to),
+            cc: addresses(&summary.cc),
+            subject: summary.subject.clone(),
+            date: summary.date,
+            size: message.raw.len(),
+            attachment_count: summary.attachments,
+            snippet: summary.snippet.clone(),
+            spam_score: message.spam.as_ref().map(|spam| spam.score),
+            flags: message.flags.iter().cloned().collect(),
+            pinned: message.pinned,
+        }
+    }
+}
+
+/// One entry of `GET /api/mailboxes`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MailboxJson {
+    pub name: String,
+    /// Number of messages in the mailbox.
+    pub total: usize,
+    /// The rules routing messages here; empty for the default mailbox.
+    pub rules: Vec<Matcher>,
+    /// The retention limits in effect for the mailbox.
+    pub retention: Limits,
+    /// Number of messages evicted by retention limits since startup.
+    pub evicted: u64,
+}
+
+/// A page of the message list, newest first.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageList {
+    /// Number of messages in the mailbox, across all pages.
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub messages: Vec<MessageSummary>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HeaderJson {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AttachmentJson {
+    /// IMAP-style section number, e.g. `"2.1"`.
+    pub part_id: String,
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub disposition: Option<String>,
+    pub content_id: Option<String>,
+    pub size: usize,
+}
+
+impl From<&mime::Attachment> for AttachmentJson {
+    fn from(attachment: &mime::Attachment) -> Self {
+        AttachmentJson {
+            part_id: part_id(&attachment.section),
+            filename: attachment.filename.clone(),
+            content_type: attachment.content_type.clone(),
+            disposition: attachment.disposition.clone(),
+            content_id: attachment.content_id.clone(),
+            size: attachment.size,
+        }
+    }
+}
+
+/// Formats a section number as a dotted part id.
+pub fn part_id(section: &[usize]) -> String {
+    section
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// A fully parsed message.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageDetail {
+    #[serde(flatten)]
+    pub summary: MessageSummary,
+    pub message_id: Option<String>,
+    pub reply_to: Vec<AddressJson>,
+    pub bcc: Vec<AddressJson>,
+    pub session: SessionInfo,
+    pub headers: Vec<HeaderJson>,
+    pub text: Option<String>,
+    pub html: Option<String>,
+    pub attachments: Vec<AttachmentJson>,
+    pub inlines: Vec<AttachmentJson>,
+    /// Set once the message has been scored by spamd.
+    pub spam: Option<SpamReport>,
+    /// One result per `DKIM-Signature` header, checked against the
+    /// configured records when the message was received.
+    pub dkim: Vec<DkimResult>,
+    /// SPF check of the connecting IP against the MAIL FROM domain.
+    pub spf: Option<SpfResult>,
+    /// DMARC evaluation of the header From domain.
+    pub dmarc: Option<DmarcResult>,
+}
+
+impl MessageDetail {
+    pub fn new(captured: &CapturedMessage, message: &Message) -> Self {
+        let (dkim, spf, dmarc) = match captured.authentication.clone() {
+            Some(Authentication { dkim, spf, dmarc }) => (dkim, Some(spf), Some(dmarc)),
+            None => (Vec::new(), None, None),
+        };
+        MessageDetail {
+            summary: MessageSummary::from(captured),
+            message_id: message.message_id.clone(),
+            reply_to: addresses(&message.reply_to),
+            bcc: addresses(&message.bcc),
+            session: captured.session.clone(),
+            headers: message
+                .headers()
+                .iter()
+                .map(|h| HeaderJson {
+                    name: h.name.clone(),
+                    value: h.value.clone(),
+                })
+                .collect(),
+            text: message.text.clone(),
+            html: message.html.clone(),
+            attachments: message
+                .attachments
+                .iter()
+                .map(AttachmentJson::from)
+                .collect(),
+            inlines: message.inlines.iter().map(AttachmentJson::from).collect(),
+            spam: captured.spam.clone(),
+            dkim,
+            spf,
+            dmarc,
+        }
+    }
+}
+
+/// Response body of bulk deletions.
+#[derive(Debug, Clone, Serialize)]
+pub struct Deleted {
+    pub deleted: usize,
+}
+
+/// Response body of imports.
+#[derive(Debug, Clone, Serialize)]
+pub struct Imported {
+    pub imported: usize,
+    /// Ids of the new messages, in archive order.
+    pub ids: Vec<MessageId>,
+}
+
+/// Response body of `GET /api/release`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReleaseStatus {
+    /// Unset if releasing is disabled.
+    pub upstream: Option<Upstream>,
+}
+
+/// The configured upstream server, without credentials.
+#[derive(Debug, Clone, Serialize)]
+pub struct Upstream {
+    pub host: String,
+    pub port: u16,
+    pub tls: UpstreamTls,
+    pub auth: bool,
+    pub mail_from: Option<String>,
+    pub to: Vec<String>,
+}
+
+impl From<&ReleaseConfig> for Upstream {
+    fn from(config: &ReleaseConfig) -> Self {
+        Upstream {
+            host: config.host.clone(),
+            port: config.port,
+            tls: config.tls,
+            auth: config.credentials.is_some(),
+            mail_from: config.mail_from.clone(),
+            to: config.to.clone(),
+        }
+    }
+}
+
+/// Response body of a successful release.
+#[derive(Debug, Clone, Serialize)]
+pub struct Released {
+    pub to: Vec<String>,
+    /// The upstream server's reply to the message data.
+    pub reply: String,
+}
+
+/// A live notification pushed over `/api/events` and `/api/ws`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    MessageReceived {
+        message: MessageSummary,
+    },
+    MessageDeleted {
+        message: MessageSummary,
+    },
+    MessageUpdated {
+        message: MessageSummary,
+    },
+    MailboxCleared {
+        /// Unset if every mailbox was cleared.
+        mailbox: Option<String>,
+        deleted: usize,
+    },
+}
+
+impl Event {
+    /// The `type` tag, also used as the SSE event name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::MessageReceived { .. } => "message_received",
+            Event::MessageDeleted { .. } => "message_deleted",
+            Event::MessageUpdated { .. } => "message_updated",
+            Event::MailboxCleared { .. } => "mailbox_cleared",
+        }
+    }
+}
+
+impl From<&StoreEvent> for Event {
+    fn from(event: &StoreEvent) -> Self {
+        match event {
+            // Clients only need to know the message is there.
+            StoreEvent::Received(message) | StoreEvent::Imported(message) => {
+                Event::MessageReceived {
+                    message: MessageSummary::from(message.as_ref()),
+                }
+            }
+            StoreEvent::Deleted(message) => Event::MessageDeleted {
+                message: MessageSummary::from(message.as_ref()),
+            },
+            StoreEvent::Updated(message) => Event::MessageUpdated {
+                message: MessageSummary::from(message.as_ref()),
+            },
+            StoreEvent::Cleared { mailbox, messages } => Event::MailboxCleared {
+                mailbox: mailbox.clone(),
+                deleted: messages.len(),
+            },
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Handlers for `/api/sessions`: the log of recent client sessions.
+
+use axum::extract::{Path, Query, State};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Deserialize;
+
+use super::error::ApiError;
+use super::model::Deleted;
+use super::AppState;
+use crate::sessions::{SessionId, SessionRecord};
+
+const DEFAULT_LIMIT: usize = 50;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/api/sessions", get(list_sessions).delete(clear_sessions))
+        .route("/api/sessions/{id}", get(get_session))
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    limit: Option<usize>,
+}
+
+/// Recent sessions, newest first.
+async fn list_sessions(
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
+) -> Json<Vec<SessionRecord>> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    Json(state.sessions.list().into_iter().take(limit).collect())
+}
+
+async fn get_session(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<SessionRecord>, ApiError> {
+    let id: SessionId = id
+        .parse()
+        .map_err(|_| ApiError::BadRequest(format!("invalid session id {id:?}")))?;
+    state
+        .sessions
+        .get(id)
+        .map(Json)
+        .ok_or(ApiError::SessionNotFound(id))
+}
+
+async fn clear_sessions(State(state): State<AppState>) -> Json<Deleted> {
+    Json(Deleted {
+        deleted: state.sessions.clear(),
+    })
+}
@@ -0,0 +1,45 @@
+//! Handlers for `/api/webhooks`: viewing and changing webhooks.
+
+use axum::extract::rejection::JsonRejection;
+use axum::extract::State;
+use axum::routing::get;
+use axum::{Json, Router};
+use tracing::info;
+
+use super::error::ApiError;
+use super::AppState;
+use crate::webhooks::WebhookSet;
+
+pub(crate) fn routes() -> Router<AppState> {
+    Router::new().route(
+        "/api/webhooks",
+        get(get_webhooks).put(put_webhooks).delete(delete_webhooks),
+    )
+}
+
+async fn get_webhooks(State(state): State<AppState>) -> Json<WebhookSet> {
+    Json(WebhookSet {
+        webhooks: state.webhooks.webhooks(),
+    })
+}
+
+/// Replaces every webhook. An invalid set is rejected as a whole.
+async fn put_webhooks(
+    State(state): State<AppState>,
+    body: Result<Json<WebhookSet>, JsonRejection>,
+) -> Result<Json<WebhookSet>, ApiError> {
+    let Json(set) = body.map_err(|err| ApiError::BadRequest(err.body_text()))?;
+    state
+        .webhooks
+        .set(set.webhooks)
+        .map_err(ApiError::BadRequest)?;
+    let webhooks = state.webhooks.webhooks();
+    info!(webhooks = webhooks.len(), "webhooks replaced");
+    Ok(Json(WebhookSet { webhooks }))
+}
+
+async fn delete_webhooks(State(state): State<AppState>) -> Json<WebhookSet> {
+    state.webhooks.set(Vec::new()).unwrap_or_default();
+    info!("webhooks cleared");
+    Json(WebhookSet::default())
+}
@@ -0,0 +1,467 @@
+//! Rendering FETCH data: ENVELOPE, BODYSTRUCTURE and body sections, from
+//! the raw bytes of a captured message.
+//!
+//! [`crate::mime`] decodes parts for display; IMAP instead needs each part's
+//! bytes as transmitted, so this walks the raw message with the same
+//! splitting rules.
+
+use chrono::{DateTime, Utc};
+
+use super::parse::{BodySection, SectionText};
+use crate::mime::address::parse_list;
+use crate::mime::{
+    sniff_boundary, split_head_body, split_multipart, ContentType, Disposition, Headers,
+};
+
+/// Nesting deeper than this is treated as a leaf, as in [`crate::mime`].
+const MAX_DEPTH: usize = 32;
+
+/// Response bytes, plus a copy for the session log with literal contents
+/// left out.
+#[derive(Debug, Default)]
+pub(crate) struct Out {
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) log: String,
+}
+
+impl Out {
+    pub(crate) fn text(&mut self, text: &str) {
+        self.bytes.extend_from_slice(text.as_bytes());
+        self.log.push_str(text);
+    }
+
+    /// Writes a string, quoted when it is short plain ASCII and as a
+    /// literal otherwise.
+    pub(crate) fn string(&mut self, value: &[u8]) {
+        let quotable = value.len() <= 1024
+            && value
+                .iter()
+                .all(|&b| (0x20..0x7f).contains(&b) || b == b'\t');
+        if quotable {
+            let mut quoted = String::from("\"");
+            for &b in value {
+                if b == b'"' || b == b'\\' {
+                    quoted.push('\\');
+                }
+                quoted.push(char::from(b));
+            }
+            quoted.push('"');
+            self.text(&quoted);
+        } else {
+            self.literal(value);
+        }
+    }
+
+    pub(crate) fn nstring(&mut self, value: Option<&str>) {
+        match value {
+            Some(value) => self.string(value.as_bytes()),
+            None => self.text("NIL"),
+        }
+    }
+
+    pub(crate) fn literal(&mut self, value: &[u8]) {
+        let header = format!("{{{}}}\r\n", value.len());
+        self.bytes.extend_from_slice(header.as_bytes());
+        self.bytes.extend_from_slice(value);
+        self.log.push_str(&format!("{{{}}}", value.len()));
+    }
+}
+
+/// A MIME entity as transmitted.
+pub(crate) struct Entity<'a> {
+    /// The header block, including the empty line that ends it.
+    header: &'a [u8],
+    body: &'a [u8],
+    headers: Headers,
+    content_type: ContentType,
+    kind: Kind<'a>,
+}
+
+enum Kind<'a> {
+    Single,
+    Multipart(Vec<Entity<'a>>),
+    /// An encapsulated `message/rfc822`.
+    Message(Box<Entity<'a>>),
+}
+
+impl<'a> Entity<'a> {
+    /// Splits a whole message.
+    pub(crate) fn parse(raw: &'a [u8]) -> Entity<'a> {
+        Entity::parse_at(raw, &ContentType::new("text/plain"), 0)
+    }
+
+    fn parse_at(raw: &'a [u8], default_type: &ContentType, depth: usize) -> Entity<'a> {
+        let (_, body) = split_head_body(raw);
+        let header = &raw[..raw.len() - body.len()];
+        let headers = Headers::parse(header);
+        let content_type = headers
+            .get_raw("Content-Type")
+            .and_then(ContentType::parse)
+            .unwrap_or_else(|| default_type.clone());
+        let kind = if depth >= MAX_DEPTH {
+            Kind::Single
+        } else if content_type.is_multipart() {
+            let boundary = content_type
+                .param("boundary")
+                .filter(|b| !b.is_empty())
+                .map(str::to_string)
+                .or_else(|| sniff_boundary(body));
+            let child_default = if content_type.sub_type() == "digest" {
+                ContentType::new("message/rfc822")
+            } else {
+                ContentType::new("text/plain")
+            };
+            let children: Vec<Entity> = boundary
+                .map(|b| split_multipart(body, &b))
+                .unwrap_or_default()
+                .into_iter()
+                .map(|chunk| Entity::parse_at(chunk, &child_default, depth + 1))
+                .collect();
+            if children.is_empty() {
+                Kind::Single
+            } else {
+                Kind::Multipart(children)
+            }
+        } else if content_type.mime_type == "message/rfc822" {
+            Kind::Message(Box::new(Entity::parse_at(
+                body,
+                &ContentType::new("text/plain"),
+                depth + 1,
+            )))
+        } else {
+            Kind::Single
+        };
+        Entity {
+            header,
+            body,
+            headers,
+            content_type,
+            kind,
+        }
+    }
+
+    /// Looks up a part by section number, treating this entity as a
+    /// message: part 1 of a single-part message is its body.
+    fn message_part(&self, section: &[usize]) -> Option<&Entity<'a>> {
+        match (&self.kind, section) {
+            (Kind::Multipart(_), _) => self.child_part(section),
+            (_, [1]) => Some(self),
+            (_, [1, rest @ ..]) => self.child_part(rest),
+            _ => None,
+        }
+    }
+
+    fn child_part(&self, section: &[usize]) -> Option<&Entity<'a>> {
+        let (&n, rest) = section.split_first()?;
+        let child = match &self.kind {
+            Kind::Multipart(children) => children.get(n.checked_sub(1)?)?,
+            Kind::Message(message) => return message.message_part(section),
+            Kind::Single => return None,
+        };
+        if rest.is_empty() {
+            Some(child)
+        } else {
+            child.child_part(rest)
+        }
+    }
+
+    /// The content of `BODY[section]`, or `None` if the section does not
+    /// exist.
+    pub(crate) fn section(&self, section: &BodySection) -> Option<Vec<u8>> {
+        let (entity, message) = if section.part.is_empty() {
+            (self, Some(self))
+        } else {
+            let part = self.message_part(&section.part)?;
+            let message = match &part.kind {
+                Kind::Message(message) => Some(&**message),
+                _ => None,
+            };
+            (part, message)
+        };
+        Some(match &section.text {
+            None if section.part.is_empty() => [self.header, self.body].concat(),
+            None => entity.body.to_vec(),
+            Some(SectionText::Mime) => entity.header.to_vec(),
+            Some(SectionText::Header) => message?.header.to_vec(),
+            Some(SectionText::Text) => message?.body.to_vec(),
+            Some(SectionText::HeaderFields { not, fields }) => {
+                header_fields(message?.header, fields, *not)
+            }
+        })
+    }
+
+    pub(crate) fn header(&self) -> &[u8] {
+        self.header
+    }
+
+    pub(crate) fn body(&self) -> &[u8] {
+        self.body
+    }
+
+    /// Writes the ENVELOPE structure of this message.
+    pub(crate) fn envelope(&self, out: &mut Out) {
+        let headers = &self.headers;
+        let field = |name| headers.get_raw(name);
+        out.text("(");
+        out.nstring(field("Date"));
+        out.text(" ");
+        out.nstring(field("Subject"));
+        let from = field("From");
+        // RFC 3501: Sender and Reply-To default to From.
+        for name in ["From", "Sender", "Reply-To", "To", "Cc", "Bcc"] {
+            out.text(" ");
+            let value = match name {
+                "Sender" | "Reply-To" => field(name).or(from),
+                _ => field(name),
+            };
+            addresses(out, value);
+        }
+        out.text(" ");
+        out.nstring(field("In-Reply-To"));
+        out.text(" ");
+        out.nstring(field("Message-ID"));
+        out.text(")");
+    }
+
+    /// Writes BODYSTRUCTURE, or BODY without `extended`.
+    pub(crate) fn structure(&self, out: &mut Out, extended: bool) {
+        out.text("(");
+        match &self.kind {
+            Kind::Multipart(children) => {
+                for child in children {
+                    child.structure(out, extended);
+                }
+                out.text(" ");
+                out.string(self.content_type.sub_type().to_ascii_uppercase().as_bytes());
+                if extended {
+                    out.text(" ");
+                    params(out, &self.content_type.params);
+                    self.extension(out);
+                }
+            }
+            kind => {
+                let encoding = self
+                    .headers
+                    .get("Content-Transfer-Encoding")
+                    .map_or("7BIT".to_string(), |e| e.trim().to_ascii_uppercase());
+                out.string(
+                    self.content_type
+                        .main_type()
+                        .to_ascii_uppercase()
+                        .as_bytes(),
+                );
+                out.text(" ");
+                out.string(self.content_type.sub_type().to_ascii_uppercase().as_bytes());
+                out.text(" ");
+                params(out, &self.content_type.params);
+                out.text(" ");
+                out.nstring(self.headers.get_raw("Content-ID"));
+                out.text(" ");
+                out.nstring(self.headers.get_raw("Content-Description"));
+                out.text(" ");
+                out.string(encoding.as_bytes());
+                out.text(&format!(" {}", self.body.len()));
+                if let Kind::Message(message) = kind {
+                    out.text(" ");
+                    message.envelope(out);
+                    out.text(" ");
+                    message.structure(out, extended);
+                    out.text(&format!(" {}", count_lines(self.body)));
+                } else if self.content_type.main_type() == "text" {
+                    out.text(&format!(" {}", count_lines(self.body)));
+                }
+                if extended {
+                    out.text(" ");
+                    out.nstring(self.headers.get_raw("Content-MD5"));
+                    self.extension(out);
+                }
+            }
+        }
+        out.text(")");
+    }
+
+    /// The disposition, language and location extension fields.
+    fn extension(&self, out: &mut Out) {
+        out.text(" ");
+        match self
+            .headers
+            .get_raw("Content-Disposition")
+            .map(Disposition::parse)
+        {
+            Some(disposition) => {
+                out.text("(");
+                out.string(disposition.kind.to_ascii_uppercase().as_bytes());
+                out.text(" ");
+                params(out, &disposition.params);
+                out.text(")");
+            }
+            None => out.text("NIL"),
+        }
+        out.text(" ");
+        out.nstring(self.headers.get_raw("Content-Language"));
+        out.text(" ");
+        out.nstring(self.headers.get_raw("Content-Location"));
+    }
+}
+
+fn params(out: &mut Out, params: &[(String, String)]) {
+    if params.is_empty() {
+        return out.text("NIL");
+    }
+    out.text("(");
+    for (i, (name, value)) in params.iter().enumerate() {
+        if i > 0 {
+            out.text(" ");
+        }
+        out.string(name.to_ascii_uppercase().as_bytes());
+        out.text(" ");
+        out.string(value.as_bytes());
+    }
+    out.text(")");
+}
+
+/// Writes an address list as `((name adl mailbox host) ...)`, or NIL.
+fn addresses(out: &mut Out, raw: Option<&str>) {
+    let list = raw.map(parse_list).unwrap_or_default();
+    if list.is_empty() {
+        return out.text("NIL");
+    }
+    out.text("(");
+    for address in list {
+        let (mailbox, host) = match address.email.rsplit_once('@') {
+            Some((mailbox, host)) => (mailbox, Some(host)),
+            None => (address.email.as_str(), None),
+        };
+        out.text("(");
+        out.nstring(address.name.as_deref());
+        out.text(" NIL ");
+        out.string(mailbox.as_bytes());
+        out.text(" ");
+        out.nstring(host);
+        out.text(")");
+    }
+    out.text(")");
+}
+
+/// The header fields named in `fields` (or all others, with `not`), followed
+/// by the empty line.
+fn header_fields(header: &[u8], fields: &[String], not: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut keep = false;
+    for line in header.split_inclusive(|&b| b == b'\n') {
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        if !matches!(line.first(), Some(b' ' | b'\t')) {
+            let name = line
+                .iter()
+                .position(|&b| b == b':')
+                .map(|colon| String::from_utf8_lossy(&line[..colon]).trim().to_string());
+            keep = name.is_some_and(|name| {
+                fields.iter().any(|field| field.eq_ignore_ascii_case(&name)) != not
+            });
+        }
+        if keep {
+            out.extend_from_slice(line);
+        }
+    }
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+fn count_lines(body: &[u8]) -> usize {
+    let lines = body.iter().filter(|&&b| b == b'\n').count();
+    if body.last().is_some_and(|&b| b != b'\n') {
+        lines + 1
+    } else {
+        lines
+    }
+}
+
+/// Formats an INTERNALDATE, e.g. `17-Jul-1996 09:44:25 +0000`.
+pub(crate) fn internal_date(date: DateTime<Utc>) -> String {
+    date.format("%d-%b-%Y %H:%M:%S %z").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imap::parse::{fetch_items, FetchItem, Token};
+
+    const RAW: &[u8] = b"From: \"Ann\" <ann@example.test>\r\nTo: bob@example.test\r\n\
+Subject: Report\r\nContent-Type: multipart/mixed; boundary=b1\r\n\r\n\
+--b1\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nSee attached.\r\n\
+--b1\r\nContent-Type: application/pdf; name=r.pdf\r\nContent-Disposition: attachment; filename=r.pdf\r\n\
+Content-Transfer-Encoding: base64\r\n\r\nJVBERi0=\r\n\
+--b1\r\nContent-Type: message/rfc822\r\n\r\nSubject: inner\r\nX-A: 1\r\n\r\ninner body\r\n--b1--\r\n";
+
+    fn render(f: impl FnOnce(&mut Out)) -> String {
+        let mut out = Out::default();
+        f(&mut out);
+        String::from_utf8(out.bytes).unwrap()
+    }
+
+    fn section(spec: &str) -> Option<String> {
+        let items = fetch_items(&Token::Atom(format!("BODY.PEEK[{spec}]"))).unwrap();
+        let FetchItem::Section(section) = &items[0] else {
+            unreachable!()
+        };
+        Entity::parse(RAW)
+            .section(section)
+            .map(|bytes| String::from_utf8(bytes).unwrap())
+    }
+
+    #[test]
+    fn renders_envelope_and_structure() {
+        let message = Entity::parse(RAW);
+        assert_eq!(
+            render(|out| message.envelope(out)),
+            "(NIL \"Report\" ((\"Ann\" NIL \"ann\" \"example.test\")) \
+((\"Ann\" NIL \"ann\" \"example.test\")) ((\"Ann\" NIL \"ann\" \"example.test\")) \
+((NIL NIL \"bob\" \"example.test\")) NIL NIL NIL NIL)"
+        );
+        assert_eq!(
+            render(|out| message.structure(out, false)),
+            "((\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 13 1)\
+(\"APPLICATION\" \"PDF\" (\"NAME\" \"r.pdf\") NIL NIL \"BASE64\" 8)\
+(\"MESSAGE\" \"RFC822\" NIL NIL NIL \"7BIT\" 36 \
+(NIL \"inner\" NIL NIL NIL NIL NIL NIL NIL NIL) \
+(\"TEXT\" \"PLAIN\" NIL NIL NIL \"7BIT\" 10 1) 4) \"MIXED\")"
+        );
+        let extended = render(|out| message.structure(out, true));
+        assert!(extended
+            .contains("\"BASE64\" 8 NIL (\"ATTACHMENT\" (\"FILENAME\" \"r.pdf\")) NIL NIL)"));
+        assert!(extended.ends_with("\"MIXED\" (\"BOUNDARY\" \"b1\") NIL NIL NIL)"));
+    }
+
+    #[test]
+    fn extracts_sections() {
+        assert_eq!(section("").unwrap().as_bytes(), RAW);
+        assert!(section("HEADER").unwrap().ends_with("boundary=b1\r\n\r\n"));
+        assert!(section("TEXT").unwrap().starts_with("--b1\r\n"));
+        assert_eq!(section("1").unwrap(), "See attached.");
+        assert_eq!(section("2").unwrap(), "JVBERi0=");
+        assert_eq!(
+            section("1.MIME").unwrap(),
+            "Content-Type: text/plain; charset=utf-8\r\n\r\n"
+        );
+        assert_eq!(section("3.HEADER.FIELDS (x-a)").unwrap(), "X-A: 1\r\n\r\n");
+        assert_eq!(
+            section("HEADER.FIELDS.NOT (From To Content-Type)").unwrap(),
+            "Subject: Report\r\n\r\n"
+        );
+        assert_eq!(section("3.TEXT").unwrap(), "inner body");
+        assert_eq!(section("3.1").unwrap(), "inner body");
+        assert_eq!(section("4"), None);
+        assert_eq!(section("1.TEXT"), None);
+    }
+
+    #[test]
+    fn quotes_or_sends_literals() {
+        assert_eq!(render(|out| out.string(b"a \"b\"")), "\"a \\\"b\\\"\"");
+        assert_eq!(render(|out| out.string("é".as_bytes())), "{2}\r\né");
+        let mut out = Out::default();
+        out.literal(b"hello");
+        assert_eq!(out.log, "{5}");
+    }
+}
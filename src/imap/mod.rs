@@ -0,0 +1,140 @@
+//! The IMAP listener.
+//!
+//! Serves captured messages over IMAP4rev1 (RFC 3501) so a real mail client
+//! can render them. Each user sees one mailbox, chosen by
+//! [`Mailboxes::for_user`], as their `INBOX`.
+
+mod fetch;
+mod parse;
+mod search;
+mod session;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+use crate::auth::AuthPolicy;
+use crate::listener::{self, Protocol};
+use crate::mailbox::Mailboxes;
+use crate::sessions::{SessionId, SessionLog};
+use crate::store::MessageStore;
+use crate::tls::{TlsInfo, TlsMode};
+use session::Session;
+
+/// Settings for the IMAP listener.
+#[derive(Debug, Clone)]
+pub struct ImapConfig {
+    /// Host name used in the greeting.
+    pub hostname: String,
+    /// Largest message accepted with `APPEND`, in bytes.
+    pub max_message_size: usize,
+    /// How long a client may stay silent before the connection is dropped.
+    /// RFC 3501 asks for at least 30 minutes.
+    pub idle_timeout: Duration,
+    /// Whether the listener offers STARTTLS or expects TLS from the start.
+    pub tls: TlsMode,
+    /// Which `LOGIN` credentials are accepted.
+    pub auth: AuthPolicy,
+    /// Where sessions are logged, shared with the HTTP API.
+    pub sessions: SessionLog,
+    /// Which mailbox each user reads.
+    pub mailboxes: Mailboxes,
+}
+
+impl Default for ImapConfig {
+    fn default() -> Self {
+        ImapConfig {
+            hostname: "rubbermail".to_string(),
+            max_message_size: 25 * 1024 * 1024,
+            idle_timeout: Duration::from_secs(30 * 60),
+            tls: TlsMode::Off,
+            auth: AuthPolicy::AcceptAny,
+            sessions: SessionLog::default(),
+            mailboxes: Mailboxes::default(),
+        }
+    }
+}
+
+/// State shared by every session of one listener.
+pub(crate) struct Shared {
+    pub(crate) config: ImapConfig,
+    pub(crate) store: MessageStore,
+    /// Message ids serve as UIDs. They are only unique within one run, so
+    /// every run announces a new UIDVALIDITY.
+    pub(crate) uid_validity: u32,
+}
+
+/// A bound IMAP listener that has not started accepting yet.
+pub struct ImapServer {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+impl ImapServer {
+    pub async fn bind(
+        addr: SocketAddr,
+        config: ImapConfig,
+        store: MessageStore,
+    ) -> io::Result<Self> {
+        let uid_validity = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |elapsed| elapsed.as_secs() as u32);
+        Ok(ImapServer {
+            listener: TcpListener::bind(addr).await?,
+            shared: Arc::new(Shared {
+                config,
+                store,
+                uid_validity,
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the task is dropped.
+    pub async fn serve(self) -> io::Result<()> {
+        listener::serve(self.listener, self.shared).await
+    }
+}
+
+impl Protocol for Shared {
+    const NAME: &'static str = "IMAP";
+    const PROTOCOL: &'static str = "imap";
+
+    fn tls(&self) -> &TlsMode {
+        &self.config.tls
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        self.config.idle_timeout
+    }
+
+    fn sessions(&self) -> &SessionLog {
+        &self.config.sessions
+    }
+
+    async fn session<S>(
+        self: Arc<Self>,
+        stream: S,
+        peer: SocketAddr,
+        log: SessionId,
+        tls: Option<TlsInfo>,
+        resume: bool,
+    ) -> io::Result<Option<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let session = Session::new(stream, peer, log, self, tls);
+        if resume {
+            session.resume().await
+        } else {
+            session.run().await
+        }
+    }
+}
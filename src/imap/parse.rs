@@ -0,0 +1,535 @@
+//! IMAP command syntax (RFC 3501 section 9): tokens, sequence sets, fetch
+//! attributes, flag lists and dates.
+
+use chrono::{DateTime, FixedOffset, NaiveDate};
+
+/// One argument of a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Token {
+    /// An atom, including `NIL` and fetch attributes with a bracketed
+    /// section such as `BODY[HEADER.FIELDS (SUBJECT)]<0.100>`.
+    Atom(String),
+    /// A quoted string or a literal.
+    Str(Vec<u8>),
+    List(Vec<Token>),
+}
+
+impl Token {
+    /// The value of an astring: an atom or a string.
+    pub(crate) fn astring(&self) -> Option<String> {
+        match self {
+            Token::Atom(atom) => Some(atom.clone()),
+            Token::Str(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+            Token::List(_) => None,
+        }
+    }
+
+    pub(crate) fn atom(&self) -> Option<&str> {
+        match self {
+            Token::Atom(atom) => Some(atom),
+            _ => None,
+        }
+    }
+}
+
+/// A tagged command.
+#[derive(Debug)]
+pub(crate) struct Command {
+    pub(crate) tag: String,
+    /// The command name, upper-cased.
+    pub(crate) name: String,
+    pub(crate) args: Vec<Token>,
+}
+
+/// Parses a complete command. Literals appear inline, as `{n}` followed by
+/// CRLF and the `n` bytes.
+pub(crate) fn command(input: &[u8]) -> Result<Command, String> {
+    let mut parser = Parser { input, pos: 0 };
+    let tag = match parser.token()? {
+        Some(Token::Atom(tag)) if !tag.contains(['*', '+', '[', ']']) => tag,
+        _ => return Err("missing tag".to_string()),
+    };
+    let name = match parser.token()? {
+        Some(Token::Atom(name)) => name.to_ascii_uppercase(),
+        _ => return Err("missing command".to_string()),
+    };
+    let mut args = Vec::new();
+    while let Some(token) = parser.token()? {
+        args.push(token);
+    }
+    Ok(Command { tag, name, args })
+}
+
+/// The length announced by a trailing `{n}` or `{n+}`, if the line ends in
+/// a literal.
+pub(crate) fn literal_length(line: &[u8]) -> Option<usize> {
+    let inner = line.strip_suffix(b"}")?;
+    let open = inner.iter().rposition(|&b| b == b'{')?;
+    let digits = &inner[open + 1..];
+    let digits = digits.strip_suffix(b"+").unwrap_or(digits);
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn token(&mut self) -> Result<Option<Token>, String> {
+        self.skip_spaces();
+        match self.input.get(self.pos) {
+            None => Ok(None),
+            Some(b')') => Err("unexpected ')'".to_string()),
+            Some(_) => self.value().map(Some),
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.input.get(self.pos) == Some(&b' ') {
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self) -> Result<Token, String> {
+        match self.input[self.pos] {
+            b'(' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip_spaces();
+                    match self.input.get(self.pos) {
+                        None => return Err("unterminated list".to_string()),
+                        Some(b')') => {
+                            self.pos += 1;
+                            return Ok(Token::List(items));
+                        }
+                        Some(_) => items.push(self.value()?),
+                    }
+                }
+            }
+            b'"' => self.quoted(),
+            b'{' => self.literal(),
+            _ => self.atom(),
+        }
+    }
+
+    fn quoted(&mut self) -> Result<Token, String> {
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            match self.input.get(self.pos) {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(Token::Str(out));
+                }
+                Some(b'\\') => {
+                    let escaped = self.input.get(self.pos + 1).ok_or("unterminated string")?;
+                    out.push(*escaped);
+                    self.pos += 2;
+                }
+                Some(b'\r' | b'\n') | None => return Err("unterminated string".to_string()),
+                Some(&b) => {
+                    out.push(b);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn literal(&mut self) -> Result<Token, String> {
+        let rest = &self.input[self.pos..];
+        let close = rest
+            .iter()
+            .position(|&b| b == b'}')
+            .ok_or("invalid literal")?;
+        let length = literal_length(&rest[..=close]).ok_or("invalid literal")?;
+        let start = self.pos + close + 1;
+        let start = if self.input[start..].starts_with(b"\r\n") {
+            start + 2
+        } else {
+            return Err("invalid literal".to_string());
+        };
+        let data = self
+            .input
+            .get(start..start + length)
+            .ok_or("literal too short")?;
+        self.pos = start + length;
+        Ok(Token::Str(data.to_vec()))
+    }
+
+    fn atom(&mut self) -> Result<Token, String> {
+        let start = self.pos;
+        let mut depth = 0usize;
+        while let Some(&b) = self.input.get(self.pos) {
+            match b {
+                b'[' => depth += 1,
+                b']' => depth = depth.saturating_sub(1),
+                b' ' | b'(' | b')' if depth == 0 => break,
+                b'"' | b'{' if depth == 0 => break,
+                b'\r' | b'\n' => break,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return Err(format!("unexpected {:?}", char::from(self.input[start])));
+        }
+        Ok(Token::Atom(
+            String::from_utf8_lossy(&self.input[start..self.pos]).into_owned(),
+        ))
+    }
+}
+
+/// A set of message numbers or UIDs, such as `1:4,7,9:*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SequenceSet(Vec<(Bound, Bound)>);
+
+/// One end of a range; `*` is the largest number in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bound {
+    Number(u64),
+    Largest,
+}
+
+impl Bound {
+    fn parse(text: &str) -> Option<Bound> {
+        if text == "*" {
+            return Some(Bound::Largest);
+        }
+        match text.parse() {
+            Ok(0) | Err(_) => None,
+            Ok(n) => Some(Bound::Number(n)),
+        }
+    }
+
+    fn resolve(self, largest: u64) -> u64 {
+        match self {
+            Bound::Number(n) => n,
+            Bound::Largest => largest,
+        }
+    }
+}
+
+impl SequenceSet {
+    pub(crate) fn parse(text: &str) -> Option<SequenceSet> {
+        text.split(',')
+            .map(|range| match range.split_once(':') {
+                Some((from, to)) => Some((Bound::parse(from)?, Bound::parse(to)?)),
+                None => Bound::parse(range).map(|n| (n, n)),
+            })
+            .collect::<Option<_>>()
+            .map(SequenceSet)
+    }
+
+    /// Whether `n` is in the set, with `*` standing for `largest`. Ranges
+    /// may be given in either order.
+    pub(crate) fn contains(&self, n: u64, largest: u64) -> bool {
+        self.0.iter().any(|(from, to)| {
+            let (from, to) = (from.resolve(largest), to.resolve(largest));
+            (from.min(to)..=from.max(to)).contains(&n)
+        })
+    }
+}
+
+/// One attribute requested by FETCH.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum FetchItem {
+    Flags,
+    InternalDate,
+    Size,
+    Envelope,
+    Uid,
+    BodyStructure,
+    /// `BODY` without a section: the structure without extension data.
+    Body,
+    Rfc822,
+    Rfc822Header,
+    Rfc822Text,
+    Section(BodySection),
+}
+
+/// `BODY[section]<partial>` or `BODY.PEEK[...]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BodySection {
+    /// Set for `BODY.PEEK`, which leaves `\Seen` alone.
+    pub(crate) peek: bool,
+    /// Part numbers, empty for the whole message.
+    pub(crate) part: Vec<usize>,
+    pub(crate) text: Option<SectionText>,
+    /// Byte offset and maximum length.
+    pub(crate) partial: Option<(usize, usize)>,
+    /// The bracket contents as the client wrote them, echoed in responses.
+    pub(crate) spec: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SectionText {
+    Header,
+    /// `HEADER.FIELDS`, or `HEADER.FIELDS.NOT` when `not` is set.
+    HeaderFields {
+        not: bool,
+        fields: Vec<String>,
+    },
+    Text,
+    Mime,
+}
+
+/// Parses the attribute argument of FETCH: one attribute, a macro or a
+/// parenthesized list.
+pub(crate) fn fetch_items(arg: &Token) -> Result<Vec<FetchItem>, String> {
+    match arg {
+        Token::Atom(atom) => match atom.to_ascii_uppercase().as_str() {
+            "ALL" => Ok(vec![
+                FetchItem::Flags,
+                FetchItem::InternalDate,
+                FetchItem::Size,
+                FetchItem::Envelope,
+            ]),
+            "FAST" => Ok(vec![
+                FetchItem::Flags,
+                FetchItem::InternalDate,
+                FetchItem::Size,
+            ]),
+            "FULL" => Ok(vec![
+                FetchItem::Flags,
+                FetchItem::InternalDate,
+                FetchItem::Size,
+                FetchItem::Envelope,
+                FetchItem::Body,
+            ]),
+            _ => Ok(vec![fetch_item(atom)?]),
+        },
+        Token::List(items) => items
+            .iter()
+            .map(|item| {
+                item.atom()
+                    .ok_or_else(|| "invalid fetch attribute".to_string())
+                    .and_then(fetch_item)
+            })
+            .collect(),
+        Token::Str(_) => Err("invalid fetch attribute".to_string()),
+    }
+}
+
+fn fetch_item(atom: &str) -> Result<FetchItem, String> {
+    let upper = atom.to_ascii_uppercase();
+    let item = match upper.as_str() {
+        "FLAGS" => FetchItem::Flags,
+        "INTERNALDATE" => FetchItem::InternalDate,
+        "RFC822.SIZE" => FetchItem::Size,
+        "ENVELOPE" => FetchItem::Envelope,
+        "UID" => FetchItem::Uid,
+        "BODYSTRUCTURE" => FetchItem::BodyStructure,
+        "BODY" => FetchItem::Body,
+        "RFC822" => FetchItem::Rfc822,
+        "RFC822.HEADER" => FetchItem::Rfc822Header,
+        "RFC822.TEXT" => FetchItem::Rfc822Text,
+        _ => {
+            let invalid = || format!("invalid fetch attribute {atom:?}");
+            let (name, rest) = atom.split_once('[').ok_or_else(invalid)?;
+            let peek = match name.to_ascii_uppercase().as_str() {
+                "BODY" => false,
+                "BODY.PEEK" => true,
+                _ => return Err(invalid()),
+            };
+            let (spec, partial) = rest.split_once(']').ok_or_else(invalid)?;
+            let partial = match partial {
+                "" => None,
+                partial => Some(parse_partial(partial).ok_or_else(invalid)?),
+            };
+            let (part, text) = parse_section(spec).ok_or_else(invalid)?;
+            FetchItem::Section(BodySection {
+                peek,
+                part,
+                text,
+                partial,
+                spec: spec.to_string(),
+            })
+        }
+    };
+    Ok(item)
+}
+
+/// Parses `<offset.length>`.
+fn parse_partial(text: &str) -> Option<(usize, usize)> {
+    let inner = text.strip_prefix('<')?.strip_suffix('>')?;
+    let (offset, length) = inner.split_once('.')?;
+    Some((offset.parse().ok()?, length.parse().ok()?))
+}
+
+/// Parses a section spec such as `1.2.HEADER` or `HEADER.FIELDS (FROM TO)`.
+fn parse_section(spec: &str) -> Option<(Vec<usize>, Option<SectionText>)> {
+    let (head, fields) = match spec.split_once(' ') {
+        Some((head, fields)) => (head, Some(fields)),
+        None => (spec, None),
+    };
+    let mut part = Vec::new();
+    let mut words = head.split('.').filter(|word| !word.is_empty()).peekable();
+    while let Some(n) = words.peek().and_then(|word| word.parse::<usize>().ok()) {
+        if n == 0 {
+            return None;
+        }
+        part.push(n);
+        words.next();
+    }
+    let text = words.collect::<Vec<_>>().join(".").to_ascii_uppercase();
+    let text = match (text.as_str(), fields) {
+        ("", None) => None,
+        ("HEADER", None) => Some(SectionText::Header),
+        ("TEXT", None) => Some(SectionText::Text),
+        ("MIME", None) if !part.is_empty() => Some(SectionText::Mime),
+        ("HEADER.FIELDS", Some(fields)) | ("HEADER.FIELDS.NOT", Some(fields)) => {
+            let fields = fields.trim().strip_prefix('(')?.strip_suffix(')')?;
+            Some(SectionText::HeaderFields {
+                not: text.ends_with(".NOT"),
+                fields: fields
+                    .split_whitespace()
+                    .map(|field| field.trim_matches('"').to_string())
+                    .collect(),
+            })
+        }
+        _ => return None,
+    };
+    Some((part, text))
+}
+
+/// Parses a flag list, or a single flag, into canonical flag names.
+pub(crate) fn flags(arg: &Token) -> Result<Vec<String>, String> {
+    let atoms: Vec<&Token> = match arg {
+        Token::List(items) => items.iter().collect(),
+        token => vec![token],
+    };
+    atoms
+        .into_iter()
+        .map(|token| {
+            token
+                .atom()
+                .map(canonical_flag)
+                .ok_or_else(|| "invalid flag".to_string())
+        })
+        .collect()
+}
+
+/// System flags are case-insensitive; keywords are kept as given.
+pub(crate) fn canonical_flag(flag: &str) -> String {
+    const SYSTEM: [&str; 6] = [
+        "\\Seen",
+        "\\Answered",
+        "\\Flagged",
+        "\\Deleted",
+        "\\Draft",
+        "\\Recent",
+    ];
+    SYSTEM
+        .iter()
+        .find(|system| system.eq_ignore_ascii_case(flag))
+        .map_or_else(|| flag.to_string(), |system| system.to_string())
+}
+
+/// Parses a search date such as `1-Feb-1994`.
+pub(crate) fn date(text: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(text, "%d-%b-%Y").ok()
+}
+
+/// Parses an APPEND date-time such as `17-Jul-1996 02:44:25 -0700`.
+pub(crate) fn date_time(text: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_str(text.trim(), "%d-%b-%Y %H:%M:%S %z").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atom(text: &str) -> Token {
+        Token::Atom(text.to_string())
+    }
+
+    #[test]
+    fn parses_commands_with_strings_and_literals() {
+        let command =
+            command(b"a1 login \"al\\\"ice\" {5}\r\npa ss (\\Seen x) BODY[HEADER.FIELDS (A B)]")
+                .unwrap();
+        assert_eq!(command.tag, "a1");
+        assert_eq!(command.name, "LOGIN");
+        assert_eq!(
+            command.args,
+            [
+                Token::Str(b"al\"ice".to_vec()),
+                Token::Str(b"pa ss".to_vec()),
+                Token::List(vec![atom("\\Seen"), atom("x")]),
+                atom("BODY[HEADER.FIELDS (A B)]"),
+            ]
+        );
+        assert!(super::command(b"* LOGIN").is_err());
+        assert!(super::command(b"a1 LOGIN (x").is_err());
+        assert!(super::command(b"a1 LOGIN {10}\r\nshort").is_err());
+        assert_eq!(literal_length(b"a APPEND INBOX {310}"), Some(310));
+        assert_eq!(literal_length(b"a APPEND INBOX {12+}"), Some(12));
+        assert_eq!(literal_length(b"a NOOP"), None);
+    }
+
+    #[test]
+    fn sequence_sets() {
+        let set = SequenceSet::parse("2:4,7,9:*").unwrap();
+        assert!(!set.contains(1, 10));
+        assert!(set.contains(3, 10));
+        assert!(set.contains(7, 10));
+        assert!(!set.contains(8, 10));
+        assert!(set.contains(10, 10));
+        // `5:*` with only three messages still means 3:5.
+        assert!(SequenceSet::parse("5:*").unwrap().contains(3, 3));
+        assert!(SequenceSet::parse("0").is_none());
+        assert!(SequenceSet::parse("1:x").is_none());
+    }
+
+    #[test]
+    fn fetch_attributes() {
+        assert_eq!(fetch_items(&atom("fast")).unwrap().len(), 3);
+        let items = fetch_items(&Token::List(vec![
+            atom("UID"),
+            atom("BODY.PEEK[1.2.MIME]<10.20>"),
+            atom("body[header.fields.not (From To)]"),
+        ]))
+        .unwrap();
+        assert_eq!(items[0], FetchItem::Uid);
+        assert_eq!(
+            items[1],
+            FetchItem::Section(BodySection {
+                peek: true,
+                part: vec![1, 2],
+                text: Some(SectionText::Mime),
+                partial: Some((10, 20)),
+                spec: "1.2.MIME".into(),
+            })
+        );
+        let FetchItem::Section(section) = &items[2] else {
+            panic!("{:?}", items[2]);
+        };
+        assert_eq!(
+            section.text,
+            Some(SectionText::HeaderFields {
+                not: true,
+                fields: vec!["From".into(), "To".into()],
+            })
+        );
+        assert!(fetch_items(&atom("BODY[MIME]")).is_err());
+        assert!(fetch_items(&atom("BODY[0]")).is_err());
+        assert!(fetch_items(&atom("SIZE")).is_err());
+    }
+
+    #[test]
+    fn dates_and_flags() {
+        assert_eq!(date("1-Feb-1994"), NaiveDate::from_ymd_opt(1994, 2, 1));
+        assert_eq!(
+            date_time("17-Jul-1996 02:44:25 -0700")
+                .unwrap()
+                .to_rfc3339(),
+            "1996-07-17T02:44:25-07:00"
+        );
+        assert_eq!(
+            flags(&Token::List(vec![atom("\\SEEN"), atom("$Label1")])).unwrap(),
+            ["\\Seen", "$Label1"]
+        );
+    }
+}
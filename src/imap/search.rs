@@ -0,0 +1,293 @@
+//! IMAP SEARCH criteria (RFC 3501 section 6.4.4).
+
+use std::cell::OnceCell;
+
+use chrono::NaiveDate;
+
+use super::parse::{self, SequenceSet, Token};
+use crate::mime::{split_head_body, Headers, Message};
+use crate::store::CapturedMessage;
+
+/// A parsed search criterion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SearchKey {
+    All,
+    /// Whether the flag is set (or, when `false`, not set).
+    Flag(String, bool),
+    /// RubberMail has no notion of recent messages, so `RECENT` and `NEW`
+    /// match nothing.
+    Recent,
+    /// A header field contains the text, ignoring case.
+    Header(String, String),
+    Body(String),
+    Text(String),
+    Before(NaiveDate),
+    On(NaiveDate),
+    Since(NaiveDate),
+    SentBefore(NaiveDate),
+    SentOn(NaiveDate),
+    SentSince(NaiveDate),
+    Larger(usize),
+    Smaller(usize),
+    Uid(SequenceSet),
+    Sequence(SequenceSet),
+    Not(Box<SearchKey>),
+    Or(Box<SearchKey>, Box<SearchKey>),
+    And(Vec<SearchKey>),
+}
+
+/// Parses the arguments of SEARCH, after an optional `CHARSET`.
+pub(crate) fn parse(args: &[Token]) -> Result<SearchKey, String> {
+    let mut args = args.iter().peekable();
+    if args
+        .peek()
+        .and_then(|arg| arg.atom())
+        .is_some_and(|atom| atom.eq_ignore_ascii_case("CHARSET"))
+    {
+        args.next();
+        let charset = args
+            .next()
+            .and_then(Token::astring)
+            .ok_or("charset required")?;
+        if !["US-ASCII", "UTF-8"].contains(&charset.to_ascii_uppercase().as_str()) {
+            return Err(format!(
+                "[BADCHARSET (US-ASCII UTF-8)] unsupported charset {charset}"
+            ));
+        }
+    }
+    let mut keys = Vec::new();
+    while args.peek().is_some() {
+        keys.push(key(&mut args)?);
+    }
+    if keys.is_empty() {
+        return Err("search criteria required".to_string());
+    }
+    Ok(SearchKey::And(keys))
+}
+
+fn key<'a>(args: &mut impl Iterator<Item = &'a Token>) -> Result<SearchKey, String> {
+    let token = args.next().ok_or("search criteria required")?;
+    let atom = match token {
+        Token::List(items) => {
+            let mut items = items.iter().peekable();
+            let mut keys = Vec::new();
+            while items.peek().is_some() {
+                keys.push(key(&mut items)?);
+            }
+            return Ok(SearchKey::And(keys));
+        }
+        Token::Atom(atom) => atom.to_ascii_uppercase(),
+        Token::Str(_) => return Err("invalid search key".to_string()),
+    };
+    let mut string = |name: &str| {
+        args.next()
+            .and_then(Token::astring)
+            .ok_or_else(|| format!("{name} needs an argument"))
+    };
+    let flag = |name: &str, set| Ok(SearchKey::Flag(name.to_string(), set));
+    match atom.as_str() {
+        "ALL" | "OLD" => Ok(SearchKey::All),
+        "NEW" | "RECENT" => Ok(SearchKey::Recent),
+        "ANSWERED" => flag("\\Answered", true),
+        "DELETED" => flag("\\Deleted", true),
+        "DRAFT" => flag("\\Draft", true),
+        "FLAGGED" => flag("\\Flagged", true),
+        "SEEN" => flag("\\Seen", true),
+        "UNANSWERED" => flag("\\Answered", false),
+        "UNDELETED" => flag("\\Deleted", false),
+        "UNDRAFT" => flag("\\Draft", false),
+        "UNFLAGGED" => flag("\\Flagged", false),
+        "UNSEEN" => flag("\\Seen", false),
+        "KEYWORD" => Ok(SearchKey::Flag(
+            parse::canonical_flag(&string("KEYWORD")?),
+            true,
+        )),
+        "UNKEYWORD" => Ok(SearchKey::Flag(
+            parse::canonical_flag(&string("UNKEYWORD")?),
+            false,
+        )),
+        "FROM" | "TO" | "CC" | "BCC" | "SUBJECT" => {
+            Ok(SearchKey::Header(atom.clone(), string(&atom)?))
+        }
+        "HEADER" => {
+            let name = string("HEADER")?;
+            Ok(SearchKey::Header(name, string("HEADER")?))
+        }
+        "BODY" => Ok(SearchKey::Body(string("BODY")?)),
+        "TEXT" => Ok(SearchKey::Text(string("TEXT")?)),
+        "BEFORE" | "ON" | "SINCE" | "SENTBEFORE" | "SENTON" | "SENTSINCE" => {
+            let date = parse::date(&string(&atom)?).ok_or("invalid date")?;
+            Ok(match atom.as_str() {
+                "BEFORE" => SearchKey::Before(date),
+                "ON" => SearchKey::On(date),
+                "SINCE" => SearchKey::Since(date),
+                "SENTBEFORE" => SearchKey::SentBefore(date),
+                "SENTON" => SearchKey::SentOn(date),
+                _ => SearchKey::SentSince(date),
+            })
+        }
+        "LARGER" | "SMALLER" => {
+            let size = string(&atom)?.parse().map_err(|_| "invalid size")?;
+            Ok(if atom == "LARGER" {
+                SearchKey::Larger(size)
+            } else {
+                SearchKey::Smaller(size)
+            })
+        }
+        "UID" => SequenceSet::parse(&string("UID")?)
+            .map(SearchKey::Uid)
+            .ok_or_else(|| "invalid sequence set".to_string()),
+        "NOT" => Ok(SearchKey::Not(Box::new(key(args)?))),
+        "OR" => {
+            let left = key(args)?;
+            Ok(SearchKey::Or(Box::new(left), Box::new(key(args)?)))
+        }
+        _ => SequenceSet::parse(&atom)
+            .map(SearchKey::Sequence)
+            .ok_or_else(|| format!("unknown search key {atom}")),
+    }
+}
+
+/// A message being checked against a search, with its number in the
+/// selected mailbox.
+pub(crate) struct Candidate<'a> {
+    pub(crate) message: &'a CapturedMessage,
+    pub(crate) seq: u64,
+    /// Parsed on first use, since most keys only need the metadata.
+    parsed: OnceCell<Message>,
+}
+
+impl<'a> Candidate<'a> {
+    pub(crate) fn new(message: &'a CapturedMessage, seq: u64) -> Candidate<'a> {
+        Candidate {
+            message,
+            seq,
+            parsed: OnceCell::new(),
+        }
+    }
+
+    fn parsed(&self) -> &Message {
+        self.parsed.get_or_init(|| self.message.parse())
+    }
+}
+
+/// The largest sequence number and UID, which `*` stands for.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Largest {
+    pub(crate) seq: u64,
+    pub(crate) uid: u64,
+}
+
+impl SearchKey {
+    pub(crate) fn matches(&self, candidate: &Candidate, largest: Largest) -> bool {
+        let message = candidate.message;
+        let received = message.received_at.date_naive();
+        let sent = || message.summary.date.map(|date| date.date_naive());
+        match self {
+            SearchKey::All => true,
+            SearchKey::Flag(flag, set) => message.flags.contains(flag) == *set,
+            SearchKey::Recent => false,
+            SearchKey::Header(name, text) => candidate
+                .parsed()
+                .headers()
+                .get_all(name)
+                .any(|value| contains(value, text)),
+            SearchKey::Body(text) => body_contains(candidate, text),
+            SearchKey::Text(text) => {
+                let (head, _) = split_head_body(&message.raw);
+                contains(&String::from_utf8_lossy(head), text)
+                    || Headers::parse(head)
+                        .iter()
+                        .any(|header| contains(&header.value, text))
+                    || body_contains(candidate, text)
+            }
+            SearchKey::Before(date) => received < *date,
+            SearchKey::On(date) => received == *date,
+            SearchKey::Since(date) => received >= *date,
+            SearchKey::SentBefore(date) => sent().is_some_and(|sent| sent < *date),
+            SearchKey::SentOn(date) => sent() == Some(*date),
+            SearchKey::SentSince(date) => sent().is_some_and(|sent| sent >= *date),
+            SearchKey::Larger(size) => message.raw.len() > *size,
+            SearchKey::Smaller(size) => message.raw.len() < *size,
+            SearchKey::Uid(set) => set.contains(message.id, largest.uid),
+            SearchKey::Sequence(set) => set.contains(candidate.seq, largest.seq),
+            SearchKey::Not(key) => !key.matches(candidate, largest),
+            SearchKey::Or(left, right) => {
+                left.matches(candidate, largest) || right.matches(candidate, largest)
+            }
+            SearchKey::And(keys) => keys.iter().all(|key| key.matches(candidate, largest)),
+        }
+    }
+}
+
+/// Looks in the decoded text and HTML bodies, then in the raw body.
+fn body_contains(candidate: &Candidate, text: &str) -> bool {
+    let parsed = candidate.parsed();
+    let (_, body) = split_head_body(&candidate.message.raw);
+    [parsed.text.as_deref(), parsed.html.as_deref()]
+        .into_iter()
+        .flatten()
+        .any(|body| contains(body, text))
+        || contains(&String::from_utf8_lossy(body), text)
+}
+
+fn contains(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imap::parse::command;
+    use crate::store::{Envelope, SessionInfo};
+    use chrono::{TimeZone, Utc};
+
+    fn search(criteria: &str, message: &CapturedMessage) -> bool {
+        let parsed = command(format!("a SEARCH {criteria}").as_bytes()).unwrap();
+        let key = parse(&parsed.args).unwrap();
+        let largest = Largest { seq: 3, uid: 9 };
+        key.matches(&Candidate::new(message, 2), largest)
+    }
+
+    #[test]
+    fn evaluates_criteria() {
+        let mut message = CapturedMessage::new(
+            7,
+            Utc.with_ymd_and_hms(2024, 3, 5, 12, 0, 0).unwrap(),
+            Envelope::default(),
+            SessionInfo {
+                peer: "127.0.0.1:1".parse().unwrap(),
+                helo: String::new(),
+                tls: None,
+                username: None,
+            },
+            b"From: Alice <alice@example.test>\r\nSubject: Your =?UTF-8?Q?r=C3=A9sum=C3=A9?=\r\n\
+Date: Mon, 4 Mar 2024 09:00:00 +0000\r\n\r\nHello there\r\n"
+                .to_vec(),
+        );
+        message.flags.insert("\\Seen".to_string());
+
+        assert!(search("ALL", &message));
+        assert!(search("SEEN UNFLAGGED", &message));
+        assert!(!search("UNSEEN", &message));
+        assert!(search("FROM alice SUBJECT résumé", &message));
+        assert!(search("HEADER subject \"your\"", &message));
+        assert!(!search("TO alice", &message));
+        assert!(search("BODY HELLO", &message));
+        assert!(search("TEXT example.test", &message));
+        assert!(search("ON 5-Mar-2024 SENTON 4-Mar-2024", &message));
+        assert!(search("SINCE 5-Mar-2024 BEFORE 6-Mar-2024", &message));
+        assert!(!search("SENTSINCE 5-Mar-2024", &message));
+        assert!(search("UID 7:* 2", &message));
+        assert!(search("NOT 1 OR 3 UID 7", &message));
+        assert!(search("(LARGER 10 SMALLER 1000)", &message));
+        assert!(!search("NEW", &message));
+        assert!(!search("KEYWORD $Junk", &message));
+        assert!(search("CHARSET UTF-8 UNKEYWORD $Junk", &message));
+
+        let koi8 = command(b"a SEARCH CHARSET KOI8-R ALL").unwrap();
+        assert!(parse(&koi8.args).unwrap_err().starts_with("[BADCHARSET"));
+        let incomplete = command(b"a SEARCH FROM").unwrap();
+        assert!(parse(&incomplete.args).is_err());
+    }
+}
@@ -0,0 +1,947 @@
+//! One IMAP conversation.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::time::timeout;
+use tracing::{debug, error, info};
+
+use super::fetch::{self, Entity, Out};
+use super::parse::{self, FetchItem, SequenceSet, Token};
+use super::search::{self, Candidate, Largest};
+use super::Shared;
+use crate::line::{read_line, trim_eol, Line};
+use crate::sessions::{EntryKind, SessionId};
+use crate::store::{CapturedMessage, Envelope, MessageId, NewMessage, SessionInfo};
+use crate::tls::{TlsInfo, TlsMode};
+
+/// Longest command line we accept, not counting literals.
+const MAX_COMMAND_LINE: usize = 64 * 1024;
+
+/// Largest literal accepted before login, which is plenty for credentials.
+/// After login literals may be as large as the largest message.
+const MAX_LOGIN_LITERAL: usize = 4 * 1024;
+
+/// The flags clients may set; any keyword is allowed too.
+const SYSTEM_FLAGS: &str = "\\Answered \\Flagged \\Deleted \\Seen \\Draft";
+
+/// How a command completed, sent as its tagged response.
+enum Done {
+    Ok(String),
+    No(String),
+    Bad(String),
+}
+
+/// A command as read from the client, literals included.
+enum Input {
+    Command(Vec<u8>),
+    /// The command cannot be read; the tag is answered with BAD.
+    Invalid(String, &'static str),
+    Eof,
+}
+
+/// The logged-in user and the mailbox they see as `INBOX`.
+struct User {
+    name: String,
+    mailbox: String,
+}
+
+/// The selected `INBOX`.
+struct Selected {
+    /// Set by EXAMINE.
+    read_only: bool,
+    /// Message ids, which serve as UIDs, by sequence number.
+    uids: Vec<MessageId>,
+}
+
+impl Selected {
+    fn largest(&self) -> Largest {
+        Largest {
+            seq: self.uids.len() as u64,
+            uid: self.uids.last().copied().unwrap_or(0),
+        }
+    }
+
+    /// The sequence numbers and UIDs of the messages in `set`.
+    fn resolve(&self, set: &SequenceSet, by_uid: bool) -> Vec<(u64, MessageId)> {
+        let largest = self.largest();
+        (1..)
+            .zip(self.uids.iter().copied())
+            .filter(|&(seq, uid)| {
+                if by_uid {
+                    set.contains(uid, largest.uid)
+                } else {
+                    set.contains(seq, largest.seq)
+                }
+            })
+            .collect()
+    }
+}
+
+pub(crate) struct Session<S> {
+    stream: BufReader<S>,
+    shared: Arc<Shared>,
+    peer: SocketAddr,
+    /// This session's entry in the session log.
+    log: SessionId,
+    /// Set once the connection is encrypted.
+    tls: Option<TlsInfo>,
+    /// Set once the client has logged in.
+    user: Option<User>,
+    selected: Option<Selected>,
+    line: Vec<u8>,
+}
+
+impl<S> Session<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub(crate) fn new(
+        stream: S,
+        peer: SocketAddr,
+        log: SessionId,
+        shared: Arc<Shared>,
+        tls: Option<TlsInfo>,
+    ) -> Self {
+        Session {
+            stream: BufReader::new(stream),
+            shared,
+            peer,
+            log,
+            tls,
+            user: None,
+            selected: None,
+            line: Vec::new(),
+        }
+    }
+
+    /// Greets the client and serves commands until the connection ends.
+    ///
+    /// Returns the underlying stream if the client issued STARTTLS, so the
+    /// caller can run the handshake and [`resume`](Self::resume) over TLS.
+    pub(crate) async fn run(mut self) -> io::Result<Option<S>> {
+        let greeting = format!(
+            "* OK [CAPABILITY {}] {} RubberMail IMAP4rev1 ready",
+            self.capabilities(),
+            self.shared.config.hostname
+        );
+        self.reply(&greeting).await?;
+        self.resume().await
+    }
+
+    /// Serves commands without sending a greeting first.
+    pub(crate) async fn resume(mut self) -> io::Result<Option<S>> {
+        loop {
+            let input = match self.read_command().await? {
+                Input::Eof => return Ok(None),
+                Input::Invalid(tag, reason) => {
+                    self.reply(&format!("{tag} BAD {reason}")).await?;
+                    continue;
+                }
+                Input::Command(input) => input,
+            };
+            let command = match parse::command(&input) {
+                Ok(command) => command,
+                Err(err) => {
+                    let tag = tag_of(&input);
+                    self.reply(&format!("{tag} BAD {err}")).await?;
+                    continue;
+                }
+            };
+            let tag = command.tag.clone();
+            let name = command.name.clone();
+            let done = match name.as_str() {
+                "LOGOUT" => {
+                    self.reply("* BYE RubberMail logging out").await?;
+                    self.reply(&format!("{tag} OK LOGOUT completed")).await?;
+                    return Ok(None);
+                }
+                "STARTTLS" if self.offers_starttls() => {
+                    self.reply(&format!("{tag} OK Begin TLS negotiation now"))
+                        .await?;
+                    return Ok(Some(self.stream.into_inner()));
+                }
+                _ => self.dispatch(&name, &command.args).await?,
+            };
+            if self.selected.is_some() {
+                // RFC 3501 7.4.1: no EXPUNGE responses while answering FETCH,
+                // STORE or SEARCH, which use sequence numbers.
+                let expunges = !matches!(name.as_str(), "FETCH" | "STORE" | "SEARCH");
+                self.refresh(expunges).await?;
+            }
+            let (status, text) = match done {
+                Done::Ok(text) => ("OK", text),
+                Done::No(text) => ("NO", text),
+                Done::Bad(text) => ("BAD", text),
+            };
+            self.reply(&format!("{tag} {status} {text}")).await?;
+        }
+    }
+
+    async fn dispatch(&mut self, name: &str, args: &[Token]) -> io::Result<Done> {
+        let logged_in = self.user.is_some();
+        let selected = self.selected.is_some();
+        Ok(match name {
+            "CAPABILITY" => {
+                let line = format!("* CAPABILITY {}", self.capabilities());
+                self.reply(&line).await?;
+                Done::Ok("CAPABILITY completed".into())
+            }
+            "NOOP" | "CHECK" if name == "NOOP" || selected => Done::Ok(format!("{name} completed")),
+            "STARTTLS" => Done::Bad("STARTTLS not available".into()),
+            "LOGIN" if logged_in => Done::Bad("already logged in".into()),
+            "LOGIN" => self.login(args),
+            "AUTHENTICATE" => Done::No("unsupported authentication mechanism".into()),
+            _ if !logged_in => Done::Bad(format!("{name} needs LOGIN first")),
+            "SELECT" | "EXAMINE" => self.select(args, name == "EXAMINE").await?,
+            "LIST" | "LSUB" => self.list(name, args).await?,
+            "STATUS" => self.status(args).await?,
+            "APPEND" => self.append(args).await?,
+            "SUBSCRIBE" | "UNSUBSCRIBE" => Done::Ok(format!("{name} completed")),
+            "CREATE" | "DELETE" | "RENAME" => {
+                Done::No("[CANNOT] mailboxes are set by the server configuration".into())
+            }
+            _ if !selected => match name {
+                "CLOSE" | "EXPUNGE" | "SEARCH" | "FETCH" | "STORE" | "COPY" | "UID" | "CHECK" => {
+                    Done::Bad(format!("{name} needs a selected mailbox"))
+                }
+                _ => Done::Bad("unknown command".into()),
+            },
+            "CLOSE" => {
+                if !self.selected().read_only {
+                    self.expunge(None, true).await?;
+                }
+                self.selected = None;
+                Done::Ok("CLOSE completed".into())
+            }
+            "EXPUNGE" => self.expunge(None, false).await?,
+            "SEARCH" | "FETCH" | "STORE" | "COPY" => self.run_on_set(name, args, false).await?,
+            "UID" => match args.split_first() {
+                Some((command, rest)) => {
+                    let command = command.atom().unwrap_or_default().to_ascii_uppercase();
+                    match command.as_str() {
+                        "SEARCH" | "FETCH" | "STORE" | "COPY" => {
+                            self.run_on_set(&command, rest, true).await?
+                        }
+                        "EXPUNGE" => match rest.first().and_then(Token::atom) {
+                            Some(set) => match SequenceSet::parse(set) {
+                                Some(set) => self.expunge(Some(set), false).await?,
+                                None => Done::Bad("invalid sequence set".into()),
+                            },
+                            None => Done::Bad("sequence set required".into()),
+                        },
+                        _ => Done::Bad("unknown UID command".into()),
+                    }
+                }
+                None => Done::Bad("UID needs a command".into()),
+            },
+            _ => Done::Bad("unknown command".into()),
+        })
+    }
+
+    fn capabilities(&self) -> String {
+        let mut capabilities = "IMAP4rev1 UIDPLUS".to_string();
+        if self.offers_starttls() {
+            capabilities.push_str(" STARTTLS");
+        }
+        capabilities
+    }
+
+    fn offers_starttls(&self) -> bool {
+        matches!(self.shared.config.tls, TlsMode::StartTls(_))
+            && self.tls.is_none()
+            && self.user.is_none()
+    }
+
+    fn selected(&self) -> &Selected {
+        self.selected.as_ref().expect("mailbox selected")
+    }
+
+    fn mailbox(&self) -> &str {
+        &self.user.as_ref().expect("logged in").mailbox
+    }
+
+    fn login(&mut self, args: &[Token]) -> Done {
+        let [user, password] = args else {
+            return Done::Bad("LOGIN needs a user name and a password".into());
+        };
+        let (Some(user), Some(password)) = (user.astring(), password.astring()) else {
+            return Done::Bad("LOGIN needs a user name and a password".into());
+        };
+        if !self.shared.config.auth.check_password(&user, &password) {
+            self.log(EntryKind::Event, format!("login failed for {user}"));
+            return Done::No("[AUTHENTICATIONFAILED] invalid credentials".into());
+        }
+        let mailbox = self.shared.config.mailboxes.for_user(&user).to_string();
+        self.log(
+            EntryKind::Event,
+            format!("logged in as {user}, reading mailbox {mailbox}"),
+        );
+        self.user = Some(User {
+            name: user,
+            mailbox,
+        });
+        Done::Ok(format!(
+            "[CAPABILITY {}] LOGIN completed",
+            self.capabilities()
+        ))
+    }
+
+    async fn select(&mut self, args: &[Token], read_only: bool) -> io::Result<Done> {
+        self.selected = None;
+        let Some(name) = args.first().and_then(Token::astring) else {
+            return Ok(Done::Bad("mailbox name required".into()));
+        };
+        if !name.eq_ignore_ascii_case("INBOX") {
+            return Ok(Done::No("[NONEXISTENT] no such mailbox".into()));
+        }
+        let messages = self.shared.store.list_mailbox(self.mailbox());
+        let uids: Vec<MessageId> = messages.iter().rev().map(|message| message.id).collect();
+        let first_unseen = messages
+            .iter()
+            .rev()
+            .position(|message| !message.flags.contains("\\Seen"));
+        let mut lines = vec![
+            format!("* FLAGS ({SYSTEM_FLAGS})"),
+            format!("* OK [PERMANENTFLAGS ({SYSTEM_FLAGS} \\*)] Flags permitted"),
+            format!("* {} EXISTS", uids.len()),
+            "* 0 RECENT".to_string(),
+        ];
+        if let Some(index) = first_unseen {
+            lines.push(format!("* OK [UNSEEN {}] First unseen", index + 1));
+        }
+        lines.push(format!(
+            "* OK [UIDVALIDITY {}] UIDs valid",
+            self.shared.uid_validity
+        ));
+        lines.push(format!(
+            "* OK [UIDNEXT {}] Predicted next UID",
+            self.shared.store.next_id()
+        ));
+        self.reply_lines(lines).await?;
+        self.selected = Some(Selected { read_only, uids });
+        let (access, verb) = if read_only {
+            ("READ-ONLY", "EXAMINE")
+        } else {
+            ("READ-WRITE", "SELECT")
+        };
+        Ok(Done::Ok(format!("[{access}] {verb} completed")))
+    }
+
+    /// Answers LIST and LSUB: there is only `INBOX`.
+    async fn list(&mut self, name: &str, args: &[Token]) -> io::Result<Done> {
+        let [_, pattern] = args else {
+            return Ok(Done::Bad(format!("{name} needs a reference and a pattern")));
+        };
+        let Some(pattern) = pattern.astring() else {
+            return Ok(Done::Bad("invalid pattern".into()));
+        };
+        if pattern.is_empty() {
+            self.reply(&format!("* {name} (\\Noselect) \"/\" \"\""))
+                .await?;
+        } else if matches_pattern(&pattern.to_ascii_uppercase(), "INBOX") {
+            self.reply(&format!("* {name} (\\HasNoChildren) \"/\" INBOX"))
+                .await?;
+        }
+        Ok(Done::Ok(format!("{name} completed")))
+    }
+
+    async fn status(&mut self, args: &[Token]) -> io::Result<Done> {
+        let [mailbox, Token::List(items)] = args else {
+            return Ok(Done::Bad(
+                "STATUS needs a mailbox and a list of items".into(),
+            ));
+        };
+        if !mailbox
+            .astring()
+            .is_some_and(|name| name.eq_ignore_ascii_case("INBOX"))
+        {
+            return Ok(Done::No("[NONEXISTENT] no such mailbox".into()));
+        }
+        let messages = self.shared.store.list_mailbox(self.mailbox());
+        let mut values = Vec::new();
+        for item in items {
+            let item = item.atom().unwrap_or_default().to_ascii_uppercase();
+            let value = match item.as_str() {
+                "MESSAGES" => messages.len() as u64,
+                "RECENT" => 0,
+                "UIDNEXT" => self.shared.store.next_id(),
+                "UIDVALIDITY" => u64::from(self.shared.uid_validity),
+                "UNSEEN" => messages
+                    .iter()
+                    .filter(|message| !message.flags.contains("\\Seen"))
+                    .count() as u64,
+                _ => return Ok(Done::Bad(format!("unknown status item {item}"))),
+            };
+            values.push(format!("{item} {value}"));
+        }
+        self.reply(&format!("* STATUS INBOX ({})", values.join(" ")))
+            .await?;
+        Ok(Done::Ok("STATUS completed".into()))
+    }
+
+    /// Stores a message given by the client, keeping its flags and date.
+    async fn append(&mut self, args: &[Token]) -> io::Result<Done> {
+        let Some((Token::Str(raw), rest)) = args.split_last() else {
+            return Ok(Done::Bad("APPEND needs a message literal".into()));
+        };
+        let Some((mailbox, options)) = rest.split_first() else {
+            return Ok(Done::Bad("APPEND needs a mailbox".into()));
+        };
+        if !mailbox
+            .astring()
+            .is_some_and(|name| name.eq_ignore_ascii_case("INBOX"))
+        {
+            return Ok(Done::No("[TRYCREATE] no such mailbox".into()));
+        }
+        let mut flags = BTreeSet::new();
+        let mut received_at = Utc::now();
+        for option in options {
+            match option {
+                Token::List(_) => match parse::flags(option) {
+                    Ok(list) => flags.extend(list),
+                    Err(err) => return Ok(Done::Bad(err)),
+                },
+                Token::Str(date) => match parse::date_time(&String::from_utf8_lossy(date)) {
+                    Some(date) => received_at = date.with_timezone(&Utc),
+                    None => return Ok(Done::Bad("invalid date-time".into())),
+                },
+                Token::Atom(_) => return Ok(Done::Bad("invalid APPEND argument".into())),
+            }
+        }
+        let session = SessionInfo {
+            peer: self.peer,
+            helo: String::new(),
+            tls: self.tls.clone(),
+            username: self.user.as_ref().map(|user| user.name.clone()),
+        };
+        let message = NewMessage {
+            envelope: Envelope::default(),
+            session,
+            raw: raw.clone(),
+            authentication: None,
+        };
+        match self.copy_in(message, received_at, flags) {
+            Ok(message) => Ok(Done::Ok(format!(
+                "[APPENDUID {} {}] APPEND completed",
+                self.shared.uid_validity, message.id
+            ))),
+            Err(err) => {
+                error!("failed to append message: {err}");
+                Ok(Done::No("failed to store message".into()))
+            }
+        }
+    }
+
+    /// Stores a message in the user's mailbox with the given flags.
+    fn copy_in(
+        &self,
+        message: NewMessage,
+        received_at: DateTime<Utc>,
+        mut flags: BTreeSet<String>,
+    ) -> crate::Result<Arc<CapturedMessage>> {
+        let store = &self.shared.store;
+        let mut message = store.insert_at(self.mailbox(), message, received_at)?;
+        flags_without_recent(&mut flags);
+        if !flags.is_empty() {
+            if let Some(updated) = store.update(message.id, |message| message.flags = flags)? {
+                message = updated;
+            }
+        }
+        info!(id = message.id, mailbox = %message.mailbox, "stored message over IMAP");
+        self.log(EntryKind::Event, format!("stored message {}", message.id));
+        Ok(message)
+    }
+
+    /// Runs SEARCH, FETCH, STORE or COPY, with UIDs when `by_uid` is set.
+    async fn run_on_set(&mut self, name: &str, args: &[Token], by_uid: bool) -> io::Result<Done> {
+        if name == "SEARCH" {
+            return self.search(args, by_uid).await;
+        }
+        let Some((set, rest)) = args.split_first() else {
+            return Ok(Done::Bad(format!("{name} needs a sequence set")));
+        };
+        let Some(set) = set.atom().and_then(SequenceSet::parse) else {
+            return Ok(Done::Bad("invalid sequence set".into()));
+        };
+        let targets = self.selected().resolve(&set, by_uid);
+        match name {
+            "FETCH" => self.fetch(targets, rest, by_uid).await,
+            "STORE" => self.store(targets, rest, by_uid).await,
+            _ => Ok(self.copy(targets, rest)),
+        }
+    }
+
+    async fn search(&mut self, args: &[Token], by_uid: bool) -> io::Result<Done> {
+        let key = match search::parse(args) {
+            Ok(key) => key,
+            Err(err) => return Ok(Done::Bad(err)),
+        };
+        let selected = self.selected();
+        let largest = selected.largest();
+        let mut found = Vec::new();
+        for (seq, uid) in (1..).zip(selected.uids.iter().copied()) {
+            let Some(message) = self.shared.store.get(uid) else {
+                continue;
+            };
+            if key.matches(&Candidate::new(&message, seq), largest) {
+                found.push(if by_uid { uid } else { seq });
+            }
+        }
+        let numbers: String = found.iter().map(|n| format!(" {n}")).collect();
+        self.reply(&format!("* SEARCH{numbers}")).await?;
+        Ok(Done::Ok("SEARCH completed".into()))
+    }
+
+    async fn fetch(
+        &mut self,
+        targets: Vec<(u64, MessageId)>,
+        args: &[Token],
+        by_uid: bool,
+    ) -> io::Result<Done> {
+        let [items] = args else {
+            return Ok(Done::Bad("FETCH needs a list of attributes".into()));
+        };
+        let mut items = match parse::fetch_items(items) {
+            Ok(items) => items,
+            Err(err) => return Ok(Done::Bad(err)),
+        };
+        if by_uid && !items.contains(&FetchItem::Uid) {
+            items.insert(0, FetchItem::Uid);
+        }
+        let sets_seen = !self.selected().read_only
+            && items.iter().any(|item| match item {
+                FetchItem::Rfc822 | FetchItem::Rfc822Text => true,
+                FetchItem::Section(section) => !section.peek,
+                _ => false,
+            });
+        for (seq, uid) in targets {
+            let Some(mut message) = self.shared.store.get(uid) else {
+                continue;
+            };
+            let mut items = items.clone();
+            if sets_seen && !message.flags.contains("\\Seen") {
+                match self.shared.store.update(uid, |message| {
+                    message.flags.insert("\\Seen".to_string());
+                }) {
+                    Ok(Some(updated)) => message = updated,
+                    Ok(None) => {}
+                    Err(err) => error!(id = uid, "failed to set \\Seen: {err}"),
+                }
+                if !items.contains(&FetchItem::Flags) {
+                    items.push(FetchItem::Flags);
+                }
+            }
+            let out = fetch_response(seq, &message, &items);
+            self.send(out).await?;
+        }
+        Ok(Done::Ok("FETCH completed".into()))
+    }
+
+    async fn store(
+        &mut self,
+        targets: Vec<(u64, MessageId)>,
+        args: &[Token],
+        by_uid: bool,
+    ) -> io::Result<Done> {
+        let [item, flags] = args else {
+            return Ok(Done::Bad("STORE needs an item and flags".into()));
+        };
+        let item = item.atom().unwrap_or_default().to_ascii_uppercase();
+        let (item, silent) = match item.strip_suffix(".SILENT") {
+            Some(item) => (item.to_string(), true),
+            None => (item, false),
+        };
+        if !matches!(item.as_str(), "FLAGS" | "+FLAGS" | "-FLAGS") {
+            return Ok(Done::Bad(format!("unknown STORE item {item}")));
+        }
+        let mut flags: BTreeSet<String> = match parse::flags(flags) {
+            Ok(flags) => flags.into_iter().collect(),
+            Err(err) => return Ok(Done::Bad(err)),
+        };
+        flags_without_recent(&mut flags);
+        if self.selected().read_only {
+            return Ok(Done::No("[READ-ONLY] mailbox is read-only".into()));
+        }
+        for (seq, uid) in targets {
+            let updated = self
+                .shared
+                .store
+                .update(uid, |message| match item.as_str() {
+                    "FLAGS" => message.flags = flags.clone(),
+                    "+FLAGS" => message.flags.extend(flags.iter().cloned()),
+                    _ => message.flags.retain(|flag| !flags.contains(flag)),
+                });
+            match updated {
+                Ok(Some(message)) if !silent => {
+                    let mut items = vec![FetchItem::Flags];
+                    if by_uid {
+                        items.insert(0, FetchItem::Uid);
+                    }
+                    self.send(fetch_response(seq, &message, &items)).await?;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    error!(id = uid, "failed to store flags: {err}");
+                    return Ok(Done::No("failed to store flags".into()));
+                }
+            }
+        }
+        Ok(Done::Ok("STORE completed".into()))
+    }
+
+    /// Copies messages into `INBOX`, which is the only mailbox.
+    fn copy(&self, targets: Vec<(u64, MessageId)>, args: &[Token]) -> Done {
+        let [mailbox] = args else {
+            return Done::Bad("COPY needs a mailbox".into());
+        };
+        if !mailbox
+            .astring()
+            .is_some_and(|name| name.eq_ignore_ascii_case("INBOX"))
+        {
+            return Done::No("[TRYCREATE] no such mailbox".into());
+        }
+        let mut from = Vec::new();
+        let mut to = Vec::new();
+        for (_, uid) in targets {
+            let Some(message) = self.shared.store.get(uid) else {
+                continue;
+            };
+            let copy = NewMessage {
+                envelope: message.envelope.clone(),
+                session: message.session.clone(),
+                raw: message.raw.clone(),
+                authentication: message.authentication.clone(),
+            };
+            match self.copy_in(copy, message.received_at, message.flags.clone()) {
+                Ok(copy) => {
+                    from.push(uid.to_string());
+                    to.push(copy.id.to_string());
+                }
+                Err(err) => {
+                    error!(id = uid, "failed to copy message: {err}");
+                    return Done::No("failed to copy messages".into());
+                }
+            }
+        }
+        if from.is_empty() {
+            return Done::Ok("COPY completed".into());
+        }
+        Done::Ok(format!(
+            "[COPYUID {} {} {}] COPY completed",
+            self.shared.uid_validity,
+            from.join(","),
+            to.join(",")
+        ))
+    }
+
+    /// Deletes messages flagged `\Deleted`, only those in `uids` if given.
+    /// CLOSE does this `silent`ly.
+    async fn expunge(&mut self, uids: Option<SequenceSet>, silent: bool) -> io::Result<Done> {
+        if self.selected().read_only {
+            return Ok(Done::No("[READ-ONLY] mailbox is read-only".into()));
+        }
+        let largest = self.selected().largest();
+        let mut lines = Vec::new();
+        let mut failed = false;
+        for seq in (1..=self.selected().uids.len()).rev() {
+            let uid = self.selected().uids[seq - 1];
+            if uids
+                .as_ref()
+                .is_some_and(|set| !set.contains(uid, largest.uid))
+            {
+                continue;
+            }
+            let deleted = self
+                .shared
+                .store
+                .get(uid)
+                .is_some_and(|message| message.flags.contains("\\Deleted"));
+            if !deleted {
+                continue;
+            }
+            match self.shared.store.delete(uid) {
+                Ok(_) => {
+                    self.log(EntryKind::Event, format!("deleted message {uid}"));
+                    self.selected
+                        .as_mut()
+                        .expect("mailbox selected")
+                        .uids
+                        .remove(seq - 1);
+                    lines.push(format!("* {seq} EXPUNGE"));
+                }
+                Err(err) => {
+                    error!(id = uid, "failed to delete message: {err}");
+                    failed = true;
+                }
+            }
+        }
+        if !silent {
+            self.reply_lines(lines).await?;
+        }
+        Ok(if failed {
+            Done::No("some messages could not be deleted".into())
+        } else {
+            Done::Ok("EXPUNGE completed".into())
+        })
+    }
+
+    /// Tells the client about messages that arrived or were deleted by
+    /// others since the last command.
+    async fn refresh(&mut self, expunges: bool) -> io::Result<()> {
+        let current: BTreeSet<MessageId> = self
+            .shared
+            .store
+            .list_mailbox(self.mailbox())
+            .iter()
+            .map(|message| message.id)
+            .collect();
+        let selected = self.selected.as_mut().expect("mailbox selected");
+        let mut lines = Vec::new();
+        if expunges {
+            for seq in (1..=selected.uids.len()).rev() {
+                if !current.contains(&selected.uids[seq - 1]) {
+                    selected.uids.remove(seq - 1);
+                    lines.push(format!("* {seq} EXPUNGE"));
+                }
+            }
+        }
+        let last = selected.largest().uid;
+        let count = selected.uids.len();
+        selected.uids.extend(current.range(last + 1..));
+        if selected.uids.len() != count {
+            lines.push(format!("* {} EXISTS", selected.uids.len()));
+        }
+        self.reply_lines(lines).await
+    }
+
+    /// Reads one command, asking for and collecting its literals.
+    async fn read_command(&mut self) -> io::Result<Input> {
+        let mut command = Vec::new();
+        loop {
+            match self.read_line().await? {
+                Line::Eof => return Ok(Input::Eof),
+                Line::TooLong => return Ok(Input::Invalid(tag_of(&command), "line too long")),
+                Line::Complete => {}
+            }
+            let line = trim_eol(&self.line).to_vec();
+            // The rest of a LOGIN after a literal is part of the credentials.
+            if command.is_empty() || !is_login(&command) {
+                let text = redact(&String::from_utf8_lossy(&line));
+                debug!(peer = %self.peer, "C: {text}");
+                self.log(EntryKind::Client, text);
+            }
+            command.extend_from_slice(&line);
+            let Some(length) = parse::literal_length(&line) else {
+                return Ok(Input::Command(command));
+            };
+            let synchronizing = !line.ends_with(b"+}");
+            let (max_literal, max_command) = self.literal_limits();
+            if length > max_literal || command.len() + length > max_command {
+                if synchronizing {
+                    // The client waits for a go-ahead, so nothing follows.
+                    return Ok(Input::Invalid(tag_of(&command), "literal too large"));
+                }
+                // The literal is on its way and must not be read as commands.
+                self.reply("* BYE literal too large").await?;
+                return Ok(Input::Eof);
+            }
+            if synchronizing {
+                self.reply("+ Ready for literal data").await?;
+            }
+            command.extend_from_slice(b"\r\n");
+            // Read as the data arrives, so a client announcing a literal it
+            // never sends does not cost its size in memory.
+            let expected = command.len() + length;
+            let idle = self.shared.config.idle_timeout;
+            let mut literal = (&mut self.stream).take(length as u64);
+            match timeout(idle, literal.read_to_end(&mut command)).await {
+                Ok(Ok(_)) if command.len() == expected => {}
+                Ok(Ok(_)) => return Ok(Input::Eof),
+                Ok(Err(err)) => return Err(err),
+                Err(_) => {
+                    self.reply("* BYE idle timeout").await?;
+                    return Ok(Input::Eof);
+                }
+            }
+        }
+    }
+
+    /// The largest literal and the largest command, literals included, the
+    /// client may send: enough for credentials until it logs in, and for a
+    /// message with `APPEND` afterwards.
+    fn literal_limits(&self) -> (usize, usize) {
+        if self.user.is_some() {
+            let max_message_size = self.shared.config.max_message_size;
+            (max_message_size, max_message_size + MAX_COMMAND_LINE)
+        } else {
+            (MAX_LOGIN_LITERAL, MAX_COMMAND_LINE)
+        }
+    }
+
+    async fn read_line(&mut self) -> io::Result<Line> {
+        let idle = self.shared.config.idle_timeout;
+        match timeout(
+            idle,
+            read_line(&mut self.stream, &mut self.line, MAX_COMMAND_LINE),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => {
+                self.reply("* BYE idle timeout").await?;
+                Ok(Line::Eof)
+            }
+        }
+    }
+
+    fn log(&self, kind: EntryKind, text: impl Into<String>) {
+        self.shared.config.sessions.record(self.log, kind, text);
+    }
+
+    async fn reply(&mut self, text: &str) -> io::Result<()> {
+        debug!(peer = %self.peer, "S: {text}");
+        self.log(EntryKind::Server, text);
+        let stream = self.stream.get_mut();
+        stream.write_all(format!("{text}\r\n").as_bytes()).await?;
+        stream.flush().await
+    }
+
+    async fn reply_lines(&mut self, lines: Vec<String>) -> io::Result<()> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let out: String = lines.iter().map(|line| format!("{line}\r\n")).collect();
+        self.log(EntryKind::Server, out.trim_end());
+        let stream = self.stream.get_mut();
+        stream.write_all(out.as_bytes()).await?;
+        stream.flush().await
+    }
+
+    /// Sends a response built with literals, logging it without them.
+    async fn send(&mut self, out: Out) -> io::Result<()> {
+        debug!(peer = %self.peer, "S: {}", out.log.trim_end());
+        self.log(EntryKind::Server, out.log.trim_end());
+        let stream = self.stream.get_mut();
+        stream.write_all(&out.bytes).await?;
+        stream.flush().await
+    }
+}
+
+/// Builds `* seq FETCH (...)` for one message.
+fn fetch_response(seq: u64, message: &CapturedMessage, items: &[FetchItem]) -> Out {
+    let entity = Entity::parse(&message.raw);
+    let mut out = Out::default();
+    out.text(&format!("* {seq} FETCH ("));
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            out.text(" ");
+        }
+        match item {
+            FetchItem::Flags => {
+                let flags: Vec<&str> = message.flags.iter().map(String::as_str).collect();
+                out.text(&format!("FLAGS ({})", flags.join(" ")));
+            }
+            FetchItem::InternalDate => out.text(&format!(
+                "INTERNALDATE \"{}\"",
+                fetch::internal_date(message.received_at)
+            )),
+            FetchItem::Size => out.text(&format!("RFC822.SIZE {}", message.raw.len())),
+            FetchItem::Uid => out.text(&format!("UID {}", message.id)),
+            FetchItem::Envelope => {
+                out.text("ENVELOPE ");
+                entity.envelope(&mut out);
+            }
+            FetchItem::BodyStructure => {
+                out.text("BODYSTRUCTURE ");
+                entity.structure(&mut out, true);
+            }
+            FetchItem::Body => {
+                out.text("BODY ");
+                entity.structure(&mut out, false);
+            }
+            FetchItem::Rfc822 => {
+                out.text("RFC822 ");
+                out.literal(&message.raw);
+            }
+            FetchItem::Rfc822Header => {
+                out.text("RFC822.HEADER ");
+                out.literal(entity.header());
+            }
+            FetchItem::Rfc822Text => {
+                out.text("RFC822.TEXT ");
+                out.literal(entity.body());
+            }
+            FetchItem::Section(section) => {
+                out.text(&format!("BODY[{}]", section.spec));
+                let data = entity.section(section);
+                match (data, section.partial) {
+                    (None, _) => out.text(" NIL"),
+                    (Some(data), None) => {
+                        out.text(" ");
+                        out.literal(&data);
+                    }
+                    (Some(data), Some((offset, length))) => {
+                        out.text(&format!("<{offset}> "));
+                        let start = offset.min(data.len());
+                        let end = start.saturating_add(length).min(data.len());
+                        out.literal(&data[start..end]);
+                    }
+                }
+            }
+        }
+    }
+    out.text(")\r\n");
+    out
+}
+
+/// `\Recent` is maintained by the server and cannot be stored.
+fn flags_without_recent(flags: &mut BTreeSet<String>) {
+    flags.remove("\\Recent");
+}
+
+/// Matches a LIST pattern, where `*` and `%` match any run of characters.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.find(['*', '%']) {
+        None => pattern == name,
+        Some(at) => {
+            name.starts_with(&pattern[..at])
+                && (at..=name.len())
+                    .any(|split| matches_pattern(&pattern[at + 1..], &name[split..]))
+        }
+    }
+}
+
+/// The first word of a command, for answering commands that cannot be
+/// parsed.
+fn tag_of(command: &[u8]) -> String {
+    let tag = command
+        .split(|&b| b == b' ')
+        .next()
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| String::from_utf8_lossy(tag).into_owned());
+    tag.unwrap_or_else(|| "*".to_string())
+}
+
+fn is_login(command: &[u8]) -> bool {
+    let mut words = command.split(|&b| b == b' ');
+    words.next();
+    words
+        .next()
+        .is_some_and(|name| name.eq_ignore_ascii_case(b"LOGIN"))
+}
+
+/// Hides the credentials given with LOGIN.
+fn redact(line: &str) -> String {
+    let mut words = line.splitn(3, ' ');
+    match (words.next(), words.next(), words.next()) {
+        (Some(tag), Some(name), Some(_)) if name.eq_ignore_ascii_case("LOGIN") => {
+            format!("{tag} {name} <credentials>")
+        }
+        _ => line.to_string(),
+    }
+}
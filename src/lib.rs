@@ -0,0 +1,152 @@
+//! RubberMail captures mail sent by applications under test so developers can
+//! inspect it instead of having it delivered.
+
+pub mod archive;
+pub mod auth;
+pub mod compat;
+pub mod config;
+pub mod dkim;
+pub mod dmarc;
+pub mod dns;
+mod error;
+pub mod faults;
+mod html;
+pub mod http;
+pub mod imap;
+mod line;
+pub mod links;
+mod listener;
+pub mod mailbox;
+pub mod mime;
+pub mod pop3;
+pub mod release;
+pub mod retention;
+pub mod search;
+pub mod sessions;
+pub mod smtp;
+pub mod spam;
+pub mod spf;
+pub mod storage;
+pub mod store;
+pub mod testing;
+pub mod tls;
+pub mod webhooks;
+
+pub use config::Config;
+pub use error::{Error, Result};
+pub use store::{CapturedMessage, MessageId, MessageStore};
+pub use testing::TestServer;
+
+use std::io;
+use std::sync::Arc;
+
+use http::{AppState, HttpServer};
+use imap::{ImapConfig, ImapServer};
+use mailbox::Mailboxes;
+use pop3::{Pop3Config, Pop3Server};
+use retention::{Retention, ALL_MAILBOXES};
+use sessions::SessionLog;
+use smtp::{SmtpConfig, SmtpServer};
+use tls::TlsMode;
+use tokio::task::JoinSet;
+use tracing::info;
+
+/// Starts every listener described by `config` and runs until one fails.
+pub async fn run(config: Config) -> Result<()> {
+    let storage = storage::open(config.storage, config.storage_path.clone())?;
+    let store = MessageStore::open(storage)?;
+    info!(
+        backend = ?config.storage,
+        messages = store.len(),
+        "message store ready"
+    );
+    let spamd = config.spamd().map(Arc::new);
+    if let Some(spamd) = &spamd {
+        spamd.clone().watch(store.clone());
+    }
+    let faults = config.fault_rules()?;
+    let webhooks = config.webhooks()?;
+    for webhook in webhooks.webhooks() {
+        info!(url = %webhook.url, "webhook");
+    }
+    webhooks.clone().watch(store.clone());
+    let sessions = SessionLog::new();
+    let mailboxes = Mailboxes::new(config.mailbox_rules.clone());
+    let retention = Retention::new(config.retention_rules.clone());
+    for rule in retention.rules() {
+        if rule.mailbox != ALL_MAILBOXES && !mailboxes.contains(&rule.mailbox) {
+            return Err(Error::Config(format!(
+                "retention limit for unknown mailbox {:?}",
+                rule.mailbox
+            )));
+        }
+        info!(mailbox = %rule.mailbox, limit = %rule.limit, "retention limit");
+    }
+    if !retention.rules().is_empty() {
+        retention.clone().watch(store.clone());
+    }
+    let dns = config.dns_records()?;
+    let smtp_config = |tls| SmtpConfig {
+        faults: faults.clone(),
+        sessions: sessions.clone(),
+        mailboxes: mailboxes.clone(),
+        dns: dns.clone(),
+        ..config.smtp(tls)
+    };
+
+    let acceptor = config
+        .tls_enabled()
+        .then(|| config.tls_acceptor())
+        .transpose()?;
+    let starttls = match (&acceptor, config.starttls) {
+        (Some(acceptor), true) => TlsMode::StartTls(acceptor.clone()),
+        _ => TlsMode::Off,
+    };
+    let mut listeners = JoinSet::new();
+    for &addr in &config.smtp_addr {
+        let smtp = SmtpServer::bind(addr, smtp_config(starttls.clone()), store.clone()).await?;
+        listeners.spawn(smtp.serve());
+    }
+    if let (Some(addr), Some(acceptor)) = (config.smtps_addr, acceptor.clone()) {
+        let tls = TlsMode::Implicit(acceptor);
+        let smtps = SmtpServer::bind(addr, smtp_config(tls), store.clone()).await?;
+        listeners.spawn(smtps.serve());
+    }
+    if let (Some(addr), Some(acceptor)) = (config.pop3_addr, acceptor.clone()) {
+        let pop3_config = Pop3Config {
+            sessions: sessions.clone(),
+            mailboxes: mailboxes.clone(),
+            ..config.pop3(TlsMode::StartTls(acceptor))
+        };
+        let pop3 = Pop3Server::bind(addr, pop3_config, store.clone()).await?;
+        listeners.spawn(pop3.serve());
+    }
+    if let (Some(addr), Some(acceptor)) = (config.imap_addr, acceptor) {
+        let imap_config = ImapConfig {
+            sessions: sessions.clone(),
+            mailboxes: mailboxes.clone(),
+            ..config.imap(TlsMode::StartTls(acceptor))
+        };
+        let imap = ImapServer::bind(addr, imap_config, store.clone()).await?;
+        listeners.spawn(imap.serve());
+    }
+    let state = AppState {
+        store,
+        faults,
+        sessions,
+        release: config.relay()?.map(Arc::new),
+        spamd,
+        mailboxes,
+        retention,
+        webhooks,
+        check_internal_links: config.check_internal_links,
+    };
+    let http = HttpServer::bind(config.http_addr, state).await?;
+    listeners.spawn(http.serve());
+    // Listeners only return when they fail, so the first to finish ends the
+    // run.
+    if let Some(result) = listeners.join_next().await {
+        result.map_err(io::Error::other)??;
+    }
+    Ok(())
+}
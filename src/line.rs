@@ -0,0 +1,59 @@
+//! Line reading shared by the text protocols.
+
+use std::io;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// The outcome of reading one line.
+pub(crate) enum Line {
+    Complete,
+    TooLong,
+    Eof,
+}
+
+/// Reads one line into `line`, keeping its line ending.
+///
+/// Lines longer than `limit` are consumed and discarded. Both CRLF and bare
+/// LF terminate a line.
+pub(crate) async fn read_line<R>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+    limit: usize,
+) -> io::Result<Line>
+where
+    R: AsyncBufRead + Unpin,
+{
+    line.clear();
+    let mut too_long = false;
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(Line::Eof);
+        }
+        let (chunk, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(i) => (&available[..=i], true),
+            None => (available, false),
+        };
+        if !too_long && line.len() + chunk.len() <= limit {
+            line.extend_from_slice(chunk);
+        } else {
+            too_long = true;
+            line.clear();
+        }
+        let consumed = chunk.len();
+        reader.consume(consumed);
+        if done {
+            return Ok(if too_long {
+                Line::TooLong
+            } else {
+                Line::Complete
+            });
+        }
+    }
+}
+
+/// Strips a trailing CRLF or LF.
+pub(crate) fn trim_eol(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
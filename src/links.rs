@@ -0,0 +1,527 @@
+//! Links in captured messages: extraction and health checks.
+//!
+//! Every `href` and `src` in the HTML body and every URL in the text body is
+//! collected and checked for mistakes that are visible without going online:
+//! malformed or relative URLs, `http` links next to `https` ones, hosts that
+//! only resolve inside the sender's network, and template variables that
+//! were never filled in. Reachability is only checked on request, since it
+//! contacts the linked hosts, and internal hosts are left alone unless
+//! allowed, so captured mail cannot make the server probe its own network.
+
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use reqwest::redirect::Policy;
+use reqwest::{Method, StatusCode};
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use url::{Host, Url};
+
+use crate::html;
+
+/// Limit on each reachability request, redirects included.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Reachability requests in flight at once, per report.
+const MAX_CONCURRENT_CHECKS: usize = 8;
+/// Redirects followed per reachability request.
+const MAX_REDIRECTS: usize = 10;
+
+/// Domain suffixes that only resolve on private networks.
+const INTERNAL_SUFFIXES: &[&str] = &[
+    "localhost",
+    "local",
+    "internal",
+    "intranet",
+    "lan",
+    "corp",
+    "home.arpa",
+];
+
+/// The links of one message.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LinkReport {
+    /// Each distinct URL once, in order of first appearance.
+    pub links: Vec<Link>,
+    /// Whether reachability was checked.
+    pub checked: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Link {
+    /// The URL as written, with HTML entities decoded.
+    pub url: String,
+    /// Where it appears: `text`, or the element and attribute, e.g. `a href`.
+    pub found_in: Vec<String>,
+    pub count: usize,
+    pub issues: Vec<Issue>,
+    /// Set once reachability has been checked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub check: Option<Check>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// Empty, relative, or not a URL at all.
+    Malformed,
+    /// An `http` link in a message that also links over `https`.
+    MixedScheme,
+    /// A host such as `localhost`, a private address or a single-label name.
+    InternalHost,
+    /// A placeholder like `{{token}}` that was never replaced.
+    TemplateVariable,
+    /// The reachability check failed or returned an error status.
+    Unreachable,
+}
+
+/// The outcome of a reachability check.
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    /// `HEAD`, or `GET` when the server does not support `HEAD`.
+    pub method: &'static str,
+    /// Final status, after redirects; unset if no response arrived.
+    pub status: Option<u16>,
+    /// Where redirects ended, if anywhere else.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirected_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub millis: u64,
+}
+
+impl LinkReport {
+    /// Collects and inspects the links of a message's bodies.
+    pub fn of(html: Option<&str>, text: Option<&str>) -> LinkReport {
+        let mut found = Found::default();
+        if let Some(html) = html {
+            for tag in html::tags(html) {
+                for attribute in ["href", "src"] {
+                    if let Some(url) = tag.attribute(attribute) {
+                        found.add(url.trim(), format!("{} {attribute}", tag.name));
+                    }
+                }
+            }
+        }
+        if let Some(text) = text {
+            for url in text_urls(text) {
+                found.add(url, "text".to_string());
+            }
+        }
+
+        let mut links = found.links;
+        let scheme = |link: &Link| {
+            Url::parse(&link.url)
+                .ok()
+                .map(|url| url.scheme().to_string())
+        };
+        let has_https = links
+            .iter()
+            .any(|link| scheme(link).as_deref() == Some("https"));
+        for link in &mut links {
+            link.issues = inspect(&link.url);
+            if has_https && scheme(link).as_deref() == Some("http") {
+                link.issues.push(Issue {
+                    kind: IssueKind::MixedScheme,
+                    message: "uses http while other links use https".to_string(),
+                });
+            }
+        }
+        LinkReport {
+            links,
+            checked: false,
+        }
+    }
+
+    /// Checks every well-formed web link for reachability. Links to internal
+    /// hosts, and redirects to them, are only followed if `internal` is set.
+    pub async fn check(&mut self, internal: bool) {
+        let limit = Arc::new(Semaphore::new(MAX_CONCURRENT_CHECKS));
+        let mut tasks = JoinSet::new();
+        for (index, link) in self.links.iter().enumerate() {
+            if !is_checkable(link, internal) {
+                continue;
+            }
+            let url = link.url.clone();
+            let limit = limit.clone();
+            tasks.spawn(async move {
+                let _permit = limit.acquire_owned().await;
+                (index, check(&url, internal).await)
+            });
+        }
+        while let Some(joined) = tasks.join_next().await {
+            let Ok((index, check)) = joined else {
+                continue;
+            };
+            let link = &mut self.links[index];
+            let problem = match (&check.error, check.status) {
+                (Some(error), _) => Some(error.clone()),
+                (None, Some(status)) if status >= 400 => Some(format!(
+                    "returned {}",
+                    StatusCode::from_u16(status)
+                        .map(|status| status.to_string())
+                        .unwrap_or_else(|_| status.to_string())
+                )),
+                _ => None,
+            };
+            if let Some(message) = problem {
+                link.issues.push(Issue {
+                    kind: IssueKind::Unreachable,
+                    message,
+                });
+            }
+            link.check = Some(check);
+        }
+        self.checked = true;
+    }
+}
+
+/// Distinct URLs in order of appearance.
+#[derive(Default)]
+struct Found {
+    links: Vec<Link>,
+    index: BTreeMap<String, usize>,
+}
+
+impl Found {
+    fn add(&mut self, url: &str, location: String) {
+        let index = *self.index.entry(url.to_string()).or_insert_with(|| {
+            self.links.push(Link {
+                url: url.to_string(),
+                found_in: Vec::new(),
+                count: 0,
+                issues: Vec::new(),
+                check: None,
+            });
+            self.links.len() - 1
+        });
+        let link = &mut self.links[index];
+        link.count += 1;
+        if !link.found_in.contains(&location) {
+            link.found_in.push(location);
+        }
+    }
+}
+
+/// URLs written out in plain text.
+fn text_urls(text: &str) -> impl Iterator<Item = &str> {
+    let lower = text.to_ascii_lowercase();
+    let starts: Vec<usize> = lower
+        .match_indices("http")
+        .map(|(start, _)| start)
+        .filter(|&start| {
+            let rest = &lower[start..];
+            let at_word_start = start == 0 || !lower.as_bytes()[start - 1].is_ascii_alphanumeric();
+            at_word_start && (rest.starts_with("http://") || rest.starts_with("https://"))
+        })
+        .collect();
+    starts.into_iter().map(move |start| {
+        let rest = &text[start..];
+        let end = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '\''))
+            .unwrap_or(rest.len());
+        let mut url = &rest[..end];
+        // Punctuation that ends a sentence or closes a parenthesis.
+        loop {
+            let trimmed = url.trim_end_matches(['.', ',', ';', ':', '!', '?']);
+            let trimmed = match trimmed.strip_suffix(')') {
+                Some(inner) if !inner.contains('(') => inner,
+                _ => trimmed,
+            };
+            if trimmed.len() == url.len() {
+                break;
+            }
+            url = trimmed;
+        }
+        url
+    })
+}
+
+/// The issues that can be seen without going online.
+fn inspect(raw: &str) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let malformed = |message: String| Issue {
+        kind: IssueKind::Malformed,
+        message,
+    };
+
+    if let Some(placeholder) = find_placeholder(raw) {
+        issues.push(Issue {
+            kind: IssueKind::TemplateVariable,
+            message: format!("contains the unresolved template variable {placeholder}"),
+        });
+        // Whatever else is wrong is most likely due to the placeholder.
+        return issues;
+    }
+    if raw.is_empty() {
+        issues.push(malformed("empty link".to_string()));
+        return issues;
+    }
+    if raw.contains(char::is_whitespace) {
+        issues.push(malformed("contains whitespace".to_string()));
+        return issues;
+    }
+    let url = match Url::parse(raw) {
+        Ok(url) => url,
+        Err(url::ParseError::RelativeUrlWithoutBase) => {
+            issues.push(malformed(
+                "relative URL; links in email need to be absolute".to_string(),
+            ));
+            return issues;
+        }
+        Err(err) => {
+            issues.push(malformed(format!("not a valid URL: {err}")));
+            return issues;
+        }
+    };
+    if !matches!(url.scheme(), "http" | "https") {
+        return issues;
+    }
+    match url.host() {
+        None => issues.push(malformed("has no host".to_string())),
+        Some(host) if is_internal(&host) => issues.push(Issue {
+            kind: IssueKind::InternalHost,
+            message: format!("points at {host}, which is not reachable from outside"),
+        }),
+        Some(_) => {}
+    }
+    issues
+}
+
+/// Finds the first template placeholder in a URL, percent-encoded or not.
+fn find_placeholder(url: &str) -> Option<String> {
+    let decoded = url
+        .replace("%7B", "{")
+        .replace("%7b", "{")
+        .replace("%7D", "}")
+        .replace("%7d", "}")
+        .replace("%25", "%")
+        .replace("%7C", "|")
+        .replace("%7c", "|");
+    // Opening and closing markers of common template languages: Handlebars
+    // and Jinja, ERB, shell-style, Mailchimp merge tags, and others.
+    const MARKERS: &[(&str, &str)] = &[
+        ("{{", "}}"),
+        ("{%", "%}"),
+        ("<%", "%>"),
+        ("${", "}"),
+        ("*|", "|*"),
+        ("[[", "]]"),
+        ("%%", "%%"),
+        ("{", "}"),
+    ];
+    MARKERS.iter().find_map(|(open, close)| {
+        let start = decoded.find(open)?;
+        let after = &decoded[start + open.len()..];
+        let end = after.find(close)?;
+        let name = &after[..end];
+        let plausible =
+            !name.is_empty() && name.len() <= 64 && !name.contains(['/', '?', '&', '#', '=']);
+        plausible.then(|| format!("{open}{name}{close}"))
+    })
+}
+
+fn is_internal(host: &Host<&str>) -> bool {
+    match host {
+        Host::Domain(domain) => {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            !domain.contains('.')
+                || INTERNAL_SUFFIXES
+                    .iter()
+                    .any(|suffix| domain == *suffix || domain.ends_with(&format!(".{suffix}")))
+        }
+        Host::Ipv4(ip) => is_internal_v4(ip),
+        Host::Ipv6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || first & 0xfe00 == 0xfc00 // unique local
+                || first & 0xffc0 == 0xfe80 // link local
+                || ip.to_ipv4_mapped().is_some_and(|ip| is_internal_v4(&ip))
+                || *ip == Ipv6Addr::LOCALHOST
+        }
+    }
+}
+
+fn is_internal_v4(ip: &Ipv4Addr) -> bool {
+    ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+}
+
+fn is_checkable(link: &Link, internal: bool) -> bool {
+    let ok = link.issues.iter().all(|issue| match issue.kind {
+        IssueKind::Malformed | IssueKind::TemplateVariable => false,
+        IssueKind::InternalHost => internal,
+        _ => true,
+    });
+    ok && (link.url.starts_with("http://") || link.url.starts_with("https://"))
+}
+
+/// The client for checks, which only follows redirects to internal hosts if
+/// `internal` is set.
+fn client(internal: bool) -> &'static reqwest::Client {
+    static PUBLIC: OnceLock<reqwest::Client> = OnceLock::new();
+    static INTERNAL: OnceLock<reqwest::Client> = OnceLock::new();
+    let cell = if internal { &INTERNAL } else { &PUBLIC };
+    cell.get_or_init(|| {
+        let redirects = if internal {
+            Policy::limited(MAX_REDIRECTS)
+        } else {
+            Policy::custom(|attempt| {
+                if attempt.url().host().is_some_and(|host| is_internal(&host)) {
+                    attempt.error("redirected to an internal host")
+                } else if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else {
+                    attempt.follow()
+                }
+            })
+        };
+        reqwest::Client::builder()
+            .timeout(CHECK_TIMEOUT)
+            .redirect(redirects)
+            .user_agent(concat!(
+                "RubberMail/",
+                env!("CARGO_PKG_VERSION"),
+                " link checker"
+            ))
+            .build()
+            .expect("HTTP client configuration is valid")
+    })
+}
+
+/// Requests `url` with HEAD, falling back to GET for servers that do not
+/// allow HEAD.
+async fn check(url: &str, internal: bool) -> Check {
+    let started = Instant::now();
+    let mut method = Method::HEAD;
+    let mut result = client(internal).request(method.clone(), url).send().await;
+    if let Ok(response) = &result {
+        let status = response.status();
+        if status == StatusCode::METHOD_NOT_ALLOWED || status == StatusCode::NOT_IMPLEMENTED {
+            method = Method::GET;
+            result = client(internal).get(url).send().await;
+        }
+    }
+    let millis = started.elapsed().as_millis() as u64;
+    let method = if method == Method::HEAD {
+        "HEAD"
+    } else {
+        "GET"
+    };
+    match result {
+        Ok(response) => {
+            let redirected_to = (Url::parse(url).ok().as_ref() != Some(response.url()))
+                .then(|| response.url().to_string());
+            Check {
+                method,
+                status: Some(response.status().as_u16()),
+                redirected_to,
+                error: None,
+                millis,
+            }
+        }
+        Err(err) => Check {
+            method,
+            status: None,
+            redirected_to: None,
+            error: Some(describe(&err)),
+            millis,
+        },
+    }
+}
+
+/// The error with its causes, which is where reqwest keeps the details.
+fn describe(err: &reqwest::Error) -> String {
+    let kind = if err.is_timeout() {
+        "timed out"
+    } else if err.is_connect() {
+        "connection failed"
+    } else {
+        "request failed"
+    };
+    let mut message = kind.to_string();
+    let mut source = std::error::Error::source(err);
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(url: &str) -> Vec<IssueKind> {
+        inspect(url).into_iter().map(|issue| issue.kind).collect()
+    }
+
+    #[test]
+    fn flags_offline_problems() {
+        assert_eq!(kinds("https://example.com/welcome?a=1"), []);
+        assert_eq!(kinds("mailto:help@example.com"), []);
+        assert_eq!(kinds(""), [IssueKind::Malformed]);
+        assert_eq!(kinds("/account/settings"), [IssueKind::Malformed]);
+        assert_eq!(kinds("https://exa mple.com"), [IssueKind::Malformed]);
+        assert_eq!(kinds("http://"), [IssueKind::Malformed]);
+        for url in [
+            "http://localhost:3000/reset",
+            "https://app.localhost/",
+            "http://127.0.0.1/",
+            "http://10.1.2.3/",
+            "http://192.168.0.10:8080/",
+            "http://[::1]/",
+            "http://staging/",
+            "https://build.corp/x",
+        ] {
+            assert_eq!(kinds(url), [IssueKind::InternalHost], "{url}");
+        }
+        for (url, placeholder) in [
+            ("https://example.com/reset?token={{token}}", "{{token}}"),
+            ("{{ base_url }}/login", "{{ base_url }}"),
+            ("https://example.com/u/%7B%7Buser_id%7D%7D", "{{user_id}}"),
+            ("https://example.com/?e=*|EMAIL|*", "*|EMAIL|*"),
+            ("https://example.com/${path}", "${path}"),
+            ("https://example.com/%%LINK%%", "%%LINK%%"),
+        ] {
+            let issues = inspect(url);
+            assert_eq!(issues.len(), 1, "{url}");
+            assert_eq!(issues[0].kind, IssueKind::TemplateVariable);
+            assert!(issues[0].message.ends_with(placeholder), "{url}");
+        }
+    }
+
+    #[test]
+    fn extracts_links_from_both_bodies() {
+        let report = LinkReport::of(
+            Some(
+                r#"<a href="https://example.com/a?x=1&amp;y=2">A</a>
+                <img src="http://cdn.example.com/logo.png"><a href='https://example.com/a?x=1&y=2'>again</a>"#,
+            ),
+            Some("Visit https://example.com/a?x=1&y=2.\r\n(see https://example.com/b) or <https://example.com/c>"),
+        );
+        let urls: Vec<&str> = report.links.iter().map(|link| link.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://example.com/a?x=1&y=2",
+                "http://cdn.example.com/logo.png",
+                "https://example.com/b",
+                "https://example.com/c",
+            ]
+        );
+        assert_eq!(report.links[0].count, 3);
+        assert_eq!(report.links[0].found_in, ["a href", "text"]);
+        assert_eq!(report.links[1].found_in, ["img src"]);
+        assert_eq!(report.links[1].issues[0].kind, IssueKind::MixedScheme);
+    }
+}
@@ -0,0 +1,128 @@
+//! Connection handling shared by the mail listeners: the accept loop, the
+//! session log around each connection, and the TLS modes.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, info, warn};
+
+use crate::sessions::{EntryKind, SessionId, SessionLog};
+use crate::tls::{TlsAcceptor, TlsInfo, TlsMode};
+
+/// A protocol a listener speaks, implemented by its shared state.
+pub(crate) trait Protocol: Send + Sync + Sized + 'static {
+    /// Name in log lines, such as `SMTP`.
+    const NAME: &'static str;
+    /// Protocol recorded in the session log, such as `smtp`.
+    const PROTOCOL: &'static str;
+
+    fn tls(&self) -> &TlsMode;
+
+    /// How long the TLS handshake may take.
+    fn idle_timeout(&self) -> Duration;
+
+    fn sessions(&self) -> &SessionLog;
+
+    /// Serves one connection, starting with the greeting unless `resume` is
+    /// set because the connection was just upgraded with STARTTLS. Returns
+    /// the stream if the client asked for STARTTLS.
+    fn session<S>(
+        self: Arc<Self>,
+        stream: S,
+        peer: SocketAddr,
+        log: SessionId,
+        tls: Option<TlsInfo>,
+        resume: bool,
+    ) -> impl Future<Output = io::Result<Option<S>>> + Send
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+}
+
+/// Accepts connections until the task is dropped, serving each on a task of
+/// its own.
+pub(crate) async fn serve<P: Protocol>(listener: TcpListener, shared: Arc<P>) -> io::Result<()> {
+    let tls = match shared.tls() {
+        TlsMode::Off => "off",
+        TlsMode::StartTls(_) => "starttls",
+        TlsMode::Implicit(_) => "implicit",
+    };
+    info!(addr = %listener.local_addr()?, tls, "{} listener ready", P::NAME);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!("failed to accept {} connection: {err}", P::NAME);
+                continue;
+            }
+        };
+        tokio::spawn(handle(stream, peer, shared.clone()));
+    }
+}
+
+async fn handle<P: Protocol>(stream: TcpStream, peer: SocketAddr, shared: Arc<P>) {
+    debug!(%peer, "{} connection opened", P::NAME);
+    let sessions = shared.sessions().clone();
+    let log = sessions.open(P::PROTOCOL, peer);
+    if let Err(err) = converse(stream, peer, log, shared).await {
+        debug!(%peer, "{} session ended with error: {err}", P::NAME);
+        sessions.record(log, EntryKind::Event, format!("connection error: {err}"));
+    }
+    sessions.close(log);
+    debug!(%peer, "{} connection closed", P::NAME);
+}
+
+async fn converse<P: Protocol>(
+    stream: TcpStream,
+    peer: SocketAddr,
+    log: SessionId,
+    shared: Arc<P>,
+) -> io::Result<()> {
+    match shared.tls().clone() {
+        TlsMode::Off => {
+            shared.session(stream, peer, log, None, false).await?;
+        }
+        TlsMode::StartTls(acceptor) => {
+            let upgrade = shared
+                .clone()
+                .session(stream, peer, log, None, false)
+                .await?;
+            if let Some(stream) = upgrade {
+                let (stream, info) = handshake(&acceptor, stream, log, &*shared).await?;
+                // SMTP (RFC 3207), POP3 (RFC 2595) and IMAP (RFC 3501) all
+                // carry on without a new greeting.
+                shared.session(stream, peer, log, Some(info), true).await?;
+            }
+        }
+        TlsMode::Implicit(acceptor) => {
+            let (stream, info) = handshake(&acceptor, stream, log, &*shared).await?;
+            shared.session(stream, peer, log, Some(info), false).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs a TLS handshake, giving up after the idle timeout.
+async fn handshake<P: Protocol>(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    log: SessionId,
+    shared: &P,
+) -> io::Result<(TlsStream<TcpStream>, TlsInfo)> {
+    let (stream, info) = timeout(shared.idle_timeout(), acceptor.accept(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+    debug!(version = %info.version, cipher = %info.cipher, "TLS established");
+    shared.sessions().record(
+        log,
+        EntryKind::Event,
+        format!("TLS established: {} {}", info.version, info.cipher),
+    );
+    Ok((stream, info))
+}
@@ -0,0 +1,297 @@
+//! Named mailboxes, so several teams can share one instance without seeing
+//! each other's mail.
+//!
+//! Every message belongs to one mailbox. Routing rules decide where new
+//! messages go: a message is filed into every mailbox with a rule that
+//! matches it, and into [`DEFAULT_MAILBOX`] if none does.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::store::Envelope;
+
+/// The mailbox unrouted messages go to. It always exists.
+pub const DEFAULT_MAILBOX: &str = "default";
+
+/// What a rule matches on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Matcher {
+    /// Any envelope recipient matches this pattern, ignoring case. `*`
+    /// stands for any run of characters, e.g. `*@team-a.test`.
+    Rcpt(String),
+    /// The client authenticated as this user.
+    User(String),
+    /// The message arrived on the listener with this port.
+    Port(u16),
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Rcpt(pattern) => write!(f, "rcpt={pattern}"),
+            Matcher::User(user) => write!(f, "user={user}"),
+            Matcher::Port(port) => write!(f, "port={port}"),
+        }
+    }
+}
+
+/// Facts about a message that rules are checked against.
+#[derive(Debug, Clone, Copy)]
+pub struct Delivery<'a> {
+    pub envelope: &'a Envelope,
+    /// The user the client authenticated as, if it did.
+    pub username: Option<&'a str>,
+    /// Port of the listener the message arrived on.
+    pub port: u16,
+}
+
+impl Matcher {
+    fn matches(&self, delivery: &Delivery) -> bool {
+        match self {
+            Matcher::Rcpt(pattern) => delivery
+                .envelope
+                .rcpt_to
+                .iter()
+                .any(|rcpt| glob(&pattern.to_lowercase(), &rcpt.to_lowercase())),
+            Matcher::User(user) => delivery.username == Some(user.as_str()),
+            Matcher::Port(port) => delivery.port == *port,
+        }
+    }
+}
+
+/// Routes messages matching `matcher` into `mailbox`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MailboxRule {
+    pub mailbox: String,
+    #[serde(rename = "match")]
+    pub matcher: Matcher,
+}
+
+impl FromStr for MailboxRule {
+    type Err = String;
+
+    /// Parses `NAME:rcpt=PATTERN`, `NAME:user=USER` or `NAME:port=PORT`.
+    fn from_str(value: &str) -> Result<MailboxRule, String> {
+        let expected =
+            || "expected NAME:rcpt=PATTERN, NAME:user=USER or NAME:port=PORT".to_string();
+        let (mailbox, rule) = value.split_once(':').ok_or_else(expected)?;
+        validate_name(mailbox)?;
+        let (kind, argument) = rule.split_once('=').ok_or_else(expected)?;
+        if argument.is_empty() {
+            return Err(expected());
+        }
+        let matcher = match kind {
+            "rcpt" => Matcher::Rcpt(argument.to_string()),
+            "user" => Matcher::User(argument.to_string()),
+            "port" => Matcher::Port(
+                argument
+                    .parse()
+                    .map_err(|_| format!("invalid port {argument:?}"))?,
+            ),
+            _ => return Err(expected()),
+        };
+        Ok(MailboxRule {
+            mailbox: mailbox.to_string(),
+            matcher,
+        })
+    }
+}
+
+/// Checks that `name` can be used as a mailbox name, which also appears in
+/// URLs.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "invalid mailbox name {name:?}: use letters, digits, '-', '_' and '.'"
+        ))
+    }
+}
+
+/// The configured mailboxes and their routing rules. Cloning is cheap.
+#[derive(Debug, Clone, Default)]
+pub struct Mailboxes {
+    rules: Arc<Vec<MailboxRule>>,
+}
+
+impl Mailboxes {
+    pub fn new(rules: Vec<MailboxRule>) -> Mailboxes {
+        Mailboxes {
+            rules: Arc::new(rules),
+        }
+    }
+
+    pub fn rules(&self) -> &[MailboxRule] {
+        &self.rules
+    }
+
+    /// Every mailbox name: the default one, then the others in the order
+    /// their first rule was given.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = vec![DEFAULT_MAILBOX];
+        for rule in self.rules.iter() {
+            if !names.contains(&rule.mailbox.as_str()) {
+                names.push(&rule.mailbox);
+            }
+        }
+        names
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        name == DEFAULT_MAILBOX || self.rules.iter().any(|rule| rule.mailbox == name)
+    }
+
+    /// The mailbox a mail reader logging in as `user` sees: the mailbox of
+    /// that name, else the first mailbox with a `user=` rule for them, else
+    /// the default one.
+    pub fn for_user(&self, user: &str) -> &str {
+        if let Some(name) = self.names().into_iter().find(|name| *name == user) {
+            return name;
+        }
+        self.rules
+            .iter()
+            .find(|rule| rule.matcher == Matcher::User(user.to_string()))
+            .map_or(DEFAULT_MAILBOX, |rule| &rule.mailbox)
+    }
+
+    /// The mailboxes a message belongs in, in the order of [`Self::names`].
+    pub fn route(&self, delivery: &Delivery) -> Vec<String> {
+        let mut matched: Vec<String> = Vec::new();
+        for rule in self.rules.iter() {
+            if !matched.contains(&rule.mailbox) && rule.matcher.matches(delivery) {
+                matched.push(rule.mailbox.clone());
+            }
+        }
+        if matched.is_empty() {
+            matched.push(DEFAULT_MAILBOX.to_string());
+        }
+        let names = self.names();
+        matched.sort_by_key(|name| names.iter().position(|known| known == name));
+        matched
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` matches any run of
+/// characters.
+fn glob(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delivery<'a>(envelope: &'a Envelope, username: Option<&'a str>, port: u16) -> Delivery<'a> {
+        Delivery {
+            envelope,
+            username,
+            port,
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(
+            "team-a:rcpt=*@team-a.test".parse(),
+            Ok(MailboxRule {
+                mailbox: "team-a".into(),
+                matcher: Matcher::Rcpt("*@team-a.test".into()),
+            })
+        );
+        assert_eq!(
+            "ci:port=2526".parse::<MailboxRule>().unwrap().matcher,
+            Matcher::Port(2526)
+        );
+        assert!("ci:port=x".parse::<MailboxRule>().is_err());
+        assert!("ci:size=10".parse::<MailboxRule>().is_err());
+        assert!("team a:user=alice".parse::<MailboxRule>().is_err());
+        assert!("team-a".parse::<MailboxRule>().is_err());
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob("*@team-a.test", "bob@team-a.test"));
+        assert!(!glob("*@team-a.test", "bob@team-a.test.evil"));
+        assert!(glob("qa-*@*.test", "qa-1@example.test"));
+        assert!(glob("exact@x.test", "exact@x.test"));
+        assert!(!glob("exact@x.test", "inexact@x.test"));
+        assert!(glob("a*a", "aa"));
+        assert!(!glob("a*a", "a"));
+    }
+
+    #[test]
+    fn routes_to_every_matching_mailbox() {
+        let mailboxes = Mailboxes::new(
+            [
+                "billing:user=billing-app",
+                "team-a:rcpt=*@team-a.test",
+                "team-b:rcpt=*@TEAM-B.test",
+                "ci:port=2526",
+            ]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect(),
+        );
+        let envelope = Envelope {
+            mail_from: "app@example.test".into(),
+            rcpt_to: vec!["x@team-b.test".into(), "y@team-a.test".into()],
+        };
+        assert_eq!(
+            mailboxes.route(&delivery(&envelope, None, 1025)),
+            ["team-a", "team-b"]
+        );
+        assert_eq!(
+            mailboxes.route(&delivery(&envelope, Some("billing-app"), 2526)),
+            ["billing", "team-a", "team-b", "ci"]
+        );
+        let other = Envelope {
+            mail_from: String::new(),
+            rcpt_to: vec!["z@example.test".into()],
+        };
+        assert_eq!(
+            mailboxes.route(&delivery(&other, Some("someone"), 1025)),
+            [DEFAULT_MAILBOX]
+        );
+        assert_eq!(
+            mailboxes.names(),
+            [DEFAULT_MAILBOX, "billing", "team-a", "team-b", "ci"]
+        );
+    }
+
+    #[test]
+    fn maps_readers_to_mailboxes() {
+        let mailboxes = Mailboxes::new(vec![
+            "billing:user=billing-app".parse().unwrap(),
+            "team-a:rcpt=*@team-a.test".parse().unwrap(),
+        ]);
+        assert_eq!(mailboxes.for_user("team-a"), "team-a");
+        assert_eq!(mailboxes.for_user("billing-app"), "billing");
+        assert_eq!(mailboxes.for_user("billing"), "billing");
+        assert_eq!(mailboxes.for_user("someone"), DEFAULT_MAILBOX);
+    }
+}
@@ -0,0 +1,23 @@
+use std::process::ExitCode;
+
+use clap::Parser;
+use tracing_subscriber::EnvFilter;
+
+use rubbermail::Config;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("rubbermail=info")),
+        )
+        .init();
+
+    match rubbermail::run(Config::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("rubbermail: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Mailbox lists in address header fields (RFC 5322 section 3.4).
+
+use super::header::{decode_encoded_words, strip_comments};
+
+/// One mailbox from an address field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    /// Decoded display name, if any.
+    pub name: Option<String>,
+    /// The `local@domain` part.
+    pub email: String,
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{name} <{}>", self.email),
+            None => f.write_str(&self.email),
+        }
+    }
+}
+
+/// Parses an undecoded address field value into its mailboxes.
+///
+/// Group syntax is flattened into its members. Entries without an address
+/// are dropped.
+pub fn parse_list(raw: &str) -> Vec<Address> {
+    split_top_level(raw)
+        .iter()
+        .filter_map(|entry| parse_mailbox(entry))
+        .collect()
+}
+
+fn parse_mailbox(entry: &str) -> Option<Address> {
+    let entry = entry.trim();
+    if let Some(open) = find_unquoted(entry, '<') {
+        let close = entry[open..].find('>').map_or(entry.len(), |i| open + i);
+        let email = strip_comments(&entry[open + 1..close]).trim().to_string();
+        let name = clean_name(&entry[..open]);
+        if email.is_empty() {
+            return None;
+        }
+        return Some(Address { name, email });
+    }
+
+    // Bare `addr-spec`, optionally followed by an old-style `(Name)` comment.
+    let email = strip_comments(entry).trim().trim_matches('"').to_string();
+    if email.is_empty() {
+        return None;
+    }
+    let name = entry
+        .find('(')
+        .and_then(|start| {
+            entry
+                .rfind(')')
+                .map(|end| &entry[start + 1..end.max(start + 1)])
+        })
+        .and_then(clean_name);
+    Some(Address { name, email })
+}
+
+fn clean_name(raw: &str) -> Option<String> {
+    let raw = raw.trim();
+    let unquoted = match raw.strip_prefix('"') {
+        Some(inner) => inner
+            .strip_suffix('"')
+            .unwrap_or(inner)
+            .replace("\\\"", "\"")
+            .replace("\\\\", "\\"),
+        None => raw.to_string(),
+    };
+    let name = decode_encoded_words(unquoted.trim());
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Splits a field on commas that are outside quotes, comments and angle
+/// brackets, and removes group labels (`Team: a@x, b@y;`).
+fn split_top_level(raw: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut comment = 0usize;
+    let mut angle = false;
+    for c in raw.chars() {
+        if escaped {
+            current.push(c);
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted || comment > 0 => escaped = true,
+            '"' if comment == 0 => quoted = !quoted,
+            '(' if !quoted => comment += 1,
+            ')' if !quoted && comment > 0 => comment -= 1,
+            '<' if !quoted && comment == 0 => angle = true,
+            '>' if !quoted && comment == 0 => angle = false,
+            ':' if !quoted && comment == 0 && !angle => {
+                // Group name: drop what we have so far.
+                current.clear();
+                continue;
+            }
+            ',' | ';' if !quoted && comment == 0 && !angle => {
+                entries.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    entries.push(current);
+    entries.retain(|e| !e.trim().is_empty());
+    entries
+}
+
+fn find_unquoted(input: &str, needle: char) -> Option<usize> {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == needle && !quoted => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(name: Option<&str>, email: &str) -> Address {
+        Address {
+            name: name.map(str::to_string),
+            email: email.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_common_forms() {
+        assert_eq!(
+            parse_list(
+                "\"Doe, Jane\" <jane@example.test>, bob@example.test (Bob), <c@example.test>"
+            ),
+            vec![
+                addr(Some("Doe, Jane"), "jane@example.test"),
+                addr(Some("Bob"), "bob@example.test"),
+                addr(None, "c@example.test"),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_names_and_flattens_groups() {
+        assert_eq!(
+            parse_list(
+                "=?utf-8?Q?J=C3=BCrgen?= <j@example.test>, Team: a@example.test, b@example.test;"
+            ),
+            vec![
+                addr(Some("Jürgen"), "j@example.test"),
+                addr(None, "a@example.test"),
+                addr(None, "b@example.test"),
+            ]
+        );
+        assert!(parse_list("undisclosed-recipients:;").is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+//! Content-Transfer-Encoding and charset decoding.
+//!
+//! Everything here is lenient: malformed input yields a best-effort result
+//! rather than an error, because captured mail must always be displayable.
+
+use base64::alphabet;
+use base64::engine::{DecodePaddingMode, Engine, GeneralPurpose, GeneralPurposeConfig};
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+
+const LENIENT_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new()
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent)
+        .with_decode_allow_trailing_bits(true),
+);
+
+/// Decodes base64, skipping line breaks and any other non-alphabet bytes.
+pub fn base64(input: &[u8]) -> Vec<u8> {
+    let mut clean: Vec<u8> = input
+        .iter()
+        .copied()
+        .filter(|b| b.is_ascii_alphanumeric() || *b == b'+' || *b == b'/')
+        .collect();
+    // A single dangling character cannot encode a byte.
+    if clean.len() % 4 == 1 {
+        clean.pop();
+    }
+    LENIENT_BASE64.decode(&clean).unwrap_or_default()
+}
+
+/// Decodes a quoted-printable body (RFC 2045 section 6.7).
+pub fn quoted_printable(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    for line in input.split_inclusive(|&b| b == b'\n') {
+        let (content, eol): (&[u8], &[u8]) = if let Some(c) = line.strip_suffix(b"\r\n") {
+            (c, b"\r\n")
+        } else if let Some(c) = line.strip_suffix(b"\n") {
+            (c, b"\n")
+        } else {
+            (line, b"")
+        };
+        // Trailing whitespace is transport padding, not content.
+        let content = trim_end_wsp(content);
+        let soft_break = content.last() == Some(&b'=');
+        let content = if soft_break {
+            &content[..content.len() - 1]
+        } else {
+            content
+        };
+
+        let mut i = 0;
+        while i < content.len() {
+            if content[i] == b'=' {
+                if let Some(byte) = content.get(i + 1..i + 3).and_then(hex_pair) {
+                    out.push(byte);
+                    i += 3;
+                    continue;
+                }
+            }
+            out.push(content[i]);
+            i += 1;
+        }
+        if !soft_break {
+            out.extend_from_slice(eol);
+        }
+    }
+    out
+}
+
+/// Decodes the `Q` flavour of RFC 2047 encoded-words.
+pub fn q_encoding(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'_' => out.push(b' '),
+            b'=' => {
+                if let Some(byte) = input.get(i + 1..i + 3).and_then(hex_pair) {
+                    out.push(byte);
+                    i += 3;
+                    continue;
+                }
+                out.push(b'=');
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// Converts bytes in the given charset to a string.
+///
+/// Unknown or missing charsets fall back to UTF-8 when the bytes are valid
+/// UTF-8 and to Windows-1252 otherwise, which never fails. Text labelled
+/// `us-ascii` that is really UTF-8 is also decoded as UTF-8, since mislabelled
+/// mail of that kind is common.
+pub fn charset(bytes: &[u8], label: Option<&str>) -> String {
+    let label = label.map(str::trim).unwrap_or_default();
+    let encoding = Encoding::for_label(label.as_bytes());
+    let ascii_label = matches!(
+        label.to_ascii_lowercase().as_str(),
+        "us-ascii" | "ascii" | "ansi_x3.4-1968"
+    );
+    match encoding {
+        Some(encoding) if !ascii_label => encoding.decode(bytes).0.into_owned(),
+        _ => match std::str::from_utf8(bytes) {
+            Ok(text) => text.to_string(),
+            Err(_) => WINDOWS_1252.decode(bytes).0.into_owned(),
+        },
+    }
+}
+
+/// Converts raw header bytes to a string: UTF-8 if valid, otherwise the
+/// Windows-1252 superset of Latin-1 that legacy mailers use for 8-bit headers.
+pub fn header_bytes(bytes: &[u8]) -> String {
+    match UTF_8.decode_without_bom_handling_and_without_replacement(bytes) {
+        Some(text) => text.into_owned(),
+        None => WINDOWS_1252.decode(bytes).0.into_owned(),
+    }
+}
+
+fn hex_pair(pair: &[u8]) -> Option<u8> {
+    let hi = (pair[0] as char).to_digit(16)?;
+    let lo = (pair[1] as char).to_digit(16)?;
+    Some((hi * 16 + lo) as u8)
+}
+
+fn trim_end_wsp(mut bytes: &[u8]) -> &[u8] {
+    while let Some((last, rest)) = bytes.split_last() {
+        if *last == b' ' || *last == b'\t' {
+            bytes = rest;
+        } else {
+            break;
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_ignores_garbage_and_missing_padding() {
+        assert_eq!(base64(b"SGVs\r\nbG8\r\n"), b"Hello");
+        assert_eq!(base64(b"SGVsbG8=!!"), b"Hello");
+        assert_eq!(base64(b"SGVsbG8gd"), b"Hello ");
+    }
+
+    #[test]
+    fn quoted_printable_soft_breaks_and_bad_escapes() {
+        assert_eq!(
+            quoted_printable(b"caf=C3=A9 =\r\nau lait  \r\n100%=\n"),
+            "café au lait\r\n100%".as_bytes()
+        );
+        assert_eq!(quoted_printable(b"a=ZZb=4"), b"a=ZZb=4");
+    }
+
+    #[test]
+    fn charset_fallbacks() {
+        assert_eq!(charset(b"caf\xe9", Some("iso-8859-1")), "café");
+        assert_eq!(charset("café".as_bytes(), Some("us-ascii")), "café");
+        assert_eq!(charset(b"caf\xe9", Some("x-unknown-charset")), "café");
+        assert_eq!(charset("日本".as_bytes(), None), "日本");
+    }
+}
@@ -0,0 +1,511 @@
+//! Header fields: unfolding, RFC 2047 encoded-words and structured values.
+
+use chrono::{DateTime, FixedOffset};
+
+use super::decode;
+
+/// One header field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Field name as written in the message.
+    pub name: String,
+    /// Unfolded value with encoded-words decoded.
+    pub value: String,
+    /// Unfolded value exactly as transmitted, needed to parse structured
+    /// fields before decoding.
+    pub raw: String,
+}
+
+/// The header block of a message or body part, in transmission order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(Vec<Header>);
+
+impl Headers {
+    /// Parses a header block. Lines that are neither fields nor
+    /// continuations are skipped.
+    pub fn parse(block: &[u8]) -> Headers {
+        let mut fields: Vec<(String, String)> = Vec::new();
+        for line in block.split_inclusive(|&b| b == b'\n') {
+            let line = trim_eol(line);
+            if line.is_empty() {
+                continue;
+            }
+            if line[0] == b' ' || line[0] == b'\t' {
+                if let Some((_, value)) = fields.last_mut() {
+                    value.push_str(&decode::header_bytes(line));
+                }
+                continue;
+            }
+            let Some(colon) = line.iter().position(|&b| b == b':') else {
+                continue;
+            };
+            let name = decode::header_bytes(&line[..colon]);
+            let name = name.trim_end();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                continue;
+            }
+            let value = decode::header_bytes(&line[colon + 1..]);
+            fields.push((name.to_string(), value));
+        }
+
+        Headers(
+            fields
+                .into_iter()
+                .map(|(name, raw)| {
+                    let raw = raw.trim().to_string();
+                    Header {
+                        value: decode_encoded_words(&raw),
+                        name,
+                        raw,
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Decoded value of the first field with this name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.find(name).map(|h| h.value.as_str())
+    }
+
+    /// Undecoded value of the first field with this name.
+    pub fn get_raw(&self, name: &str) -> Option<&str> {
+        self.find(name).map(|h| h.raw.as_str())
+    }
+
+    /// Decoded values of every field with this name.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value.as_str())
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Header> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn find(&self, name: &str) -> Option<&Header> {
+        self.0.iter().find(|h| h.name.eq_ignore_ascii_case(name))
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = &'a Header;
+    type IntoIter = std::slice::Iter<'a, Header>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+/// Decodes RFC 2047 encoded-words (`=?charset?B|Q?text?=`) in a header
+/// value. Whitespace between adjacent encoded-words is dropped as the RFC
+/// requires; anything that does not parse as an encoded-word is kept as is.
+pub fn decode_encoded_words(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+        match parse_encoded_word(candidate) {
+            Some((decoded, len)) => {
+                if !(after_word && before.chars().all(char::is_whitespace)) {
+                    out.push_str(before);
+                }
+                out.push_str(&decoded);
+                rest = &candidate[len..];
+                after_word = true;
+            }
+            None => {
+                out.push_str(before);
+                out.push_str("=?");
+                rest = &candidate[2..];
+                after_word = false;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Parses one encoded-word at the start of `input`, returning the decoded
+/// text and the number of bytes consumed.
+fn parse_encoded_word(input: &str) -> Option<(String, usize)> {
+    let body = input.strip_prefix("=?")?;
+    let charset_end = body.find('?')?;
+    let charset = &body[..charset_end];
+    let rest = &body[charset_end + 1..];
+    let encoding = rest.chars().next()?;
+    let text = rest.get(1..)?.strip_prefix('?')?;
+    let text_end = text.find("?=")?;
+    let text = &text[..text_end];
+    if charset.is_empty() || text.contains(char::is_whitespace) {
+        return None;
+    }
+
+    let bytes = match encoding {
+        'B' | 'b' => decode::base64(text.as_bytes()),
+        'Q' | 'q' => decode::q_encoding(text.as_bytes()),
+        _ => return None,
+    };
+    // RFC 2231 allows a language suffix: `=?utf-8*en?Q?...?=`.
+    let charset = charset.split('*').next().unwrap_or(charset);
+    let consumed = 2 + charset_end + 1 + 1 + 1 + text_end + 2;
+    Some((decode::charset(&bytes, Some(charset)), consumed))
+}
+
+/// A parsed `Content-Type` field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    /// Lowercased `type/subtype`.
+    pub mime_type: String,
+    /// Parameters with lowercased names and decoded values.
+    pub params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    pub fn new(mime_type: &str) -> ContentType {
+        ContentType {
+            mime_type: mime_type.to_string(),
+            params: Vec::new(),
+        }
+    }
+
+    /// Parses a raw field value, or returns `None` if it has no usable
+    /// `type/subtype`.
+    pub fn parse(raw: &str) -> Option<ContentType> {
+        let (value, params) = split_value_params(raw);
+        let value = value.to_ascii_lowercase();
+        let (main, sub) = value.split_once('/')?;
+        if main.is_empty() || sub.is_empty() {
+            return None;
+        }
+        Some(ContentType {
+            mime_type: format!("{}/{}", main.trim(), sub.trim()),
+            params,
+        })
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        find_param(&self.params, name)
+    }
+
+    /// The top-level media type, such as `text` or `multipart`.
+    pub fn main_type(&self) -> &str {
+        self.mime_type.split('/').next().unwrap_or_default()
+    }
+
+    pub fn sub_type(&self) -> &str {
+        self.mime_type.split('/').nth(1).unwrap_or_default()
+    }
+
+    pub fn is_multipart(&self) -> bool {
+        self.main_type() == "multipart"
+    }
+}
+
+/// A parsed `Content-Disposition` field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disposition {
+    /// Lowercased disposition type, usually `inline` or `attachment`.
+    pub kind: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl Disposition {
+    pub fn parse(raw: &str) -> Disposition {
+        let (kind, params) = split_value_params(raw);
+        Disposition {
+            kind: kind.to_ascii_lowercase(),
+            params,
+        }
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        find_param(&self.params, name)
+    }
+
+    pub fn is_attachment(&self) -> bool {
+        self.kind == "attachment"
+    }
+}
+
+fn find_param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Splits `value; a=1; b="two"` into the leading value and its parameters,
+/// applying RFC 2231 continuations and charsets and decoding the RFC 2047
+/// encoded-words that some clients put in quoted filenames.
+fn split_value_params(raw: &str) -> (String, Vec<(String, String)>) {
+    let mut segments = split_unquoted(raw, ';').into_iter();
+    let value = strip_comments(&segments.next().unwrap_or_default())
+        .trim()
+        .to_string();
+
+    // (name, section, extended, value)
+    let mut pieces: Vec<(String, Option<u32>, bool, String)> = Vec::new();
+    for segment in segments {
+        let Some((name, val)) = segment.split_once('=') else {
+            continue;
+        };
+        let mut name = name.trim().to_ascii_lowercase();
+        let val = unquote(val.trim());
+        let extended = name.ends_with('*');
+        if extended {
+            name.pop();
+        }
+        let section = match name.rsplit_once('*') {
+            Some((base, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+                let n = n.parse().ok();
+                name = base.to_string();
+                n
+            }
+            _ => None,
+        };
+        if !name.is_empty() {
+            pieces.push((name, section, extended, val));
+        }
+    }
+
+    let mut params: Vec<(String, String)> = Vec::new();
+    let mut names: Vec<String> = Vec::new();
+    for (name, ..) in &pieces {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    for name in names {
+        let mut parts: Vec<_> = pieces.iter().filter(|p| p.0 == name).collect();
+        parts.sort_by_key(|p| p.1.unwrap_or(0));
+
+        let mut bytes = Vec::new();
+        let mut charset = None;
+        let mut any_extended = false;
+        for (i, (_, _, extended, val)) in parts.iter().enumerate() {
+            if *extended {
+                any_extended = true;
+                let mut val = val.as_str();
+                if i == 0 {
+                    // charset'language'percent-encoded-text
+                    let mut fields = val.splitn(3, '\'');
+                    if let (Some(cs), Some(_), Some(text)) =
+                        (fields.next(), fields.next(), fields.next())
+                    {
+                        charset = Some(cs.to_string()).filter(|c| !c.is_empty());
+                        val = text;
+                    }
+                }
+                bytes.extend(percent_decode(val));
+            } else {
+                bytes.extend_from_slice(val.as_bytes());
+            }
+        }
+        let value = if any_extended {
+            decode::charset(&bytes, charset.as_deref())
+        } else {
+            decode_encoded_words(&String::from_utf8_lossy(&bytes))
+        };
+        params.push((name, value));
+    }
+    (value, params)
+}
+
+/// Splits on `sep` outside of double quotes.
+fn split_unquoted(input: &str, sep: char) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in input.chars() {
+        if escaped {
+            current.push(c);
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted => {
+                current.push(c);
+                escaped = true;
+            }
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            c if c == sep && !quoted => out.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    out.push(current);
+    out
+}
+
+fn unquote(value: &str) -> String {
+    let Some(inner) = value.strip_prefix('"') else {
+        return strip_comments(value).trim().to_string();
+    };
+    let inner = inner.strip_suffix('"').unwrap_or(inner);
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(next) = chars.next() {
+                out.push(next);
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Removes RFC 5322 `(comments)` outside of quoted strings.
+pub(crate) fn strip_comments(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in input.chars() {
+        if escaped {
+            if depth == 0 {
+                out.push(c);
+            }
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted || depth > 0 => {
+                escaped = true;
+                if depth == 0 {
+                    out.push(c);
+                }
+            }
+            '"' if depth == 0 => {
+                quoted = !quoted;
+                out.push(c);
+            }
+            '(' if !quoted => depth += 1,
+            ')' if !quoted && depth > 0 => depth -= 1,
+            c if depth == 0 => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(byte) = bytes
+                .get(i + 1..i + 3)
+                .and_then(|h| std::str::from_utf8(h).ok())
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    out
+}
+
+/// Parses an RFC 5322 date, tolerating comments, a missing weekday and
+/// redundant whitespace.
+pub fn parse_date(value: &str) -> Option<DateTime<FixedOffset>> {
+    let cleaned = strip_comments(value)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if let Ok(date) = DateTime::parse_from_rfc2822(&cleaned) {
+        return Some(date);
+    }
+    // Drop a weekday that chrono rejected, e.g. because it does not match
+    // the date.
+    let without_weekday = match cleaned.split_once(", ") {
+        Some((_, rest)) => rest,
+        None => &cleaned,
+    };
+    DateTime::parse_from_str(without_weekday, "%d %b %Y %H:%M:%S %z")
+        .or_else(|_| DateTime::parse_from_str(without_weekday, "%d %b %Y %H:%M %z"))
+        .ok()
+}
+
+pub(crate) fn trim_eol(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unfolds_and_decodes() {
+        let headers = Headers::parse(
+            b"Subject: =?UTF-8?B?SGVsbG8g?=\r\n =?UTF-8?Q?w=C3=B6rld?= again\r\nX-Empty:\r\n",
+        );
+        assert_eq!(headers.get("subject"), Some("Hello wörld again"));
+        assert_eq!(headers.get("X-EMPTY"), Some(""));
+        assert_eq!(
+            headers.get_raw("Subject"),
+            Some("=?UTF-8?B?SGVsbG8g?= =?UTF-8?Q?w=C3=B6rld?= again")
+        );
+    }
+
+    #[test]
+    fn keeps_malformed_encoded_words() {
+        assert_eq!(
+            decode_encoded_words("=?utf-8?X?abc?= a=?b"),
+            "=?utf-8?X?abc?= a=?b"
+        );
+        assert_eq!(
+            decode_encoded_words("=?iso-8859-1?q?caf=E9?= ok"),
+            "café ok"
+        );
+    }
+
+    #[test]
+    fn content_type_with_rfc2231_params() {
+        let ct = ContentType::parse(
+            "Application/PDF; name*0*=UTF-8''r%C3%A9sum; name*1=\"e.pdf\"; x=\"a;b\"",
+        )
+        .unwrap();
+        assert_eq!(ct.mime_type, "application/pdf");
+        assert_eq!(ct.param("name"), Some("résume.pdf"));
+        assert_eq!(ct.param("x"), Some("a;b"));
+        assert!(ContentType::parse("text").is_none());
+    }
+
+    #[test]
+    fn disposition_with_encoded_word_filename() {
+        let d = Disposition::parse("attachment; filename=\"=?utf-8?B?w6ku?= txt\"");
+        assert!(d.is_attachment());
+        assert_eq!(d.param("filename"), Some("é. txt"));
+    }
+
+    #[test]
+    fn dates() {
+        let date = parse_date("Tue, 1 Jul 2003 10:52:37 +0200 (CEST)").unwrap();
+        assert_eq!(date.to_rfc3339(), "2003-07-01T10:52:37+02:00");
+        assert!(parse_date("Mon, 1 Jul 2003 10:52 +0000").is_some());
+        assert!(parse_date("yesterday").is_none());
+    }
+}
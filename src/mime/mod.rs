@@ -0,0 +1,468 @@
+//! RFC 5322 / MIME parsing of captured messages.
+//!
+//! [`Message::parse`] never fails: broken mail (bare LF line endings, missing
+//! or unterminated boundaries, unknown charsets, undecodable transfer
+//! encodings) is parsed on a best-effort basis so it can still be inspected.
+
+pub mod address;
+pub mod decode;
+pub mod header;
+
+use chrono::{DateTime, FixedOffset};
+
+pub use address::Address;
+pub use header::{ContentType, Disposition, Header, Headers};
+
+/// Nesting deeper than this is treated as an opaque leaf, so hostile input
+/// cannot exhaust the stack.
+const MAX_DEPTH: usize = 32;
+
+/// A parsed message.
+#[derive(Debug, Clone)]
+pub struct Message {
+    /// The top-level entity; its headers are the message headers.
+    pub root: Part,
+    pub subject: Option<String>,
+    pub from: Vec<Address>,
+    pub sender: Option<Address>,
+    pub reply_to: Vec<Address>,
+    pub to: Vec<Address>,
+    pub cc: Vec<Address>,
+    pub bcc: Vec<Address>,
+    pub date: Option<DateTime<FixedOffset>>,
+    /// `Message-ID` without angle brackets.
+    pub message_id: Option<String>,
+    /// The plain-text body, if the message has one.
+    pub text: Option<String>,
+    /// The HTML body, if the message has one.
+    pub html: Option<String>,
+    /// Parts meant to be saved rather than displayed.
+    pub attachments: Vec<Attachment>,
+    /// Parts with a `Content-ID`, such as images referenced from the HTML
+    /// body via `cid:` URLs.
+    pub inlines: Vec<Attachment>,
+}
+
+/// One MIME entity.
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub headers: Headers,
+    pub content_type: ContentType,
+    pub disposition: Option<Disposition>,
+    /// `Content-ID` without angle brackets.
+    pub content_id: Option<String>,
+    pub body: Body,
+}
+
+/// The content of a [`Part`].
+#[derive(Debug, Clone)]
+pub enum Body {
+    /// A leaf part: transfer-decoded bytes, plus the charset-decoded text
+    /// for `text/*` parts.
+    Single {
+        data: Vec<u8>,
+        text: Option<String>,
+    },
+    Multipart(Vec<Part>),
+    /// An encapsulated `message/rfc822`, with its raw bytes.
+    Message {
+        data: Vec<u8>,
+        message: Box<Message>,
+    },
+}
+
+/// A non-body part of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    /// IMAP-style section number locating the part, e.g. `[2, 1]` for "2.1".
+    pub section: Vec<usize>,
+    pub filename: Option<String>,
+    pub content_type: String,
+    /// Lowercased `Content-Disposition` type, if the part had one.
+    pub disposition: Option<String>,
+    pub content_id: Option<String>,
+    /// Decoded size in bytes.
+    pub size: usize,
+}
+
+impl Message {
+    /// Parses raw message bytes as received in SMTP DATA.
+    pub fn parse(raw: &[u8]) -> Message {
+        Message::parse_at_depth(raw, 0)
+    }
+
+    fn parse_at_depth(raw: &[u8], depth: usize) -> Message {
+        let (head, body) = split_head_body(raw);
+        let headers = Headers::parse(head);
+        let root = Part::parse(headers, body, &ContentType::new("text/plain"), depth);
+
+        let headers = &root.headers;
+        let addresses = |name| {
+            headers
+                .get_raw(name)
+                .map(address::parse_list)
+                .unwrap_or_default()
+        };
+        let mut message = Message {
+            subject: headers.get("Subject").map(str::to_string),
+            from: addresses("From"),
+            sender: addresses("Sender").into_iter().next(),
+            reply_to: addresses("Reply-To"),
+            to: addresses("To"),
+            cc: addresses("Cc"),
+            bcc: addresses("Bcc"),
+            date: headers.get("Date").and_then(header::parse_date),
+            message_id: headers.get("Message-ID").map(strip_angles),
+            text: None,
+            html: None,
+            attachments: Vec::new(),
+            inlines: Vec::new(),
+            root,
+        };
+
+        let mut collected = Collected::default();
+        let sections = match message.root.body {
+            Body::Multipart(_) => Vec::new(),
+            _ => vec![1],
+        };
+        collect(&message.root, sections, &mut collected);
+        message.text = collected.text;
+        message.html = collected.html;
+        message.attachments = collected.attachments;
+        message.inlines = collected.inlines;
+        message
+    }
+
+    /// The message header fields.
+    pub fn headers(&self) -> &Headers {
+        &self.root.headers
+    }
+
+    /// Looks up a part by IMAP-style section number.
+    pub fn part(&self, section: &[usize]) -> Option<&Part> {
+        message_part(&self.root, section)
+    }
+
+    /// Finds an inline part by its `Content-ID`, with or without the `cid:`
+    /// prefix or angle brackets.
+    pub fn inline_part(&self, content_id: &str) -> Option<&Part> {
+        let wanted = strip_angles(content_id.strip_prefix("cid:").unwrap_or(content_id));
+        self.inlines
+            .iter()
+            .find(|inline| inline.content_id.as_deref() == Some(wanted.as_str()))
+            .and_then(|inline| self.part(&inline.section))
+    }
+}
+
+impl Part {
+    fn parse(headers: Headers, body: &[u8], default_type: &ContentType, depth: usize) -> Part {
+        let mut content_type = headers
+            .get_raw("Content-Type")
+            .and_then(ContentType::parse)
+            .unwrap_or_else(|| default_type.clone());
+        let disposition = headers
+            .get_raw("Content-Disposition")
+            .map(Disposition::parse);
+        let content_id = headers
+            .get("Content-ID")
+            .map(strip_angles)
+            .filter(|id| !id.is_empty());
+
+        let body = if content_type.is_multipart() && depth < MAX_DEPTH {
+            let boundary = content_type
+                .param("boundary")
+                .filter(|b| !b.is_empty())
+                .map(str::to_string)
+                .or_else(|| sniff_boundary(body));
+            let chunks = boundary
+                .map(|b| split_multipart(body, &b))
+                .unwrap_or_default();
+            if chunks.is_empty() {
+                // No usable boundary: show whatever is there as text.
+                content_type = ContentType::new("text/plain");
+                leaf_body(&headers, &content_type, body)
+            } else {
+                let child_default = if content_type.sub_type() == "digest" {
+                    ContentType::new("message/rfc822")
+                } else {
+                    ContentType::new("text/plain")
+                };
+                Body::Multipart(
+                    chunks
+                        .into_iter()
+                        .map(|chunk| {
+                            let (head, body) = split_head_body(chunk);
+                            Part::parse(Headers::parse(head), body, &child_default, depth + 1)
+                        })
+                        .collect(),
+                )
+            }
+        } else if content_type.mime_type == "message/rfc822" && depth < MAX_DEPTH {
+            let data = transfer_decode(&headers, body);
+            let message = Box::new(Message::parse_at_depth(&data, depth + 1));
+            Body::Message { data, message }
+        } else {
+            leaf_body(&headers, &content_type, body)
+        };
+
+        Part {
+            headers,
+            content_type,
+            disposition,
+            content_id,
+            body,
+        }
+    }
+
+    /// Suggested file name from `Content-Disposition` or `Content-Type`.
+    pub fn filename(&self) -> Option<&str> {
+        self.disposition
+            .as_ref()
+            .and_then(|d| d.param("filename"))
+            .or_else(|| self.content_type.param("name"))
+            .filter(|name| !name.is_empty())
+    }
+
+    /// The decoded content of a leaf or encapsulated-message part.
+    pub fn data(&self) -> &[u8] {
+        match &self.body {
+            Body::Single { data, .. } | Body::Message { data, .. } => data,
+            Body::Multipart(_) => &[],
+        }
+    }
+
+    /// The charset-decoded content of a `text/*` part.
+    pub fn text(&self) -> Option<&str> {
+        match &self.body {
+            Body::Single { text, .. } => text.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn is_attachment(&self) -> bool {
+        self.disposition
+            .as_ref()
+            .is_some_and(Disposition::is_attachment)
+    }
+}
+
+fn leaf_body(headers: &Headers, content_type: &ContentType, body: &[u8]) -> Body {
+    let data = transfer_decode(headers, body);
+    let text = (content_type.main_type() == "text")
+        .then(|| decode::charset(&data, content_type.param("charset")));
+    Body::Single { data, text }
+}
+
+fn transfer_decode(headers: &Headers, body: &[u8]) -> Vec<u8> {
+    let encoding = headers
+        .get("Content-Transfer-Encoding")
+        .map(|e| e.trim().to_ascii_lowercase());
+    match encoding.as_deref() {
+        Some("base64") => decode::base64(body),
+        Some("quoted-printable") => decode::quoted_printable(body),
+        _ => body.to_vec(),
+    }
+}
+
+#[derive(Default)]
+struct Collected {
+    text: Option<String>,
+    html: Option<String>,
+    attachments: Vec<Attachment>,
+    inlines: Vec<Attachment>,
+}
+
+/// Walks the part tree, picking out the display bodies and classifying every
+/// other leaf as an attachment or inline part.
+fn collect(part: &Part, section: Vec<usize>, out: &mut Collected) {
+    if let Body::Multipart(children) = &part.body {
+        for (i, child) in children.iter().enumerate() {
+            let mut child_section = section.clone();
+            child_section.push(i + 1);
+            collect(child, child_section, out);
+        }
+        return;
+    }
+
+    let displayable = !part.is_attachment() && part.filename().is_none();
+    if let (true, Some(text)) = (displayable, part.text()) {
+        let slot = match part.content_type.mime_type.as_str() {
+            "text/plain" => Some(&mut out.text),
+            "text/html" => Some(&mut out.html),
+            _ => None,
+        };
+        if let Some(slot) = slot {
+            match slot {
+                Some(existing) => {
+                    existing.push('\n');
+                    existing.push_str(text);
+                }
+                None => *slot = Some(text.to_string()),
+            }
+            return;
+        }
+    }
+
+    let attachment = Attachment {
+        section,
+        filename: part.filename().map(str::to_string),
+        content_type: part.content_type.mime_type.clone(),
+        disposition: part.disposition.as_ref().map(|d| d.kind.clone()),
+        content_id: part.content_id.clone(),
+        size: part.data().len(),
+    };
+    if part.content_id.is_some() && !part.is_attachment() {
+        out.inlines.push(attachment);
+    } else {
+        out.attachments.push(attachment);
+    }
+}
+
+/// Resolves a section number relative to a message's root entity.
+fn message_part<'a>(root: &'a Part, section: &[usize]) -> Option<&'a Part> {
+    match (&root.body, section) {
+        (Body::Multipart(_), _) => child_part(root, section),
+        (_, [1]) => Some(root),
+        (_, [1, rest @ ..]) => child_part(root, rest),
+        _ => None,
+    }
+}
+
+/// Resolves a section number relative to a multipart or encapsulated message.
+fn child_part<'a>(part: &'a Part, section: &[usize]) -> Option<&'a Part> {
+    let (&n, rest) = section.split_first()?;
+    let child = match &part.body {
+        Body::Multipart(children) => children.get(n.checked_sub(1)?)?,
+        Body::Message { message, .. } => return message_part(&message.root, section),
+        Body::Single { .. } => return None,
+    };
+    if rest.is_empty() {
+        Some(child)
+    } else {
+        child_part(child, rest)
+    }
+}
+
+/// Splits at the first empty line, accepting CRLF or bare LF.
+pub(crate) fn split_head_body(raw: &[u8]) -> (&[u8], &[u8]) {
+    let mut start = 0;
+    for line in raw.split_inclusive(|&b| b == b'\n') {
+        let end = start + line.len();
+        if header::trim_eol(line).is_empty() {
+            return (&raw[..start], &raw[end..]);
+        }
+        // A first line that cannot be a header means there is no header block.
+        if start == 0 && !looks_like_header(line) {
+            return (&[], raw);
+        }
+        start = end;
+    }
+    (raw, &[])
+}
+
+fn looks_like_header(line: &[u8]) -> bool {
+    match line.iter().position(|&b| b == b':') {
+        Some(colon) => colon > 0 && !line[..colon].iter().any(u8::is_ascii_whitespace),
+        None => false,
+    }
+}
+
+/// Splits a multipart body on its boundary lines. A missing close delimiter
+/// ends the last part at the end of the input.
+pub(crate) fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{boundary}");
+    let mut parts = Vec::new();
+    let mut part_start: Option<usize> = None;
+    let mut start = 0;
+    for line in body.split_inclusive(|&b| b == b'\n') {
+        let end = start + line.len();
+        let content = header::trim_eol(line);
+        if let Some(tail) = content.strip_prefix(delimiter.as_bytes()) {
+            let close = tail.starts_with(b"--");
+            let tail = if close { &tail[2..] } else { tail };
+            if tail.iter().all(u8::is_ascii_whitespace) {
+                if let Some(s) = part_start.take() {
+                    parts.push(strip_trailing_eol(&body[s..start]));
+                }
+                if close {
+                    return parts;
+                }
+                part_start = Some(end);
+            }
+        }
+        start = end;
+    }
+    if let Some(s) = part_start {
+        parts.push(&body[s..]);
+    }
+    parts
+}
+
+/// The line break before a delimiter belongs to the delimiter.
+fn strip_trailing_eol(part: &[u8]) -> &[u8] {
+    let part = part.strip_suffix(b"\n").unwrap_or(part);
+    part.strip_suffix(b"\r").unwrap_or(part)
+}
+
+/// Guesses the boundary of a multipart body whose `boundary` parameter is
+/// missing, from the first line that looks like a delimiter.
+pub(crate) fn sniff_boundary(body: &[u8]) -> Option<String> {
+    body.split(|&b| b == b'\n').find_map(|line| {
+        let line = header::trim_eol(line);
+        let boundary = line.strip_prefix(b"--")?;
+        let boundary = boundary.strip_suffix(b"--").unwrap_or(boundary);
+        let valid = !boundary.is_empty()
+            && boundary.len() <= 70
+            && boundary.iter().all(|b| b.is_ascii_graphic());
+        valid.then(|| String::from_utf8_lossy(boundary).into_owned())
+    })
+}
+
+fn strip_angles(value: &str) -> String {
+    let value = value.trim();
+    let value = value.strip_prefix('<').unwrap_or(value);
+    value.strip_suffix('>').unwrap_or(value).trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_part_defaults() {
+        let message = Message::parse(b"Subject: hi\r\n\r\nbody\r\n");
+        assert_eq!(message.subject.as_deref(), Some("hi"));
+        assert_eq!(message.text.as_deref(), Some("body\r\n"));
+        assert_eq!(message.root.content_type.mime_type, "text/plain");
+        assert_eq!(message.part(&[1]).unwrap().text(), Some("body\r\n"));
+        assert!(message.part(&[2]).is_none());
+    }
+
+    #[test]
+    fn headerless_input_is_all_body() {
+        let message = Message::parse(b"just some text\nno headers\n");
+        assert!(message.headers().is_empty());
+        assert_eq!(
+            message.text.as_deref(),
+            Some("just some text\nno headers\n")
+        );
+    }
+
+    #[test]
+    fn section_numbers_follow_imap() {
+        let raw = b"Content-Type: multipart/mixed; boundary=a\r\n\r\n\
+--a\r\nContent-Type: multipart/alternative; boundary=b\r\n\r\n\
+--b\r\n\r\nplain\r\n--b\r\nContent-Type: text/html\r\n\r\n<p>html</p>\r\n--b--\r\n\
+--a\r\nContent-Type: message/rfc822\r\n\r\nSubject: inner\r\n\r\ninner body\r\n--a--\r\n";
+        let message = Message::parse(raw);
+        assert_eq!(message.part(&[1, 1]).unwrap().text(), Some("plain"));
+        assert_eq!(message.part(&[1, 2]).unwrap().text(), Some("<p>html</p>"));
+        assert_eq!(
+            message.part(&[2]).unwrap().content_type.mime_type,
+            "message/rfc822"
+        );
+        assert_eq!(message.part(&[2, 1]).unwrap().text(), Some("inner body"));
+        assert_eq!(message.attachments.len(), 1);
+        assert_eq!(message.attachments[0].section, [2]);
+    }
+}
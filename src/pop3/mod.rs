@@ -0,0 +1,124 @@
+//! The POP3 listener.
+//!
+//! Serves captured messages over RFC 1939 so mail clients and end-to-end
+//! tests can read them back the way they would from a real mail server. Each
+//! user sees one mailbox, chosen by [`Mailboxes::for_user`].
+
+mod session;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+use crate::auth::AuthPolicy;
+use crate::listener::{self, Protocol};
+use crate::mailbox::Mailboxes;
+use crate::sessions::{SessionId, SessionLog};
+use crate::store::MessageStore;
+use crate::tls::{TlsInfo, TlsMode};
+use session::Session;
+
+/// Settings for the POP3 listener.
+#[derive(Debug, Clone)]
+pub struct Pop3Config {
+    /// Host name used in the greeting.
+    pub hostname: String,
+    /// How long a client may stay silent before the connection is dropped.
+    /// RFC 1939 asks for at least ten minutes.
+    pub idle_timeout: Duration,
+    /// Whether the listener offers STLS or expects TLS from the start.
+    pub tls: TlsMode,
+    /// Which `USER`/`PASS` credentials are accepted.
+    pub auth: AuthPolicy,
+    /// Where sessions are logged, shared with the HTTP API.
+    pub sessions: SessionLog,
+    /// Which mailbox each user reads.
+    pub mailboxes: Mailboxes,
+}
+
+impl Default for Pop3Config {
+    fn default() -> Self {
+        Pop3Config {
+            hostname: "rubbermail".to_string(),
+            idle_timeout: Duration::from_secs(600),
+            tls: TlsMode::Off,
+            auth: AuthPolicy::AcceptAny,
+            sessions: SessionLog::default(),
+            mailboxes: Mailboxes::default(),
+        }
+    }
+}
+
+/// State shared by every session of one listener.
+pub(crate) struct Shared {
+    pub(crate) config: Pop3Config,
+    pub(crate) store: MessageStore,
+}
+
+/// A bound POP3 listener that has not started accepting yet.
+pub struct Pop3Server {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+impl Pop3Server {
+    pub async fn bind(
+        addr: SocketAddr,
+        config: Pop3Config,
+        store: MessageStore,
+    ) -> io::Result<Self> {
+        Ok(Pop3Server {
+            listener: TcpListener::bind(addr).await?,
+            shared: Arc::new(Shared { config, store }),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the task is dropped.
+    pub async fn serve(self) -> io::Result<()> {
+        listener::serve(self.listener, self.shared).await
+    }
+}
+
+impl Protocol for Shared {
+    const NAME: &'static str = "POP3";
+    const PROTOCOL: &'static str = "pop3";
+
+    fn tls(&self) -> &TlsMode {
+        &self.config.tls
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        self.config.idle_timeout
+    }
+
+    fn sessions(&self) -> &SessionLog {
+        &self.config.sessions
+    }
+
+    async fn session<S>(
+        self: Arc<Self>,
+        stream: S,
+        peer: SocketAddr,
+        log: SessionId,
+        tls: Option<TlsInfo>,
+        resume: bool,
+    ) -> io::Result<Option<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let session = Session::new(stream, peer, log, self, tls);
+        if resume {
+            session.resume().await
+        } else {
+            session.run().await
+        }
+    }
+}
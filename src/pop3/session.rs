@@ -0,0 +1,482 @@
+//! One POP3 conversation.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::time::timeout;
+use tracing::{debug, error, info};
+
+use super::Shared;
+use crate::line::{read_line, trim_eol, Line};
+use crate::sessions::{EntryKind, SessionId};
+use crate::store::CapturedMessage;
+use crate::tls::{TlsInfo, TlsMode};
+
+/// Longest command line we accept, including CRLF. RFC 2449 allows 255
+/// octets; we allow more for long passwords.
+const MAX_COMMAND_LINE: usize = 1024;
+
+enum Flow {
+    Continue,
+    Quit,
+    StartTls,
+}
+
+/// The messages a logged-in user sees. RFC 1939 numbers them once at login,
+/// so the list stays fixed for the rest of the session.
+struct Maildrop {
+    mailbox: String,
+    messages: Vec<Arc<CapturedMessage>>,
+    deleted: Vec<bool>,
+}
+
+impl Maildrop {
+    /// Looks up a message by the number the client gave.
+    fn get(&self, arg: Option<&str>) -> Result<(usize, &CapturedMessage), &'static str> {
+        let number: usize = arg
+            .ok_or("message number required")?
+            .parse()
+            .map_err(|_| "invalid message number")?;
+        let index = number.checked_sub(1).ok_or("no such message")?;
+        match self.messages.get(index) {
+            Some(_) if self.deleted[index] => Err("message already deleted"),
+            Some(message) => Ok((number, message)),
+            None => Err("no such message"),
+        }
+    }
+
+    /// Messages not marked as deleted, with their numbers.
+    fn live(&self) -> impl Iterator<Item = (usize, &CapturedMessage)> {
+        self.messages
+            .iter()
+            .zip(&self.deleted)
+            .enumerate()
+            .filter(|(_, (_, deleted))| !**deleted)
+            .map(|(i, (message, _))| (i + 1, &**message))
+    }
+
+    fn stat(&self) -> (usize, usize) {
+        self.live().fold((0, 0), |(count, size), (_, message)| {
+            (count + 1, size + octets(&message.raw))
+        })
+    }
+}
+
+pub(crate) struct Session<S> {
+    stream: BufReader<S>,
+    shared: Arc<Shared>,
+    peer: SocketAddr,
+    /// This session's entry in the session log.
+    log: SessionId,
+    /// Set once the connection is encrypted.
+    tls: Option<TlsInfo>,
+    /// The name given with USER, until PASS.
+    user: Option<String>,
+    /// Set once the client has logged in.
+    maildrop: Option<Maildrop>,
+    line: Vec<u8>,
+}
+
+impl<S> Session<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub(crate) fn new(
+        stream: S,
+        peer: SocketAddr,
+        log: SessionId,
+        shared: Arc<Shared>,
+        tls: Option<TlsInfo>,
+    ) -> Self {
+        Session {
+            stream: BufReader::new(stream),
+            shared,
+            peer,
+            log,
+            tls,
+            user: None,
+            maildrop: None,
+            line: Vec::new(),
+        }
+    }
+
+    /// Greets the client and serves commands until the connection ends.
+    ///
+    /// Returns the underlying stream if the client issued STLS, so the
+    /// caller can run the handshake and [`resume`](Self::resume) over TLS.
+    pub(crate) async fn run(mut self) -> io::Result<Option<S>> {
+        let greeting = format!("+OK {} RubberMail POP3 ready", self.shared.config.hostname);
+        self.reply(&greeting).await?;
+        self.resume().await
+    }
+
+    /// Serves commands without sending a greeting first.
+    pub(crate) async fn resume(mut self) -> io::Result<Option<S>> {
+        loop {
+            match self.read_line().await? {
+                // A dropped connection never reaches the UPDATE state, so
+                // messages marked for deletion are kept.
+                Line::Eof => return Ok(None),
+                Line::TooLong => {
+                    self.reply("-ERR line too long").await?;
+                    continue;
+                }
+                Line::Complete => {}
+            }
+            let text = String::from_utf8_lossy(trim_eol(&self.line)).into_owned();
+            debug!(peer = %self.peer, "C: {}", redact(&text));
+            self.log(EntryKind::Client, redact(&text));
+            let (verb, argument) = text.split_once(' ').unwrap_or((&text, ""));
+            match self.dispatch(&verb.to_ascii_uppercase(), argument).await? {
+                Flow::Continue => {}
+                Flow::Quit => return Ok(None),
+                Flow::StartTls => return Ok(Some(self.stream.into_inner())),
+            }
+        }
+    }
+
+    async fn dispatch(&mut self, verb: &str, argument: &str) -> io::Result<Flow> {
+        let mut args = argument.split_whitespace();
+        let first = args.next();
+        let second = args.next();
+        let logged_in = self.maildrop.is_some();
+        match verb {
+            "CAPA" => self.capa().await?,
+            "QUIT" => return self.quit().await,
+            "USER" | "PASS" | "STLS" | "APOP" if logged_in => {
+                self.reply("-ERR already logged in").await?
+            }
+            "USER" => match first {
+                Some(user) => {
+                    self.user = Some(user.to_string());
+                    self.reply("+OK send PASS").await?;
+                }
+                None => self.reply("-ERR user name required").await?,
+            },
+            "PASS" => self.pass(argument).await?,
+            "STLS" => return self.stls().await,
+            "APOP" => self.reply("-ERR APOP not supported").await?,
+            "STAT" | "LIST" | "UIDL" | "RETR" | "TOP" | "DELE" | "RSET" | "NOOP" if !logged_in => {
+                self.reply("-ERR log in first").await?
+            }
+            "STAT" => {
+                let (count, size) = self.maildrop().stat();
+                self.reply(&format!("+OK {count} {size}")).await?;
+            }
+            "LIST" => {
+                self.listing(first, |message| octets(&message.raw).to_string())
+                    .await?
+            }
+            "UIDL" => {
+                self.listing(first, |message| message.id.to_string())
+                    .await?
+            }
+            "RETR" => self.retr(first, None).await?,
+            "TOP" => match second.map(str::parse) {
+                Some(Ok(lines)) => self.retr(first, Some(lines)).await?,
+                Some(Err(_)) => self.reply("-ERR invalid line count").await?,
+                None => self.reply("-ERR line count required").await?,
+            },
+            "DELE" => {
+                let maildrop = self.maildrop.as_mut().expect("logged in");
+                match maildrop.get(first) {
+                    Ok((number, _)) => {
+                        maildrop.deleted[number - 1] = true;
+                        self.reply(&format!("+OK message {number} deleted")).await?;
+                    }
+                    Err(err) => self.reply(&format!("-ERR {err}")).await?,
+                }
+            }
+            "RSET" => {
+                let maildrop = self.maildrop.as_mut().expect("logged in");
+                maildrop.deleted.fill(false);
+                let (count, size) = maildrop.stat();
+                self.reply(&format!(
+                    "+OK maildrop has {count} messages ({size} octets)"
+                ))
+                .await?;
+            }
+            "NOOP" => self.reply("+OK").await?,
+            _ => self.reply("-ERR unknown command").await?,
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn maildrop(&self) -> &Maildrop {
+        self.maildrop.as_ref().expect("logged in")
+    }
+
+    async fn capa(&mut self) -> io::Result<()> {
+        let mut lines = vec![
+            "+OK capability list follows".to_string(),
+            "TOP".to_string(),
+            "USER".to_string(),
+            "UIDL".to_string(),
+        ];
+        if self.offers_stls() {
+            lines.push("STLS".to_string());
+        }
+        lines.push("IMPLEMENTATION RubberMail".to_string());
+        lines.push(".".to_string());
+        self.reply_lines(lines).await
+    }
+
+    fn offers_stls(&self) -> bool {
+        matches!(self.shared.config.tls, TlsMode::StartTls(_))
+            && self.tls.is_none()
+            && self.maildrop.is_none()
+    }
+
+    async fn stls(&mut self) -> io::Result<Flow> {
+        if !self.offers_stls() {
+            self.reply("-ERR STLS not available").await?;
+            return Ok(Flow::Continue);
+        }
+        self.reply("+OK begin TLS negotiation").await?;
+        Ok(Flow::StartTls)
+    }
+
+    /// Checks the password and opens the user's mailbox.
+    async fn pass(&mut self, password: &str) -> io::Result<()> {
+        let Some(user) = self.user.take() else {
+            return self.reply("-ERR send USER first").await;
+        };
+        if !self.shared.config.auth.check_password(&user, password) {
+            self.log(EntryKind::Event, format!("login failed for {user}"));
+            return self.reply("-ERR [AUTH] invalid credentials").await;
+        }
+        let mailbox = self.shared.config.mailboxes.for_user(&user).to_string();
+        let mut messages = self.shared.store.list_mailbox(&mailbox);
+        messages.reverse();
+        let maildrop = Maildrop {
+            mailbox,
+            deleted: vec![false; messages.len()],
+            messages,
+        };
+        let (count, size) = maildrop.stat();
+        self.log(
+            EntryKind::Event,
+            format!("logged in as {user}, reading mailbox {}", maildrop.mailbox),
+        );
+        let text = format!(
+            "+OK {user} has {count} messages ({size} octets) in mailbox {}",
+            maildrop.mailbox
+        );
+        self.maildrop = Some(maildrop);
+        self.reply(&text).await
+    }
+
+    /// Answers LIST or UIDL, for one message or all of them.
+    async fn listing(
+        &mut self,
+        arg: Option<&str>,
+        value: impl Fn(&CapturedMessage) -> String,
+    ) -> io::Result<()> {
+        let maildrop = self.maildrop();
+        if arg.is_some() {
+            let text = match maildrop.get(arg) {
+                Ok((number, message)) => format!("+OK {number} {}", value(message)),
+                Err(err) => format!("-ERR {err}"),
+            };
+            return self.reply(&text).await;
+        }
+        let (count, size) = maildrop.stat();
+        let mut lines = vec![format!("+OK {count} messages ({size} octets)")];
+        lines.extend(
+            maildrop
+                .live()
+                .map(|(number, message)| format!("{number} {}", value(message))),
+        );
+        lines.push(".".to_string());
+        self.reply_lines(lines).await
+    }
+
+    /// Answers RETR, or TOP when `body_lines` is given.
+    async fn retr(&mut self, arg: Option<&str>, body_lines: Option<usize>) -> io::Result<()> {
+        let (status, out) = match self.maildrop().get(arg) {
+            Ok((_, message)) => {
+                let lines: Vec<&[u8]> = match body_lines {
+                    Some(count) => top(&message.raw, count),
+                    None => lines(&message.raw).collect(),
+                };
+                let status = match body_lines {
+                    Some(_) => "+OK top of message follows".to_string(),
+                    None => format!("+OK {} octets", octets(&message.raw)),
+                };
+                let mut out = format!("{status}\r\n").into_bytes();
+                dot_stuff(&mut out, lines);
+                (status, out)
+            }
+            Err(err) => {
+                let status = format!("-ERR {err}");
+                let out = format!("{status}\r\n").into_bytes();
+                (status, out)
+            }
+        };
+        // The message itself is not logged; it can be fetched by id.
+        debug!(peer = %self.peer, "S: {status}");
+        self.log(EntryKind::Server, status);
+        let stream = self.stream.get_mut();
+        stream.write_all(&out).await?;
+        stream.flush().await
+    }
+
+    /// Enters the UPDATE state: deletes the marked messages and says goodbye.
+    async fn quit(&mut self) -> io::Result<Flow> {
+        let Some(maildrop) = self.maildrop.take() else {
+            self.reply("+OK bye").await?;
+            return Ok(Flow::Quit);
+        };
+        let mut deleted = 0;
+        let mut failed = false;
+        for (message, _) in maildrop
+            .messages
+            .iter()
+            .zip(&maildrop.deleted)
+            .filter(|(_, deleted)| **deleted)
+        {
+            match self.shared.store.delete(message.id) {
+                Ok(true) => {
+                    info!(id = message.id, mailbox = %maildrop.mailbox, "deleted message over POP3");
+                    self.log(EntryKind::Event, format!("deleted message {}", message.id));
+                    deleted += 1;
+                }
+                // Already gone, e.g. through the HTTP API.
+                Ok(false) => {}
+                Err(err) => {
+                    error!(id = message.id, "failed to delete message: {err}");
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            self.reply("-ERR some deleted messages not removed").await?;
+        } else {
+            self.reply(&format!("+OK bye, {deleted} messages deleted"))
+                .await?;
+        }
+        Ok(Flow::Quit)
+    }
+
+    fn log(&self, kind: EntryKind, text: impl Into<String>) {
+        self.shared.config.sessions.record(self.log, kind, text);
+    }
+
+    /// Reads one command line into `self.line`.
+    ///
+    /// An idle client is logged out without entering the UPDATE state.
+    async fn read_line(&mut self) -> io::Result<Line> {
+        let idle = self.shared.config.idle_timeout;
+        match timeout(
+            idle,
+            read_line(&mut self.stream, &mut self.line, MAX_COMMAND_LINE),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => {
+                self.reply("-ERR idle timeout, closing connection").await?;
+                Ok(Line::Eof)
+            }
+        }
+    }
+
+    async fn reply(&mut self, text: &str) -> io::Result<()> {
+        debug!(peer = %self.peer, "S: {text}");
+        self.log(EntryKind::Server, text);
+        let stream = self.stream.get_mut();
+        stream.write_all(format!("{text}\r\n").as_bytes()).await?;
+        stream.flush().await
+    }
+
+    async fn reply_lines(&mut self, lines: Vec<String>) -> io::Result<()> {
+        let out: String = lines.iter().map(|line| format!("{line}\r\n")).collect();
+        self.log(EntryKind::Server, out.trim_end());
+        let stream = self.stream.get_mut();
+        stream.write_all(out.as_bytes()).await?;
+        stream.flush().await
+    }
+}
+
+/// Hides the password given with PASS.
+fn redact(line: &str) -> String {
+    match line.split_once(' ') {
+        Some((verb, _)) if verb.eq_ignore_ascii_case("PASS") => format!("{verb} <credentials>"),
+        _ => line.to_string(),
+    }
+}
+
+/// Splits a message into lines, without their line endings.
+fn lines(raw: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let raw = raw.strip_suffix(b"\n").unwrap_or(raw);
+    raw.split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+/// The size of a message as sent, with every line ending in CRLF.
+fn octets(raw: &[u8]) -> usize {
+    lines(raw).map(|line| line.len() + 2).sum()
+}
+
+/// The header, the blank line after it and the first `count` body lines.
+fn top(raw: &[u8], count: usize) -> Vec<&[u8]> {
+    let mut lines = lines(raw);
+    let mut out: Vec<&[u8]> = lines.by_ref().take_while(|line| !line.is_empty()).collect();
+    out.push(b"");
+    out.extend(lines.take(count));
+    out
+}
+
+/// Appends `lines` as a multi-line response body: CRLF line endings, a dot
+/// doubled at the start of a line, and a lone dot at the end.
+fn dot_stuff<'a>(out: &mut Vec<u8>, lines: impl IntoIterator<Item = &'a [u8]>) {
+    for line in lines {
+        if line.starts_with(b".") {
+            out.push(b'.');
+        }
+        out.extend_from_slice(line);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b".\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW: &[u8] = b"Subject: hi\nFrom: a@example.test\n\n.hidden\r\nsecond\n\nlast\n";
+
+    #[test]
+    fn counts_octets_with_crlf_line_endings() {
+        assert_eq!(octets(b"a\r\nbc\n"), 7);
+        assert_eq!(octets(b"a\r\nbc"), 7);
+        // Six of the seven lines end in a bare LF.
+        assert_eq!(octets(RAW), RAW.len() + 6);
+    }
+
+    #[test]
+    fn stuffs_leading_dots() {
+        let mut out = Vec::new();
+        dot_stuff(&mut out, lines(RAW));
+        assert_eq!(
+            out,
+            b"Subject: hi\r\nFrom: a@example.test\r\n\r\n..hidden\r\nsecond\r\n\r\nlast\r\n.\r\n"
+        );
+    }
+
+    #[test]
+    fn takes_the_header_and_some_body_lines() {
+        let head: &[&[u8]] = &[b"Subject: hi", b"From: a@example.test", b""];
+        assert_eq!(top(RAW, 0), head);
+        assert_eq!(top(RAW, 2)[3..], [b".hidden".as_slice(), b"second"]);
+        assert_eq!(top(RAW, 100).len(), 7);
+    }
+
+    #[test]
+    fn redacts_passwords() {
+        assert_eq!(redact("PASS secret words"), "PASS <credentials>");
+        assert_eq!(redact("USER alice"), "USER alice");
+    }
+}
@@ -0,0 +1,431 @@
+//! Releasing captured messages to a real SMTP server, so they can be checked
+//! in an actual inbox.
+//!
+//! This is a small SMTP client: it connects, optionally upgrades with
+//! STARTTLS or uses TLS from the start, optionally logs in, and hands over
+//! the message as it was captured, with any bare line endings made CRLF.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::ValueEnum;
+use rustls::pki_types::{CertificateDer, ServerName};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tracing::info;
+
+use crate::auth::Credential;
+use crate::store::CapturedMessage;
+use crate::{tls, Result};
+
+/// Longest reply line accepted from the upstream server.
+const MAX_REPLY_LINE: usize = 4096;
+
+/// How the connection to the upstream server is secured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamTls {
+    /// Plaintext.
+    #[default]
+    None,
+    /// Plaintext, upgraded with STARTTLS before anything else is sent.
+    Starttls,
+    /// TLS from the first byte, as on port 465.
+    Tls,
+}
+
+/// Where and how released messages are sent.
+#[derive(Debug, Clone)]
+pub struct ReleaseConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: UpstreamTls,
+    /// Logged in with AUTH PLAIN or LOGIN if set.
+    pub credentials: Option<Credential>,
+    /// Envelope sender to use instead of the captured one, for upstreams
+    /// that only accept mail from their own users.
+    pub mail_from: Option<String>,
+    /// Recipients used when a release does not name any, instead of the
+    /// captured ones.
+    pub to: Vec<String>,
+    /// Name given in EHLO.
+    pub hostname: String,
+    /// Certificates trusted in addition to the public roots.
+    pub ca_certs: Vec<CertificateDer<'static>>,
+    /// Limit on the whole exchange.
+    pub timeout: Duration,
+}
+
+impl ReleaseConfig {
+    pub fn new(host: impl Into<String>, port: u16) -> ReleaseConfig {
+        ReleaseConfig {
+            host: host.into(),
+            port,
+            tls: UpstreamTls::None,
+            credentials: None,
+            mail_from: None,
+            to: Vec::new(),
+            hostname: "rubbermail".to_string(),
+            ca_certs: Vec::new(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Why a release failed.
+#[derive(Debug, thiserror::Error)]
+pub enum ReleaseError {
+    #[error("connection to upstream failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("upstream rejected {command}: {reply}")]
+    Rejected { command: String, reply: Reply },
+    #[error("upstream does not offer {0}")]
+    Unsupported(&'static str),
+    #[error("upstream did not finish within {}s", .0.as_secs_f64())]
+    Timeout(Duration),
+    #[error("no recipients to release to")]
+    NoRecipients,
+}
+
+/// An SMTP reply from the upstream server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.lines.join(" "))
+    }
+}
+
+/// Checks that `address` is a plain `local@domain` address that can be put
+/// in a `RCPT TO` command as it is.
+pub fn validate_address(address: &str) -> std::result::Result<(), String> {
+    let valid = address
+        .rsplit_once('@')
+        .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+        && !address
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '<' | '>'));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "invalid recipient {address:?}: expected local@domain"
+        ))
+    }
+}
+
+/// Sends messages to the configured upstream server.
+pub struct Relay {
+    config: ReleaseConfig,
+    connector: TlsConnector,
+}
+
+impl fmt::Debug for Relay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Relay")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Relay {
+    pub fn new(config: ReleaseConfig) -> Result<Relay> {
+        let connector = tls::connector(&config.ca_certs)?;
+        Ok(Relay { config, connector })
+    }
+
+    pub fn config(&self) -> &ReleaseConfig {
+        &self.config
+    }
+
+    /// The recipients a release goes to: those asked for, else the
+    /// configured ones, else the captured envelope's.
+    pub fn recipients(&self, message: &CapturedMessage, requested: Vec<String>) -> Vec<String> {
+        if !requested.is_empty() {
+            requested
+        } else if !self.config.to.is_empty() {
+            self.config.to.clone()
+        } else {
+            message.envelope.rcpt_to.clone()
+        }
+    }
+
+    /// Sends `message` to `to` and returns the upstream's final reply.
+    pub async fn release(
+        &self,
+        message: &CapturedMessage,
+        to: &[String],
+    ) -> Result<Reply, ReleaseError> {
+        if to.is_empty() {
+            return Err(ReleaseError::NoRecipients);
+        }
+        let limit = self.config.timeout;
+        let reply = timeout(limit, self.deliver(message, to))
+            .await
+            .map_err(|_| ReleaseError::Timeout(limit))??;
+        info!(
+            id = message.id,
+            upstream = %self.config.host,
+            recipients = to.len(),
+            "message released"
+        );
+        Ok(reply)
+    }
+
+    async fn deliver(
+        &self,
+        message: &CapturedMessage,
+        to: &[String],
+    ) -> Result<Reply, ReleaseError> {
+        let stream = TcpStream::connect((self.config.host.as_str(), self.config.port)).await?;
+        match self.config.tls {
+            UpstreamTls::None => {
+                let mut client = Client::greeted(stream).await?;
+                let capabilities = client.ehlo(&self.config.hostname).await?;
+                self.transact(client, &capabilities, message, to).await
+            }
+            UpstreamTls::Tls => {
+                let stream = self.handshake(stream).await?;
+                let mut client = Client::greeted(stream).await?;
+                let capabilities = client.ehlo(&self.config.hostname).await?;
+                self.transact(client, &capabilities, message, to).await
+            }
+            UpstreamTls::Starttls => {
+                let mut client = Client::greeted(stream).await?;
+                let capabilities = client.ehlo(&self.config.hostname).await?;
+                if !capabilities.has("STARTTLS") {
+                    return Err(ReleaseError::Unsupported("STARTTLS"));
+                }
+                client.expect("STARTTLS", "STARTTLS", 220).await?;
+                let stream = self.handshake(client.into_inner()).await?;
+                let mut client = Client::new(stream);
+                let capabilities = client.ehlo(&self.config.hostname).await?;
+                self.transact(client, &capabilities, message, to).await
+            }
+        }
+    }
+
+    async fn handshake(
+        &self,
+        stream: TcpStream,
+    ) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let name = ServerName::try_from(self.config.host.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.connector.connect(name, stream).await
+    }
+
+    /// Logs in if configured, then runs the mail transaction.
+    async fn transact<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut client: Client<S>,
+        capabilities: &Capabilities,
+        message: &CapturedMessage,
+        to: &[String],
+    ) -> Result<Reply, ReleaseError> {
+        if let Some(credentials) = &self.config.credentials {
+            client.login(capabilities, credentials).await?;
+        }
+
+        let from = self
+            .config
+            .mail_from
+            .as_deref()
+            .unwrap_or(&message.envelope.mail_from);
+        let mail = format!("MAIL FROM:<{from}>");
+        client.expect(&mail, &mail, 250).await?;
+        for rcpt in to {
+            let line = format!("RCPT TO:<{rcpt}>");
+            client.expect(&line, &line, 250).await?;
+        }
+        client.expect("DATA", "DATA", 354).await?;
+        client.send_data(&message.raw).await?;
+        let reply = client.read_reply().await?;
+        if reply.code != 250 {
+            return Err(ReleaseError::Rejected {
+                command: "the message".to_string(),
+                reply,
+            });
+        }
+        // The message is accepted; a failing QUIT changes nothing.
+        let _ = client.expect("QUIT", "QUIT", 221).await;
+        Ok(reply)
+    }
+}
+
+/// EHLO keywords, uppercased.
+struct Capabilities(Vec<String>);
+
+impl Capabilities {
+    fn has(&self, keyword: &str) -> bool {
+        self.0
+            .iter()
+            .any(|line| line.split_whitespace().next() == Some(keyword))
+    }
+
+    fn auth_mechanisms(&self) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter_map(|line| line.strip_prefix("AUTH "))
+            .flat_map(str::split_whitespace)
+            .collect()
+    }
+}
+
+struct Client<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    fn new(stream: S) -> Client<S> {
+        Client {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    async fn greeted(stream: S) -> Result<Client<S>, ReleaseError> {
+        let mut client = Client::new(stream);
+        let reply = client.read_reply().await?;
+        if reply.code != 220 {
+            return Err(ReleaseError::Rejected {
+                command: "the connection".to_string(),
+                reply,
+            });
+        }
+        Ok(client)
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    async fn ehlo(&mut self, hostname: &str) -> Result<Capabilities, ReleaseError> {
+        let line = format!("EHLO {hostname}");
+        let reply = self.expect(&line, &line, 250).await?;
+        let keywords = reply
+            .lines
+            .iter()
+            .skip(1)
+            .map(|line| line.to_ascii_uppercase())
+            .collect();
+        Ok(Capabilities(keywords))
+    }
+
+    async fn login(
+        &mut self,
+        capabilities: &Capabilities,
+        credentials: &Credential,
+    ) -> Result<(), ReleaseError> {
+        let mechanisms = capabilities.auth_mechanisms();
+        if mechanisms.contains(&"PLAIN") {
+            let token = format!("\0{}\0{}", credentials.username, credentials.password);
+            let line = format!("AUTH PLAIN {}", BASE64.encode(token));
+            self.expect(&line, "AUTH PLAIN", 235).await?;
+        } else if mechanisms.contains(&"LOGIN") {
+            self.expect("AUTH LOGIN", "AUTH LOGIN", 334).await?;
+            self.expect(&BASE64.encode(&credentials.username), "AUTH LOGIN", 334)
+                .await?;
+            self.expect(&BASE64.encode(&credentials.password), "AUTH LOGIN", 235)
+                .await?;
+        } else {
+            return Err(ReleaseError::Unsupported("AUTH PLAIN or LOGIN"));
+        }
+        Ok(())
+    }
+
+    /// Sends `line` and fails unless the reply has the `expected` code.
+    /// `command` names the line in errors, so credentials never show up.
+    async fn expect(
+        &mut self,
+        line: &str,
+        command: &str,
+        expected: u16,
+    ) -> Result<Reply, ReleaseError> {
+        let stream = self.stream.get_mut();
+        stream.write_all(line.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+        let reply = self.read_reply().await?;
+        if reply.code != expected {
+            return Err(ReleaseError::Rejected {
+                command: command.to_string(),
+                reply,
+            });
+        }
+        Ok(reply)
+    }
+
+    async fn read_reply(&mut self) -> io::Result<Reply> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            let read = (&mut self.stream)
+                .take(MAX_REPLY_LINE as u64)
+                .read_line(&mut line)
+                .await?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim_end();
+            let code = line
+                .get(..3)
+                .and_then(|digits| digits.parse().ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("malformed reply {line:?}"),
+                    )
+                })?;
+            lines.push(line.get(4..).unwrap_or_default().to_string());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(Reply { code, lines });
+            }
+        }
+    }
+
+    /// Writes the message dot-stuffed, followed by the end-of-data line.
+    async fn send_data(&mut self, raw: &[u8]) -> io::Result<()> {
+        let data = encode_data(raw);
+        let stream = self.stream.get_mut();
+        stream.write_all(&data).await?;
+        stream.flush().await
+    }
+}
+
+/// Encodes a message as DATA: every line ending, including a bare CR or LF,
+/// becomes CRLF, lines starting with a dot get another, and the end-of-data
+/// line follows. Upstreams reject bare line endings, and passing them on
+/// would let a message smuggle its own end of data.
+fn encode_data(raw: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(raw.len() + 64);
+    let mut line_start = true;
+    let mut bytes = raw.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        if line_start && byte == b'.' {
+            data.push(b'.');
+        }
+        line_start = matches!(byte, b'\r' | b'\n');
+        match byte {
+            b'\r' => {
+                bytes.next_if_eq(&&b'\n');
+                data.extend_from_slice(b"\r\n");
+            }
+            b'\n' => data.extend_from_slice(b"\r\n"),
+            _ => data.push(byte),
+        }
+    }
+    if !line_start {
+        data.extend_from_slice(b"\r\n");
+    }
+    data.extend_from_slice(b".\r\n");
+    data
+}
@@ -0,0 +1,366 @@
+//! Retention limits, so an instance left running does not fill the disk.
+//!
+//! Each mailbox can be limited by message count, message age and total
+//! size. A background task evicts the oldest messages until every mailbox is
+//! within its limits again. Pinned messages are never evicted, even if that
+//! leaves a mailbox over its limits.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::mailbox::validate_name;
+use crate::store::{CapturedMessage, MessageStore, StoreEvent};
+use crate::Result;
+
+/// Stands for every mailbox in a rule. A mailbox's own rules take precedence.
+pub const ALL_MAILBOXES: &str = "*";
+
+/// How often mailboxes are checked for expired messages. Count and size
+/// limits are also enforced as soon as a message arrives.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// One limit on a mailbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// Keep at most this many messages.
+    Messages(usize),
+    /// Evict messages received longer ago than this.
+    Age(Duration),
+    /// Keep at most this many bytes of raw messages.
+    Bytes(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Messages(count) => write!(f, "messages={count}"),
+            Limit::Age(age) => write!(f, "age={}s", age.as_secs()),
+            Limit::Bytes(bytes) => write!(f, "bytes={bytes}"),
+        }
+    }
+}
+
+/// Applies `limit` to `mailbox`, or to every mailbox for [`ALL_MAILBOXES`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionRule {
+    pub mailbox: String,
+    pub limit: Limit,
+}
+
+impl FromStr for RetentionRule {
+    type Err = String;
+
+    /// Parses `NAME:messages=COUNT`, `NAME:age=AGE` or `NAME:bytes=SIZE`,
+    /// where `NAME` may be `*`. Ages take an `s`, `m`, `h` or `d` suffix and
+    /// sizes a `K`, `M` or `G` one.
+    fn from_str(value: &str) -> Result<RetentionRule, String> {
+        let expected =
+            || "expected NAME:messages=COUNT, NAME:age=AGE or NAME:bytes=SIZE".to_string();
+        let (mailbox, limit) = value.split_once(':').ok_or_else(expected)?;
+        if mailbox != ALL_MAILBOXES {
+            validate_name(mailbox)?;
+        }
+        let (kind, argument) = limit.split_once('=').ok_or_else(expected)?;
+        let limit = match kind {
+            "messages" => Limit::Messages(
+                argument
+                    .parse()
+                    .map_err(|_| format!("invalid message count {argument:?}"))?,
+            ),
+            "age" => Limit::Age(parse_age(argument)?),
+            "bytes" => Limit::Bytes(parse_size(argument)?),
+            _ => return Err(expected()),
+        };
+        Ok(RetentionRule {
+            mailbox: mailbox.to_string(),
+            limit,
+        })
+    }
+}
+
+/// Parses an age such as `90s`, `30m`, `12h` or `7d`. A bare number is in
+/// seconds.
+fn parse_age(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid age {value:?}: use e.g. 90s, 30m, 12h or 7d");
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => value.split_at(at),
+        None => (value, "s"),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    match number.checked_mul(seconds) {
+        Some(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses a size such as `500000`, `512K`, `100M` or `2GB`, in powers of
+/// 1024.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid size {value:?}: use e.g. 512K, 100M or 2G");
+    let upper = value.to_ascii_uppercase();
+    let (number, unit) = match upper.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => upper.split_at(at),
+        None => (upper.as_str(), ""),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let factor = match unit.strip_suffix('B').unwrap_or(unit) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    number.checked_mul(factor).ok_or_else(invalid)
+}
+
+/// The limits in effect for one mailbox.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Limits {
+    pub max_messages: Option<usize>,
+    pub max_age_secs: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        *self == Limits::default()
+    }
+
+    fn set(&mut self, limit: Limit) {
+        match limit {
+            Limit::Messages(count) => self.max_messages = Some(count),
+            Limit::Age(age) => self.max_age_secs = Some(age.as_secs()),
+            Limit::Bytes(bytes) => self.max_bytes = Some(bytes),
+        }
+    }
+}
+
+/// The configured retention rules and how many messages they evicted.
+/// Cloning is cheap; clones share the counts.
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    rules: Arc<Vec<RetentionRule>>,
+    evicted: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+
+impl Retention {
+    pub fn new(rules: Vec<RetentionRule>) -> Retention {
+        Retention {
+            rules: Arc::new(rules),
+            evicted: Arc::default(),
+        }
+    }
+
+    pub fn rules(&self) -> &[RetentionRule] {
+        &self.rules
+    }
+
+    /// The limits for `mailbox`: those given for it by name, and for the
+    /// others those given for [`ALL_MAILBOXES`].
+    pub fn limits(&self, mailbox: &str) -> Limits {
+        let mut limits = Limits::default();
+        let mut own = Limits::default();
+        for rule in self.rules.iter() {
+            if rule.mailbox == mailbox {
+                own.set(rule.limit);
+            } else if rule.mailbox == ALL_MAILBOXES {
+                limits.set(rule.limit);
+            }
+        }
+        Limits {
+            max_messages: own.max_messages.or(limits.max_messages),
+            max_age_secs: own.max_age_secs.or(limits.max_age_secs),
+            max_bytes: own.max_bytes.or(limits.max_bytes),
+        }
+    }
+
+    /// How many messages have been evicted from `mailbox` since startup.
+    pub fn evicted(&self, mailbox: &str) -> u64 {
+        let evicted = self.evicted.lock().unwrap();
+        evicted.get(mailbox).copied().unwrap_or(0)
+    }
+
+    /// Evicts the oldest unpinned messages of every mailbox over its limits,
+    /// with ages counted up to `now`. Returns how many were evicted.
+    pub fn enforce(&self, store: &MessageStore, now: DateTime<Utc>) -> Result<usize> {
+        if self.rules.is_empty() {
+            return Ok(0);
+        }
+        let mut mailboxes: BTreeMap<String, Vec<Arc<CapturedMessage>>> = BTreeMap::new();
+        for message in store.list() {
+            mailboxes
+                .entry(message.mailbox.clone())
+                .or_default()
+                .push(message);
+        }
+        let mut total = 0;
+        for (mailbox, mut messages) in mailboxes {
+            let limits = self.limits(&mailbox);
+            if limits.is_empty() {
+                continue;
+            }
+            // Imported messages can be older than their ids suggest.
+            messages.sort_by_key(|message| (message.received_at, message.id));
+            let evicted = self.evict(store, &mailbox, &messages, limits, now)?;
+            total += evicted;
+        }
+        Ok(total)
+    }
+
+    /// Evicts from one mailbox's `messages`, given oldest first.
+    fn evict(
+        &self,
+        store: &MessageStore,
+        mailbox: &str,
+        messages: &[Arc<CapturedMessage>],
+        limits: Limits,
+        now: DateTime<Utc>,
+    ) -> Result<usize> {
+        let oldest_kept = limits
+            .max_age_secs
+            .and_then(|secs| chrono::Duration::try_seconds(secs as i64))
+            .map(|age| now - age);
+        let mut count = messages.len();
+        let mut bytes: u64 = messages
+            .iter()
+            .map(|message| message.raw.len() as u64)
+            .sum();
+        let mut evicted = 0;
+        for message in messages.iter().filter(|message| !message.pinned) {
+            let reason = if oldest_kept.is_some_and(|oldest| message.received_at < oldest) {
+                "age"
+            } else if limits.max_messages.is_some_and(|max| count > max) {
+                "messages"
+            } else if limits.max_bytes.is_some_and(|max| bytes > max) {
+                "bytes"
+            } else {
+                // Newer messages are within every limit too.
+                break;
+            };
+            if !store.evict(message.id)? {
+                continue;
+            }
+            info!(id = message.id, mailbox, reason, "message evicted");
+            count -= 1;
+            bytes -= message.raw.len() as u64;
+            evicted += 1;
+        }
+        if evicted > 0 {
+            let mut counts = self.evicted.lock().unwrap();
+            *counts.entry(mailbox.to_string()).or_default() += evicted as u64;
+        }
+        Ok(evicted)
+    }
+
+    /// Enforces the limits in the background: whenever messages arrive, and
+    /// every [`SWEEP_INTERVAL`] for ages.
+    pub fn watch(self, store: MessageStore) -> JoinHandle<()> {
+        let mut events = store.subscribe();
+        tokio::spawn(async move {
+            let mut sweep = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = sweep.tick() => {}
+                    event = events.recv() => match event {
+                        Ok(StoreEvent::Received(_) | StoreEvent::Imported(_))
+                        | Err(RecvError::Lagged(_)) => {}
+                        Ok(_) => continue,
+                        Err(RecvError::Closed) => return,
+                    },
+                }
+                // One pass covers every message that arrived meanwhile.
+                loop {
+                    match events.try_recv() {
+                        Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Closed) => return,
+                    }
+                }
+                if let Err(err) = self.enforce(&store, Utc::now()) {
+                    warn!("could not enforce retention limits: {err}");
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(
+            "team-a:messages=500".parse(),
+            Ok(RetentionRule {
+                mailbox: "team-a".into(),
+                limit: Limit::Messages(500),
+            })
+        );
+        assert_eq!(
+            "*:age=7d".parse::<RetentionRule>().unwrap().limit,
+            Limit::Age(Duration::from_secs(7 * 24 * 3600))
+        );
+        assert_eq!(
+            "default:age=90".parse::<RetentionRule>().unwrap().limit,
+            Limit::Age(Duration::from_secs(90))
+        );
+        assert_eq!(
+            "*:bytes=100MB".parse::<RetentionRule>().unwrap().limit,
+            Limit::Bytes(100 << 20)
+        );
+        assert_eq!(
+            "*:bytes=512k".parse::<RetentionRule>().unwrap().limit,
+            Limit::Bytes(512 << 10)
+        );
+        assert!("*:age=0s".parse::<RetentionRule>().is_err());
+        assert!("*:age=1w".parse::<RetentionRule>().is_err());
+        assert!("*:bytes=1T".parse::<RetentionRule>().is_err());
+        assert!("*:messages=-1".parse::<RetentionRule>().is_err());
+        assert!("*:count=1".parse::<RetentionRule>().is_err());
+        assert!("team a:messages=1".parse::<RetentionRule>().is_err());
+        assert!("messages=1".parse::<RetentionRule>().is_err());
+    }
+
+    #[test]
+    fn mailbox_rules_override_wildcards() {
+        let retention = Retention::new(
+            [
+                "*:messages=100",
+                "*:age=1d",
+                "ci:messages=10",
+                "ci:bytes=1K",
+            ]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect(),
+        );
+        assert_eq!(
+            retention.limits("ci"),
+            Limits {
+                max_messages: Some(10),
+                max_age_secs: Some(86400),
+                max_bytes: Some(1024),
+            }
+        );
+        assert_eq!(retention.limits("default").max_messages, Some(100));
+        assert_eq!(retention.limits("default").max_bytes, None);
+        assert!(Retention::default().limits("default").is_empty());
+    }
+}
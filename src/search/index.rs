@@ -0,0 +1,220 @@
+//! The inverted index behind [`Query`] evaluation.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use chrono::{DateTime, Utc};
+
+use super::query::{Query, Term};
+use super::words;
+use crate::mime::Address;
+use crate::store::{strip_tags, CapturedMessage, MessageId};
+
+/// The searchable fields of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Field {
+    From,
+    To,
+    Subject,
+    Body,
+}
+
+/// The words of one message, kept to confirm the candidates the word
+/// postings produce: postings only say that every word is present, not that
+/// they form the phrase that was asked for.
+#[derive(Debug)]
+pub(crate) struct Document {
+    mailbox: String,
+    from: Vec<String>,
+    to: Vec<String>,
+    subject: Vec<String>,
+    body: Vec<String>,
+    received_at: DateTime<Utc>,
+    has_attachment: bool,
+}
+
+impl Document {
+    /// Parses `message`, which is the slow part of indexing it.
+    pub fn of(message: &CapturedMessage) -> Document {
+        let parsed = message.parse();
+        let envelope = &message.envelope;
+        let mut body = parsed.text.clone().unwrap_or_default();
+        if let Some(html) = &parsed.html {
+            body.push(' ');
+            body.push_str(&strip_tags(html));
+        }
+        Document {
+            mailbox: message.mailbox.clone(),
+            from: addresses(std::iter::once(&envelope.mail_from), [&parsed.from]),
+            to: addresses(&envelope.rcpt_to, [&parsed.to, &parsed.cc, &parsed.bcc]),
+            subject: words(parsed.subject.as_deref().unwrap_or_default()).collect(),
+            body: words(&body).collect(),
+            received_at: message.received_at,
+            has_attachment: !parsed.attachments.is_empty(),
+        }
+    }
+
+    fn field(&self, field: Field) -> &[String] {
+        match field {
+            Field::From => &self.from,
+            Field::To => &self.to,
+            Field::Subject => &self.subject,
+            Field::Body => &self.body,
+        }
+    }
+
+    fn matches_query(&self, query: &Query) -> bool {
+        query
+            .clauses
+            .iter()
+            .all(|clause| self.matches(&clause.term) != clause.negated)
+    }
+
+    fn matches(&self, term: &Term) -> bool {
+        match term {
+            Term::From(value) => contains_phrase(&self.from, value),
+            Term::To(value) => contains_phrase(&self.to, value),
+            Term::Subject(value) => contains_phrase(&self.subject, value),
+            Term::Text(value) => {
+                contains_phrase(&self.subject, value) || contains_phrase(&self.body, value)
+            }
+            Term::Mailbox(name) => self.mailbox == *name,
+            Term::HasAttachment => self.has_attachment,
+            Term::Before(at) => self.received_at < *at,
+            Term::After(at) => self.received_at >= *at,
+        }
+    }
+}
+
+impl Query {
+    /// Whether `message` matches, for messages that are not in an index,
+    /// such as one that was just deleted.
+    pub fn matches(&self, message: &CapturedMessage) -> bool {
+        Document::of(message).matches_query(self)
+    }
+}
+
+/// Whether the words of `phrase` start consecutive words of `field`. This is
+/// the rule the postings are looked up by, so an indexed search and a single
+/// message checked on its own agree.
+fn contains_phrase(field: &[String], phrase: &str) -> bool {
+    let phrase: Vec<String> = words(phrase).collect();
+    phrase.is_empty()
+        || field.windows(phrase.len()).any(|window| {
+            window
+                .iter()
+                .zip(&phrase)
+                .all(|(word, prefix)| word.starts_with(prefix.as_str()))
+        })
+}
+
+/// The words of envelope paths and header addresses, in order.
+fn addresses<'a, const N: usize>(
+    envelope: impl IntoIterator<Item = &'a String>,
+    headers: [&Vec<Address>; N],
+) -> Vec<String> {
+    let mut parts: Vec<String> = envelope.into_iter().cloned().collect();
+    parts.extend(headers.into_iter().flatten().map(ToString::to_string));
+    words(&parts.join(" ")).collect()
+}
+
+/// Word postings per field plus the documents they point at.
+#[derive(Debug, Default)]
+pub(crate) struct Index {
+    postings: HashMap<Field, BTreeMap<String, BTreeSet<MessageId>>>,
+    documents: BTreeMap<MessageId, Document>,
+}
+
+const FIELDS: [Field; 4] = [Field::From, Field::To, Field::Subject, Field::Body];
+
+impl Index {
+    /// Adds message `id`, with its text already taken apart by
+    /// [`Document::of`].
+    pub fn insert(&mut self, id: MessageId, document: Document) {
+        for field in FIELDS {
+            let postings = self.postings.entry(field).or_default();
+            for word in document.field(field) {
+                postings.entry(word.clone()).or_default().insert(id);
+            }
+        }
+        self.documents.insert(id, document);
+    }
+
+    pub fn remove(&mut self, id: MessageId) {
+        let Some(document) = self.documents.remove(&id) else {
+            return;
+        };
+        for field in FIELDS {
+            let Some(postings) = self.postings.get_mut(&field) else {
+                continue;
+            };
+            for word in document.field(field) {
+                if let Some(ids) = postings.get_mut(word) {
+                    ids.remove(&id);
+                    if ids.is_empty() {
+                        postings.remove(word);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Index::default();
+    }
+
+    /// Returns the ids of matching messages in ascending order.
+    pub fn search(&self, query: &Query) -> Vec<MessageId> {
+        let mut candidates: Option<BTreeSet<MessageId>> = None;
+        for clause in query.clauses.iter().filter(|clause| !clause.negated) {
+            let (fields, value): (&[Field], &str) = match &clause.term {
+                Term::From(value) => (&[Field::From], value),
+                Term::To(value) => (&[Field::To], value),
+                Term::Subject(value) => (&[Field::Subject], value),
+                Term::Text(value) => (&[Field::Subject, Field::Body], value),
+                _ => continue,
+            };
+            for word in words(value) {
+                let ids = self.lookup(fields, &word);
+                candidates = Some(match candidates {
+                    Some(candidates) => candidates.intersection(&ids).copied().collect(),
+                    None => ids,
+                });
+            }
+        }
+
+        let matches =
+            |(id, document): (&MessageId, &Document)| document.matches_query(query).then_some(*id);
+        match candidates {
+            Some(ids) => ids
+                .iter()
+                .filter_map(|id| self.documents.get_key_value(id))
+                .filter_map(matches)
+                .collect(),
+            None => self.documents.iter().filter_map(matches).collect(),
+        }
+    }
+
+    /// Whether the indexed message `id` matches `query`.
+    pub fn matches(&self, query: &Query, id: MessageId) -> bool {
+        self.documents
+            .get(&id)
+            .is_some_and(|document| document.matches_query(query))
+    }
+
+    /// Messages with a word in any of `fields` that starts with `prefix`.
+    fn lookup(&self, fields: &[Field], prefix: &str) -> BTreeSet<MessageId> {
+        let mut ids = BTreeSet::new();
+        for field in fields {
+            let Some(postings) = self.postings.get(field) else {
+                continue;
+            };
+            for (word, posting) in postings.range(prefix.to_string()..) {
+                if !word.starts_with(prefix) {
+                    break;
+                }
+                ids.extend(posting);
+            }
+        }
+        ids
+    }
+}
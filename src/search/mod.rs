@@ -0,0 +1,36 @@
+//! Searching captured mail.
+//!
+//! A [`Query`] is parsed from a small Gmail-like language and evaluated
+//! against an [`Index`] kept up to date by the store, so a search only looks
+//! at messages that contain every word it asks for.
+//!
+//! ```text
+//! from:alice to:bob@example.test subject:"password reset" has:attachment
+//! after:2025-01-01 before:2025-02-01 token -expired
+//! ```
+
+mod index;
+mod query;
+
+pub(crate) use index::{Document, Index};
+pub use query::{ParseError, Query, Term};
+
+/// Splits text into lowercase words, the unit the index is keyed on.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Lowercases text and collapses runs of whitespace, so phrases match
+/// regardless of how the message was wrapped.
+fn normalize(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for word in text.split_whitespace() {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        out.push_str(&word.to_lowercase());
+    }
+    out
+}
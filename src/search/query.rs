@@ -0,0 +1,241 @@
+//! The search query language.
+
+use chrono::{DateTime, NaiveDate, Utc};
+use thiserror::Error;
+
+use super::normalize;
+
+/// A parsed search: every clause must match.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    pub clauses: Vec<Clause>,
+}
+
+/// One term of a query, possibly negated with a leading `-`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Clause {
+    pub negated: bool,
+    pub term: Term,
+}
+
+/// What a clause tests. Text values are stored normalized (lowercase,
+/// single spaces).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Term {
+    /// `from:` - the `From` header or the envelope sender.
+    From(String),
+    /// `to:` - the `To`, `Cc` and `Bcc` headers or the envelope recipients.
+    To(String),
+    /// `subject:` - the decoded subject.
+    Subject(String),
+    /// `has:attachment`.
+    HasAttachment,
+    /// `before:` - received strictly before this instant.
+    Before(DateTime<Utc>),
+    /// `after:` - received at or after this instant.
+    After(DateTime<Utc>),
+    /// `mailbox:` - the mailbox the message was routed to, matched exactly.
+    Mailbox(String),
+    /// A bare word or quoted phrase, matched against the subject and the
+    /// decoded text and HTML bodies.
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ParseError {
+    #[error("{0}: needs a value")]
+    MissingValue(String),
+    #[error("unknown has: value {0:?}, expected \"attachment\"")]
+    UnknownHas(String),
+    #[error("invalid date {0:?}, expected YYYY-MM-DD or an RFC 3339 timestamp")]
+    InvalidDate(String),
+}
+
+impl Query {
+    /// Parses a query. Whitespace separates clauses, double quotes group
+    /// words into a phrase, and a leading `-` negates a clause. Words with
+    /// a colon that is not one of the known fields are searched as text.
+    pub fn parse(input: &str) -> Result<Query, ParseError> {
+        let mut clauses = Vec::new();
+        for token in tokens(input) {
+            let (negated, token) = match token.strip_prefix('-') {
+                Some(rest) if !rest.is_empty() => (true, rest.to_string()),
+                _ => (false, token),
+            };
+            if let Some(term) = parse_term(&token)? {
+                clauses.push(Clause { negated, term });
+            }
+        }
+        Ok(Query { clauses })
+    }
+
+    /// Restricts the query to messages in `mailbox`.
+    pub fn in_mailbox(mut self, mailbox: &str) -> Query {
+        self.clauses.push(Clause {
+            negated: false,
+            term: Term::Mailbox(mailbox.to_string()),
+        });
+        self
+    }
+
+    /// Whether the query has no clauses and so matches everything.
+    pub fn is_empty(&self) -> bool {
+        self.clauses.is_empty()
+    }
+}
+
+impl std::str::FromStr for Query {
+    type Err = ParseError;
+
+    fn from_str(input: &str) -> Result<Query, ParseError> {
+        Query::parse(input)
+    }
+}
+
+fn parse_term(token: &str) -> Result<Option<Term>, ParseError> {
+    let unquoted = |value: &str| value.replace('"', "");
+    if let Some((field, value)) = token.split_once(':') {
+        let field = field.to_ascii_lowercase();
+        let value = unquoted(value);
+        let text = |make: fn(String) -> Term| {
+            let value = normalize(&value);
+            if value.is_empty() {
+                Err(ParseError::MissingValue(field.clone()))
+            } else {
+                Ok(Some(make(value)))
+            }
+        };
+        match field.as_str() {
+            "from" => return text(Term::From),
+            "to" => return text(Term::To),
+            "subject" => return text(Term::Subject),
+            "has" => {
+                return match value.to_ascii_lowercase().as_str() {
+                    "attachment" | "attachments" => Ok(Some(Term::HasAttachment)),
+                    "" => Err(ParseError::MissingValue(field)),
+                    _ => Err(ParseError::UnknownHas(value)),
+                }
+            }
+            "mailbox" if value.is_empty() => return Err(ParseError::MissingValue(field)),
+            "mailbox" => return Ok(Some(Term::Mailbox(value))),
+            "before" => return parse_date(&value, &field).map(|at| Some(Term::Before(at))),
+            "after" => return parse_date(&value, &field).map(|at| Some(Term::After(at))),
+            _ => {}
+        }
+    }
+    let text = normalize(&unquoted(token));
+    Ok((!text.is_empty()).then_some(Term::Text(text)))
+}
+
+/// Accepts a calendar date (midnight UTC, `-` or `/` separated) or a full
+/// RFC 3339 timestamp.
+fn parse_date(value: &str, field: &str) -> Result<DateTime<Utc>, ParseError> {
+    if value.is_empty() {
+        return Err(ParseError::MissingValue(field.to_string()));
+    }
+    if let Ok(at) = DateTime::parse_from_rfc3339(value) {
+        return Ok(at.to_utc());
+    }
+    NaiveDate::parse_from_str(&value.replace('/', "-"), "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| ParseError::InvalidDate(value.to_string()))
+}
+
+/// Splits on whitespace outside double quotes, keeping the quotes. An
+/// unterminated quote runs to the end of the input.
+fn tokens(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(input: &str) -> Vec<(bool, Term)> {
+        Query::parse(input)
+            .unwrap()
+            .clauses
+            .into_iter()
+            .map(|clause| (clause.negated, clause.term))
+            .collect()
+    }
+
+    #[test]
+    fn fields_phrases_and_negation() {
+        assert_eq!(
+            terms(r#"from:Alice TO:bob@example.test subject:"Password  Reset" has:attachment"#),
+            [
+                (false, Term::From("alice".into())),
+                (false, Term::To("bob@example.test".into())),
+                (false, Term::Subject("password reset".into())),
+                (false, Term::HasAttachment),
+            ]
+        );
+        assert_eq!(
+            terms("-mailbox:Team-A"),
+            [(true, Term::Mailbox("Team-A".into()))]
+        );
+        assert_eq!(
+            terms(r#"token -"link expired" - https://x.test/a"#),
+            [
+                (false, Term::Text("token".into())),
+                (true, Term::Text("link expired".into())),
+                (false, Term::Text("-".into())),
+                (false, Term::Text("https://x.test/a".into())),
+            ]
+        );
+        assert!(Query::parse("  ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn dates() {
+        let midnight = "2025-03-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(
+            terms("after:2025-03-01 before:2025/03/01"),
+            [
+                (false, Term::After(midnight)),
+                (false, Term::Before(midnight))
+            ]
+        );
+        assert_eq!(
+            terms("before:2025-03-01T02:00:00+02:00"),
+            [(false, Term::Before(midnight))]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Query::parse("from:"),
+            Err(ParseError::MissingValue("from".into()))
+        );
+        assert_eq!(
+            Query::parse("has:pdf"),
+            Err(ParseError::UnknownHas("pdf".into()))
+        );
+        assert_eq!(
+            Query::parse("before:yesterday"),
+            Err(ParseError::InvalidDate("yesterday".into()))
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! A log of recent client sessions: what was said, and any faults injected.
+//!
+//! Only the most recent sessions are kept, and each keeps a bounded number
+//! of entries; message data itself is never logged.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::store::MessageId;
+
+/// Sessions kept before the oldest are forgotten.
+const MAX_SESSIONS: usize = 500;
+
+/// Entries kept per session; later ones are counted but dropped.
+const MAX_ENTRIES: usize = 1000;
+
+/// Identifier of a logged session, increasing with start time.
+pub type SessionId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    /// A line sent by the client.
+    Client,
+    /// A reply sent by the server.
+    Server,
+    /// Something the server did or noticed, such as a TLS handshake.
+    Event,
+    /// A deliberately injected fault.
+    Fault,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    pub at: DateTime<Utc>,
+    pub kind: EntryKind,
+    pub text: String,
+}
+
+/// Everything logged about one session.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionRecord {
+    pub id: SessionId,
+    /// `smtp`, or another mail protocol.
+    pub protocol: &'static str,
+    pub peer: SocketAddr,
+    pub started_at: DateTime<Utc>,
+    /// Unset while the session is still open.
+    pub ended_at: Option<DateTime<Utc>>,
+    /// Messages captured during the session.
+    pub messages: Vec<MessageId>,
+    pub entries: Vec<Entry>,
+    /// Entries dropped because the session went over the limit.
+    pub truncated: usize,
+}
+
+/// Shared handle to the log. Cloning it is cheap.
+#[derive(Debug, Clone, Default)]
+pub struct SessionLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    last_id: SessionId,
+    sessions: VecDeque<SessionRecord>,
+}
+
+impl Inner {
+    fn get_mut(&mut self, id: SessionId) -> Option<&mut SessionRecord> {
+        // Ids are increasing, and active sessions are near the back.
+        self.sessions
+            .iter_mut()
+            .rev()
+            .find(|session| session.id == id)
+    }
+}
+
+impl SessionLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts logging a new session.
+    pub fn open(&self, protocol: &'static str, peer: SocketAddr) -> SessionId {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_id += 1;
+        let id = inner.last_id;
+        if inner.sessions.len() == MAX_SESSIONS {
+            inner.sessions.pop_front();
+        }
+        inner.sessions.push_back(SessionRecord {
+            id,
+            protocol,
+            peer,
+            started_at: Utc::now(),
+            ended_at: None,
+            messages: Vec::new(),
+            entries: Vec::new(),
+            truncated: 0,
+        });
+        id
+    }
+
+    pub fn record(&self, id: SessionId, kind: EntryKind, text: impl Into<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(session) = inner.get_mut(id) else {
+            return;
+        };
+        if session.entries.len() < MAX_ENTRIES {
+            session.entries.push(Entry {
+                at: Utc::now(),
+                kind,
+                text: text.into(),
+            });
+        } else {
+            session.truncated += 1;
+        }
+    }
+
+    /// Notes that `message` was captured during the session.
+    pub fn captured(&self, id: SessionId, message: MessageId) {
+        if let Some(session) = self.inner.lock().unwrap().get_mut(id) {
+            session.messages.push(message);
+        }
+    }
+
+    pub fn close(&self, id: SessionId) {
+        if let Some(session) = self.inner.lock().unwrap().get_mut(id) {
+            session.ended_at = Some(Utc::now());
+        }
+    }
+
+    /// Returns every logged session, newest first.
+    pub fn list(&self) -> Vec<SessionRecord> {
+        let inner = self.inner.lock().unwrap();
+        inner.sessions.iter().rev().cloned().collect()
+    }
+
+    pub fn get(&self, id: SessionId) -> Option<SessionRecord> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.get_mut(id).map(|session| session.clone())
+    }
+
+    /// Forgets every session and returns how many there were.
+    pub fn clear(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let count = inner.sessions.len();
+        inner.sessions.clear();
+        count
+    }
+}
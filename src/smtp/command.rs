@@ -0,0 +1,213 @@
+//! Parsing of SMTP command lines (RFC 5321 section 4.1).
+
+/// A single parsed SMTP command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Helo(String),
+    Ehlo(String),
+    Mail {
+        from: String,
+        params: Vec<String>,
+    },
+    Rcpt {
+        to: String,
+        params: Vec<String>,
+    },
+    Data,
+    Rset,
+    Noop,
+    Quit,
+    Vrfy,
+    Help,
+    StartTls,
+    /// `AUTH mechanism [initial-response]` (RFC 4954).
+    Auth {
+        mechanism: String,
+        initial: Option<String>,
+    },
+}
+
+/// Why a command line could not be turned into a [`Command`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The verb is not one we implement.
+    Unrecognized,
+    /// The verb is known but its arguments are malformed.
+    Syntax(&'static str),
+}
+
+impl Command {
+    /// Parses a command line with the trailing CRLF already stripped.
+    pub fn parse(line: &str) -> Result<Command, ParseError> {
+        let (verb, rest) = match line.find(' ') {
+            Some(i) => (&line[..i], line[i + 1..].trim()),
+            None => (line, ""),
+        };
+
+        match verb.to_ascii_uppercase().as_str() {
+            "HELO" => domain_arg(rest).map(Command::Helo),
+            "EHLO" => domain_arg(rest).map(Command::Ehlo),
+            "MAIL" => {
+                let (from, params) = path_arg(rest, "FROM:")?;
+                Ok(Command::Mail { from, params })
+            }
+            "RCPT" => {
+                let (to, params) = path_arg(rest, "TO:")?;
+                if to.is_empty() {
+                    return Err(ParseError::Syntax("Empty forward-path is not allowed"));
+                }
+                Ok(Command::Rcpt { to, params })
+            }
+            "DATA" => no_arg(rest, Command::Data),
+            "RSET" => no_arg(rest, Command::Rset),
+            // NOOP, VRFY and HELP may carry arbitrary arguments that we ignore.
+            "NOOP" => Ok(Command::Noop),
+            "QUIT" => no_arg(rest, Command::Quit),
+            "VRFY" => Ok(Command::Vrfy),
+            "HELP" => Ok(Command::Help),
+            "STARTTLS" => no_arg(rest, Command::StartTls),
+            "AUTH" => {
+                let mut args = rest.split_whitespace();
+                let mechanism = args.next().ok_or(ParseError::Syntax(
+                    "Syntax: AUTH mechanism [initial-response]",
+                ))?;
+                Ok(Command::Auth {
+                    mechanism: mechanism.to_ascii_uppercase(),
+                    initial: args.next().map(str::to_string),
+                })
+            }
+            _ => Err(ParseError::Unrecognized),
+        }
+    }
+}
+
+fn domain_arg(rest: &str) -> Result<String, ParseError> {
+    match rest.split_whitespace().next() {
+        Some(domain) => Ok(domain.to_string()),
+        None => Err(ParseError::Syntax("Domain or address literal required")),
+    }
+}
+
+fn no_arg(rest: &str, command: Command) -> Result<Command, ParseError> {
+    if rest.is_empty() {
+        Ok(command)
+    } else {
+        Err(ParseError::Syntax("Command takes no arguments"))
+    }
+}
+
+/// Parses `FROM:<path> params...` / `TO:<path> params...`.
+///
+/// Some clients put a space after the colon or omit the angle brackets, so
+/// both are tolerated.
+fn path_arg(rest: &str, prefix: &str) -> Result<(String, Vec<String>), ParseError> {
+    if rest.len() < prefix.len() || !rest[..prefix.len()].eq_ignore_ascii_case(prefix) {
+        return Err(ParseError::Syntax(
+            "Syntax: MAIL FROM:<address> / RCPT TO:<address>",
+        ));
+    }
+    let rest = rest[prefix.len()..].trim_start();
+
+    let (path, params) = if let Some(stripped) = rest.strip_prefix('<') {
+        let end = stripped
+            .find('>')
+            .ok_or(ParseError::Syntax("Unterminated path, missing '>'"))?;
+        (&stripped[..end], &stripped[end + 1..])
+    } else {
+        match rest.find(' ') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, ""),
+        }
+    };
+
+    // Drop a source route (`@a,@b:user@host`), which RFC 5321 says to ignore.
+    let path = match path.rfind(':') {
+        Some(i) if path.starts_with('@') => &path[i + 1..],
+        _ => path,
+    };
+
+    let params = params.split_whitespace().map(str::to_string).collect();
+    Ok((path.to_string(), params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mail_with_params() {
+        assert_eq!(
+            Command::parse("MAIL FROM:<a@example.test> SIZE=100 BODY=8BITMIME"),
+            Ok(Command::Mail {
+                from: "a@example.test".into(),
+                params: vec!["SIZE=100".into(), "BODY=8BITMIME".into()],
+            })
+        );
+    }
+
+    #[test]
+    fn tolerates_sloppy_paths() {
+        assert_eq!(
+            Command::parse("mail from: bob@example.test"),
+            Ok(Command::Mail {
+                from: "bob@example.test".into(),
+                params: vec![]
+            })
+        );
+        assert_eq!(
+            Command::parse("RCPT TO:<@relay.test:carol@example.test>"),
+            Ok(Command::Rcpt {
+                to: "carol@example.test".into(),
+                params: vec![]
+            })
+        );
+    }
+
+    #[test]
+    fn null_reverse_path_is_allowed_only_for_mail() {
+        assert_eq!(
+            Command::parse("MAIL FROM:<>"),
+            Ok(Command::Mail {
+                from: String::new(),
+                params: vec![]
+            })
+        );
+        assert!(matches!(
+            Command::parse("RCPT TO:<>"),
+            Err(ParseError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_and_malformed() {
+        assert_eq!(Command::parse("EXPN list"), Err(ParseError::Unrecognized));
+        assert!(matches!(Command::parse("HELO"), Err(ParseError::Syntax(_))));
+        assert!(matches!(
+            Command::parse("DATA now"),
+            Err(ParseError::Syntax(_))
+        ));
+        assert!(matches!(
+            Command::parse("MAIL <a@b>"),
+            Err(ParseError::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn parses_auth() {
+        assert_eq!(
+            Command::parse("AUTH plain AGFsaWNlAHNlY3JldA=="),
+            Ok(Command::Auth {
+                mechanism: "PLAIN".into(),
+                initial: Some("AGFsaWNlAHNlY3JldA==".into()),
+            })
+        );
+        assert_eq!(
+            Command::parse("auth LOGIN"),
+            Ok(Command::Auth {
+                mechanism: "LOGIN".into(),
+                initial: None,
+            })
+        );
+        assert!(matches!(Command::parse("AUTH"), Err(ParseError::Syntax(_))));
+    }
+}
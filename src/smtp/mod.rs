@@ -0,0 +1,145 @@
+//! The SMTP capture listener.
+//!
+//! Speaks enough of RFC 5321 for any mailer to hand over a message, then keeps
+//! the message in the [`MessageStore`] instead of relaying it.
+
+mod command;
+mod session;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+pub use command::{Command, ParseError};
+
+use crate::auth::AuthPolicy;
+use crate::dns::Records;
+use crate::faults::Faults;
+use crate::listener::{self, Protocol};
+use crate::mailbox::Mailboxes;
+use crate::sessions::{SessionId, SessionLog};
+use crate::store::MessageStore;
+use crate::tls::{TlsInfo, TlsMode};
+use session::Session;
+
+/// Settings for the SMTP listener.
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    /// Host name used in the greeting and EHLO response.
+    pub hostname: String,
+    /// Largest DATA payload accepted, in bytes.
+    pub max_message_size: usize,
+    /// How long a client may stay silent before the connection is dropped.
+    pub idle_timeout: Duration,
+    /// Whether the listener offers STARTTLS or expects TLS from the start.
+    pub tls: TlsMode,
+    /// Which AUTH credentials are accepted.
+    pub auth: AuthPolicy,
+    /// Fault rules, shared with the HTTP API so they can change at runtime.
+    pub faults: Faults,
+    /// Where sessions are logged, shared with the HTTP API.
+    pub sessions: SessionLog,
+    /// Which mailboxes captured messages are filed into.
+    pub mailboxes: Mailboxes,
+    /// Records DKIM keys and SPF and DMARC policies are looked up in when a
+    /// message is received.
+    pub dns: Records,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        SmtpConfig {
+            hostname: "rubbermail".to_string(),
+            max_message_size: 25 * 1024 * 1024,
+            idle_timeout: Duration::from_secs(300),
+            tls: TlsMode::Off,
+            auth: AuthPolicy::AcceptAny,
+            faults: Faults::default(),
+            sessions: SessionLog::default(),
+            mailboxes: Mailboxes::default(),
+            dns: Records::default(),
+        }
+    }
+}
+
+/// State shared by every session of one listener.
+pub(crate) struct Shared {
+    pub(crate) config: SmtpConfig,
+    pub(crate) store: MessageStore,
+    /// Port the listener is bound to, for routing by port.
+    pub(crate) port: u16,
+}
+
+/// A bound SMTP listener that has not started accepting yet.
+pub struct SmtpServer {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+impl SmtpServer {
+    pub async fn bind(
+        addr: SocketAddr,
+        config: SmtpConfig,
+        store: MessageStore,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let port = listener.local_addr()?.port();
+        Ok(SmtpServer {
+            listener,
+            shared: Arc::new(Shared {
+                config,
+                store,
+                port,
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the task is dropped.
+    pub async fn serve(self) -> io::Result<()> {
+        listener::serve(self.listener, self.shared).await
+    }
+}
+
+impl Protocol for Shared {
+    const NAME: &'static str = "SMTP";
+    const PROTOCOL: &'static str = "smtp";
+
+    fn tls(&self) -> &TlsMode {
+        &self.config.tls
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        self.config.idle_timeout
+    }
+
+    fn sessions(&self) -> &SessionLog {
+        &self.config.sessions
+    }
+
+    async fn session<S>(
+        self: Arc<Self>,
+        stream: S,
+        peer: SocketAddr,
+        log: SessionId,
+        tls: Option<TlsInfo>,
+        resume: bool,
+    ) -> io::Result<Option<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let session = Session::new(stream, peer, log, self, tls);
+        if resume {
+            session.resume().await
+        } else {
+            session.run().await
+        }
+    }
+}
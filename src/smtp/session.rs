@@ -0,0 +1,587 @@
+//! One SMTP conversation, from greeting to QUIT.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::time::{sleep, timeout};
+use tracing::{debug, error, info};
+
+use super::command::{Command, ParseError};
+use super::Shared;
+use crate::auth;
+use crate::dmarc::Authentication;
+use crate::faults::{FaultAction, Stage};
+use crate::line::{read_line, trim_eol, Line};
+use crate::mailbox::Delivery;
+use crate::sessions::{EntryKind, SessionId};
+use crate::store::{Envelope, NewMessage, SessionInfo};
+use crate::tls::{TlsInfo, TlsMode};
+
+/// Longest command line we accept, including CRLF. RFC 5321 asks for 512;
+/// we allow more because real clients overshoot with long parameters.
+const MAX_COMMAND_LINE: usize = 4096;
+
+/// RFC 5321 requires accepting at least 100 recipients per transaction.
+const MAX_RECIPIENTS: usize = 1000;
+
+enum Flow {
+    Continue,
+    Quit,
+    StartTls,
+}
+
+pub(crate) struct Session<S> {
+    stream: BufReader<S>,
+    shared: Arc<Shared>,
+    peer: SocketAddr,
+    /// This session's entry in the session log.
+    log: SessionId,
+    helo: Option<String>,
+    transaction: Option<Envelope>,
+    /// Set once the connection is encrypted.
+    tls: Option<TlsInfo>,
+    /// Set once the client has authenticated.
+    username: Option<String>,
+    line: Vec<u8>,
+}
+
+impl<S> Session<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub(crate) fn new(
+        stream: S,
+        peer: SocketAddr,
+        log: SessionId,
+        shared: Arc<Shared>,
+        tls: Option<TlsInfo>,
+    ) -> Self {
+        Session {
+            stream: BufReader::new(stream),
+            shared,
+            peer,
+            log,
+            helo: None,
+            transaction: None,
+            tls,
+            username: None,
+            line: Vec::new(),
+        }
+    }
+
+    /// Greets the client and serves commands until the connection ends.
+    ///
+    /// Returns the underlying stream if the client issued STARTTLS, so the
+    /// caller can run the handshake and [`resume`](Self::resume) over TLS.
+    pub(crate) async fn run(mut self) -> io::Result<Option<S>> {
+        if self
+            .inject(Stage::Connect, &self.peer.ip().to_string())
+            .await?
+            .is_some()
+        {
+            return Ok(None);
+        }
+        let greeting = format!("{} RubberMail ESMTP ready", self.shared.config.hostname);
+        self.reply(220, &greeting).await?;
+        self.resume().await
+    }
+
+    /// Serves commands without sending a greeting first.
+    pub(crate) async fn resume(mut self) -> io::Result<Option<S>> {
+        loop {
+            match self.read_line(MAX_COMMAND_LINE).await? {
+                Line::Eof => return Ok(None),
+                Line::TooLong => {
+                    self.reply(500, "5.5.2 Line too long").await?;
+                    continue;
+                }
+                Line::Complete => {}
+            }
+
+            let text = String::from_utf8_lossy(trim_eol(&self.line)).into_owned();
+            debug!(peer = %self.peer, "C: {}", redact(&text));
+            self.log(EntryKind::Client, redact(&text));
+            let flow = match Command::parse(&text) {
+                Ok(command) => self.handle(command).await?,
+                Err(ParseError::Unrecognized) => {
+                    self.reply(500, "5.5.2 Command not recognized").await?;
+                    Flow::Continue
+                }
+                Err(ParseError::Syntax(reason)) => {
+                    self.reply(501, &format!("5.5.4 {reason}")).await?;
+                    Flow::Continue
+                }
+            };
+            match flow {
+                Flow::Continue => {}
+                Flow::Quit => return Ok(None),
+                // Anything the client pipelined after STARTTLS is still in
+                // the read buffer and is dropped here, as RFC 3207 requires.
+                Flow::StartTls => return Ok(Some(self.stream.into_inner())),
+            }
+        }
+    }
+
+    async fn handle(&mut self, command: Command) -> io::Result<Flow> {
+        match command {
+            Command::Helo(domain) => {
+                if let Some(flow) = self.inject(Stage::Helo, &domain).await? {
+                    return Ok(flow);
+                }
+                self.helo = Some(domain);
+                self.transaction = None;
+                let hostname = self.shared.config.hostname.clone();
+                self.reply(250, &hostname).await?;
+            }
+            Command::Ehlo(domain) => {
+                if let Some(flow) = self.inject(Stage::Helo, &domain).await? {
+                    return Ok(flow);
+                }
+                let mut lines = vec![
+                    format!("{} greets {}", self.shared.config.hostname, domain),
+                    "PIPELINING".to_string(),
+                    "8BITMIME".to_string(),
+                    "SMTPUTF8".to_string(),
+                    "ENHANCEDSTATUSCODES".to_string(),
+                    format!("SIZE {}", self.shared.config.max_message_size),
+                    "AUTH PLAIN LOGIN CRAM-MD5".to_string(),
+                ];
+                if self.can_start_tls() {
+                    lines.push("STARTTLS".to_string());
+                }
+                self.helo = Some(domain);
+                self.transaction = None;
+                self.reply_multi(250, &lines).await?;
+            }
+            Command::Mail { from, params } => {
+                if self.helo.is_none() {
+                    self.reply(503, "5.5.1 Send HELO/EHLO first").await?;
+                } else if self.transaction.is_some() {
+                    self.reply(503, "5.5.1 Sender already specified").await?;
+                } else if self.username.is_none() && self.shared.config.auth.is_strict() {
+                    self.reply(530, "5.7.0 Authentication required").await?;
+                } else if declared_size(&params) > Some(self.shared.config.max_message_size) {
+                    self.reply(552, "5.3.4 Message size exceeds fixed maximum")
+                        .await?;
+                } else {
+                    if let Some(flow) = self.inject(Stage::Mail, &from).await? {
+                        return Ok(flow);
+                    }
+                    self.transaction = Some(Envelope {
+                        mail_from: from,
+                        rcpt_to: Vec::new(),
+                    });
+                    self.reply(250, "2.1.0 Ok").await?;
+                }
+            }
+            Command::Rcpt { to, .. } => match self.transaction.as_ref().map(|e| e.rcpt_to.len()) {
+                None => self.reply(503, "5.5.1 Need MAIL before RCPT").await?,
+                Some(count) if count >= MAX_RECIPIENTS => {
+                    self.reply(452, "4.5.3 Too many recipients").await?
+                }
+                Some(_) => {
+                    if let Some(flow) = self.inject(Stage::Rcpt, &to).await? {
+                        return Ok(flow);
+                    }
+                    if let Some(envelope) = &mut self.transaction {
+                        envelope.rcpt_to.push(to);
+                    }
+                    self.reply(250, "2.1.5 Ok").await?;
+                }
+            },
+            Command::Data => {
+                let ready = self
+                    .transaction
+                    .as_ref()
+                    .is_some_and(|envelope| !envelope.rcpt_to.is_empty());
+                if ready {
+                    if let Some(flow) = self.inject(Stage::Data, "").await? {
+                        return Ok(flow);
+                    }
+                    return self.receive_data().await;
+                }
+                self.reply(503, "5.5.1 Need RCPT before DATA").await?;
+            }
+            Command::Rset => {
+                self.transaction = None;
+                self.reply(250, "2.0.0 Ok").await?;
+            }
+            Command::Noop => self.reply(250, "2.0.0 Ok").await?,
+            Command::Vrfy => {
+                self.reply(252, "2.1.5 Cannot VRFY user, but will accept message")
+                    .await?
+            }
+            Command::Help => {
+                self.reply(
+                    214,
+                    "2.0.0 HELO EHLO MAIL RCPT DATA RSET NOOP QUIT VRFY HELP STARTTLS AUTH",
+                )
+                .await?
+            }
+            Command::StartTls => {
+                if self.tls.is_some() {
+                    self.reply(503, "5.5.1 TLS already active").await?;
+                } else if !self.can_start_tls() {
+                    self.reply(502, "5.5.1 STARTTLS not available").await?;
+                } else {
+                    self.reply(220, "2.0.0 Ready to start TLS").await?;
+                    return Ok(Flow::StartTls);
+                }
+            }
+            Command::Auth { mechanism, initial } => {
+                if self.helo.is_none() {
+                    self.reply(503, "5.5.1 Send EHLO first").await?;
+                } else if self.username.is_some() {
+                    self.reply(503, "5.5.1 Already authenticated").await?;
+                } else if self.transaction.is_some() {
+                    self.reply(503, "5.5.1 AUTH not allowed during a mail transaction")
+                        .await?;
+                } else if let Some(flow) = self.inject(Stage::Auth, &mechanism).await? {
+                    return Ok(flow);
+                } else {
+                    self.authenticate(&mechanism, initial).await?;
+                }
+            }
+            Command::Quit => {
+                self.reply(221, "2.0.0 Bye").await?;
+                return Ok(Flow::Quit);
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// Runs the SASL exchange for `mechanism` (RFC 4954) and replies with
+    /// the outcome.
+    async fn authenticate(&mut self, mechanism: &str, initial: Option<String>) -> io::Result<()> {
+        let policy = self.shared.config.auth.clone();
+        let user = match mechanism {
+            "PLAIN" => {
+                let response = match initial {
+                    Some(initial) => self.decode_response(&initial).await?,
+                    None => self.challenge("").await?,
+                };
+                let Some(response) = response else {
+                    return Ok(());
+                };
+                match auth::decode_plain(&response) {
+                    Some((user, password)) => {
+                        policy.check_password(&user, &password).then_some(user)
+                    }
+                    None => {
+                        return self.reply(501, "5.5.2 Malformed PLAIN response").await;
+                    }
+                }
+            }
+            "LOGIN" => {
+                let user = match initial {
+                    Some(initial) => self.decode_response(&initial).await?,
+                    None => self.challenge("Username:").await?,
+                };
+                let Some(user) = user else {
+                    return Ok(());
+                };
+                let Some(password) = self.challenge("Password:").await? else {
+                    return Ok(());
+                };
+                let user = String::from_utf8_lossy(&user).into_owned();
+                let password = String::from_utf8_lossy(&password);
+                policy.check_password(&user, &password).then_some(user)
+            }
+            "CRAM-MD5" => {
+                let challenge = cram_md5_challenge(&self.shared.config.hostname);
+                let Some(response) = self.challenge(&challenge).await? else {
+                    return Ok(());
+                };
+                match auth::decode_cram_md5(&response) {
+                    Some((user, digest)) => policy
+                        .check_cram_md5(&user, &challenge, &digest)
+                        .then_some(user),
+                    None => {
+                        return self.reply(501, "5.5.2 Malformed CRAM-MD5 response").await;
+                    }
+                }
+            }
+            _ => {
+                return self
+                    .reply(504, "5.5.4 Unrecognized authentication type")
+                    .await;
+            }
+        };
+
+        match user {
+            Some(user) => {
+                info!(peer = %self.peer, %user, mechanism, "client authenticated");
+                self.log(EntryKind::Event, format!("authenticated as {user}"));
+                self.username = Some(user);
+                self.reply(235, "2.7.0 Authentication successful").await
+            }
+            None => {
+                info!(peer = %self.peer, mechanism, "authentication failed");
+                self.reply(535, "5.7.8 Authentication credentials invalid")
+                    .await
+            }
+        }
+    }
+
+    /// Sends a `334` challenge and reads the client's base64 answer.
+    ///
+    /// Returns `None`, having already replied, if the client cancelled with
+    /// `*` or sent something that is not base64.
+    async fn challenge(&mut self, text: &str) -> io::Result<Option<Vec<u8>>> {
+        self.reply(334, &BASE64.encode(text)).await?;
+        match self.read_line(MAX_COMMAND_LINE).await? {
+            Line::Complete => {}
+            Line::TooLong => {
+                self.reply(500, "5.5.2 Line too long").await?;
+                return Ok(None);
+            }
+            Line::Eof => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed during AUTH",
+                ))
+            }
+        }
+        let answer = String::from_utf8_lossy(trim_eol(&self.line)).into_owned();
+        self.log(EntryKind::Client, "<credentials>");
+        if answer == "*" {
+            self.reply(501, "5.7.0 Authentication cancelled").await?;
+            return Ok(None);
+        }
+        self.decode_response(&answer).await
+    }
+
+    /// Decodes a base64 SASL response; `=` stands for an empty one.
+    async fn decode_response(&mut self, text: &str) -> io::Result<Option<Vec<u8>>> {
+        if text == "=" {
+            return Ok(Some(Vec::new()));
+        }
+        match BASE64.decode(text.trim()) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(_) => {
+                self.reply(501, "5.5.2 Cannot decode response").await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Applies the first fault rule that fires for `stage`, if any.
+    ///
+    /// Returns `None` when the stage should be handled normally (possibly
+    /// after an injected delay), or how the conversation continues after
+    /// the fault.
+    async fn inject(&mut self, stage: Stage, argument: &str) -> io::Result<Option<Flow>> {
+        let Some(action) = self.shared.config.faults.check(stage, argument) else {
+            return Ok(None);
+        };
+        info!(peer = %self.peer, %stage, fault = %action, "injecting fault");
+        self.log(EntryKind::Fault, format!("{stage}: {action}"));
+        match action {
+            FaultAction::Delay { millis } => {
+                sleep(Duration::from_millis(millis)).await;
+                Ok(None)
+            }
+            FaultAction::Reply { code, message } => {
+                let text = FaultAction::reply_text(code, message.as_deref());
+                self.reply(code, &text).await?;
+                Ok(Some(Flow::Continue))
+            }
+            FaultAction::Disconnect => {
+                if stage == Stage::Data {
+                    // Let the client start sending so the drop is mid-DATA.
+                    self.reply(354, "End data with <CR><LF>.<CR><LF>").await?;
+                    let max = self.shared.config.max_message_size;
+                    self.read_line(max + 2).await?;
+                }
+                Ok(Some(Flow::Quit))
+            }
+            FaultAction::Timeout => {
+                sleep(self.shared.config.idle_timeout).await;
+                Ok(Some(Flow::Quit))
+            }
+        }
+    }
+
+    fn log(&self, kind: EntryKind, text: impl Into<String>) {
+        self.shared.config.sessions.record(self.log, kind, text);
+    }
+
+    fn can_start_tls(&self) -> bool {
+        self.tls.is_none() && matches!(self.shared.config.tls, TlsMode::StartTls(_))
+    }
+
+    async fn receive_data(&mut self) -> io::Result<Flow> {
+        self.reply(354, "End data with <CR><LF>.<CR><LF>").await?;
+
+        let max = self.shared.config.max_message_size;
+        let mut data = Vec::new();
+        let mut oversized = false;
+        loop {
+            match self.read_line(max + 2).await? {
+                Line::Eof => return Ok(Flow::Quit),
+                Line::TooLong => oversized = true,
+                Line::Complete => {
+                    if trim_eol(&self.line) == b"." {
+                        break;
+                    }
+                    // Undo dot-stuffing (RFC 5321 section 4.5.2).
+                    let content = self.line.strip_prefix(b".").unwrap_or(&self.line);
+                    if oversized || data.len() + content.len() > max {
+                        oversized = true;
+                        data = Vec::new();
+                    } else {
+                        data.extend_from_slice(content);
+                    }
+                }
+            }
+        }
+
+        let envelope = self.transaction.take().unwrap_or_default();
+        if oversized {
+            self.reply(552, "5.3.4 Message size exceeds fixed maximum")
+                .await?;
+            return Ok(Flow::Continue);
+        }
+        self.log(
+            EntryKind::Event,
+            format!("received {} bytes of message data", data.len()),
+        );
+        if let Some(flow) = self
+            .inject(Stage::Message, &envelope.rcpt_to.join(" "))
+            .await?
+        {
+            return Ok(flow);
+        }
+
+        let mailboxes = self.shared.config.mailboxes.route(&Delivery {
+            envelope: &envelope,
+            username: self.username.as_deref(),
+            port: self.shared.port,
+        });
+        let session = SessionInfo {
+            peer: self.peer,
+            helo: self.helo.clone().unwrap_or_default(),
+            tls: self.tls.clone(),
+            username: self.username.clone(),
+        };
+        let authentication =
+            Authentication::check(&data, &envelope, &session, &self.shared.config.dns);
+        let message = NewMessage {
+            envelope,
+            session,
+            raw: data,
+            authentication: Some(authentication),
+        };
+        // Each mailbox gets its own copy, so deleting from one leaves the
+        // others alone. A 451 means none of them was kept.
+        let copies = match self.shared.store.insert_copies(&mailboxes, message) {
+            Ok(copies) => copies,
+            Err(err) => {
+                error!(peer = %self.peer, "failed to store message: {err}");
+                self.reply(451, "4.3.0 Could not store message, try again later")
+                    .await?;
+                return Ok(Flow::Continue);
+            }
+        };
+        let mut ids = Vec::new();
+        for message in copies {
+            let mailbox = &message.mailbox;
+            self.shared.config.sessions.captured(self.log, message.id);
+            self.log(
+                EntryKind::Event,
+                format!("captured message {} in mailbox {mailbox}", message.id),
+            );
+            info!(
+                id = message.id,
+                mailbox,
+                from = %message.envelope.mail_from,
+                to = ?message.envelope.rcpt_to,
+                size = message.raw.len(),
+                tls = message.session.tls.is_some(),
+                "captured message"
+            );
+            ids.push(message.id.to_string());
+        }
+        self.reply(250, &format!("2.0.0 Ok: queued as {}", ids.join(",")))
+            .await?;
+        Ok(Flow::Continue)
+    }
+
+    /// Reads one line into `self.line`, keeping its line ending.
+    ///
+    /// Lines longer than `limit` are consumed and discarded. Both CRLF and
+    /// bare LF terminate a line.
+    async fn read_line(&mut self, limit: usize) -> io::Result<Line> {
+        let idle = self.shared.config.idle_timeout;
+        match timeout(idle, read_line(&mut self.stream, &mut self.line, limit)).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.reply(421, "4.4.2 Idle timeout, closing connection")
+                    .await?;
+                Ok(Line::Eof)
+            }
+        }
+    }
+
+    async fn reply(&mut self, code: u16, text: &str) -> io::Result<()> {
+        debug!(peer = %self.peer, "S: {code} {text}");
+        self.log(EntryKind::Server, format!("{code} {text}"));
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(format!("{code} {text}\r\n").as_bytes())
+            .await?;
+        stream.flush().await
+    }
+
+    async fn reply_multi(&mut self, code: u16, lines: &[String]) -> io::Result<()> {
+        let mut out = String::new();
+        for (i, line) in lines.iter().enumerate() {
+            let sep = if i + 1 == lines.len() { ' ' } else { '-' };
+            out.push_str(&format!("{code}{sep}{line}\r\n"));
+        }
+        self.log(EntryKind::Server, out.trim_end());
+        let stream = self.stream.get_mut();
+        stream.write_all(out.as_bytes()).await?;
+        stream.flush().await
+    }
+}
+
+/// A unique CRAM-MD5 challenge in the RFC 2195 `<id.timestamp@host>` form.
+fn cram_md5_challenge(hostname: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("<{}.{seq}.{nanos}@{hostname}>", std::process::id())
+}
+
+/// Hides the initial response of an AUTH command, which carries credentials.
+fn redact(line: &str) -> String {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some(verb), Some(mechanism), Some(_)) if verb.eq_ignore_ascii_case("AUTH") => {
+            format!("{verb} {mechanism} <credentials>")
+        }
+        _ => line.to_string(),
+    }
+}
+
+/// Extracts the RFC 1870 `SIZE=` parameter from MAIL FROM.
+fn declared_size(params: &[String]) -> Option<usize> {
+    params.iter().find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.eq_ignore_ascii_case("SIZE") {
+            value.parse().ok()
+        } else {
+            None
+        }
+    })
+}
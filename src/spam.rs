@@ -0,0 +1,339 @@
+//! Spam scoring with SpamAssassin, over the spamd protocol.
+//!
+//! Every captured message is sent to a configured spamd with the SPAMC
+//! `REPORT` command. The score, the threshold and the rules that matched are
+//! stored with the message, so a campaign can be checked before it goes out.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use crate::store::{MessageStore, StoreEvent};
+
+/// The port spamd listens on by default.
+pub const DEFAULT_PORT: u16 = 783;
+
+/// Protocol version sent with each request.
+const PROTOCOL: &str = "SPAMC/1.5";
+
+/// Largest response accepted from spamd; reports are a few kilobytes.
+const MAX_RESPONSE: u64 = 1024 * 1024;
+
+/// Messages scored at once, so a burst of mail does not open a spamd
+/// connection per message.
+const MAX_CONCURRENT_CHECKS: usize = 4;
+
+/// Where messages are scored.
+#[derive(Debug, Clone)]
+pub struct SpamdConfig {
+    pub host: String,
+    pub port: u16,
+    /// User whose SpamAssassin preferences spamd applies, if any.
+    pub user: Option<String>,
+    /// Limit on scoring one message.
+    pub timeout: Duration,
+}
+
+impl SpamdConfig {
+    pub fn new(host: impl Into<String>, port: u16) -> SpamdConfig {
+        SpamdConfig {
+            host: host.into(),
+            port,
+            user: None,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// How SpamAssassin scored a message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpamReport {
+    pub score: f64,
+    /// The score from which spamd considers a message spam.
+    pub threshold: f64,
+    pub is_spam: bool,
+    /// The rules that matched, in the order spamd listed them.
+    pub rules: Vec<SpamRule>,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// A SpamAssassin rule that matched.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpamRule {
+    pub name: String,
+    /// Points the rule added; negative rules count in the message's favor.
+    pub score: f64,
+    pub description: String,
+}
+
+/// Why a message could not be scored.
+#[derive(Debug, thiserror::Error)]
+pub enum SpamdError {
+    #[error("connection to spamd failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("spamd did not answer within {}s", .0.as_secs_f64())]
+    Timeout(Duration),
+    #[error("spamd refused the message: {0}")]
+    Refused(String),
+    #[error("unexpected response from spamd: {0}")]
+    Protocol(String),
+}
+
+/// Scores messages with a spamd server.
+#[derive(Debug)]
+pub struct Spamd {
+    config: SpamdConfig,
+}
+
+impl Spamd {
+    pub fn new(config: SpamdConfig) -> Spamd {
+        Spamd { config }
+    }
+
+    pub fn config(&self) -> &SpamdConfig {
+        &self.config
+    }
+
+    /// Scores a raw message.
+    pub async fn check(&self, raw: &[u8]) -> Result<SpamReport, SpamdError> {
+        let limit = self.config.timeout;
+        let response = timeout(limit, self.exchange(raw))
+            .await
+            .map_err(|_| SpamdError::Timeout(limit))??;
+        parse_response(&response)
+    }
+
+    async fn exchange(&self, raw: &[u8]) -> Result<Vec<u8>, SpamdError> {
+        let mut stream = TcpStream::connect((self.config.host.as_str(), self.config.port)).await?;
+        let mut request = format!("REPORT {PROTOCOL}\r\nContent-length: {}\r\n", raw.len());
+        if let Some(user) = &self.config.user {
+            request.push_str(&format!("User: {user}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        stream.write_all(raw).await?;
+        // spamc half-closes after the body; some servers wait for it.
+        stream.shutdown().await?;
+        let mut response = Vec::new();
+        stream.take(MAX_RESPONSE).read_to_end(&mut response).await?;
+        Ok(response)
+    }
+
+    /// Scores every message the store receives from now on, in the
+    /// background, and stores the result with the message.
+    pub fn watch(self: Arc<Self>, store: MessageStore) -> JoinHandle<()> {
+        let mut events = store.subscribe();
+        let limit = Arc::new(Semaphore::new(MAX_CONCURRENT_CHECKS));
+        tokio::spawn(async move {
+            loop {
+                let message = match events.recv().await {
+                    Ok(StoreEvent::Received(message)) => message,
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(missed, "spam scoring fell behind; messages left unscored");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                let spamd = self.clone();
+                let store = store.clone();
+                let limit = limit.clone();
+                tokio::spawn(async move {
+                    let _permit = limit.acquire_owned().await;
+                    match spamd.check(&message.raw).await {
+                        Ok(report) => {
+                            debug!(id = message.id, score = report.score, "message scored");
+                            let result = store.update(message.id, |message| {
+                                message.spam = Some(report);
+                            });
+                            if let Err(err) = result {
+                                warn!(id = message.id, "could not store spam score: {err}");
+                            }
+                        }
+                        Err(err) => warn!(id = message.id, "spam scoring failed: {err}"),
+                    }
+                });
+            }
+        })
+    }
+}
+
+/// Parses a spamd response to `REPORT`:
+///
+/// ```text
+/// SPAMD/1.1 0 EX_OK
+/// Content-length: 512
+/// Spam: True ; 7.2 / 5.0
+///
+/// <report text>
+/// ```
+fn parse_response(response: &[u8]) -> Result<SpamReport, SpamdError> {
+    let text = String::from_utf8_lossy(response);
+    let (head, body) = text
+        .split_once("\r\n\r\n")
+        .or_else(|| text.split_once("\n\n"))
+        .unwrap_or((&text, ""));
+    let mut lines = head.lines();
+    let status = lines.next().unwrap_or_default();
+    let mut parts = status.splitn(3, ' ');
+    let (version, code, reason) = (parts.next(), parts.next(), parts.next());
+    if !version.is_some_and(|version| version.starts_with("SPAMD/")) {
+        return Err(SpamdError::Protocol(format!("bad status line {status:?}")));
+    }
+    match code {
+        Some("0") => {}
+        Some(code) => {
+            return Err(SpamdError::Refused(format!(
+                "{code} {}",
+                reason.unwrap_or_default()
+            )))
+        }
+        None => return Err(SpamdError::Protocol(format!("bad status line {status:?}"))),
+    }
+
+    let spam = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("spam"))
+        .map(|(_, value)| value.trim())
+        .ok_or_else(|| SpamdError::Protocol("no Spam header".to_string()))?;
+    // `True ; 7.2 / 5.0`
+    let bad_header = || SpamdError::Protocol(format!("bad Spam header {spam:?}"));
+    let (verdict, scores) = spam.split_once(';').ok_or_else(bad_header)?;
+    let (score, threshold) = scores.split_once('/').ok_or_else(bad_header)?;
+    let verdict = verdict.trim();
+    Ok(SpamReport {
+        score: score.trim().parse().map_err(|_| bad_header())?,
+        threshold: threshold.trim().parse().map_err(|_| bad_header())?,
+        is_spam: verdict.eq_ignore_ascii_case("true") || verdict.eq_ignore_ascii_case("yes"),
+        rules: parse_rules(body),
+        checked_at: Utc::now(),
+    })
+}
+
+/// Reads the rule table at the end of a SpamAssassin report:
+///
+/// ```text
+///  pts rule name              description
+/// ---- ---------------------- --------------------------------------------
+///  1.4 MISSING_DATE           Missing Date: header
+/// -0.0 NO_RELAYS              Informational: message was not relayed via
+///                             SMTP
+/// ```
+fn parse_rules(report: &str) -> Vec<SpamRule> {
+    let mut rules: Vec<SpamRule> = Vec::new();
+    let mut in_table = false;
+    for line in report.lines() {
+        if !in_table {
+            in_table = line.starts_with("----");
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Points are printed with `%4.1f`, so a row starts with them, while
+        // wrapped descriptions are indented to the description column.
+        let indent = line.len() - line.trim_start().len();
+        let row = line
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .filter(|_| indent < 4)
+            .and_then(|(points, rest)| Some((points.parse::<f64>().ok()?, rest.trim_start())));
+        match row {
+            Some((score, rest)) => {
+                let (name, description) =
+                    rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                rules.push(SpamRule {
+                    name: name.to_string(),
+                    score,
+                    description: description.trim().to_string(),
+                });
+            }
+            None => {
+                // A wrapped description.
+                if let Some(rule) = rules.last_mut() {
+                    if !rule.description.is_empty() {
+                        rule.description.push(' ');
+                    }
+                    rule.description.push_str(line.trim());
+                }
+            }
+        }
+    }
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPORT: &str = concat!(
+        "SPAMD/1.1 0 EX_OK\r\n",
+        "Content-length: 600\r\n",
+        "Spam: True ; 7.3 / 5.0\r\n",
+        "\r\n",
+        "Spam detection software, running on the system \"mx\", has\n",
+        "identified this incoming email as possible spam.\n",
+        "\n",
+        "Content analysis details:   (7.3 points, 5.0 required)\n",
+        "\n",
+        " pts rule name              description\n",
+        "---- ---------------------- --------------------------------------------------\n",
+        " 1.4 MISSING_DATE           Missing Date: header\n",
+        " 5.9 GTUBE                  BODY: Generic Test for Unsolicited Bulk Email\n",
+        "-0.0 NO_RELAYS              Informational: message was not relayed via\n",
+        "                            SMTP\n",
+        "10.0 BIG_ONE                Made up\n",
+    );
+
+    #[test]
+    fn parses_report() {
+        let report = parse_response(REPORT.as_bytes()).unwrap();
+        assert_eq!(report.score, 7.3);
+        assert_eq!(report.threshold, 5.0);
+        assert!(report.is_spam);
+        let rules: Vec<(&str, f64)> = report
+            .rules
+            .iter()
+            .map(|rule| (rule.name.as_str(), rule.score))
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                ("MISSING_DATE", 1.4),
+                ("GTUBE", 5.9),
+                ("NO_RELAYS", -0.0),
+                ("BIG_ONE", 10.0)
+            ]
+        );
+        assert_eq!(
+            report.rules[2].description,
+            "Informational: message was not relayed via SMTP"
+        );
+    }
+
+    #[test]
+    fn rejects_errors_and_garbage() {
+        assert!(matches!(
+            parse_response(b"SPAMD/1.0 76 Bad header line: (EOF)\r\n"),
+            Err(SpamdError::Refused(reason)) if reason == "76 Bad header line: (EOF)"
+        ));
+        assert!(matches!(
+            parse_response(b"HTTP/1.1 200 OK\r\n\r\n"),
+            Err(SpamdError::Protocol(_))
+        ));
+        assert!(matches!(
+            parse_response(b"SPAMD/1.1 0 EX_OK\r\nSpam: maybe\r\n\r\n"),
+            Err(SpamdError::Protocol(_))
+        ));
+    }
+}
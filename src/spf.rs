@@ -0,0 +1,685 @@
+//! SPF evaluation (RFC 7208) of the connecting IP against the MAIL FROM
+//! domain, with records from the offline [`Records`].
+//!
+//! `ptr` mechanisms never match, since the record set has no PTR records,
+//! and `exp=` explanations are not expanded.
+
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dns::{Record, Records};
+
+/// Most mechanisms and modifiers that need a lookup, per check (section 4.6.4).
+const MAX_LOOKUPS: usize = 10;
+
+/// Most lookups that may find nothing, per check (section 4.6.4).
+const MAX_VOID_LOOKUPS: usize = 2;
+
+/// Most MX names an `mx` mechanism may return (section 4.6.4).
+const MAX_MX_NAMES: usize = 10;
+
+/// The result of `check_host()`, as in section 2.6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpfStatus {
+    /// The domain publishes no SPF record.
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    Softfail,
+    /// The record is invalid or exceeds the lookup limits.
+    Permerror,
+}
+
+/// Which identity was checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpfIdentity {
+    Mailfrom,
+    /// The HELO name, checked for the null sender.
+    Helo,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpfResult {
+    pub status: SpfStatus,
+    pub identity: SpfIdentity,
+    /// The domain checked.
+    pub domain: String,
+    /// The connecting client.
+    pub ip: IpAddr,
+    /// The mechanism that decided the result, e.g. `-all`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mechanism: Option<String>,
+    /// Why the check gave no verdict, for `none` and `permerror`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Checks whether `ip` may send for the MAIL FROM address, or for the HELO
+/// name if the sender is null.
+pub fn check(ip: IpAddr, mail_from: &str, helo: &str, records: &Records) -> SpfResult {
+    let (identity, sender) = match mail_from.rsplit_once('@') {
+        Some(("", domain)) => (SpfIdentity::Mailfrom, format!("postmaster@{domain}")),
+        Some(_) => (SpfIdentity::Mailfrom, mail_from.to_string()),
+        None if !mail_from.is_empty() => (SpfIdentity::Mailfrom, format!("postmaster@{mail_from}")),
+        None => (SpfIdentity::Helo, format!("postmaster@{helo}")),
+    };
+    let domain = sender
+        .rsplit_once('@')
+        .map_or("", |(_, domain)| domain)
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    let ip = ip.to_canonical();
+    let mut evaluation = Evaluation {
+        records,
+        ip,
+        sender: &sender,
+        helo,
+        lookups: 0,
+        void_lookups: 0,
+    };
+    let outcome = evaluation.check_host(&domain);
+    SpfResult {
+        status: outcome.status,
+        identity,
+        domain,
+        ip,
+        mechanism: outcome.mechanism,
+        reason: outcome.reason,
+    }
+}
+
+#[derive(Debug)]
+struct Outcome {
+    status: SpfStatus,
+    mechanism: Option<String>,
+    reason: Option<String>,
+}
+
+impl Outcome {
+    fn error(status: SpfStatus, reason: impl Into<String>) -> Outcome {
+        Outcome {
+            status,
+            mechanism: None,
+            reason: Some(reason.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    All,
+    Include,
+    A,
+    Mx,
+    Ptr,
+    Ip4,
+    Ip6,
+    Exists,
+}
+
+#[derive(Debug)]
+struct Mechanism<'a> {
+    text: &'a str,
+    status: SpfStatus,
+    kind: Kind,
+    /// What follows the `:`, if anything.
+    target: Option<&'a str>,
+    /// Prefix lengths for IPv4 and IPv6.
+    cidr: (u8, u8),
+}
+
+#[derive(Debug, Default)]
+struct Policy<'a> {
+    mechanisms: Vec<Mechanism<'a>>,
+    redirect: Option<&'a str>,
+}
+
+/// Parses the terms after `v=spf1`. Any syntax error makes the whole
+/// record invalid (section 4.6).
+fn parse(record: &str) -> Result<Policy<'_>, String> {
+    let mut policy = Policy::default();
+    let mut exp = false;
+    for term in record.split_ascii_whitespace().skip(1) {
+        let modifier = term.find('=').filter(|&eq| {
+            !term[..eq].contains([':', '/'])
+                && term[..eq].starts_with(|c: char| c.is_ascii_alphabetic())
+        });
+        if let Some(eq) = modifier {
+            let (name, value) = (&term[..eq], &term[eq + 1..]);
+            if name.eq_ignore_ascii_case("redirect") {
+                if policy.redirect.replace(value).is_some() {
+                    return Err("more than one redirect= modifier".into());
+                }
+            } else if name.eq_ignore_ascii_case("exp") {
+                if exp {
+                    return Err("more than one exp= modifier".into());
+                }
+                exp = true;
+            }
+            continue;
+        }
+        policy.mechanisms.push(mechanism(term)?);
+    }
+    Ok(policy)
+}
+
+fn mechanism(term: &str) -> Result<Mechanism<'_>, String> {
+    let (status, rest) = match term.as_bytes()[0] {
+        b'+' => (SpfStatus::Pass, &term[1..]),
+        b'-' => (SpfStatus::Fail, &term[1..]),
+        b'~' => (SpfStatus::Softfail, &term[1..]),
+        b'?' => (SpfStatus::Neutral, &term[1..]),
+        _ => (SpfStatus::Pass, term),
+    };
+    let name_end = rest.find([':', '/']).unwrap_or(rest.len());
+    let kind = match rest[..name_end].to_ascii_lowercase().as_str() {
+        "all" => Kind::All,
+        "include" => Kind::Include,
+        "a" => Kind::A,
+        "mx" => Kind::Mx,
+        "ptr" => Kind::Ptr,
+        "ip4" => Kind::Ip4,
+        "ip6" => Kind::Ip6,
+        "exists" => Kind::Exists,
+        _ => return Err(format!("unknown mechanism {term}")),
+    };
+    let invalid = || format!("invalid mechanism {term}");
+    let mut target = None;
+    let mut cidr = (32, 128);
+    let mut arguments = &rest[name_end..];
+    if let Some(argument) = arguments.strip_prefix(':') {
+        let end = argument.find('/').unwrap_or(argument.len());
+        if end == 0 {
+            return Err(invalid());
+        }
+        target = Some(&argument[..end]);
+        arguments = &argument[end..];
+    }
+    if !arguments.is_empty() {
+        // `/24`, `//64` or `/24//64`; `ip6` takes its length after one slash.
+        let (ip4, ip6) = match arguments.split_once("//") {
+            Some((ip4, ip6)) => (ip4, Some(ip6)),
+            None => (arguments, None),
+        };
+        let length = |text: Option<&str>, max: u8| {
+            text.and_then(|text| text.parse::<u8>().ok())
+                .filter(|&length| length <= max)
+                .ok_or_else(invalid)
+        };
+        match (kind, ip6) {
+            (Kind::Ip6, None) => cidr.1 = length(ip4.strip_prefix('/'), 128)?,
+            (Kind::Ip4 | Kind::Ip6, Some(_)) => return Err(invalid()),
+            _ => {
+                if !ip4.is_empty() {
+                    cidr.0 = length(ip4.strip_prefix('/'), 32)?;
+                }
+                if ip6.is_some() {
+                    cidr.1 = length(ip6, 128)?;
+                }
+            }
+        }
+    }
+    match kind {
+        Kind::All if target.is_some() || !arguments.is_empty() => return Err(invalid()),
+        Kind::Include | Kind::Exists if target.is_none() || !arguments.is_empty() => {
+            return Err(invalid())
+        }
+        Kind::Ptr if !arguments.is_empty() => return Err(invalid()),
+        Kind::Ip4
+            if target
+                .and_then(|ip| ip.parse::<std::net::Ipv4Addr>().ok())
+                .is_none() =>
+        {
+            return Err(invalid())
+        }
+        Kind::Ip6
+            if target
+                .and_then(|ip| ip.parse::<std::net::Ipv6Addr>().ok())
+                .is_none() =>
+        {
+            return Err(invalid())
+        }
+        _ => {}
+    }
+    Ok(Mechanism {
+        text: term,
+        status,
+        kind,
+        target,
+        cidr,
+    })
+}
+
+struct Evaluation<'a> {
+    records: &'a Records,
+    ip: IpAddr,
+    sender: &'a str,
+    helo: &'a str,
+    lookups: usize,
+    void_lookups: usize,
+}
+
+impl Evaluation<'_> {
+    fn check_host(&mut self, domain: &str) -> Outcome {
+        if !valid_domain(domain) {
+            return Outcome::error(SpfStatus::None, format!("{domain:?} is not a domain name"));
+        }
+        let records: Vec<&str> = self
+            .records
+            .txt(domain)
+            .into_iter()
+            .filter(|text| {
+                let version = text.split(' ').next().unwrap_or_default();
+                version.eq_ignore_ascii_case("v=spf1")
+            })
+            .collect();
+        let record = match records.as_slice() {
+            [] => return Outcome::error(SpfStatus::None, format!("no SPF record at {domain}")),
+            [record] => *record,
+            _ => {
+                return Outcome::error(
+                    SpfStatus::Permerror,
+                    format!("more than one SPF record at {domain}"),
+                )
+            }
+        };
+        let policy = match parse(record) {
+            Ok(policy) => policy,
+            Err(err) => return Outcome::error(SpfStatus::Permerror, format!("{domain}: {err}")),
+        };
+        for mechanism in &policy.mechanisms {
+            match self.matches(mechanism, domain) {
+                Ok(true) => {
+                    return Outcome {
+                        status: mechanism.status,
+                        mechanism: Some(mechanism.text.to_string()),
+                        reason: None,
+                    }
+                }
+                Ok(false) => {}
+                Err(outcome) => return outcome,
+            }
+        }
+        if let Some(redirect) = policy.redirect {
+            if let Err(outcome) = self.count_lookup() {
+                return outcome;
+            }
+            let target = match self.expand(redirect, domain) {
+                Ok(target) => target,
+                Err(outcome) => return outcome,
+            };
+            let outcome = self.check_host(&target);
+            if outcome.status == SpfStatus::None {
+                return Outcome::error(
+                    SpfStatus::Permerror,
+                    format!("redirect to {target}, which has no SPF record"),
+                );
+            }
+            return outcome;
+        }
+        Outcome {
+            status: SpfStatus::Neutral,
+            mechanism: None,
+            reason: None,
+        }
+    }
+
+    fn matches(&mut self, mechanism: &Mechanism, domain: &str) -> Result<bool, Outcome> {
+        let target = |this: &Self| match mechanism.target {
+            Some(target) => this.expand(target, domain),
+            None => Ok(domain.to_string()),
+        };
+        match mechanism.kind {
+            Kind::All => Ok(true),
+            Kind::Ip4 | Kind::Ip6 => {
+                let network: IpAddr = mechanism
+                    .target
+                    .and_then(|ip| ip.parse().ok())
+                    .expect("checked when parsing");
+                Ok(in_network(self.ip, network, mechanism.cidr))
+            }
+            Kind::Include => {
+                self.count_lookup()?;
+                let target = target(self)?;
+                let outcome = self.check_host(&target);
+                match outcome.status {
+                    SpfStatus::Pass => Ok(true),
+                    SpfStatus::Fail | SpfStatus::Softfail | SpfStatus::Neutral => Ok(false),
+                    SpfStatus::Permerror => Err(outcome),
+                    SpfStatus::None => Err(Outcome::error(
+                        SpfStatus::Permerror,
+                        format!("include of {target}, which has no SPF record"),
+                    )),
+                }
+            }
+            Kind::A => {
+                self.count_lookup()?;
+                let target = target(self)?;
+                let addresses = self.addresses(&target)?;
+                Ok(addresses
+                    .into_iter()
+                    .any(|address| in_network(self.ip, address, mechanism.cidr)))
+            }
+            Kind::Mx => {
+                self.count_lookup()?;
+                let target = target(self)?;
+                let exchanges: Vec<String> = self
+                    .records
+                    .get(&target)
+                    .iter()
+                    .filter_map(|record| match record {
+                        Record::Mx { exchange, .. } => Some(exchange.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                if exchanges.is_empty() {
+                    self.count_void()?;
+                }
+                if exchanges.len() > MAX_MX_NAMES {
+                    return Err(Outcome::error(
+                        SpfStatus::Permerror,
+                        format!("{target} has more than {MAX_MX_NAMES} MX records"),
+                    ));
+                }
+                for exchange in exchanges {
+                    let addresses = self.addresses(&exchange)?;
+                    if addresses
+                        .into_iter()
+                        .any(|address| in_network(self.ip, address, mechanism.cidr))
+                    {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Kind::Ptr => {
+                self.count_lookup()?;
+                Ok(false)
+            }
+            Kind::Exists => {
+                self.count_lookup()?;
+                let target = target(self)?;
+                let exists = self
+                    .records
+                    .get(&target)
+                    .iter()
+                    .any(|record| matches!(record, Record::A(_)));
+                if !exists {
+                    self.count_void()?;
+                }
+                Ok(exists)
+            }
+        }
+    }
+
+    /// The addresses at `name` of the client's address family.
+    fn addresses(&mut self, name: &str) -> Result<Vec<IpAddr>, Outcome> {
+        let addresses: Vec<IpAddr> = self
+            .records
+            .get(name)
+            .iter()
+            .filter_map(|record| match (record, self.ip) {
+                (Record::A(address), IpAddr::V4(_)) => Some(IpAddr::V4(*address)),
+                (Record::Aaaa(address), IpAddr::V6(_)) => Some(IpAddr::V6(*address)),
+                _ => None,
+            })
+            .collect();
+        if addresses.is_empty() {
+            self.count_void()?;
+        }
+        Ok(addresses)
+    }
+
+    fn count_lookup(&mut self) -> Result<(), Outcome> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err(Outcome::error(
+                SpfStatus::Permerror,
+                format!("more than {MAX_LOOKUPS} DNS lookups"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn count_void(&mut self) -> Result<(), Outcome> {
+        self.void_lookups += 1;
+        if self.void_lookups > MAX_VOID_LOOKUPS {
+            return Err(Outcome::error(
+                SpfStatus::Permerror,
+                format!("more than {MAX_VOID_LOOKUPS} lookups found nothing"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Expands the macros of a domain-spec (section 7).
+    fn expand(&self, spec: &str, domain: &str) -> Result<String, Outcome> {
+        let invalid = || Outcome::error(SpfStatus::Permerror, format!("invalid macro in {spec}"));
+        let mut out = String::new();
+        let mut chars = spec.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => out.push('%'),
+                Some('_') => out.push(' '),
+                Some('-') => out.push_str("%20"),
+                Some('{') => {
+                    let body: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    out.push_str(&self.macro_value(&body, domain).ok_or_else(invalid)?);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        // Over-long results lose labels from the left (section 7.3).
+        while out.len() > 253 {
+            match out.split_once('.') {
+                Some((_, rest)) => out = rest.to_string(),
+                None => break,
+            }
+        }
+        Ok(out)
+    }
+
+    fn macro_value(&self, body: &str, domain: &str) -> Option<String> {
+        let mut chars = body.chars();
+        let letter = chars.next()?;
+        let rest = chars.as_str();
+        let (local, sender_domain) = self.sender.rsplit_once('@')?;
+        let value = match letter.to_ascii_lowercase() {
+            's' => self.sender.to_string(),
+            'l' => local.to_string(),
+            'o' => sender_domain.to_string(),
+            'd' => domain.to_string(),
+            'i' => match self.ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => ip
+                    .octets()
+                    .iter()
+                    .flat_map(|byte| [byte >> 4, byte & 0xf])
+                    .map(|nibble| format!("{nibble:x}"))
+                    .collect::<Vec<_>>()
+                    .join("."),
+            },
+            // Validated names need PTR records, which the set cannot hold.
+            'p' => "unknown".to_string(),
+            'v' => match self.ip {
+                IpAddr::V4(_) => "in-addr".to_string(),
+                IpAddr::V6(_) => "ip6".to_string(),
+            },
+            'h' => self.helo.to_string(),
+            _ => return None,
+        };
+        let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+        let rest = &rest[digits.len()..];
+        let (reverse, delimiters) = match rest.strip_prefix(['r', 'R']) {
+            Some(delimiters) => (true, delimiters),
+            None => (false, rest),
+        };
+        if !delimiters.chars().all(|c| ".-+,/_=".contains(c)) {
+            return None;
+        }
+        let delimiters = if delimiters.is_empty() {
+            "."
+        } else {
+            delimiters
+        };
+        let mut parts: Vec<&str> = value.split(|c| delimiters.contains(c)).collect();
+        if reverse {
+            parts.reverse();
+        }
+        if !digits.is_empty() {
+            let keep: usize = digits.parse().ok().filter(|&keep| keep > 0)?;
+            parts = parts.split_off(parts.len().saturating_sub(keep));
+        }
+        let value = parts.join(".");
+        Some(if letter.is_ascii_uppercase() {
+            url_escape(&value)
+        } else {
+            value
+        })
+    }
+}
+
+fn url_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Whether `domain` is a fully qualified name with valid label lengths.
+fn valid_domain(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.contains('.')
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, (ip4, ip6): (u8, u8)) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(ip4)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(ip6)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORDS: &str = r#"{
+        "example.com": {
+            "TXT": ["v=spf1 ip4:192.0.2.0/24 a:relay.example.com include:_spf.example.net mx/30 -all"],
+            "MX": ["10 mx.example.com"]
+        },
+        "relay.example.com": { "A": ["198.51.100.7"], "AAAA": ["2001:db8::7"] },
+        "mx.example.com": { "A": ["203.0.113.9"] },
+        "_spf.example.net": "v=spf1 ip6:2001:db8:1::/48 ~all",
+        "soft.example.com": "v=spf1 redirect=_spf.example.net",
+        "macro.example.com": "v=spf1 exists:%{ir}.%{l1r+-}._spf.%{d} -all",
+        "1.2.0.192.alice._spf.macro.example.com": { "A": ["127.0.0.2"] },
+        "twice.example.com": ["v=spf1 -all", "v=spf1 +all"],
+        "broken.example.com": "v=spf1 ip4:192.0.2.300 -all",
+        "loop.example.com": "v=spf1 include:loop.example.com -all"
+    }"#;
+
+    fn check_ip(ip: &str, mail_from: &str) -> SpfResult {
+        let records = Records::from_json(RECORDS).unwrap();
+        check(ip.parse().unwrap(), mail_from, "client.test", &records)
+    }
+
+    #[test]
+    fn matches_mechanisms_in_order() {
+        let result = check_ip("192.0.2.55", "app@example.com");
+        assert_eq!(result.status, SpfStatus::Pass);
+        assert_eq!(result.mechanism.as_deref(), Some("ip4:192.0.2.0/24"));
+        assert_eq!(result.identity, SpfIdentity::Mailfrom);
+        assert_eq!(result.domain, "example.com");
+
+        let result = check_ip("2001:db8::7", "app@example.com");
+        assert_eq!(result.mechanism.as_deref(), Some("a:relay.example.com"));
+        // An included record passing makes the include match.
+        let result = check_ip("2001:db8:1::1", "app@example.com");
+        assert_eq!(
+            result.mechanism.as_deref(),
+            Some("include:_spf.example.net")
+        );
+        assert_eq!(
+            check_ip("203.0.113.10", "app@example.com")
+                .mechanism
+                .as_deref(),
+            Some("mx/30")
+        );
+        // IPv4-mapped IPv6 addresses are checked as IPv4.
+        assert_eq!(
+            check_ip("::ffff:192.0.2.1", "app@example.com").status,
+            SpfStatus::Pass
+        );
+
+        let result = check_ip("10.0.0.1", "app@example.com");
+        assert_eq!(result.status, SpfStatus::Fail);
+        assert_eq!(result.mechanism.as_deref(), Some("-all"));
+    }
+
+    #[test]
+    fn follows_redirects_and_macros() {
+        let result = check_ip("10.0.0.1", "app@soft.example.com");
+        assert_eq!(result.status, SpfStatus::Softfail);
+        assert_eq!(result.mechanism.as_deref(), Some("~all"));
+
+        let result = check_ip("192.0.2.1", "alice@macro.example.com");
+        assert_eq!(result.status, SpfStatus::Pass);
+        let result = check_ip("192.0.2.1", "bob@macro.example.com");
+        assert_eq!(result.status, SpfStatus::Fail);
+    }
+
+    #[test]
+    fn reports_missing_and_invalid_records() {
+        let result = check_ip("192.0.2.1", "app@unknown.example.com");
+        assert_eq!(result.status, SpfStatus::None);
+        assert_eq!(
+            result.reason.as_deref(),
+            Some("no SPF record at unknown.example.com")
+        );
+        for (domain, reason) in [
+            ("twice", "more than one SPF record at twice.example.com"),
+            (
+                "broken",
+                "broken.example.com: invalid mechanism ip4:192.0.2.300",
+            ),
+            ("loop", "more than 10 DNS lookups"),
+        ] {
+            let result = check_ip("192.0.2.1", &format!("app@{domain}.example.com"));
+            assert_eq!(result.status, SpfStatus::Permerror, "{domain}");
+            assert_eq!(result.reason.as_deref(), Some(reason));
+        }
+    }
+
+    #[test]
+    fn checks_the_helo_name_for_the_null_sender() {
+        let records = Records::from_json(RECORDS).unwrap();
+        let result = check("192.0.2.9".parse().unwrap(), "", "example.com", &records);
+        assert_eq!(result.identity, SpfIdentity::Helo);
+        assert_eq!(result.domain, "example.com");
+        assert_eq!(result.status, SpfStatus::Pass);
+    }
+}
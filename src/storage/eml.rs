@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{Storage, StoredMeta};
+use crate::store::{CapturedMessage, MessageId};
+use crate::Result;
+
+const INDEX_FILE: &str = "index.jsonl";
+
+/// Stores each message as `<id>.eml` in a directory, next to an append-only
+/// `index.jsonl` journal holding the envelope and session data.
+///
+/// The journal is compacted every time the directory is opened.
+#[derive(Debug)]
+pub struct EmlDirStorage {
+    dir: PathBuf,
+    index: Mutex<File>,
+}
+
+/// One line of the index journal.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    Insert(StoredMeta),
+    /// New metadata for a message inserted earlier.
+    Update(StoredMeta),
+    Delete {
+        id: MessageId,
+    },
+    Clear,
+    /// The highest id inserted so far, kept through compaction so the ids
+    /// of deleted messages are not handed out again.
+    LastId {
+        id: MessageId,
+    },
+}
+
+/// The state a replayed journal describes.
+#[derive(Debug, Default)]
+struct Journal {
+    entries: BTreeMap<MessageId, StoredMeta>,
+    last_id: MessageId,
+}
+
+impl EmlDirStorage {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let journal = replay(&dir.join(INDEX_FILE))?;
+        let compacted = dir.join(format!("{INDEX_FILE}.tmp"));
+        {
+            let mut out = io::BufWriter::new(File::create(&compacted)?);
+            for meta in journal.entries.into_values() {
+                write_record(&mut out, &Record::Insert(meta))?;
+            }
+            if journal.last_id > 0 {
+                let id = journal.last_id;
+                write_record(&mut out, &Record::LastId { id })?;
+            }
+            out.flush()?;
+        }
+        fs::rename(&compacted, dir.join(INDEX_FILE))?;
+
+        let index = OpenOptions::new().append(true).open(dir.join(INDEX_FILE))?;
+        Ok(EmlDirStorage {
+            dir,
+            index: Mutex::new(index),
+        })
+    }
+
+    fn eml_path(&self, id: MessageId) -> PathBuf {
+        self.dir.join(format!("{id}.eml"))
+    }
+
+    fn append(&self, record: &Record) -> Result<()> {
+        let mut index = self.index.lock().unwrap();
+        write_record(&mut *index, record)?;
+        index.flush()?;
+        Ok(())
+    }
+}
+
+impl Storage for EmlDirStorage {
+    fn load(&self) -> Result<Vec<Arc<CapturedMessage>>> {
+        let entries = replay(&self.dir.join(INDEX_FILE))?.entries;
+        let mut messages = Vec::with_capacity(entries.len());
+        for (id, meta) in entries {
+            match fs::read(self.eml_path(id)) {
+                Ok(raw) => messages.push(Arc::new(meta.into_message(raw))),
+                Err(err) => warn!(id, "skipping indexed message without .eml file: {err}"),
+            }
+        }
+        Ok(messages)
+    }
+
+    fn insert(&self, message: &Arc<CapturedMessage>) -> Result<()> {
+        // Write the body first and rename it into place, so the index never
+        // refers to a half-written file.
+        let tmp = self.dir.join(format!("{}.eml.tmp", message.id));
+        fs::write(&tmp, &message.raw)?;
+        fs::rename(&tmp, self.eml_path(message.id))?;
+        self.append(&Record::Insert(StoredMeta::of(message)))
+    }
+
+    fn update(&self, message: &Arc<CapturedMessage>) -> Result<()> {
+        self.append(&Record::Update(StoredMeta::of(message)))
+    }
+
+    fn delete(&self, id: MessageId) -> Result<()> {
+        self.append(&Record::Delete { id })?;
+        remove_if_exists(&self.eml_path(id))
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.append(&Record::Clear)?;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "eml") {
+                remove_if_exists(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn last_id(&self) -> Result<MessageId> {
+        Ok(replay(&self.dir.join(INDEX_FILE))?.last_id)
+    }
+}
+
+/// Replays the journal into the set of live messages. Unreadable lines,
+/// such as one cut short by a crash, are skipped.
+fn replay(path: &Path) -> Result<Journal> {
+    let mut journal = Journal::default();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(journal),
+        Err(err) => return Err(err.into()),
+    };
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(Record::Insert(meta)) => {
+                journal.last_id = journal.last_id.max(meta.id);
+                journal.entries.insert(meta.id, meta);
+            }
+            Ok(Record::Update(meta)) => {
+                if let Some(entry) = journal.entries.get_mut(&meta.id) {
+                    *entry = meta;
+                }
+            }
+            Ok(Record::Delete { id }) => {
+                journal.entries.remove(&id);
+            }
+            Ok(Record::Clear) => journal.entries.clear(),
+            Ok(Record::LastId { id }) => journal.last_id = journal.last_id.max(id),
+            Err(err) => warn!(line = number + 1, "skipping bad index entry: {err}"),
+        }
+    }
+    Ok(journal)
+}
+
+fn write_record(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, record)?;
+    out.write_all(b"\n")
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
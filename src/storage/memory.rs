@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::Storage;
+use crate::store::{CapturedMessage, MessageId};
+use crate::Result;
+
+/// Keeps messages for the lifetime of the process only.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    messages: Mutex<BTreeMap<MessageId, Arc<CapturedMessage>>>,
+    last_id: AtomicU64,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&self) -> Result<Vec<Arc<CapturedMessage>>> {
+        Ok(self.messages.lock().unwrap().values().cloned().collect())
+    }
+
+    fn insert(&self, message: &Arc<CapturedMessage>) -> Result<()> {
+        self.messages
+            .lock()
+            .unwrap()
+            .insert(message.id, message.clone());
+        self.last_id.fetch_max(message.id, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn update(&self, message: &Arc<CapturedMessage>) -> Result<()> {
+        if let Some(stored) = self.messages.lock().unwrap().get_mut(&message.id) {
+            *stored = message.clone();
+        }
+        Ok(())
+    }
+
+    fn delete(&self, id: MessageId) -> Result<()> {
+        self.messages.lock().unwrap().remove(&id);
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.messages.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn last_id(&self) -> Result<MessageId> {
+        Ok(self.last_id.load(Ordering::Relaxed))
+    }
+}
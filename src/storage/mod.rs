@@ -0,0 +1,138 @@
+//! Pluggable persistence for captured messages.
+//!
+//! The [`MessageStore`](crate::MessageStore) keeps every message in memory
+//! for fast access and writes each change through to a [`Storage`] backend,
+//! which it reads back on startup. Backends only need to persist and reload
+//! messages; ids, events and summaries are handled by the store.
+
+mod eml;
+mod memory;
+mod sqlite;
+
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+pub use eml::EmlDirStorage;
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+use crate::dmarc::Authentication;
+use crate::mailbox::DEFAULT_MAILBOX;
+use crate::spam::SpamReport;
+use crate::store::{CapturedMessage, Envelope, MessageId, SessionInfo};
+use crate::Result;
+
+/// A persistence backend.
+pub trait Storage: Send + Sync + std::fmt::Debug {
+    /// Returns every persisted message, in any order.
+    fn load(&self) -> Result<Vec<Arc<CapturedMessage>>>;
+
+    /// Persists a newly captured message.
+    fn insert(&self, message: &Arc<CapturedMessage>) -> Result<()>;
+
+    /// Persists changes to the metadata of a stored message, such as its
+    /// spam score. The raw bytes are never changed.
+    fn update(&self, message: &Arc<CapturedMessage>) -> Result<()>;
+
+    /// Removes a message. Removing an unknown id is not an error.
+    fn delete(&self, id: MessageId) -> Result<()>;
+
+    /// Removes every message.
+    fn clear(&self) -> Result<()>;
+
+    /// Returns the highest id ever inserted, counting messages that have
+    /// since been deleted or cleared, or 0 if nothing was inserted yet.
+    fn last_id(&self) -> Result<MessageId>;
+}
+
+/// Which backend to use, as chosen on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StorageKind {
+    /// Keep messages in memory only; they are lost on restart.
+    Memory,
+    /// A directory with one `.eml` file per message plus an index.
+    Eml,
+    /// An embedded SQLite database file.
+    Sqlite,
+}
+
+impl StorageKind {
+    fn default_path(self) -> Option<&'static str> {
+        match self {
+            StorageKind::Memory => None,
+            StorageKind::Eml => Some("rubbermail-mail"),
+            StorageKind::Sqlite => Some("rubbermail.db"),
+        }
+    }
+}
+
+/// Opens the backend of the given kind, at `path` or its default location.
+pub fn open(kind: StorageKind, path: Option<PathBuf>) -> Result<Arc<dyn Storage>> {
+    let path = path.or_else(|| kind.default_path().map(PathBuf::from));
+    Ok(match (kind, path) {
+        (StorageKind::Eml, Some(path)) => Arc::new(EmlDirStorage::open(path)?),
+        (StorageKind::Sqlite, Some(path)) => Arc::new(SqliteStorage::open(path)?),
+        _ => Arc::new(MemoryStorage::new()),
+    })
+}
+
+/// Everything about a message except its raw bytes, in the form persisted
+/// by the file-based backends. New fields must be `#[serde(default)]` so
+/// older data keeps loading.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredMeta {
+    id: MessageId,
+    #[serde(default = "default_mailbox")]
+    mailbox: String,
+    received_at: DateTime<Utc>,
+    envelope: Envelope,
+    session: SessionInfo,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    spam: Option<SpamReport>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    authentication: Option<Authentication>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    flags: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pinned: bool,
+}
+
+impl StoredMeta {
+    fn of(message: &CapturedMessage) -> StoredMeta {
+        StoredMeta {
+            id: message.id,
+            mailbox: message.mailbox.clone(),
+            received_at: message.received_at,
+            envelope: message.envelope.clone(),
+            session: message.session.clone(),
+            spam: message.spam.clone(),
+            authentication: message.authentication.clone(),
+            flags: message.flags.clone(),
+            pinned: message.pinned,
+        }
+    }
+
+    fn into_message(self, raw: Vec<u8>) -> CapturedMessage {
+        let mut message =
+            CapturedMessage::new(self.id, self.received_at, self.envelope, self.session, raw);
+        message.mailbox = self.mailbox;
+        message.spam = self.spam;
+        message.authentication = self.authentication;
+        message.flags = self.flags;
+        message.pinned = self.pinned;
+        message
+    }
+}
+
+fn default_mailbox() -> String {
+    DEFAULT_MAILBOX.to_string()
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
@@ -0,0 +1,115 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection};
+
+use super::{Storage, StoredMeta};
+use crate::store::{CapturedMessage, MessageId};
+use crate::{Error, Result};
+
+/// Stores messages in an embedded SQLite database.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id   INTEGER PRIMARY KEY,
+                meta TEXT NOT NULL,
+                raw  BLOB NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS counters (
+                name  TEXT PRIMARY KEY,
+                value INTEGER NOT NULL
+            );",
+        )?;
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self) -> Result<Vec<Arc<CapturedMessage>>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT meta, raw FROM messages")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+        let mut messages = Vec::new();
+        for row in rows {
+            let (meta, raw) = row?;
+            let meta: StoredMeta =
+                serde_json::from_str(&meta).map_err(|err| Error::Corrupt(err.to_string()))?;
+            messages.push(Arc::new(meta.into_message(raw)));
+        }
+        Ok(messages)
+    }
+
+    fn insert(&self, message: &Arc<CapturedMessage>) -> Result<()> {
+        let meta = meta_json(message)?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO messages (id, meta, raw) VALUES (?1, ?2, ?3)",
+            params![message.id, meta, message.raw],
+        )?;
+        // Deleting a message must not free its id, so the highest id is
+        // kept apart from the messages themselves.
+        tx.execute(
+            "INSERT INTO counters (name, value) VALUES ('last_id', ?1)
+             ON CONFLICT (name) DO UPDATE SET value = max(value, excluded.value)",
+            [message.id],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn update(&self, message: &Arc<CapturedMessage>) -> Result<()> {
+        let meta = meta_json(message)?;
+        self.conn.lock().unwrap().execute(
+            "UPDATE messages SET meta = ?2 WHERE id = ?1",
+            params![message.id, meta],
+        )?;
+        Ok(())
+    }
+
+    fn delete(&self, id: MessageId) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM messages WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM messages", [])?;
+        Ok(())
+    }
+
+    fn last_id(&self) -> Result<MessageId> {
+        // Databases written before the counter existed only have the ids of
+        // the messages they still hold.
+        let id = self.conn.lock().unwrap().query_row(
+            "SELECT max(
+                 coalesce((SELECT value FROM counters WHERE name = 'last_id'), 0),
+                 coalesce((SELECT max(id) FROM messages), 0)
+             )",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(id)
+    }
+}
+
+fn meta_json(message: &CapturedMessage) -> Result<String> {
+    serde_json::to_string(&StoredMeta::of(message)).map_err(|err| Error::Corrupt(err.to_string()))
+}
@@ -0,0 +1,603 @@
+//! The message store: an in-memory index of captured messages, written
+//! through to a [`Storage`] backend and broadcasting every change.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{self, Duration, Instant};
+use tracing::warn;
+
+use crate::dmarc::Authentication;
+use crate::mailbox::DEFAULT_MAILBOX;
+use crate::mime::{Address, Message};
+use crate::search::{Document, Index, Query};
+use crate::spam::SpamReport;
+use crate::storage::{MemoryStorage, Storage};
+use crate::tls::TlsInfo;
+use crate::Result;
+
+/// Length, in characters, of [`Summary::snippet`].
+const SNIPPET_LEN: usize = 200;
+
+/// Events buffered per subscriber before a slow one starts missing events.
+const EVENT_BUFFER: usize = 1024;
+
+/// Identifier assigned to a message when it is captured.
+///
+/// Ids are allocated in increasing order, so sorting by id is sorting by
+/// arrival.
+pub type MessageId = u64;
+
+/// The SMTP envelope a message was submitted with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Reverse-path from `MAIL FROM`; empty for the null sender `<>`.
+    pub mail_from: String,
+    /// Forward-paths from every accepted `RCPT TO`, in order.
+    pub rcpt_to: Vec<String>,
+}
+
+/// Facts about the SMTP session a message arrived on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    /// Address of the connecting client.
+    pub peer: SocketAddr,
+    /// Name the client announced with HELO or EHLO.
+    pub helo: String,
+    /// Set if the message was sent over TLS, via STARTTLS or implicit TLS.
+    #[serde(default)]
+    pub tls: Option<TlsInfo>,
+    /// The user the client authenticated as with SMTP AUTH, if it did.
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+/// A message as handed over by the SMTP listener, before it gets an id.
+#[derive(Debug, Clone)]
+pub struct NewMessage {
+    pub envelope: Envelope,
+    pub session: SessionInfo,
+    /// The DATA payload with dot-stuffing removed.
+    pub raw: Vec<u8>,
+    /// DKIM, SPF and DMARC results, for messages received over SMTP.
+    pub authentication: Option<Authentication>,
+}
+
+/// A message held by the store.
+#[derive(Debug, Clone)]
+pub struct CapturedMessage {
+    pub id: MessageId,
+    /// The mailbox the message was routed to.
+    pub mailbox: String,
+    pub received_at: DateTime<Utc>,
+    pub envelope: Envelope,
+    pub session: SessionInfo,
+    pub raw: Vec<u8>,
+    pub summary: Summary,
+    /// Set once the message has been scored by spamd.
+    pub spam: Option<SpamReport>,
+    /// DKIM, SPF and DMARC results from when the message was received over
+    /// SMTP; unset for messages that arrived another way.
+    pub authentication: Option<Authentication>,
+    /// IMAP flags set by mail clients, such as `\Seen` or `$Forwarded`.
+    pub flags: BTreeSet<String>,
+    /// Pinned messages are never evicted by retention limits.
+    pub pinned: bool,
+}
+
+impl CapturedMessage {
+    pub fn new(
+        id: MessageId,
+        received_at: DateTime<Utc>,
+        envelope: Envelope,
+        session: SessionInfo,
+        raw: Vec<u8>,
+    ) -> CapturedMessage {
+        let summary = Summary::of(&Message::parse(&raw));
+        CapturedMessage {
+            id,
+            mailbox: DEFAULT_MAILBOX.to_string(),
+            received_at,
+            envelope,
+            session,
+            raw,
+            summary,
+            spam: None,
+            authentication: None,
+            flags: BTreeSet::new(),
+            pinned: false,
+        }
+    }
+
+    /// Parses the raw bytes into a full [`Message`].
+    pub fn parse(&self) -> Message {
+        Message::parse(&self.raw)
+    }
+}
+
+/// Header facts extracted once at capture time, so listings do not have to
+/// re-parse every message.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    pub subject: Option<String>,
+    pub from: Option<Address>,
+    pub to: Vec<Address>,
+    pub cc: Vec<Address>,
+    pub date: Option<DateTime<FixedOffset>>,
+    pub attachments: usize,
+    /// The start of the text body with whitespace collapsed.
+    pub snippet: String,
+}
+
+impl Summary {
+    pub fn of(message: &Message) -> Summary {
+        Summary {
+            subject: message.subject.clone(),
+            from: message.from.first().cloned(),
+            to: message.to.clone(),
+            cc: message.cc.clone(),
+            date: message.date,
+            attachments: message.attachments.len(),
+            snippet: snippet(message),
+        }
+    }
+}
+
+fn snippet(message: &Message) -> String {
+    let text = match (&message.text, &message.html) {
+        (Some(text), _) => text.clone(),
+        (None, Some(html)) => strip_tags(html),
+        (None, None) => String::new(),
+    };
+    let mut out = String::new();
+    for word in text.split_whitespace() {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        out.push_str(word);
+        if out.chars().count() >= SNIPPET_LEN {
+            return out.chars().take(SNIPPET_LEN).collect();
+        }
+    }
+    out
+}
+
+/// Crude tag removal, good enough for a preview line or a search index.
+pub(crate) fn strip_tags(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                out.push(' ');
+            }
+            c if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// A change to the store, broadcast to every subscriber.
+#[derive(Debug, Clone)]
+pub enum StoreEvent {
+    Received(Arc<CapturedMessage>),
+    /// A message was restored from an archive. It is not new mail, so
+    /// nothing that reacts to incoming mail should act on it.
+    Imported(Arc<CapturedMessage>),
+    Deleted(Arc<CapturedMessage>),
+    /// Facts about a message were added, such as its spam score. Its raw
+    /// bytes never change.
+    Updated(Arc<CapturedMessage>),
+    /// Every message of a mailbox was removed, or of all mailboxes if
+    /// `mailbox` is unset.
+    Cleared {
+        mailbox: Option<String>,
+        /// The removed messages, oldest first.
+        messages: Vec<Arc<CapturedMessage>>,
+    },
+}
+
+impl StoreEvent {
+    /// Whether subscribers watching `mailbox` should see the event.
+    pub fn concerns(&self, mailbox: &str) -> bool {
+        match self {
+            StoreEvent::Received(message)
+            | StoreEvent::Imported(message)
+            | StoreEvent::Deleted(message)
+            | StoreEvent::Updated(message) => message.mailbox == mailbox,
+            StoreEvent::Cleared {
+                mailbox: cleared, ..
+            } => cleared.as_deref().is_none_or(|cleared| cleared == mailbox),
+        }
+    }
+}
+
+/// Thread-safe handle to the captured messages.
+///
+/// Cloning the handle is cheap; all clones share the same messages.
+#[derive(Debug, Clone)]
+pub struct MessageStore {
+    inner: Arc<RwLock<Inner>>,
+    events: broadcast::Sender<StoreEvent>,
+    storage: Arc<dyn Storage>,
+}
+
+impl Default for MessageStore {
+    fn default() -> Self {
+        MessageStore {
+            inner: Arc::default(),
+            events: broadcast::channel(EVENT_BUFFER).0,
+            storage: Arc::new(MemoryStorage::new()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    last_id: MessageId,
+    messages: BTreeMap<MessageId, Arc<CapturedMessage>>,
+    index: Index,
+}
+
+impl MessageStore {
+    /// Creates an empty store that keeps messages in memory only.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a store backed by `storage`, loading the messages it holds.
+    pub fn open(storage: Arc<dyn Storage>) -> Result<Self> {
+        let messages: BTreeMap<_, _> = storage
+            .load()?
+            .into_iter()
+            .map(|message| (message.id, message))
+            .collect();
+        // Ids of deleted messages are never handed out again, even if they
+        // were the newest ones.
+        let last_id = messages
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or(0)
+            .max(storage.last_id()?);
+        let mut index = Index::default();
+        for message in messages.values() {
+            index.insert(message.id, Document::of(message));
+        }
+        Ok(MessageStore {
+            inner: Arc::new(RwLock::new(Inner {
+                last_id,
+                messages,
+                index,
+            })),
+            events: broadcast::channel(EVENT_BUFFER).0,
+            storage,
+        })
+    }
+
+    /// Stores a message in the default mailbox and returns it with its
+    /// assigned id.
+    pub fn insert(&self, message: NewMessage) -> Result<Arc<CapturedMessage>> {
+        self.insert_into(DEFAULT_MAILBOX, message)
+    }
+
+    /// Stores a message in `mailbox` and returns it with its assigned id.
+    pub fn insert_into(&self, mailbox: &str, message: NewMessage) -> Result<Arc<CapturedMessage>> {
+        self.insert_at(mailbox, message, Utc::now())
+    }
+
+    /// Stores a message in `mailbox` as received at `received_at`, for
+    /// messages that were captured earlier or elsewhere.
+    pub fn insert_at(
+        &self,
+        mailbox: &str,
+        message: NewMessage,
+        received_at: DateTime<Utc>,
+    ) -> Result<Arc<CapturedMessage>> {
+        self.add(mailbox, message, received_at, StoreEvent::Received)
+    }
+
+    /// Stores a message restored from an archive, like [`insert_at`], but
+    /// announces it as [`StoreEvent::Imported`] rather than as new mail.
+    ///
+    /// [`insert_at`]: MessageStore::insert_at
+    pub fn import_at(
+        &self,
+        mailbox: &str,
+        message: NewMessage,
+        received_at: DateTime<Utc>,
+    ) -> Result<Arc<CapturedMessage>> {
+        self.add(mailbox, message, received_at, StoreEvent::Imported)
+    }
+
+    /// Stores a copy of a message in each of `mailboxes`, as received now,
+    /// and returns the copies in the same order. Either every copy is
+    /// stored or none is: if one cannot be written, the copies written
+    /// before it are removed again and no event is sent.
+    pub fn insert_copies(
+        &self,
+        mailboxes: &[String],
+        message: NewMessage,
+    ) -> Result<Vec<Arc<CapturedMessage>>> {
+        self.add_copies(mailboxes, message, Utc::now(), StoreEvent::Received)
+    }
+
+    fn add(
+        &self,
+        mailbox: &str,
+        message: NewMessage,
+        received_at: DateTime<Utc>,
+        event: fn(Arc<CapturedMessage>) -> StoreEvent,
+    ) -> Result<Arc<CapturedMessage>> {
+        let mut copies = self.add_copies(&[mailbox.to_string()], message, received_at, event)?;
+        Ok(copies.remove(0))
+    }
+
+    fn add_copies(
+        &self,
+        mailboxes: &[String],
+        message: NewMessage,
+        received_at: DateTime<Utc>,
+        event: fn(Arc<CapturedMessage>) -> StoreEvent,
+    ) -> Result<Vec<Arc<CapturedMessage>>> {
+        // Parsing can take a while for big messages, so it happens before
+        // taking the lock and the ids are filled in afterwards.
+        let mut captured = CapturedMessage::new(
+            0,
+            received_at,
+            message.envelope,
+            message.session,
+            message.raw,
+        );
+        captured.authentication = message.authentication;
+        let prepared: Vec<_> = mailboxes
+            .iter()
+            .map(|mailbox| {
+                let mut copy = captured.clone();
+                copy.mailbox = mailbox.clone();
+                let document = Document::of(&copy);
+                (copy, document)
+            })
+            .collect();
+
+        let mut inner = self.inner.write().unwrap();
+        let mut stored: Vec<(Arc<CapturedMessage>, Document)> = Vec::with_capacity(prepared.len());
+        for (mut copy, document) in prepared {
+            copy.id = inner.last_id + 1;
+            let copy = Arc::new(copy);
+            if let Err(err) = self.storage.insert(&copy) {
+                for (copy, _) in &stored {
+                    if let Err(err) = self.storage.delete(copy.id) {
+                        warn!(
+                            id = copy.id,
+                            "failed to remove partly stored message: {err}"
+                        );
+                    }
+                }
+                return Err(err);
+            }
+            // Ids are used up even if a later copy fails, like those of
+            // deleted messages.
+            inner.last_id = copy.id;
+            stored.push((copy, document));
+        }
+        let mut copies = Vec::with_capacity(stored.len());
+        for (copy, document) in stored {
+            inner.messages.insert(copy.id, copy.clone());
+            inner.index.insert(copy.id, document);
+            // Sent under the lock so subscribers see events in id order.
+            self.notify(event(copy.clone()));
+            copies.push(copy);
+        }
+        Ok(copies)
+    }
+
+    /// Subscribes to changes made after this call.
+    pub fn subscribe(&self) -> broadcast::Receiver<StoreEvent> {
+        self.events.subscribe()
+    }
+
+    fn notify(&self, event: StoreEvent) {
+        // Sending only fails when nobody is listening.
+        let _ = self.events.send(event);
+    }
+
+    /// The id the next stored message will get.
+    pub fn next_id(&self) -> MessageId {
+        self.inner.read().unwrap().last_id + 1
+    }
+
+    pub fn get(&self, id: MessageId) -> Option<Arc<CapturedMessage>> {
+        self.inner.read().unwrap().messages.get(&id).cloned()
+    }
+
+    /// Returns every message, newest first.
+    pub fn list(&self) -> Vec<Arc<CapturedMessage>> {
+        self.inner
+            .read()
+            .unwrap()
+            .messages
+            .values()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns every message in `mailbox`, newest first.
+    pub fn list_mailbox(&self, mailbox: &str) -> Vec<Arc<CapturedMessage>> {
+        self.inner
+            .read()
+            .unwrap()
+            .messages
+            .values()
+            .rev()
+            .filter(|message| message.mailbox == mailbox)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns how many messages each mailbox holds. Empty mailboxes are
+    /// left out.
+    pub fn mailbox_counts(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for message in self.inner.read().unwrap().messages.values() {
+            *counts.entry(message.mailbox.clone()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Returns the messages matching `query`, newest first.
+    pub fn search(&self, query: &Query) -> Vec<Arc<CapturedMessage>> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .index
+            .search(query)
+            .into_iter()
+            .rev()
+            .filter_map(|id| inner.messages.get(&id).cloned())
+            .collect()
+    }
+
+    /// Waits until a message matching `query` is stored, up to `timeout`.
+    ///
+    /// A message already in the store counts, in which case the newest
+    /// match is returned at once. Returns `None` if nothing matched in time.
+    pub async fn wait_for(&self, query: &Query, timeout: Duration) -> Option<Arc<CapturedMessage>> {
+        // Subscribe before searching so a message stored in between is
+        // still seen.
+        let mut events = self.subscribe();
+        if let Some(found) = self.search(query).into_iter().next() {
+            return Some(found);
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            match time::timeout_at(deadline, events.recv()).await {
+                Ok(Ok(StoreEvent::Received(message))) => {
+                    if self.inner.read().unwrap().index.matches(query, message.id) {
+                        return Some(message);
+                    }
+                }
+                Ok(Ok(_)) => {}
+                // Events were dropped; one of them may have been a match.
+                Ok(Err(RecvError::Lagged(_))) => {
+                    if let Some(found) = self.search(query).into_iter().next() {
+                        return Some(found);
+                    }
+                }
+                Ok(Err(RecvError::Closed)) | Err(_) => return None,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Applies `change` to a stored message and persists the result.
+    ///
+    /// Returns the updated message, or `None` if no message has the id.
+    pub fn update(
+        &self,
+        id: MessageId,
+        change: impl FnOnce(&mut CapturedMessage),
+    ) -> Result<Option<Arc<CapturedMessage>>> {
+        let mut inner = self.inner.write().unwrap();
+        let Some(current) = inner.messages.get(&id) else {
+            return Ok(None);
+        };
+        let mut message = CapturedMessage::clone(current);
+        change(&mut message);
+        let message = Arc::new(message);
+        self.storage.update(&message)?;
+        inner.messages.insert(id, message.clone());
+        self.notify(StoreEvent::Updated(message.clone()));
+        Ok(Some(message))
+    }
+
+    /// Removes a message, returning whether it existed.
+    pub fn delete(&self, id: MessageId) -> Result<bool> {
+        self.delete_if(id, |_| true)
+    }
+
+    /// Removes a message unless it is pinned, returning whether it was
+    /// removed.
+    pub fn evict(&self, id: MessageId) -> Result<bool> {
+        self.delete_if(id, |message| !message.pinned)
+    }
+
+    fn delete_if(
+        &self,
+        id: MessageId,
+        check: impl FnOnce(&CapturedMessage) -> bool,
+    ) -> Result<bool> {
+        let mut inner = self.inner.write().unwrap();
+        if !inner
+            .messages
+            .get(&id)
+            .is_some_and(|message| check(message))
+        {
+            return Ok(false);
+        }
+        self.storage.delete(id)?;
+        inner.index.remove(id);
+        if let Some(message) = inner.messages.remove(&id) {
+            self.notify(StoreEvent::Deleted(message));
+        }
+        Ok(true)
+    }
+
+    /// Removes every message and returns how many there were.
+    pub fn clear(&self) -> Result<usize> {
+        let mut inner = self.inner.write().unwrap();
+        self.storage.clear()?;
+        let messages: Vec<_> = std::mem::take(&mut inner.messages).into_values().collect();
+        inner.index.clear();
+        let deleted = messages.len();
+        self.notify(StoreEvent::Cleared {
+            mailbox: None,
+            messages,
+        });
+        Ok(deleted)
+    }
+
+    /// Removes every message in `mailbox`, leaving the other mailboxes
+    /// alone, and returns how many there were.
+    pub fn clear_mailbox(&self, mailbox: &str) -> Result<usize> {
+        let mut inner = self.inner.write().unwrap();
+        let messages: Vec<Arc<CapturedMessage>> = inner
+            .messages
+            .values()
+            .filter(|message| message.mailbox == mailbox)
+            .cloned()
+            .collect();
+        if messages.len() == inner.messages.len() {
+            // Everything goes, which backends do faster in one step.
+            self.storage.clear()?;
+            inner.messages.clear();
+            inner.index.clear();
+        } else {
+            for message in &messages {
+                self.storage.delete(message.id)?;
+                inner.messages.remove(&message.id);
+                inner.index.remove(message.id);
+            }
+        }
+        let deleted = messages.len();
+        self.notify(StoreEvent::Cleared {
+            mailbox: Some(mailbox.to_string()),
+            messages,
+        });
+        Ok(deleted)
+    }
+}
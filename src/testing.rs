@@ -0,0 +1,150 @@
+//! An in-process server for tests, so a suite can capture mail without
+//! starting the binary.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use tokio::runtime;
+use tokio::sync::oneshot;
+
+use crate::http::{AppState, HttpServer};
+use crate::smtp::{SmtpConfig, SmtpServer};
+use crate::store::{CapturedMessage, MessageStore};
+use crate::Result;
+
+/// SMTP and HTTP listeners on free loopback ports, with their own store.
+///
+/// The server runs on a thread of its own, so it works the same from plain
+/// `#[test]` functions and from async tests, and every instance is
+/// independent of the others. It shuts down when dropped.
+#[derive(Debug)]
+pub struct TestServer {
+    smtp: SocketAddr,
+    http: SocketAddr,
+    store: MessageStore,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Starts a server with the default settings.
+    pub fn start() -> Result<TestServer> {
+        TestServer::start_with(SmtpConfig::default())
+    }
+
+    /// Starts a server whose SMTP listener uses `config`. Its fault rules,
+    /// session log and mailboxes are shared with the HTTP API.
+    pub fn start_with(config: SmtpConfig) -> Result<TestServer> {
+        let state = AppState {
+            faults: config.faults.clone(),
+            sessions: config.sessions.clone(),
+            mailboxes: config.mailboxes.clone(),
+            ..AppState::default()
+        };
+        let store = state.store.clone();
+        let (ready, bound) = mpsc::channel();
+        let (shutdown, stop) = oneshot::channel();
+        let thread = thread::Builder::new()
+            .name("rubbermail-test-server".to_string())
+            .spawn(move || serve(config, state, ready, stop))?;
+        let (smtp, http) = bound
+            .recv()
+            .map_err(|_| io::Error::other("test server thread exited"))??;
+        Ok(TestServer {
+            smtp,
+            http,
+            store,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn smtp_addr(&self) -> SocketAddr {
+        self.smtp
+    }
+
+    pub fn smtp_port(&self) -> u16 {
+        self.smtp.port()
+    }
+
+    pub fn http_addr(&self) -> SocketAddr {
+        self.http
+    }
+
+    pub fn http_port(&self) -> u16 {
+        self.http.port()
+    }
+
+    /// Base URL of the HTTP API and web UI, e.g. `http://127.0.0.1:49152`.
+    pub fn http_url(&self) -> String {
+        format!("http://{}", self.http)
+    }
+
+    /// The store captured mail goes to.
+    pub fn store(&self) -> &MessageStore {
+        &self.store
+    }
+
+    /// Every captured message, newest first.
+    pub fn messages(&self) -> Vec<Arc<CapturedMessage>> {
+        self.store.list()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        // Joining makes sure the ports are closed once the drop returns.
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Binds both listeners, reports their addresses and serves until told to
+/// stop. Dropping the runtime afterwards ends every open connection.
+fn serve(
+    config: SmtpConfig,
+    state: AppState,
+    ready: mpsc::Sender<io::Result<(SocketAddr, SocketAddr)>>,
+    stop: oneshot::Receiver<()>,
+) {
+    let runtime = match runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            let _ = ready.send(Err(err));
+            return;
+        }
+    };
+    let (store, webhooks) = (state.store.clone(), state.webhooks.clone());
+    runtime.block_on(async move {
+        let loopback = SocketAddr::from(([127, 0, 0, 1], 0));
+        let bound = async {
+            let smtp = SmtpServer::bind(loopback, config, state.store.clone()).await?;
+            let http = HttpServer::bind(loopback, state).await?;
+            let addrs = (smtp.local_addr()?, http.local_addr()?);
+            Ok::<_, io::Error>((smtp, http, addrs))
+        };
+        let (smtp, http) = match bound.await {
+            Ok((smtp, http, addrs)) => {
+                let _ = ready.send(Ok(addrs));
+                (smtp, http)
+            }
+            Err(err) => {
+                let _ = ready.send(Err(err));
+                return;
+            }
+        };
+        webhooks.watch(store);
+        tokio::select! {
+            _ = smtp.serve() => {}
+            _ = http.serve() => {}
+            _ = stop => {}
+        }
+    });
+}
@@ -0,0 +1,163 @@
+//! TLS for the mail listeners: certificates and handshakes.
+//!
+//! Certificates come from PEM files supplied by the user or are generated
+//! at startup. Clients of a testing tool rarely verify them, so a throwaway
+//! self-signed certificate is a reasonable default.
+//!
+//! Outgoing connections, made when releasing a message upstream, verify the
+//! server against the usual public roots plus any configured extra ones.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::{ClientConfig, ProtocolVersion, RootCertStore, ServerConfig};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use crate::{Error, Result};
+
+/// What was negotiated on a TLS connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsInfo {
+    /// Protocol version, e.g. `TLSv1.3`.
+    pub version: String,
+    /// IANA name of the cipher suite, e.g. `TLS13_AES_256_GCM_SHA384`.
+    pub cipher: String,
+}
+
+/// A certificate chain and its private key.
+pub struct Identity {
+    pub certs: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl Identity {
+    /// Reads a PEM certificate chain and a PEM private key (PKCS#8, PKCS#1
+    /// or SEC1).
+    pub fn load(cert: &Path, key: &Path) -> Result<Identity> {
+        let certs = load_certs(cert)?;
+        let key = rustls_pemfile::private_key(&mut read_pem(key)?.as_slice())
+            .map_err(|err| Error::Tls(format!("{}: {err}", key.display())))?
+            .ok_or_else(|| Error::Tls(format!("{}: no private key found", key.display())))?;
+        Ok(Identity { certs, key })
+    }
+
+    /// Generates a self-signed certificate valid for `hostname`, `localhost`
+    /// and the loopback addresses.
+    pub fn self_signed(hostname: &str) -> Result<Identity> {
+        let mut names = vec![hostname.to_string()];
+        for name in ["localhost", "127.0.0.1", "::1"] {
+            if name != hostname {
+                names.push(name.to_string());
+            }
+        }
+        let generated =
+            rcgen::generate_simple_self_signed(names).map_err(|err| Error::Tls(err.to_string()))?;
+        Ok(Identity {
+            certs: vec![generated.cert.der().clone()],
+            key: PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der()).into(),
+        })
+    }
+
+    /// Builds an acceptor serving this identity.
+    pub fn acceptor(self) -> Result<TlsAcceptor> {
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| {
+                builder
+                    .with_no_client_auth()
+                    .with_single_cert(self.certs, self.key)
+            })
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        Ok(TlsAcceptor(tokio_rustls::TlsAcceptor::from(Arc::new(
+            config,
+        ))))
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|err| Error::Tls(format!("{}: {err}", path.display())))
+}
+
+/// Reads every certificate in a PEM file.
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut read_pem(path)?.as_slice())
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|err| Error::Tls(format!("{}: {err}", path.display())))?;
+    if certs.is_empty() {
+        return Err(Error::Tls(format!(
+            "{}: no certificate found",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+/// Builds a client that trusts the public web roots and `extra_roots`.
+pub fn connector(extra_roots: &[CertificateDer<'static>]) -> Result<TlsConnector> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    for cert in extra_roots {
+        roots
+            .add(cert.clone())
+            .map_err(|err| Error::Tls(err.to_string()))?;
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| Error::Tls(err.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Performs server-side TLS handshakes.
+#[derive(Clone)]
+pub struct TlsAcceptor(tokio_rustls::TlsAcceptor);
+
+impl fmt::Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TlsAcceptor")
+    }
+}
+
+impl TlsAcceptor {
+    /// Runs the handshake on `stream` and reports what was negotiated.
+    pub async fn accept<S>(&self, stream: S) -> io::Result<(TlsStream<S>, TlsInfo)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = self.0.accept(stream).await?;
+        let (_, connection) = stream.get_ref();
+        let version = match connection.protocol_version() {
+            Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+            Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+            Some(other) => format!("{other:?}"),
+            None => String::new(),
+        };
+        let cipher = connection
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .unwrap_or_default();
+        Ok((stream, TlsInfo { version, cipher }))
+    }
+}
+
+/// How a listener uses TLS.
+#[derive(Debug, Clone, Default)]
+pub enum TlsMode {
+    /// Plaintext only.
+    #[default]
+    Off,
+    /// Plaintext, with an in-protocol upgrade (SMTP `STARTTLS`, POP3 `STLS`).
+    StartTls(TlsAcceptor),
+    /// TLS from the first byte, as on port 465.
+    Implicit(TlsAcceptor),
+}
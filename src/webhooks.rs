@@ -0,0 +1,445 @@
+//! Outbound webhooks: JSON POSTed to configured URLs when messages are
+//! received, deleted or released, so bots can react to mail without holding
+//! a WebSocket open.
+//!
+//! Webhooks are kept behind a shared [`Webhooks`] handle and can be replaced
+//! at any time, typically through `PUT /api/webhooks`. Each delivery is
+//! signed with the webhook's secret, if it has one, and retried with
+//! exponential backoff while the receiver fails.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::http::model::MessageSummary;
+use crate::release::Reply;
+use crate::search::Query;
+use crate::store::{CapturedMessage, MessageStore, StoreEvent};
+
+/// Attempts per delivery, including the first.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry; it doubles with every further one.
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+
+/// How long a receiver may take to answer one attempt.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Header carrying the event name.
+pub const EVENT_HEADER: &str = "X-RubberMail-Event";
+
+/// Header carrying the delivery id, the same for every attempt.
+pub const DELIVERY_HEADER: &str = "X-RubberMail-Delivery";
+
+/// Header carrying `sha256=` and the hex HMAC-SHA256 of the body.
+pub const SIGNATURE_HEADER: &str = "X-RubberMail-Signature";
+
+/// Something that happened to a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Received,
+    Deleted,
+    /// Sent to the upstream server with `POST /api/messages/{id}/release`.
+    Released,
+}
+
+impl fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            WebhookEvent::Received => "message_received",
+            WebhookEvent::Deleted => "message_deleted",
+            WebhookEvent::Released => "message_released",
+        };
+        f.write_str(name)
+    }
+}
+
+/// One webhook.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Webhook {
+    /// The `http` or `https` URL deliveries are POSTed to.
+    pub url: String,
+    /// Events to deliver; every event if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<WebhookEvent>,
+    /// Only deliver events for messages matching this search.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub query: String,
+    /// Whether deliveries are signed, which the API reports instead of the
+    /// secret. When setting webhooks without a `secret`, `Some(false)` turns
+    /// signing off and anything else keeps the secret of the current webhook
+    /// with the same URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_secret: Option<bool>,
+    /// Key deliveries are signed with. It is never sent back by the API.
+    #[serde(default, skip_serializing)]
+    pub secret: Option<String>,
+}
+
+/// A complete set of webhooks, as read from `--webhooks` and exchanged over
+/// the API.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookSet {
+    pub webhooks: Vec<Webhook>,
+}
+
+/// A validated webhook with its query parsed.
+#[derive(Debug)]
+struct Hook {
+    webhook: Webhook,
+    query: Query,
+}
+
+impl Hook {
+    fn new(mut webhook: Webhook) -> Result<Hook, String> {
+        let url = Url::parse(&webhook.url).map_err(|err| format!("invalid url: {err}"))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("url must be http or https, got {}", url.scheme()));
+        }
+        let query = Query::parse(&webhook.query).map_err(|err| format!("invalid query: {err}"))?;
+        webhook.has_secret = Some(webhook.secret.is_some());
+        Ok(Hook { webhook, query })
+    }
+
+    fn wants(&self, event: WebhookEvent, message: &CapturedMessage) -> bool {
+        (self.webhook.events.is_empty() || self.webhook.events.contains(&event))
+            && (self.query.is_empty() || self.query.matches(message))
+    }
+}
+
+/// The JSON body of a delivery.
+#[derive(Debug, Serialize)]
+struct Payload {
+    #[serde(rename = "type")]
+    event: String,
+    sent_at: DateTime<Utc>,
+    message: MessageSummary,
+    /// Where a released message went.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    release: Option<Release>,
+}
+
+#[derive(Debug, Serialize)]
+struct Release {
+    to: Vec<String>,
+    reply: String,
+}
+
+/// Why an attempt failed.
+struct Failure {
+    reason: String,
+    /// Unset when the receiver rejected the delivery for good.
+    retry: bool,
+}
+
+/// The shared, runtime-changeable webhook set. Cloning the handle is cheap.
+#[derive(Debug, Clone, Default)]
+pub struct Webhooks {
+    hooks: Arc<Mutex<Vec<Arc<Hook>>>>,
+    deliveries: Arc<AtomicU64>,
+}
+
+impl Webhooks {
+    pub fn new(webhooks: Vec<Webhook>) -> Result<Webhooks, String> {
+        let hooks = Webhooks::default();
+        hooks.set(webhooks)?;
+        Ok(hooks)
+    }
+
+    /// Replaces every webhook, or leaves them untouched if one is invalid.
+    ///
+    /// A webhook without a secret keeps the one the current webhook with the
+    /// same URL has, so a set read back from [`Webhooks::webhooks`] can be
+    /// set again, unless `has_secret` is `Some(false)`.
+    pub fn set(&self, webhooks: Vec<Webhook>) -> Result<(), String> {
+        let mut current = self.hooks.lock().unwrap();
+        let hooks = webhooks
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut webhook)| {
+                if webhook.secret.is_none() && webhook.has_secret != Some(false) {
+                    webhook.secret = current
+                        .iter()
+                        .find(|hook| hook.webhook.url == webhook.url)
+                        .and_then(|hook| hook.webhook.secret.clone());
+                    if webhook.secret.is_none() && webhook.has_secret == Some(true) {
+                        return Err(format!("webhook {i}: no secret to keep for this url"));
+                    }
+                }
+                Hook::new(webhook)
+                    .map(Arc::new)
+                    .map_err(|err| format!("webhook {i}: {err}"))
+            })
+            .collect::<Result<_, _>>()?;
+        *current = hooks;
+        Ok(())
+    }
+
+    /// The current webhooks.
+    pub fn webhooks(&self) -> Vec<Webhook> {
+        self.hooks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|hook| hook.webhook.clone())
+            .collect()
+    }
+
+    /// Delivers a `message_released` event for `message`, which went to
+    /// `to` with the upstream server answering `reply`.
+    pub fn released(&self, message: &CapturedMessage, to: &[String], reply: &Reply) {
+        let release = Release {
+            to: to.to_vec(),
+            reply: reply.to_string(),
+        };
+        self.dispatch(WebhookEvent::Released, message, Some(release));
+    }
+
+    /// Delivers `message_received` and `message_deleted` events for changes
+    /// to `store` in the background. Clearing a mailbox deletes each of its
+    /// messages; imported messages are not received.
+    pub fn watch(self, store: MessageStore) -> JoinHandle<()> {
+        let mut events = store.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(StoreEvent::Received(message)) => {
+                        self.dispatch(WebhookEvent::Received, &message, None)
+                    }
+                    Ok(StoreEvent::Deleted(message)) => {
+                        self.dispatch(WebhookEvent::Deleted, &message, None)
+                    }
+                    Ok(StoreEvent::Cleared { messages, .. }) => {
+                        for message in messages {
+                            self.dispatch(WebhookEvent::Deleted, &message, None);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(missed, "webhooks lagging, events dropped");
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        })
+    }
+
+    /// Starts a delivery to every webhook that wants the event.
+    fn dispatch(&self, event: WebhookEvent, message: &CapturedMessage, release: Option<Release>) {
+        let hooks: Vec<Arc<Hook>> = self
+            .hooks
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|hook| hook.wants(event, message))
+            .cloned()
+            .collect();
+        if hooks.is_empty() {
+            return;
+        }
+        let payload = Payload {
+            event: event.to_string(),
+            sent_at: Utc::now(),
+            message: MessageSummary::from(message),
+            release,
+        };
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => Arc::new(body),
+            Err(err) => {
+                warn!("could not encode webhook payload: {err}");
+                return;
+            }
+        };
+        for hook in hooks {
+            let delivery = self.deliveries.fetch_add(1, Ordering::Relaxed) + 1;
+            tokio::spawn(deliver(hook, delivery, event, body.clone()));
+        }
+    }
+}
+
+/// Sends one delivery, retrying with exponential backoff until the receiver
+/// accepts it, rejects it for good or [`MAX_ATTEMPTS`] are used up.
+async fn deliver(hook: Arc<Hook>, delivery: u64, event: WebhookEvent, body: Arc<Vec<u8>>) {
+    let url = &hook.webhook.url;
+    let signature = hook
+        .webhook
+        .secret
+        .as_deref()
+        .map(|secret| sign(secret, &body));
+    let mut backoff = FIRST_RETRY;
+    for attempt in 1..=MAX_ATTEMPTS {
+        match attempt_delivery(&hook, delivery, event, &body, signature.as_deref()).await {
+            Ok(()) => {
+                debug!(url, delivery, %event, attempt, "webhook delivered");
+                return;
+            }
+            Err(failure) if failure.retry && attempt < MAX_ATTEMPTS => {
+                debug!(
+                    url,
+                    delivery,
+                    attempt,
+                    reason = failure.reason,
+                    "webhook failed, retrying"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(failure) => {
+                warn!(url, delivery, %event, attempt, reason = failure.reason, "webhook not delivered");
+                return;
+            }
+        }
+    }
+}
+
+async fn attempt_delivery(
+    hook: &Hook,
+    delivery: u64,
+    event: WebhookEvent,
+    body: &[u8],
+    signature: Option<&str>,
+) -> Result<(), Failure> {
+    let mut request = client()
+        .post(&hook.webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, event.to_string())
+        .header(DELIVERY_HEADER, delivery.to_string())
+        .body(body.to_vec());
+    if let Some(signature) = signature {
+        request = request.header(SIGNATURE_HEADER, signature);
+    }
+    let response = request.send().await.map_err(|err| Failure {
+        reason: err.to_string(),
+        retry: true,
+    })?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    Err(Failure {
+        reason: format!("receiver answered {status}"),
+        // Timeouts, rate limits and server errors may pass; other client
+        // errors will not.
+        retry: status.is_server_error()
+            || status == reqwest::StatusCode::REQUEST_TIMEOUT
+            || status == reqwest::StatusCode::TOO_MANY_REQUESTS,
+    })
+}
+
+/// The signature header value for `body`: `sha256=` and the lowercase hex
+/// HMAC-SHA256 keyed with `secret`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key");
+    mac.update(body);
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256={digest}")
+}
+
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .user_agent(concat!(
+                "RubberMail/",
+                env!("CARGO_PKG_VERSION"),
+                " webhooks"
+            ))
+            .build()
+            .expect("HTTP client configuration is valid")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook(json: &str) -> Webhook {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn invalid_webhooks_are_rejected_without_changing_the_set() {
+        let webhooks =
+            Webhooks::new(vec![webhook(r#"{"url": "http://127.0.0.1:9/hook"}"#)]).unwrap();
+        let err = webhooks
+            .set(vec![webhook(r#"{"url": "ftp://example.test/"}"#)])
+            .unwrap_err();
+        assert_eq!(err, "webhook 0: url must be http or https, got ftp");
+        let err = webhooks
+            .set(vec![webhook(
+                r#"{"url": "https://example.test/", "query": "after:someday"}"#,
+            )])
+            .unwrap_err();
+        assert!(err.starts_with("webhook 0: invalid query"), "{err}");
+        assert_eq!(webhooks.webhooks().len(), 1);
+    }
+
+    #[test]
+    fn secrets_are_not_serialized() {
+        let hook = webhook(
+            r#"{"url": "https://example.test/", "events": ["received"], "secret": "s3cret"}"#,
+        );
+        assert_eq!(hook.secret.as_deref(), Some("s3cret"));
+        let webhooks = Webhooks::new(vec![hook]).unwrap().webhooks();
+        assert_eq!(
+            serde_json::to_string(&webhooks).unwrap(),
+            r#"[{"url":"https://example.test/","events":["received"],"has_secret":true}]"#
+        );
+    }
+
+    #[test]
+    fn secrets_are_kept_unless_turned_off() {
+        let webhooks = Webhooks::new(vec![
+            webhook(r#"{"url": "https://a.example.test/", "secret": "a"}"#),
+            webhook(r#"{"url": "https://b.example.test/", "secret": "b"}"#),
+        ])
+        .unwrap();
+        webhooks
+            .set(vec![
+                webhook(r#"{"url": "https://a.example.test/"}"#),
+                webhook(r#"{"url": "https://b.example.test/", "has_secret": false}"#),
+            ])
+            .unwrap();
+        let secrets: Vec<_> = webhooks
+            .hooks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|hook| hook.webhook.secret.clone())
+            .collect();
+        assert_eq!(secrets, [Some("a".to_string()), None]);
+
+        let err = webhooks
+            .set(vec![webhook(
+                r#"{"url": "https://c.example.test/", "has_secret": true}"#,
+            )])
+            .unwrap_err();
+        assert_eq!(err, "webhook 0: no secret to keep for this url");
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -0,0 +1,291 @@
+mod common;
+
+use common::TestApp;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+const MULTIPART: &str = "From: \"Alice\" <alice@example.test>\r\n\
+To: bob@example.test\r\n\
+Subject: =?utf-8?Q?Caf=C3=A9?= menu\r\n\
+Message-ID: <menu@example.test>\r\n\
+Content-Type: multipart/mixed; boundary=b\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Today: soup\r\n\
+--b\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<p>Today: <b>soup</b></p>\r\n\
+--b\r\n\
+Content-Type: text/plain; name=menu.txt\r\n\
+Content-Disposition: attachment; filename=menu.txt\r\n\
+\r\n\
+soup\r\n\
+--b--\r\n";
+
+async fn get_json(url: &str) -> (StatusCode, Value) {
+    let response = reqwest::get(url).await.unwrap();
+    let status = response.status();
+    (status, response.json().await.unwrap())
+}
+
+#[tokio::test]
+async fn list_and_read_messages() {
+    let app = TestApp::start().await;
+    app.send("alice@example.test", &["bob@example.test"], MULTIPART)
+        .await;
+
+    let (status, list) = get_json(&app.url("/api/messages")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(list["total"], 1);
+    let summary = &list["messages"][0];
+    assert_eq!(summary["id"], 1);
+    assert_eq!(summary["subject"], "Café menu");
+    assert_eq!(summary["from"]["name"], "Alice");
+    assert_eq!(summary["from"]["address"], "alice@example.test");
+    assert_eq!(summary["to"][0]["address"], "bob@example.test");
+    assert_eq!(summary["envelope"]["rcpt_to"][0], "bob@example.test");
+    assert_eq!(summary["attachment_count"], 1);
+    assert_eq!(summary["snippet"], "Today: soup");
+    assert_eq!(summary["size"], MULTIPART.len());
+
+    let (status, detail) = get_json(&app.url("/api/messages/1")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(detail["subject"], "Café menu");
+    assert_eq!(detail["message_id"], "menu@example.test");
+    assert_eq!(detail["text"], "Today: soup");
+    assert_eq!(detail["html"], "<p>Today: <b>soup</b></p>");
+    assert_eq!(detail["attachments"][0]["part_id"], "3");
+    assert_eq!(detail["attachments"][0]["filename"], "menu.txt");
+    assert_eq!(detail["session"]["helo"], "client.test");
+    assert_eq!(detail["headers"][2]["name"], "Subject");
+    assert_eq!(detail["headers"][2]["value"], "Café menu");
+}
+
+#[tokio::test]
+async fn parts_serve_decoded_content() {
+    let app = TestApp::start().await;
+    let raw = "Subject: parts\r\n\
+Content-Type: multipart/mixed; boundary=b\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+body\r\n\
+--b\r\n\
+Content-Type: application/octet-stream\r\n\
+Content-Disposition: attachment; filename*=UTF-8''r%C3%A9sum%C3%A9.bin\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+AAECAw==\r\n\
+--b--\r\n";
+    app.send("a@example.test", &["b@example.test"], raw).await;
+
+    let response = reqwest::get(app.url("/api/messages/1/parts/2?download=true"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/octet-stream"
+    );
+    assert_eq!(
+        response.headers()["content-disposition"],
+        "attachment; filename=\"r_sum_.bin\"; filename*=UTF-8''r%C3%A9sum%C3%A9.bin"
+    );
+    assert_eq!(response.bytes().await.unwrap().as_ref(), [0, 1, 2, 3]);
+
+    let response = reqwest::get(app.url("/api/messages/1/parts/1"))
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["content-disposition"], "inline");
+    assert_eq!(response.text().await.unwrap(), "body");
+
+    let (status, body) = get_json(&app.url("/api/messages/1/parts/3")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], "part 3 not found");
+    let (status, _) = get_json(&app.url("/api/messages/1/parts/x.1")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn raw_returns_original_bytes() {
+    let app = TestApp::start().await;
+    app.send("a@example.test", &["b@example.test"], MULTIPART)
+        .await;
+
+    let response = reqwest::get(app.url("/api/messages/1/raw")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "message/rfc822");
+    assert_eq!(response.bytes().await.unwrap(), MULTIPART.as_bytes());
+}
+
+#[tokio::test]
+async fn pagination_is_newest_first() {
+    let app = TestApp::start().await;
+    for i in 1..=5 {
+        app.send(
+            "a@example.test",
+            &["b@example.test"],
+            &format!("Subject: {i}\r\n\r\nx\r\n"),
+        )
+        .await;
+    }
+
+    let (_, page) = get_json(&app.url("/api/messages?offset=1&limit=2")).await;
+    assert_eq!(page["total"], 5);
+    assert_eq!(page["offset"], 1);
+    assert_eq!(page["limit"], 2);
+    let subjects: Vec<_> = page["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["subject"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(subjects, ["4", "3"]);
+
+    let (_, page) = get_json(&app.url("/api/messages?offset=10")).await;
+    assert_eq!(page["messages"].as_array().unwrap().len(), 0);
+}
+
+#[tokio::test]
+async fn delete_one_and_all() {
+    let app = TestApp::start().await;
+    for _ in 0..3 {
+        app.send(
+            "a@example.test",
+            &["b@example.test"],
+            "Subject: x\r\n\r\nx\r\n",
+        )
+        .await;
+    }
+    let client = reqwest::Client::new();
+
+    let response = client
+        .delete(app.url("/api/messages/2"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = client
+        .delete(app.url("/api/messages/2"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(app.store.len(), 2);
+
+    let response = client
+        .delete(app.url("/api/messages"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.json::<Value>().await.unwrap()["deleted"], 2);
+    assert!(app.store.is_empty());
+}
+
+#[tokio::test]
+async fn missing_messages_return_json_errors() {
+    let app = TestApp::start().await;
+    for path in ["/api/messages/99", "/api/messages/99/raw"] {
+        let (status, body) = get_json(&app.url(path)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "message 99 not found");
+    }
+}
+
+#[tokio::test]
+async fn invalid_ids_are_bad_requests() {
+    let app = TestApp::start().await;
+    let (status, body) = get_json(&app.url("/api/messages/abc")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["error"], "invalid message id \"abc\"");
+}
+
+#[tokio::test]
+async fn search_messages() {
+    let app = TestApp::start().await;
+    app.send("alice@example.test", &["bob@example.test"], MULTIPART)
+        .await;
+    app.send(
+        "news@example.test",
+        &["carol@example.test"],
+        "Subject: Weekly digest\r\n\r\nsoup recipes\r\n",
+    )
+    .await;
+
+    let (status, list) = get_json(&app.url("/api/messages?query=to:bob%20soup")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(list["total"], 1);
+    assert_eq!(list["messages"][0]["id"], 1);
+
+    let (_, list) = get_json(&app.url("/api/messages?query=soup&limit=1")).await;
+    assert_eq!(list["total"], 2);
+    assert_eq!(list["messages"][0]["id"], 2);
+
+    let (status, body) = get_json(&app.url("/api/messages?query=has:pdf")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        body["error"],
+        "invalid query: unknown has: value \"pdf\", expected \"attachment\""
+    );
+}
+
+#[tokio::test]
+async fn compatibility_report() {
+    let app = TestApp::start().await;
+    app.send(
+        "news@example.test",
+        &["bob@example.test"],
+        "Subject: Launch\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<style>@media (max-width: 600px) { .col { display: block; } }</style>\r\n\
+<div style=\"display: flex; border-radius: 4px\"><video src=\"a.mp4\"></video></div>\r\n",
+    )
+    .await;
+    app.send(
+        "alice@example.test",
+        &["bob@example.test"],
+        "Subject: plain\r\n\r\nno html\r\n",
+    )
+    .await;
+
+    let (status, report) = get_json(&app.url("/api/messages/1/compatibility")).await;
+    assert_eq!(status, StatusCode::OK);
+    let names = |list: &Value| -> Vec<String> {
+        list.as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["name"].as_str().unwrap().to_string())
+            .collect()
+    };
+    assert_eq!(names(&report["elements"]), ["div", "style", "video"]);
+    assert_eq!(names(&report["properties"]), ["border-radius", "display"]);
+    assert_eq!(report["features"][0]["id"], "html-video");
+    assert_eq!(report["features"][0]["support"]["gmail"], "no");
+    let outlook = report["clients"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|client| client["id"] == "outlook")
+        .unwrap();
+    assert_eq!(outlook["name"], "Outlook");
+    // <style> yes; @media, flex and border-radius partial; <video> no.
+    assert_eq!(outlook["score"], 50);
+    assert_eq!(
+        (
+            &outlook["supported"],
+            &outlook["partial"],
+            &outlook["unsupported"]
+        ),
+        (&json!(1), &json!(3), &json!(1))
+    );
+
+    let (status, body) = get_json(&app.url("/api/messages/2/compatibility")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], "message 2 has no HTML body");
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use common::TestApp;
+use serde_json::Value;
+
+async fn export(app: &TestApp, params: &str) -> reqwest::Response {
+    let response = reqwest::get(app.url(&format!("/api/messages/export?{params}")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    response
+}
+
+async fn import(app: &TestApp, params: &str, body: Vec<u8>) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(app.url(&format!("/api/messages/import?{params}")))
+        .body(body)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn send_three(app: &TestApp) {
+    for subject in ["alpha", "beta", "gamma"] {
+        let body = format!("Subject: {subject}\r\n\r\n{subject}\r\nFrom the desk of {subject}\r\n");
+        app.send("a@example.test", &["b@example.test"], &body).await;
+    }
+}
+
+#[tokio::test]
+async fn exports_and_reimports_every_format() {
+    let app = TestApp::start().await;
+    send_three(&app).await;
+    let mut originals = app.store.list();
+    originals.reverse();
+
+    // Export everything first, so later exports do not include the imports.
+    let mut archives = Vec::new();
+    for format in ["mbox", "maildir", "eml"] {
+        let response = export(&app, &format!("format={format}")).await;
+        let disposition = response.headers()["content-disposition"].to_str().unwrap();
+        assert!(disposition.starts_with("attachment"), "{disposition}");
+        archives.push((format, response.bytes().await.unwrap().to_vec()));
+    }
+
+    for (format, archive) in archives {
+        // Without a format parameter the archive's format is detected.
+        let response = import(&app, "", archive).await;
+        assert_eq!(response.status(), 200);
+        let imported: Value = response.json().await.unwrap();
+        assert_eq!(imported["imported"], 3, "{format}");
+
+        for (original, id) in originals.iter().zip(imported["ids"].as_array().unwrap()) {
+            let copy = app.store.get(id.as_u64().unwrap()).unwrap();
+            assert_eq!(copy.raw, original.raw, "{format}");
+            assert_eq!(copy.summary.subject, original.summary.subject);
+            // mbox keeps times to the second, and .eml zips to two seconds.
+            let drift = (copy.received_at - original.received_at)
+                .num_seconds()
+                .abs();
+            assert!(drift <= 2, "{format}: {drift}s");
+            // Only mbox records the envelope sender.
+            if format == "mbox" {
+                assert_eq!(copy.envelope.mail_from, "a@example.test");
+            }
+        }
+    }
+    assert_eq!(app.store.len(), 12);
+}
+
+#[tokio::test]
+async fn exports_search_results() {
+    let app = TestApp::start().await;
+    send_three(&app).await;
+
+    let response = export(&app, "query=subject:beta").await;
+    assert_eq!(response.headers()["content-type"], "application/mbox");
+    let mbox = String::from_utf8(response.bytes().await.unwrap().to_vec()).unwrap();
+    let envelopes = mbox
+        .lines()
+        .filter(|line| line.starts_with("From "))
+        .count();
+    assert_eq!(envelopes, 1, "{mbox}");
+    assert!(mbox.contains("Subject: beta\n"));
+    assert!(mbox.contains("\n>From the desk of beta\n"));
+}
+
+#[tokio::test]
+async fn rejects_invalid_archives() {
+    let app = TestApp::start().await;
+    let response = import(&app, "", b"Subject: not an archive\r\n\r\n".to_vec()).await;
+    assert_eq!(response.status(), 400);
+    let response = import(&app, "format=eml", b"PK\x03\x04 truncated".to_vec()).await;
+    assert_eq!(response.status(), 400);
+    assert!(app.store.is_empty());
+}
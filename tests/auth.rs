@@ -0,0 +1,139 @@
+mod common;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use common::{start_smtp, SmtpClient, TestApp};
+use rubbermail::auth::{cram_md5_digest, AuthPolicy};
+use rubbermail::smtp::SmtpConfig;
+use serde_json::Value;
+
+fn b64(text: &str) -> String {
+    BASE64.encode(text)
+}
+
+async fn strict_client() -> (SmtpClient, rubbermail::MessageStore) {
+    let config = SmtpConfig {
+        auth: AuthPolicy::from_users(&["mailer:s3cret".parse().unwrap()]),
+        ..SmtpConfig::default()
+    };
+    let (addr, store) = start_smtp(config).await;
+    let mut client = SmtpClient::connect(addr).await;
+    let (_, lines) = client.cmd("EHLO client.test").await;
+    assert!(lines.contains(&"AUTH PLAIN LOGIN CRAM-MD5".to_string()));
+    (client, store)
+}
+
+async fn deliver(client: &mut SmtpClient) -> u16 {
+    assert_eq!(client.cmd("MAIL FROM:<app@example.test>").await.0, 250);
+    assert_eq!(client.cmd("RCPT TO:<bob@example.test>").await.0, 250);
+    assert_eq!(client.cmd("DATA").await.0, 354);
+    client.send_raw(b"Subject: hi\r\n\r\nhello\r\n").await;
+    client.cmd(".").await.0
+}
+
+#[tokio::test]
+async fn accepts_any_credentials_by_default_and_records_the_user() {
+    let app = TestApp::start().await;
+    let mut client = SmtpClient::connect(app.smtp).await;
+    client.cmd("EHLO client.test").await;
+    let (code, lines) = client
+        .cmd(&format!("AUTH PLAIN {}", b64("\0whoever\0whatever")))
+        .await;
+    assert_eq!(
+        (code, lines[0].as_str()),
+        (235, "2.7.0 Authentication successful")
+    );
+    assert_eq!(client.cmd("AUTH PLAIN").await.0, 503);
+    assert_eq!(deliver(&mut client).await, 250);
+
+    let detail: Value = reqwest::get(app.url("/api/messages/1"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(detail["session"]["username"], "whoever");
+}
+
+#[tokio::test]
+async fn unauthenticated_mail_is_accepted_by_default() {
+    let (addr, store) = start_smtp(SmtpConfig::default()).await;
+    let mut client = SmtpClient::connect(addr).await;
+    client.cmd("EHLO client.test").await;
+    assert_eq!(deliver(&mut client).await, 250);
+    assert_eq!(store.get(1).unwrap().session.username, None);
+}
+
+#[tokio::test]
+async fn strict_plain_with_separate_response() {
+    let (mut client, store) = strict_client().await;
+    assert_eq!(client.cmd("MAIL FROM:<app@example.test>").await.0, 530);
+
+    assert_eq!(client.cmd("AUTH PLAIN").await, (334, vec![String::new()]));
+    assert_eq!(client.cmd(&b64("\0mailer\0wrong")).await.0, 535);
+    assert_eq!(client.cmd("AUTH PLAIN").await.0, 334);
+    assert_eq!(client.cmd(&b64("\0mailer\0s3cret")).await.0, 235);
+    assert_eq!(deliver(&mut client).await, 250);
+    assert_eq!(
+        store.get(1).unwrap().session.username.as_deref(),
+        Some("mailer")
+    );
+}
+
+#[tokio::test]
+async fn strict_login() {
+    let (mut client, store) = strict_client().await;
+    assert_eq!(
+        client.cmd("AUTH LOGIN").await,
+        (334, vec![b64("Username:")])
+    );
+    assert_eq!(
+        client.cmd(&b64("mailer")).await,
+        (334, vec![b64("Password:")])
+    );
+    assert_eq!(client.cmd(&b64("s3cret")).await.0, 235);
+    assert_eq!(deliver(&mut client).await, 250);
+    assert_eq!(
+        store.get(1).unwrap().session.username.as_deref(),
+        Some("mailer")
+    );
+}
+
+#[tokio::test]
+async fn strict_cram_md5() {
+    let (mut client, store) = strict_client().await;
+    for (password, code) in [("wrong", 535), ("s3cret", 235)] {
+        let (status, lines) = client.cmd("AUTH CRAM-MD5").await;
+        assert_eq!(status, 334);
+        let challenge = String::from_utf8(BASE64.decode(&lines[0]).unwrap()).unwrap();
+        assert!(challenge.starts_with('<') && challenge.ends_with("@rubbermail>"));
+        let response = format!("mailer {}", cram_md5_digest(password, &challenge));
+        assert_eq!(client.cmd(&b64(&response)).await.0, code);
+    }
+    assert_eq!(deliver(&mut client).await, 250);
+    assert_eq!(
+        store.get(1).unwrap().session.username.as_deref(),
+        Some("mailer")
+    );
+}
+
+#[tokio::test]
+async fn cancelled_malformed_and_unknown_exchanges() {
+    let (mut client, _store) = strict_client().await;
+    assert_eq!(client.cmd("AUTH LOGIN").await.0, 334);
+    assert_eq!(client.cmd("*").await.0, 501);
+    assert_eq!(client.cmd("AUTH PLAIN !!notbase64!!").await.0, 501);
+    assert_eq!(
+        client.cmd(&format!("AUTH PLAIN {}", b64("mailer"))).await.0,
+        501
+    );
+    assert_eq!(client.cmd("AUTH GSSAPI").await.0, 504);
+    // The session is still usable afterwards.
+    assert_eq!(
+        client
+            .cmd(&format!("AUTH PLAIN {}", b64("\0mailer\0s3cret")))
+            .await
+            .0,
+        235
+    );
+}
@@ -0,0 +1,216 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use rubbermail::dns::Records;
+use rubbermail::http::{AppState, HttpServer};
+use rubbermail::release::Relay;
+use rubbermail::smtp::{SmtpConfig, SmtpServer};
+use rubbermail::MessageStore;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// Starts an SMTP listener on a random loopback port.
+pub async fn start_smtp(config: SmtpConfig) -> (SocketAddr, MessageStore) {
+    let store = MessageStore::new();
+    let server = SmtpServer::bind("127.0.0.1:0".parse().unwrap(), config, store.clone())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.serve());
+    (addr, store)
+}
+
+/// SMTP and HTTP listeners sharing one store, fault rules and session log.
+pub struct TestApp {
+    pub smtp: SocketAddr,
+    pub http: String,
+    pub store: MessageStore,
+}
+
+impl TestApp {
+    pub async fn start() -> TestApp {
+        TestApp::start_with(SmtpConfig::default()).await
+    }
+
+    pub async fn start_with(config: SmtpConfig) -> TestApp {
+        TestApp::start_with_state(config, AppState::default()).await
+    }
+
+    /// Starts with releasing to an upstream server enabled.
+    pub async fn start_with_relay(relay: Relay) -> TestApp {
+        let state = AppState {
+            release: Some(Arc::new(relay)),
+            ..AppState::default()
+        };
+        TestApp::start_with_state(SmtpConfig::default(), state).await
+    }
+
+    /// Starts with DKIM keys and SPF and DMARC policies looked up in
+    /// `records`.
+    pub async fn start_with_dns(records: Records) -> TestApp {
+        TestApp::start_with(SmtpConfig {
+            dns: records,
+            ..SmtpConfig::default()
+        })
+        .await
+    }
+
+    /// Starts with `state`, such as a relay or spamd, taking the fault
+    /// rules, session log and mailboxes from `config`. Webhooks are watched;
+    /// any other background task is for the caller to start.
+    pub async fn start_with_state(config: SmtpConfig, state: AppState) -> TestApp {
+        let state = AppState {
+            faults: config.faults.clone(),
+            sessions: config.sessions.clone(),
+            mailboxes: config.mailboxes.clone(),
+            ..state
+        };
+        let store = state.store.clone();
+        state.webhooks.clone().watch(store.clone());
+        let smtp = SmtpServer::bind("127.0.0.1:0".parse().unwrap(), config, store.clone())
+            .await
+            .unwrap();
+        let smtp_addr = smtp.local_addr().unwrap();
+        tokio::spawn(smtp.serve());
+        let server = HttpServer::bind("127.0.0.1:0".parse().unwrap(), state)
+            .await
+            .unwrap();
+        let http = format!("http://{}", server.local_addr().unwrap());
+        tokio::spawn(server.serve());
+        TestApp {
+            smtp: smtp_addr,
+            http,
+            store,
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.http)
+    }
+
+    /// Delivers a message over SMTP.
+    pub async fn send(&self, from: &str, to: &[&str], body: &str) {
+        let mut client = SmtpClient::connect(self.smtp).await;
+        assert_eq!(client.send_mail(from, to, body).await, 250);
+        client.cmd("QUIT").await;
+    }
+}
+
+/// A minimal line-oriented SMTP client for driving the listener.
+pub struct SmtpClient<S = TcpStream> {
+    stream: BufReader<S>,
+}
+
+impl SmtpClient {
+    /// Connects and consumes the 220 greeting.
+    pub async fn connect(addr: SocketAddr) -> SmtpClient {
+        SmtpClient::greeted(TcpStream::connect(addr).await.unwrap()).await
+    }
+
+    /// Connects with implicit TLS and consumes the 220 greeting.
+    pub async fn connect_tls(
+        addr: SocketAddr,
+        connector: &TlsConnector,
+    ) -> SmtpClient<TlsStream<TcpStream>> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream = connector
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        SmtpClient::greeted(stream).await
+    }
+
+    /// Runs the TLS handshake after a successful `STARTTLS`.
+    pub async fn upgrade(self, connector: &TlsConnector) -> SmtpClient<TlsStream<TcpStream>> {
+        let stream = connector
+            .connect(
+                ServerName::try_from("localhost").unwrap(),
+                self.stream.into_inner(),
+            )
+            .await
+            .unwrap();
+        SmtpClient {
+            stream: BufReader::new(stream),
+        }
+    }
+}
+
+/// A TLS client configuration trusting only `cert`.
+pub fn tls_connector(cert: CertificateDer<'static>) -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SmtpClient<S> {
+    /// Wraps a connection without reading the greeting.
+    pub fn from_stream(stream: S) -> SmtpClient<S> {
+        SmtpClient {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    async fn greeted(stream: S) -> SmtpClient<S> {
+        let mut client = SmtpClient::from_stream(stream);
+        let (code, _) = client.read_reply().await;
+        assert_eq!(code, 220);
+        client
+    }
+
+    /// Reads a possibly multi-line reply and returns its code and lines.
+    pub async fn read_reply(&mut self) -> (u16, Vec<String>) {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            let n = self.stream.read_line(&mut line).await.unwrap();
+            assert!(n > 0, "connection closed while waiting for a reply");
+            let line = line.trim_end();
+            let code = line[..3].parse().unwrap();
+            lines.push(line.get(4..).unwrap_or_default().to_string());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return (code, lines);
+            }
+        }
+    }
+
+    pub async fn send_raw(&mut self, data: &[u8]) {
+        self.stream.get_mut().write_all(data).await.unwrap();
+    }
+
+    /// Sends one command line and returns the reply.
+    pub async fn cmd(&mut self, line: &str) -> (u16, Vec<String>) {
+        self.send_raw(format!("{line}\r\n").as_bytes()).await;
+        self.read_reply().await
+    }
+
+    /// Runs a full EHLO/MAIL/RCPT/DATA transaction and returns the final reply code.
+    pub async fn send_mail(&mut self, from: &str, to: &[&str], body: &str) -> u16 {
+        assert_eq!(self.cmd("EHLO client.test").await.0, 250);
+        assert_eq!(self.cmd(&format!("MAIL FROM:<{from}>")).await.0, 250);
+        for rcpt in to {
+            assert_eq!(self.cmd(&format!("RCPT TO:<{rcpt}>")).await.0, 250);
+        }
+        assert_eq!(self.cmd("DATA").await.0, 354);
+        self.send_raw(body.as_bytes()).await;
+        self.cmd(".").await.0
+    }
+
+    pub async fn is_closed(&mut self) -> bool {
+        let mut line = String::new();
+        matches!(self.stream.read_line(&mut line).await, Ok(0) | Err(_))
+    }
+}
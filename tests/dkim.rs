@@ -0,0 +1,149 @@
+mod common;
+
+use common::TestApp;
+use rubbermail::dns::Records;
+use serde_json::Value;
+
+/// The signed example message from RFC 8463, appendix A, with one Ed25519
+/// and one RSA signature.
+const SIGNED: &str = concat!(
+    "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed;\r\n",
+    " d=football.example.com; i=@football.example.com;\r\n",
+    " q=dns/txt; s=brisbane; t=1528637909; h=from : to :\r\n",
+    " subject : date : message-id : from : subject : date;\r\n",
+    " bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n",
+    " b=/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11Bus\r\n",
+    " Fa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw==\r\n",
+    "DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed;\r\n",
+    " d=football.example.com; i=@football.example.com;\r\n",
+    " q=dns/txt; s=test; t=1528637909; h=from : to : subject :\r\n",
+    " date : message-id : from : subject : date;\r\n",
+    " bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n",
+    " b=F45dVWDfMbQDGHJFlXUNB2HKfbCeLRyhDXgFpEL8GwpsRe0IeIixNTe3\r\n",
+    " DhCVlUrSjV4BwcVcOF6+FF3Zo9Rpo1tFOeS9mPYQTnGdaSGsgeefOsk2Jz\r\n",
+    " dA+L10TeYt9BgDfQNZtKdN1WO//KgIqXP7OdEFE4LjFYNcUxZQ4FADY+8=\r\n",
+    "From: Joe SixPack <joe@football.example.com>\r\n",
+    "To: Suzie Q <suzie@shopping.example.net>\r\n",
+    "Subject: Is dinner ready?\r\n",
+    "Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r\n",
+    "Message-ID: <20030712040037.46341.5F8J@football.example.com>\r\n",
+    "\r\n",
+    "Hi.\r\n",
+    "\r\n",
+    "We lost the game.  Are you hungry yet?\r\n",
+    "\r\n",
+    "Joe.\r\n",
+);
+
+/// The public keys from the same appendix.
+const ZONE: &str = r#"
+$ORIGIN football.example.com.
+brisbane._domainkey IN TXT ( "v=DKIM1; k=ed25519;"
+                             " p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=" )
+test._domainkey     IN TXT ( "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDkHlOQoBTzWRiGs5V6NpP3idY6Wk08a5qhdR6wy5bdOKb2jLQiY/J16JYi0Qvx/byYzCNb3W91y3FutACDfzwQ/BC/e/8uBsCR+yz1Lxj+PL6lHvqMKrM3rG4hstT5QjvHO9PzoxZyVYLzBfO2EeC3Ip3G+2kryOTIKT+l/K4w3QIDAQAB" )
+"#;
+
+async fn dkim(app: &TestApp, id: u64) -> Value {
+    let message: Value = reqwest::get(app.url(&format!("/api/messages/{id}")))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    message["dkim"].clone()
+}
+
+#[tokio::test]
+async fn verifies_rsa_and_ed25519_signatures() {
+    let app = TestApp::start_with_dns(Records::parse_zone(ZONE).unwrap()).await;
+    app.send(
+        "joe@football.example.com",
+        &["suzie@shopping.example.net"],
+        SIGNED,
+    )
+    .await;
+
+    let results = dkim(&app, 1).await;
+    assert_eq!(results.as_array().unwrap().len(), 2);
+    assert_eq!(results[0]["domain"], "football.example.com");
+    assert_eq!(results[0]["selector"], "brisbane");
+    assert_eq!(results[0]["algorithm"], "ed25519-sha256");
+    assert_eq!(results[0]["status"], "pass");
+    assert!(results[0]["reason"].is_null());
+    assert_eq!(results[1]["selector"], "test");
+    assert_eq!(results[1]["algorithm"], "rsa-sha256");
+    assert_eq!(results[1]["status"], "pass");
+    assert_eq!(results[1]["signed_headers"][4], "message-id");
+}
+
+#[tokio::test]
+async fn reports_why_signatures_fail() {
+    let app = TestApp::start_with_dns(Records::parse_zone(ZONE).unwrap()).await;
+    app.send(
+        "joe@football.example.com",
+        &["suzie@shopping.example.net"],
+        &SIGNED.replace("We lost", "We won"),
+    )
+    .await;
+    app.send(
+        "joe@football.example.com",
+        &["suzie@shopping.example.net"],
+        &SIGNED.replace("Is dinner ready?", "Is lunch ready?"),
+    )
+    .await;
+
+    let body_changed = dkim(&app, 1).await;
+    for result in body_changed.as_array().unwrap() {
+        assert_eq!(result["status"], "fail");
+        assert_eq!(
+            result["reason"],
+            "body hash does not match; the body was changed after signing"
+        );
+    }
+    let header_changed = dkim(&app, 2).await;
+    assert_eq!(header_changed[0]["status"], "fail");
+    assert!(header_changed[0]["reason"]
+        .as_str()
+        .unwrap()
+        .starts_with("signature does not verify"));
+}
+
+#[tokio::test]
+async fn missing_keys_are_permanent_errors() {
+    // A revoked key has an empty p= tag.
+    let zone = ZONE.replace("11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=", "");
+    let app = TestApp::start_with_dns(Records::parse_zone(&zone).unwrap()).await;
+    app.send(
+        "joe@football.example.com",
+        &["suzie@shopping.example.net"],
+        SIGNED,
+    )
+    .await;
+    app.send(
+        "joe@example.test",
+        &["suzie@example.test"],
+        "Subject: Unsigned\r\n\r\nhi\r\n",
+    )
+    .await;
+
+    let results = dkim(&app, 1).await;
+    assert_eq!(results[0]["status"], "permerror");
+    assert_eq!(results[0]["reason"], "key has been revoked");
+    assert_eq!(results[1]["status"], "pass");
+    assert_eq!(dkim(&app, 2).await, serde_json::json!([]));
+
+    let app = TestApp::start().await;
+    app.send(
+        "joe@football.example.com",
+        &["suzie@shopping.example.net"],
+        SIGNED,
+    )
+    .await;
+    let results = dkim(&app, 1).await;
+    assert_eq!(results[0]["status"], "permerror");
+    assert_eq!(
+        results[0]["reason"],
+        "no key record at brisbane._domainkey.football.example.com"
+    );
+    assert_eq!(results[1]["status"], "permerror");
+}
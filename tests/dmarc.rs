@@ -0,0 +1,100 @@
+mod common;
+
+use common::TestApp;
+use rubbermail::dns::Records;
+use serde_json::Value;
+
+/// Test mail comes from 127.0.0.1, which only `example.com` authorizes.
+const RECORDS: &str = r#"{
+    "example.com": "v=spf1 ip4:127.0.0.0/8 -all",
+    "_dmarc.example.com": "v=DMARC1; p=reject",
+    "example.org": "v=spf1 ip4:192.0.2.0/24 ~all",
+    "_dmarc.example.org": "v=DMARC1; p=quarantine"
+}"#;
+
+async fn message(app: &TestApp, id: u64) -> Value {
+    reqwest::get(app.url(&format!("/api/messages/{id}")))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn passes_aligned_spf() {
+    let app = TestApp::start_with_dns(Records::from_json(RECORDS).unwrap()).await;
+    app.send(
+        "bounces@example.com",
+        &["user@example.test"],
+        "From: App <app@example.com>\r\nSubject: Welcome\r\n\r\nhi\r\n",
+    )
+    .await;
+
+    let message = message(&app, 1).await;
+    assert_eq!(message["spf"]["status"], "pass");
+    assert_eq!(message["spf"]["identity"], "mailfrom");
+    assert_eq!(message["spf"]["domain"], "example.com");
+    assert_eq!(message["spf"]["ip"], "127.0.0.1");
+    assert_eq!(message["spf"]["mechanism"], "ip4:127.0.0.0/8");
+    assert_eq!(message["dmarc"]["status"], "pass");
+    assert_eq!(message["dmarc"]["from_domain"], "example.com");
+    assert_eq!(message["dmarc"]["policy"], "reject");
+    assert_eq!(message["dmarc"]["spf_aligned"], true);
+    assert_eq!(message["dmarc"]["dkim_aligned"], false);
+    assert_eq!(message["dmarc"]["disposition"], "none");
+}
+
+#[tokio::test]
+async fn fails_unauthorized_or_unaligned_senders() {
+    let app = TestApp::start_with_dns(Records::from_json(RECORDS).unwrap()).await;
+    app.send(
+        "bounces@example.org",
+        &["user@example.test"],
+        "From: app@example.org\r\nSubject: Welcome\r\n\r\nhi\r\n",
+    )
+    .await;
+    // SPF passes, but for a domain unrelated to the From header.
+    app.send(
+        "bounces@example.com",
+        &["user@example.test"],
+        "From: app@example.org\r\nSubject: Welcome\r\n\r\nhi\r\n",
+    )
+    .await;
+
+    let unauthorized = message(&app, 1).await;
+    assert_eq!(unauthorized["spf"]["status"], "softfail");
+    assert_eq!(unauthorized["spf"]["mechanism"], "~all");
+    assert_eq!(unauthorized["dmarc"]["status"], "fail");
+    assert_eq!(unauthorized["dmarc"]["disposition"], "quarantine");
+
+    let unaligned = message(&app, 2).await;
+    assert_eq!(unaligned["spf"]["status"], "pass");
+    assert_eq!(unaligned["dmarc"]["status"], "fail");
+    assert_eq!(unaligned["dmarc"]["spf_aligned"], false);
+    assert_eq!(
+        unaligned["dmarc"]["reason"],
+        "no DKIM signature passed with example.org; \
+         SPF passed for example.com, which does not align"
+    );
+}
+
+#[tokio::test]
+async fn reports_domains_without_records() {
+    let app = TestApp::start().await;
+    app.send(
+        "",
+        &["user@example.test"],
+        "From: app@example.com\r\nSubject: Bounce\r\n\r\nhi\r\n",
+    )
+    .await;
+
+    let message = message(&app, 1).await;
+    assert_eq!(message["spf"]["status"], "none");
+    // The null sender is checked by its HELO name.
+    assert_eq!(message["spf"]["identity"], "helo");
+    assert_eq!(message["spf"]["domain"], "client.test");
+    assert_eq!(message["spf"]["reason"], "no SPF record at client.test");
+    assert_eq!(message["dmarc"]["status"], "none");
+    assert!(message["dmarc"]["policy"].is_null());
+}
@@ -0,0 +1,164 @@
+mod common;
+
+use std::time::Duration;
+
+use common::TestApp;
+use futures_util::StreamExt;
+use serde_json::Value;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Reads SSE frames until one complete event is available.
+struct SseReader {
+    response: reqwest::Response,
+    buffer: String,
+}
+
+impl SseReader {
+    async fn connect(app: &TestApp) -> SseReader {
+        let response = reqwest::get(app.url("/api/events")).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        SseReader {
+            response,
+            buffer: String::new(),
+        }
+    }
+
+    /// Returns the next event's name and JSON payload, skipping keep-alives.
+    async fn next(&mut self) -> (String, Value) {
+        loop {
+            if let Some(end) = self.buffer.find("\n\n") {
+                let frame: String = self.buffer.drain(..end + 2).collect();
+                let mut name = None;
+                let mut data = None;
+                for line in frame.lines() {
+                    if let Some(value) = line.strip_prefix("event:") {
+                        name = Some(value.trim().to_string());
+                    } else if let Some(value) = line.strip_prefix("data:") {
+                        data = Some(value.trim().to_string());
+                    }
+                }
+                if let (Some(name), Some(data)) = (name, data) {
+                    return (name, serde_json::from_str(&data).unwrap());
+                }
+                continue;
+            }
+            let chunk = timeout(WAIT, self.response.chunk())
+                .await
+                .expect("timed out waiting for an SSE event")
+                .unwrap()
+                .expect("SSE stream ended");
+            self.buffer.push_str(&String::from_utf8_lossy(&chunk));
+        }
+    }
+}
+
+#[tokio::test]
+async fn sse_streams_received_deleted_and_cleared() {
+    let app = TestApp::start().await;
+    let mut events = SseReader::connect(&app).await;
+
+    app.send(
+        "a@example.test",
+        &["b@example.test"],
+        "Subject: first\r\n\r\nhello\r\n",
+    )
+    .await;
+    let (name, event) = events.next().await;
+    assert_eq!(name, "message_received");
+    assert_eq!(event["type"], "message_received");
+    assert_eq!(event["message"]["id"], 1);
+    assert_eq!(event["message"]["subject"], "first");
+    assert_eq!(event["message"]["snippet"], "hello");
+
+    let client = reqwest::Client::new();
+    client
+        .delete(app.url("/api/messages/1"))
+        .send()
+        .await
+        .unwrap();
+    let (name, event) = events.next().await;
+    assert_eq!(name, "message_deleted");
+    assert_eq!(event["message"]["id"], 1);
+    assert_eq!(event["message"]["subject"], "first");
+
+    app.send(
+        "a@example.test",
+        &["b@example.test"],
+        "Subject: second\r\n\r\n",
+    )
+    .await;
+    app.send(
+        "a@example.test",
+        &["b@example.test"],
+        "Subject: third\r\n\r\n",
+    )
+    .await;
+    client
+        .delete(app.url("/api/messages"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(events.next().await.1["message"]["id"], 2);
+    assert_eq!(events.next().await.1["message"]["id"], 3);
+    let (name, event) = events.next().await;
+    assert_eq!(name, "mailbox_cleared");
+    assert_eq!(event["deleted"], 2);
+}
+
+#[tokio::test]
+async fn websocket_pushes_events_to_every_client() {
+    let app = TestApp::start().await;
+    let ws_url = app.url("/api/ws").replace("http://", "ws://");
+    let (mut first, _) = tokio_tungstenite::connect_async(&ws_url).await.unwrap();
+    let (mut second, _) = tokio_tungstenite::connect_async(&ws_url).await.unwrap();
+
+    app.send(
+        "a@example.test",
+        &["b@example.test"],
+        "Subject: live\r\n\r\nbody\r\n",
+    )
+    .await;
+
+    for socket in [&mut first, &mut second] {
+        let frame = timeout(WAIT, socket.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let Message::Text(text) = frame else {
+            panic!("expected a text frame, got {frame:?}");
+        };
+        let event: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(event["type"], "message_received");
+        assert_eq!(event["message"]["subject"], "live");
+        assert_eq!(event["message"]["envelope"]["rcpt_to"][0], "b@example.test");
+    }
+
+    app.store.delete(1).unwrap();
+    let frame = timeout(WAIT, first.next()).await.unwrap().unwrap().unwrap();
+    let event: Value = serde_json::from_str(frame.to_text().unwrap()).unwrap();
+    assert_eq!(event["type"], "message_deleted");
+}
+
+#[tokio::test]
+async fn store_events_reach_subscribers_in_order() {
+    let app = TestApp::start().await;
+    let mut events = app.store.subscribe();
+    for i in 0..3 {
+        app.send(
+            "a@example.test",
+            &["b@example.test"],
+            &format!("Subject: {i}\r\n\r\n"),
+        )
+        .await;
+    }
+    for expected in 1..=3 {
+        match events.recv().await.unwrap() {
+            rubbermail::store::StoreEvent::Received(message) => assert_eq!(message.id, expected),
+            other => panic!("unexpected event {other:?}"),
+        }
+    }
+}
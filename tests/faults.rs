@@ -0,0 +1,289 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use common::{start_smtp, SmtpClient, TestApp};
+use reqwest::StatusCode;
+use rubbermail::faults::{FaultAction, FaultRule, Faults, Stage};
+use rubbermail::smtp::SmtpConfig;
+use rubbermail::MessageStore;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+
+fn rule(stage: Stage, action: FaultAction) -> FaultRule {
+    FaultRule {
+        stage,
+        action,
+        probability: 1.0,
+        matching: None,
+        times: None,
+    }
+}
+
+async fn start_with(rules: Vec<FaultRule>) -> (SocketAddr, MessageStore) {
+    let config = SmtpConfig {
+        faults: Faults::new(rules).unwrap(),
+        ..SmtpConfig::default()
+    };
+    start_smtp(config).await
+}
+
+#[tokio::test]
+async fn rejects_connections_at_connect_stage() {
+    let (addr, _) = start_with(vec![rule(
+        Stage::Connect,
+        FaultAction::Reply {
+            code: 421,
+            message: Some("4.7.0 Try again later".to_string()),
+        },
+    )])
+    .await;
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut client = SmtpClient::from_stream(stream);
+    let (code, lines) = client.read_reply().await;
+    assert_eq!((code, lines[0].as_str()), (421, "4.7.0 Try again later"));
+    assert!(client.is_closed().await);
+}
+
+#[tokio::test]
+async fn replies_with_errors_for_matching_recipients_only() {
+    let mut bounce = rule(
+        Stage::Rcpt,
+        FaultAction::Reply {
+            code: 550,
+            message: None,
+        },
+    );
+    bounce.matching = Some("BOUNCE@".to_string());
+    let (addr, store) = start_with(vec![bounce]).await;
+
+    let mut client = SmtpClient::connect(addr).await;
+    client.cmd("EHLO client.test").await;
+    client.cmd("MAIL FROM:<app@example.test>").await;
+    let (code, lines) = client.cmd("RCPT TO:<bounce@example.test>").await;
+    assert_eq!(
+        (code, lines[0].as_str()),
+        (550, "5.3.0 Permanent failure (injected)")
+    );
+    assert_eq!(client.cmd("RCPT TO:<bob@example.test>").await.0, 250);
+    assert_eq!(client.cmd("DATA").await.0, 354);
+    client.send_raw(b"Subject: hi\r\n\r\nhello\r\n").await;
+    assert_eq!(client.cmd(".").await.0, 250);
+
+    let stored = store.list();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].envelope.rcpt_to, ["bob@example.test"]);
+}
+
+#[tokio::test]
+async fn temporary_failure_after_data_discards_the_message() {
+    let (addr, store) = start_with(vec![rule(
+        Stage::Message,
+        FaultAction::Reply {
+            code: 451,
+            message: None,
+        },
+    )])
+    .await;
+
+    let mut client = SmtpClient::connect(addr).await;
+    assert_eq!(
+        client
+            .send_mail(
+                "app@example.test",
+                &["bob@example.test"],
+                "Subject: hi\r\n\r\nhi\r\n"
+            )
+            .await,
+        451
+    );
+    // The session carries on: the client may retry on the same connection.
+    assert_eq!(client.cmd("RSET").await.0, 250);
+    assert!(store.is_empty());
+}
+
+#[tokio::test]
+async fn delays_before_handling_a_stage() {
+    let (addr, _) = start_with(vec![rule(Stage::Mail, FaultAction::Delay { millis: 300 })]).await;
+
+    let mut client = SmtpClient::connect(addr).await;
+    client.cmd("EHLO client.test").await;
+    let started = Instant::now();
+    assert_eq!(client.cmd("MAIL FROM:<app@example.test>").await.0, 250);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+}
+
+#[tokio::test]
+async fn disconnects_in_the_middle_of_data() {
+    let (addr, store) = start_with(vec![rule(Stage::Data, FaultAction::Disconnect)]).await;
+
+    let mut client = SmtpClient::connect(addr).await;
+    client.cmd("EHLO client.test").await;
+    client.cmd("MAIL FROM:<app@example.test>").await;
+    client.cmd("RCPT TO:<bob@example.test>").await;
+    assert_eq!(client.cmd("DATA").await.0, 354);
+    client.send_raw(b"Subject: hi\r\n").await;
+    assert!(client.is_closed().await);
+    assert!(store.is_empty());
+}
+
+#[tokio::test]
+async fn stops_responding_until_the_idle_timeout() {
+    let config = SmtpConfig {
+        idle_timeout: Duration::from_millis(300),
+        faults: Faults::new(vec![rule(Stage::Rcpt, FaultAction::Timeout)]).unwrap(),
+        ..SmtpConfig::default()
+    };
+    let (addr, _) = start_smtp(config).await;
+
+    let mut client = SmtpClient::connect(addr).await;
+    client.cmd("EHLO client.test").await;
+    client.cmd("MAIL FROM:<app@example.test>").await;
+    client.send_raw(b"RCPT TO:<bob@example.test>\r\n").await;
+    let started = Instant::now();
+    assert!(client.is_closed().await);
+    assert!(started.elapsed() >= Duration::from_millis(250));
+}
+
+#[tokio::test]
+async fn limited_rules_expire() {
+    let mut once = rule(
+        Stage::Helo,
+        FaultAction::Reply {
+            code: 421,
+            message: None,
+        },
+    );
+    once.times = Some(1);
+    let faults = Faults::new(vec![once]).unwrap();
+    let config = SmtpConfig {
+        faults: faults.clone(),
+        ..SmtpConfig::default()
+    };
+    let (addr, _) = start_smtp(config).await;
+
+    let mut client = SmtpClient::connect(addr).await;
+    assert_eq!(client.cmd("EHLO client.test").await.0, 421);
+    assert_eq!(client.cmd("EHLO client.test").await.0, 250);
+    assert!(faults.rules().is_empty());
+}
+
+#[tokio::test]
+async fn rules_change_at_runtime_through_the_api() {
+    let app = TestApp::start().await;
+    let http = reqwest::Client::new();
+
+    let response = http
+        .put(app.url("/api/faults"))
+        .json(&json!({
+            "rules": [{
+                "stage": "mail",
+                "action": {"type": "reply", "code": 452, "message": "4.3.1 Out of storage"},
+            }]
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["rules"][0]["probability"], 1.0);
+
+    let mut client = SmtpClient::connect(app.smtp).await;
+    client.cmd("EHLO client.test").await;
+    let (code, lines) = client.cmd("MAIL FROM:<app@example.test>").await;
+    assert_eq!((code, lines[0].as_str()), (452, "4.3.1 Out of storage"));
+
+    let response = http.delete(app.url("/api/faults")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(client.cmd("MAIL FROM:<app@example.test>").await.0, 250);
+
+    let body: Value = reqwest::get(app.url("/api/faults"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body, json!({"rules": []}));
+}
+
+#[tokio::test]
+async fn invalid_rules_are_rejected() {
+    let app = TestApp::start().await;
+    let http = reqwest::Client::new();
+    for rules in [
+        json!([{"stage": "rcpt", "action": {"type": "reply", "code": 250}}]),
+        json!([{"stage": "rcpt", "action": {"type": "disconnect"}, "probability": 2}]),
+        json!([{"stage": "quit", "action": {"type": "disconnect"}}]),
+        json!([{"stage": "rcpt", "action": {"type": "disconnect"}, "times": 0}]),
+    ] {
+        let response = http
+            .put(app.url("/api/faults"))
+            .json(&json!({ "rules": rules }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{rules}");
+    }
+}
+
+#[tokio::test]
+async fn injected_faults_show_up_in_the_session_log() {
+    let mut bounce = rule(
+        Stage::Rcpt,
+        FaultAction::Reply {
+            code: 550,
+            message: None,
+        },
+    );
+    bounce.matching = Some("bounce".to_string());
+    let app = TestApp::start_with(SmtpConfig {
+        faults: Faults::new(vec![bounce]).unwrap(),
+        ..SmtpConfig::default()
+    })
+    .await;
+
+    let mut client = SmtpClient::connect(app.smtp).await;
+    client.cmd("EHLO client.test").await;
+    client.cmd("MAIL FROM:<app@example.test>").await;
+    client.cmd("RCPT TO:<bounce@example.test>").await;
+    client.cmd("QUIT").await;
+    app.send(
+        "app@example.test",
+        &["bob@example.test"],
+        "Subject: hi\r\n\r\nhi\r\n",
+    )
+    .await;
+
+    let sessions: Value = reqwest::get(app.url("/api/sessions"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(sessions.as_array().unwrap().len(), 2);
+    assert_eq!(sessions[0]["messages"], json!([1]));
+
+    let first: Value = reqwest::get(app.url(&format!("/api/sessions/{}", sessions[1]["id"])))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(first["protocol"], "smtp");
+    let entries = first["entries"].as_array().unwrap();
+    let fault = entries
+        .iter()
+        .position(|entry| entry["kind"] == "fault")
+        .unwrap();
+    assert_eq!(
+        entries[fault]["text"],
+        "rcpt: reply 550 5.3.0 Permanent failure (injected)"
+    );
+    assert_eq!(entries[fault - 1]["text"], "RCPT TO:<bounce@example.test>");
+    assert_eq!(entries[fault + 1]["kind"], "server");
+
+    let missing = reqwest::get(app.url("/api/sessions/999")).await.unwrap();
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+}
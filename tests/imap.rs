@@ -0,0 +1,443 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{tls_connector, TestApp};
+use rubbermail::auth::AuthPolicy;
+use rubbermail::imap::{ImapConfig, ImapServer};
+use rubbermail::mailbox::Mailboxes;
+use rubbermail::sessions::SessionLog;
+use rubbermail::smtp::SmtpConfig;
+use rubbermail::tls::{Identity, TlsMode};
+use rustls::pki_types::ServerName;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// A scripted IMAP client.
+struct ImapClient<S = TcpStream> {
+    stream: BufReader<S>,
+    tag: u32,
+}
+
+impl ImapClient {
+    /// Connects and returns the client with the greeting.
+    async fn connect(addr: SocketAddr) -> (ImapClient, String) {
+        let mut client = ImapClient {
+            stream: BufReader::new(TcpStream::connect(addr).await.unwrap()),
+            tag: 0,
+        };
+        let greeting = client.read_line().await;
+        assert!(greeting.starts_with("* OK "), "{greeting}");
+        (client, greeting)
+    }
+
+    /// Runs the TLS handshake after a successful `STARTTLS`.
+    async fn upgrade(self, connector: &TlsConnector) -> ImapClient<TlsStream<TcpStream>> {
+        let stream = connector
+            .connect(
+                ServerName::try_from("localhost").unwrap(),
+                self.stream.into_inner(),
+            )
+            .await
+            .unwrap();
+        ImapClient {
+            stream: BufReader::new(stream),
+            tag: self.tag,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ImapClient<S> {
+    /// Reads one response line, with any literals it announces inlined.
+    async fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        loop {
+            let mut part = String::new();
+            let n = self.stream.read_line(&mut part).await.unwrap();
+            assert!(n > 0, "connection closed while waiting for a response");
+            assert!(part.ends_with("\r\n"), "{part:?}");
+            let part = part.trim_end_matches("\r\n");
+            line.push_str(part);
+            let Some(length) = part
+                .strip_suffix('}')
+                .and_then(|part| part.rsplit_once('{'))
+                .and_then(|(_, length)| length.parse::<usize>().ok())
+            else {
+                return line;
+            };
+            let mut literal = vec![0; length];
+            self.stream.read_exact(&mut literal).await.unwrap();
+            line.push_str("\r\n");
+            line.push_str(&String::from_utf8(literal).unwrap());
+        }
+    }
+
+    async fn send(&mut self, data: &[u8]) {
+        self.stream.get_mut().write_all(data).await.unwrap();
+    }
+
+    /// Sends a tagged command and returns the untagged responses and the
+    /// tagged status line without its tag.
+    async fn cmd(&mut self, command: &str) -> (Vec<String>, String) {
+        self.tag += 1;
+        let tag = format!("a{}", self.tag);
+        self.send(format!("{tag} {command}\r\n").as_bytes()).await;
+        self.finish(&tag).await
+    }
+
+    /// Reads responses until the tagged one.
+    async fn finish(&mut self, tag: &str) -> (Vec<String>, String) {
+        let mut untagged = Vec::new();
+        loop {
+            let line = self.read_line().await;
+            match line.strip_prefix(&format!("{tag} ")) {
+                Some(status) => return (untagged, status.to_string()),
+                None => untagged.push(line),
+            }
+        }
+    }
+
+    /// Sends a command that must succeed and returns its untagged responses.
+    async fn ok(&mut self, command: &str) -> Vec<String> {
+        let (untagged, status) = self.cmd(command).await;
+        assert!(status.starts_with("OK "), "{command}: {status}");
+        untagged
+    }
+
+    /// Appends a message with a synchronizing literal.
+    async fn append(&mut self, options: &str, message: &str) -> (Vec<String>, String) {
+        self.tag += 1;
+        let tag = format!("a{}", self.tag);
+        let command = format!("{tag} APPEND INBOX {options} {{{}}}\r\n", message.len());
+        self.send(command.as_bytes()).await;
+        assert_eq!(self.read_line().await, "+ Ready for literal data");
+        self.send(format!("{message}\r\n").as_bytes()).await;
+        self.finish(&tag).await
+    }
+}
+
+/// Starts SMTP and HTTP listeners, plus an IMAP listener on the same store.
+async fn start(mailboxes: Mailboxes, config: ImapConfig) -> (TestApp, SocketAddr) {
+    let app = TestApp::start_with(SmtpConfig {
+        mailboxes: mailboxes.clone(),
+        ..SmtpConfig::default()
+    })
+    .await;
+    let config = ImapConfig {
+        mailboxes,
+        ..config
+    };
+    let server = ImapServer::bind("127.0.0.1:0".parse().unwrap(), config, app.store.clone())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.serve());
+    (app, addr)
+}
+
+const REPORT: &str = "From: \"Ann\" <ann@example.test>\r\nTo: bob@example.test\r\n\
+Subject: Report\r\nContent-Type: multipart/mixed; boundary=b1\r\n\r\n\
+--b1\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nSee attached.\r\n\
+--b1\r\nContent-Type: application/pdf; name=r.pdf\r\n\
+Content-Transfer-Encoding: base64\r\n\r\nJVBERi0=\r\n--b1--\r\n";
+
+#[tokio::test]
+async fn fetches_messages_and_marks_them_seen() {
+    let (app, addr) = start(Mailboxes::default(), ImapConfig::default()).await;
+    app.send("ann@example.test", &["bob@example.test"], REPORT)
+        .await;
+    let id = app.store.list()[0].id;
+
+    let (mut client, greeting) = ImapClient::connect(addr).await;
+    assert!(greeting.contains("IMAP4rev1"), "{greeting}");
+    assert_eq!(
+        client.cmd("SELECT INBOX").await.1,
+        "BAD SELECT needs LOGIN first"
+    );
+    client.ok("LOGIN anyone secret").await;
+    let (listed, _) = client.cmd("LIST \"\" *").await;
+    assert_eq!(listed, ["* LIST (\\HasNoChildren) \"/\" INBOX"]);
+    assert!(client
+        .cmd("SELECT Archive")
+        .await
+        .1
+        .starts_with("NO [NONEXISTENT]"));
+
+    let (untagged, status) = client.cmd("SELECT inbox").await;
+    assert_eq!(status, "OK [READ-WRITE] SELECT completed");
+    assert!(untagged.contains(&"* 1 EXISTS".to_string()));
+    assert!(untagged.contains(&"* OK [UNSEEN 1] First unseen".to_string()));
+    assert!(untagged
+        .iter()
+        .any(|line| line.starts_with("* OK [UIDNEXT ")));
+
+    let fetched = client
+        .ok("FETCH 1 (FLAGS UID RFC822.SIZE ENVELOPE BODYSTRUCTURE)")
+        .await;
+    assert_eq!(fetched.len(), 1);
+    let line = &fetched[0];
+    assert!(
+        line.starts_with(&format!(
+            "* 1 FETCH (FLAGS () UID {id} RFC822.SIZE {} ENVELOPE (",
+            REPORT.len()
+        )),
+        "{line}"
+    );
+    assert!(line.contains("\"Report\" ((\"Ann\" NIL \"ann\" \"example.test\"))"));
+    assert!(line.contains(
+        "BODYSTRUCTURE ((\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 13 1 NIL NIL NIL NIL)"
+    ));
+    assert!(line.ends_with("\"MIXED\" (\"BOUNDARY\" \"b1\") NIL NIL NIL))"));
+
+    let part = client.ok("FETCH 1 BODY.PEEK[1]").await;
+    assert_eq!(part, ["* 1 FETCH (BODY[1] {13}\r\nSee attached.)"]);
+    let partial = client
+        .ok(&format!("UID FETCH {id} BODY.PEEK[1]<4.4>"))
+        .await;
+    assert_eq!(
+        partial,
+        [format!("* 1 FETCH (UID {id} BODY[1]<4> {{4}}\r\natta)")]
+    );
+    assert!(app.store.get(id).unwrap().flags.is_empty());
+
+    let full = client.ok("FETCH 1 BODY[]").await;
+    assert_eq!(
+        full,
+        [format!(
+            "* 1 FETCH (BODY[] {{{}}}\r\n{REPORT} FLAGS (\\Seen))",
+            REPORT.len()
+        )]
+    );
+    let summary: Value = reqwest::get(app.url(&format!("/api/messages/{id}")))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(summary["flags"], serde_json::json!(["\\Seen"]));
+    assert_eq!(client.ok("LOGOUT").await, ["* BYE RubberMail logging out"]);
+}
+
+#[tokio::test]
+async fn searches_stores_flags_and_expunges() {
+    let (app, addr) = start(Mailboxes::default(), ImapConfig::default()).await;
+    for subject in ["alpha", "beta", "gamma"] {
+        let body = format!("Subject: {subject}\r\n\r\n{subject} body\r\n");
+        app.send("a@example.test", &["b@example.test"], &body).await;
+    }
+    let ids: Vec<u64> = app.store.list().iter().rev().map(|m| m.id).collect();
+
+    let (mut client, _) = ImapClient::connect(addr).await;
+    client.ok("LOGIN anyone x").await;
+    client.ok("SELECT INBOX").await;
+    assert_eq!(client.ok("SEARCH SUBJECT beta").await, ["* SEARCH 2"]);
+    assert_eq!(
+        client.ok("SEARCH OR BODY alpha BODY gamma").await,
+        ["* SEARCH 1 3"]
+    );
+    assert_eq!(
+        client.ok("UID SEARCH NOT SUBJECT alpha").await,
+        [format!("* SEARCH {} {}", ids[1], ids[2])]
+    );
+
+    assert_eq!(
+        client.ok("STORE 1:2 +FLAGS (\\Flagged $Label1)").await,
+        [
+            "* 1 FETCH (FLAGS ($Label1 \\Flagged))",
+            "* 2 FETCH (FLAGS ($Label1 \\Flagged))"
+        ]
+    );
+    assert!(client
+        .ok(&format!("UID STORE {} -FLAGS.SILENT ($Label1)", ids[0]))
+        .await
+        .is_empty());
+    assert_eq!(
+        app.store
+            .get(ids[0])
+            .unwrap()
+            .flags
+            .iter()
+            .collect::<Vec<_>>(),
+        ["\\Flagged"]
+    );
+    assert_eq!(client.ok("SEARCH FLAGGED UNSEEN").await, ["* SEARCH 1 2"]);
+    assert_eq!(client.ok("SEARCH KEYWORD $Label1").await, ["* SEARCH 2"]);
+
+    client.ok("STORE 1,3 FLAGS.SILENT (\\Deleted)").await;
+    assert_eq!(client.ok("EXPUNGE").await, ["* 3 EXPUNGE", "* 1 EXPUNGE"]);
+    assert_eq!(app.store.len(), 1);
+    assert_eq!(
+        client.ok("FETCH 1 (UID FLAGS)").await,
+        [format!(
+            "* 1 FETCH (UID {} FLAGS ($Label1 \\Flagged))",
+            ids[1]
+        )]
+    );
+
+    // Deleting a message elsewhere is reported on the next command.
+    app.store.delete(ids[1]).unwrap();
+    assert_eq!(client.ok("NOOP").await, ["* 1 EXPUNGE"]);
+
+    client.ok("EXAMINE INBOX").await;
+    assert!(client
+        .cmd("STORE 1 +FLAGS (\\Seen)")
+        .await
+        .1
+        .starts_with("NO [READ-ONLY]"));
+}
+
+#[tokio::test]
+async fn appends_messages_and_reports_new_mail() {
+    let (app, addr) = start(Mailboxes::default(), ImapConfig::default()).await;
+    let (mut client, _) = ImapClient::connect(addr).await;
+    client.ok("LOGIN anyone x").await;
+    let selected = client.ok("SELECT INBOX").await;
+    let uid_validity = selected
+        .iter()
+        .find_map(|line| line.strip_prefix("* OK [UIDVALIDITY "))
+        .and_then(|rest| rest.split(']').next())
+        .unwrap()
+        .to_string();
+
+    let message = "Subject: draft\r\n\r\nnot sent yet\r\n";
+    let (untagged, status) = client
+        .append("(\\Draft \\Seen) \"05-Mar-2024 12:30:00 +0100\"", message)
+        .await;
+    let stored = app.store.list()[0].clone();
+    assert_eq!(
+        status,
+        format!(
+            "OK [APPENDUID {uid_validity} {}] APPEND completed",
+            stored.id
+        )
+    );
+    assert_eq!(stored.raw, message.as_bytes());
+    assert_eq!(stored.received_at.to_rfc3339(), "2024-03-05T11:30:00+00:00");
+    assert_eq!(
+        stored.flags.iter().collect::<Vec<_>>(),
+        ["\\Draft", "\\Seen"]
+    );
+    // The new message is announced with the APPEND response.
+    assert_eq!(untagged, ["* 1 EXISTS"]);
+    assert_eq!(
+        client.ok("FETCH 1 INTERNALDATE").await,
+        ["* 1 FETCH (INTERNALDATE \"05-Mar-2024 11:30:00 +0000\")"]
+    );
+
+    app.send(
+        "a@example.test",
+        &["b@example.test"],
+        "Subject: new\r\n\r\nnew\r\n",
+    )
+    .await;
+    assert_eq!(client.ok("NOOP").await, ["* 2 EXISTS"]);
+    let (_, status) = client.cmd("COPY 2 INBOX").await;
+    assert!(status.starts_with("OK [COPYUID "), "{status}");
+    assert_eq!(app.store.len(), 3);
+    assert!(client
+        .cmd("COPY 1 Trash")
+        .await
+        .1
+        .starts_with("NO [TRYCREATE]"));
+}
+
+#[tokio::test]
+async fn strict_auth_maps_users_to_mailboxes() {
+    let sessions = SessionLog::new();
+    let config = ImapConfig {
+        auth: AuthPolicy::from_users(&[
+            "alice:s3cret".parse().unwrap(),
+            "billing-app:x".parse().unwrap(),
+        ]),
+        sessions: sessions.clone(),
+        ..ImapConfig::default()
+    };
+    let mailboxes = Mailboxes::new(vec!["billing:user=billing-app".parse().unwrap()]);
+    let (app, addr) = start(mailboxes, config).await;
+    app.send(
+        "a@example.test",
+        &["b@example.test"],
+        "Subject: x\r\n\r\nx\r\n",
+    )
+    .await;
+
+    let (mut client, _) = ImapClient::connect(addr).await;
+    assert_eq!(
+        client.cmd("LOGIN alice wrong").await.1,
+        "NO [AUTHENTICATIONFAILED] invalid credentials"
+    );
+    client.ok("LOGIN alice \"s3cret\"").await;
+    let (untagged, _) = client.cmd("SELECT INBOX").await;
+    assert!(untagged.contains(&"* 1 EXISTS".to_string()));
+    client.ok("LOGOUT").await;
+
+    let (mut client, _) = ImapClient::connect(addr).await;
+    client.ok("LOGIN billing-app x").await;
+    let (untagged, _) = client.cmd("SELECT INBOX").await;
+    assert!(untagged.contains(&"* 0 EXISTS".to_string()));
+    client.ok("LOGOUT").await;
+
+    // Sessions are listed newest first.
+    let session = &sessions.list()[1];
+    assert_eq!(session.protocol, "imap");
+    let entries: Vec<&str> = session.entries.iter().map(|e| e.text.as_str()).collect();
+    assert!(entries.contains(&"a2 LOGIN <credentials>"));
+    assert!(!entries.iter().any(|text| text.contains("s3cret")));
+}
+
+#[tokio::test]
+async fn starttls_upgrades_before_login() {
+    let identity = Identity::self_signed("rubbermail").unwrap();
+    let cert = identity.certs[0].clone();
+    let config = ImapConfig {
+        tls: TlsMode::StartTls(identity.acceptor().unwrap()),
+        ..ImapConfig::default()
+    };
+    let (_app, addr) = start(Mailboxes::default(), config).await;
+    let (mut client, greeting) = ImapClient::connect(addr).await;
+    assert!(greeting.contains(" STARTTLS]"), "{greeting}");
+    client.ok("STARTTLS").await;
+
+    let mut client = client.upgrade(&tls_connector(cert)).await;
+    let capabilities = client.ok("CAPABILITY").await;
+    assert_eq!(capabilities, ["* CAPABILITY IMAP4rev1 UIDPLUS"]);
+    assert!(client.cmd("STARTTLS").await.1.starts_with("BAD"));
+    client.ok("LOGIN alice x").await;
+    client.ok("LOGOUT").await;
+}
+
+#[tokio::test]
+async fn rejects_oversized_literals() {
+    let config = ImapConfig {
+        max_message_size: 1024,
+        ..ImapConfig::default()
+    };
+    let (_app, addr) = start(Mailboxes::default(), config).await;
+    let (mut client, _) = ImapClient::connect(addr).await;
+    // Before login only small literals are accepted, and nothing is
+    // allocated for the announced size.
+    client.send(b"a1 LOGIN {67108864}\r\n").await;
+    assert_eq!(client.read_line().await, "a1 BAD literal too large");
+    client.send(b"a2 LOGIN {5}\r\n").await;
+    assert_eq!(client.read_line().await, "+ Ready for literal data");
+    client.send(b"alice x\r\n").await;
+    assert!(client.finish("a2").await.1.starts_with("OK "));
+
+    // After login the limit is the largest message.
+    client.send(b"a3 APPEND INBOX {2000}\r\n").await;
+    assert_eq!(client.read_line().await, "a3 BAD literal too large");
+    client.tag = 3;
+    let (_, status) = client.append("", "Subject: small\r\n\r\nhi").await;
+    assert!(status.starts_with("OK "), "{status}");
+
+    // A non-synchronizing literal is already on its way, so the connection
+    // is closed instead.
+    client.send(b"a5 APPEND INBOX {2000+}\r\n").await;
+    assert_eq!(client.read_line().await, "* BYE literal too large");
+    let mut rest = Vec::new();
+    client.stream.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty(), "{rest:?}");
+}
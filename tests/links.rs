@@ -0,0 +1,206 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use axum::response::{IntoResponse, Redirect};
+use axum::routing::{any, get};
+use axum::Router;
+use common::TestApp;
+use rubbermail::http::AppState;
+use rubbermail::smtp::SmtpConfig;
+use serde_json::Value;
+use tokio::net::TcpListener;
+
+/// A site with a working page, a missing one, one that rejects HEAD, and a
+/// redirect.
+async fn start_site() -> String {
+    let app = Router::new()
+        .route("/ok", get(|| async { "ok" }))
+        .route(
+            "/no-head",
+            any(|method: Method| async move {
+                if method == Method::HEAD {
+                    StatusCode::METHOD_NOT_ALLOWED.into_response()
+                } else {
+                    "ok".into_response()
+                }
+            }),
+        )
+        .route("/moved", get(|| async { Redirect::permanent("/ok") }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    format!("http://{addr}")
+}
+
+async fn links(app: &TestApp, query: &str) -> Value {
+    let response = reqwest::get(app.url(&format!("/api/messages/1/links{query}")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+fn find<'a>(report: &'a Value, url: &str) -> &'a Value {
+    report["links"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|link| link["url"] == url)
+        .unwrap_or_else(|| panic!("{url} not in {report}"))
+}
+
+fn issue_kinds(link: &Value) -> Vec<&str> {
+    link["issues"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|issue| issue["kind"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn reports_link_problems() {
+    let app = TestApp::start().await;
+    app.send(
+        "news@example.test",
+        &["bob@example.test"],
+        "Subject: Welcome\r\n\
+Content-Type: multipart/alternative; boundary=b\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Confirm at https://example.com/confirm?t={{token}}.\r\n\
+--b\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<a href=\"https://example.com/\">Home</a>\r\n\
+<img src=\"http://example.com/logo.png\">\r\n\
+<a href=\"http://localhost:3000/settings\">Settings</a>\r\n\
+<a href=\"/unsubscribe\">Unsubscribe</a>\r\n\
+<a href=\"mailto:help@example.com\">Help</a>\r\n\
+--b--\r\n",
+    )
+    .await;
+
+    let report = links(&app, "").await;
+    assert_eq!(report["checked"], false);
+    assert_eq!(report["links"].as_array().unwrap().len(), 6);
+
+    let home = find(&report, "https://example.com/");
+    assert_eq!(home["found_in"][0], "a href");
+    assert!(issue_kinds(home).is_empty());
+    assert!(home.get("check").is_none());
+    assert_eq!(
+        issue_kinds(find(&report, "http://example.com/logo.png")),
+        ["mixed_scheme"]
+    );
+    assert_eq!(
+        issue_kinds(find(&report, "http://localhost:3000/settings")),
+        ["internal_host", "mixed_scheme"]
+    );
+    assert_eq!(issue_kinds(find(&report, "/unsubscribe")), ["malformed"]);
+    assert!(issue_kinds(find(&report, "mailto:help@example.com")).is_empty());
+    let confirm = find(&report, "https://example.com/confirm?t={{token}}");
+    assert_eq!(confirm["found_in"][0], "text");
+    assert_eq!(issue_kinds(confirm), ["template_variable"]);
+}
+
+#[tokio::test]
+async fn checks_reachability_on_request() {
+    let site = start_site().await;
+    // A port with nothing listening on it.
+    let closed = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+    // The test site is on loopback.
+    let state = AppState {
+        check_internal_links: true,
+        ..AppState::default()
+    };
+    let app = TestApp::start_with_state(SmtpConfig::default(), state).await;
+    app.send(
+        "news@example.test",
+        &["bob@example.test"],
+        &format!(
+            "Subject: Links\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<a href=\"{site}/ok\">ok</a> <a href=\"{site}/missing\">missing</a>\r\n\
+<a href=\"{site}/no-head\">no head</a> <a href=\"{site}/moved\">moved</a>\r\n\
+<a href=\"http://{closed}/\">closed</a> <a href=\"{site}/{{{{id}}}}\">template</a>\r\n"
+        ),
+    )
+    .await;
+
+    let report = links(&app, "?check=true").await;
+    assert_eq!(report["checked"], true);
+
+    let ok = find(&report, &format!("{site}/ok"));
+    assert_eq!(ok["check"]["method"], "HEAD");
+    assert_eq!(ok["check"]["status"], 200);
+    assert!(!issue_kinds(ok).contains(&"unreachable"));
+
+    let missing = find(&report, &format!("{site}/missing"));
+    assert_eq!(missing["check"]["status"], 404);
+    assert!(issue_kinds(missing).contains(&"unreachable"));
+
+    let no_head = find(&report, &format!("{site}/no-head"));
+    assert_eq!(no_head["check"]["method"], "GET");
+    assert_eq!(no_head["check"]["status"], 200);
+
+    let moved = find(&report, &format!("{site}/moved"));
+    assert_eq!(moved["check"]["status"], 200);
+    assert_eq!(moved["check"]["redirected_to"], format!("{site}/ok"));
+
+    let closed = find(&report, &format!("http://{closed}/"));
+    assert!(closed["check"]["status"].is_null());
+    assert!(closed["check"]["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("connection failed"));
+    assert!(issue_kinds(closed).contains(&"unreachable"));
+
+    // Links with a placeholder are not requested.
+    let template = find(&report, &format!("{site}/{{{{id}}}}"));
+    assert!(template.get("check").is_none());
+}
+
+#[tokio::test]
+async fn internal_hosts_are_not_requested_by_default() {
+    let site = start_site().await;
+    let app = TestApp::start().await;
+    app.send(
+        "news@example.test",
+        &["bob@example.test"],
+        &format!(
+            "Subject: Links\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<a href=\"{site}/ok\">ok</a>\r\n\
+<a href=\"http://169.254.169.254/latest/meta-data/\">metadata</a>\r\n"
+        ),
+    )
+    .await;
+
+    let report = links(&app, "?check=true").await;
+    assert_eq!(report["checked"], true);
+    for url in [
+        format!("{site}/ok"),
+        "http://169.254.169.254/latest/meta-data/".to_string(),
+    ] {
+        let link = find(&report, &url);
+        assert_eq!(issue_kinds(link), ["internal_host"], "{url}");
+        assert!(link.get("check").is_none(), "{url} was requested");
+    }
+}
+
+#[tokio::test]
+async fn unknown_message_is_not_found() {
+    let app = TestApp::start().await;
+    let response = reqwest::get(app.url("/api/messages/9/links"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,236 @@
+mod common;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use common::{SmtpClient, TestApp};
+use rubbermail::mailbox::Mailboxes;
+use rubbermail::smtp::{SmtpConfig, SmtpServer};
+use serde_json::Value;
+
+fn mailboxes(rules: &[&str]) -> Mailboxes {
+    Mailboxes::new(rules.iter().map(|rule| rule.parse().unwrap()).collect())
+}
+
+async fn start(rules: &[&str]) -> TestApp {
+    TestApp::start_with(SmtpConfig {
+        mailboxes: mailboxes(rules),
+        ..SmtpConfig::default()
+    })
+    .await
+}
+
+async fn get(app: &TestApp, path: &str) -> (u16, Value) {
+    let response = reqwest::get(app.url(path)).await.unwrap();
+    let status = response.status().as_u16();
+    (status, response.json().await.unwrap())
+}
+
+fn ids(list: &Value) -> Vec<u64> {
+    list["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["id"].as_u64().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn routes_by_recipient_and_keeps_mailboxes_apart() {
+    let app = start(&["team-a:rcpt=*@team-a.test", "team-b:rcpt=*@team-b.test"]).await;
+    app.send(
+        "app@example.test",
+        &["x@team-a.test"],
+        "Subject: one\r\n\r\na\r\n",
+    )
+    .await;
+    app.send(
+        "app@example.test",
+        &["x@team-b.test"],
+        "Subject: two\r\n\r\nb\r\n",
+    )
+    .await;
+    app.send(
+        "app@example.test",
+        &["x@example.test"],
+        "Subject: three\r\n\r\nc\r\n",
+    )
+    .await;
+    // A message for both teams is filed into each, as separate copies.
+    let mut client = SmtpClient::connect(app.smtp).await;
+    client.cmd("EHLO client.test").await;
+    client.cmd("MAIL FROM:<app@example.test>").await;
+    client.cmd("RCPT TO:<y@team-b.test>").await;
+    client.cmd("RCPT TO:<y@team-a.test>").await;
+    client.cmd("DATA").await;
+    client.send_raw(b"Subject: both\r\n\r\nd\r\n").await;
+    assert_eq!(
+        client.cmd(".").await,
+        (250, vec!["2.0.0 Ok: queued as 4,5".to_string()])
+    );
+
+    let (_, list) = get(&app, "/api/mailboxes").await;
+    let totals: Vec<_> = list
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| (m["name"].as_str().unwrap(), m["total"].as_u64().unwrap()))
+        .collect();
+    assert_eq!(totals, [("default", 1), ("team-a", 2), ("team-b", 2)]);
+    assert_eq!(list[1]["rules"][0]["type"], "rcpt");
+    assert_eq!(list[1]["rules"][0]["value"], "*@team-a.test");
+
+    let (_, team_a) = get(&app, "/api/mailboxes/team-a/messages").await;
+    assert_eq!(ids(&team_a), [4, 1]);
+    assert_eq!(team_a["messages"][0]["mailbox"], "team-a");
+    let (_, default) = get(&app, "/api/messages").await;
+    assert_eq!(ids(&default), [3]);
+    let (_, named_default) = get(&app, "/api/mailboxes/default/messages").await;
+    assert_eq!(ids(&named_default), [3]);
+    let (_, found) = get(&app, "/api/mailboxes/team-b/messages?query=subject:both").await;
+    assert_eq!(ids(&found), [5]);
+
+    let (status, _) = get(&app, "/api/mailboxes/team-a/messages/2").await;
+    assert_eq!(status, 404);
+    let (status, _) = get(&app, "/api/messages/1").await;
+    assert_eq!(status, 404);
+    let (status, body) = get(&app, "/api/mailboxes/team-a/messages/1").await;
+    assert_eq!((status, body["mailbox"].as_str()), (200, Some("team-a")));
+    let (status, body) = get(&app, "/api/mailboxes/nope/messages").await;
+    assert_eq!(status, 404);
+    assert_eq!(body["error"], "mailbox \"nope\" not found");
+}
+
+#[tokio::test]
+async fn deleting_only_touches_one_mailbox() {
+    let app = start(&["team-a:rcpt=*@team-a.test"]).await;
+    app.send(
+        "app@example.test",
+        &["x@team-a.test"],
+        "Subject: a\r\n\r\na\r\n",
+    )
+    .await;
+    app.send(
+        "app@example.test",
+        &["x@example.test"],
+        "Subject: b\r\n\r\nb\r\n",
+    )
+    .await;
+    app.send(
+        "app@example.test",
+        &["y@team-a.test"],
+        "Subject: c\r\n\r\nc\r\n",
+    )
+    .await;
+    let http = reqwest::Client::new();
+
+    let status = http
+        .delete(app.url("/api/messages/1"))
+        .send()
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status, 404);
+    let deleted: Value = http
+        .delete(app.url("/api/mailboxes/team-a/messages"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(deleted["deleted"], 2);
+    assert_eq!(app.store.len(), 1);
+    let (_, default) = get(&app, "/api/messages").await;
+    assert_eq!(ids(&default), [2]);
+}
+
+#[tokio::test]
+async fn routes_by_user_and_listener_port() {
+    let probe = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = probe.local_addr().unwrap().port();
+    drop(probe);
+    let config = SmtpConfig {
+        mailboxes: mailboxes(&["billing:user=billing-app", &format!("ci:port={port}")]),
+        ..SmtpConfig::default()
+    };
+    let app = TestApp::start_with(config.clone()).await;
+    let second = SmtpServer::bind(
+        format!("127.0.0.1:{port}").parse().unwrap(),
+        config,
+        app.store.clone(),
+    )
+    .await
+    .unwrap();
+    tokio::spawn(second.serve());
+
+    let mut client = SmtpClient::connect(app.smtp).await;
+    client.cmd("EHLO client.test").await;
+    let login = BASE64.encode("\0billing-app\0secret");
+    assert_eq!(client.cmd(&format!("AUTH PLAIN {login}")).await.0, 235);
+    client.cmd("MAIL FROM:<app@example.test>").await;
+    client.cmd("RCPT TO:<x@example.test>").await;
+    client.cmd("DATA").await;
+    client.send_raw(b"Subject: invoice\r\n\r\nx\r\n").await;
+    assert_eq!(client.cmd(".").await.0, 250);
+
+    let mut client = SmtpClient::connect(format!("127.0.0.1:{port}").parse().unwrap()).await;
+    assert_eq!(
+        client
+            .send_mail(
+                "ci@example.test",
+                &["x@example.test"],
+                "Subject: build\r\n\r\nx\r\n"
+            )
+            .await,
+        250
+    );
+
+    let (_, billing) = get(&app, "/api/mailboxes/billing/messages").await;
+    assert_eq!(ids(&billing), [1]);
+    let (_, ci) = get(&app, "/api/mailboxes/ci/messages").await;
+    assert_eq!(ids(&ci), [2]);
+    let (_, default) = get(&app, "/api/messages").await;
+    assert!(ids(&default).is_empty());
+}
+
+#[tokio::test]
+async fn waits_only_for_its_own_mailbox() {
+    let app = start(&["team-a:rcpt=*@team-a.test"]).await;
+    app.send(
+        "app@example.test",
+        &["x@example.test"],
+        "Subject: other\r\n\r\nx\r\n",
+    )
+    .await;
+    let (status, body) = get(&app, "/api/mailboxes/team-a/messages/wait?timeout=0.3").await;
+    assert_eq!(status, 404);
+    assert_eq!(body["timed_out"], true);
+
+    let waiter = tokio::spawn({
+        let url = app.url("/api/mailboxes/team-a/messages/wait?timeout=5");
+        async move {
+            reqwest::get(url)
+                .await
+                .unwrap()
+                .json::<Value>()
+                .await
+                .unwrap()
+        }
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    app.send(
+        "app@example.test",
+        &["x@example.test"],
+        "Subject: still other\r\n\r\nx\r\n",
+    )
+    .await;
+    app.send(
+        "app@example.test",
+        &["x@team-a.test"],
+        "Subject: mine\r\n\r\nx\r\n",
+    )
+    .await;
+    let message = waiter.await.unwrap();
+    assert_eq!(message["subject"], "mine");
+    assert_eq!(message["mailbox"], "team-a");
+}
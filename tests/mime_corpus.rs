@@ -0,0 +1,283 @@
+//! Runs the MIME parser over the sample corpus in `tests/corpus`, checks the
+//! structure it extracts, and hammers it with mutated copies of every sample
+//! to make sure malformed input never panics.
+
+use std::fs;
+use std::path::Path;
+
+use rubbermail::mime::{Body, Message};
+
+fn corpus() -> Vec<(String, Vec<u8>)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "eml"))
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, fs::read(path).unwrap())
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+fn load(name: &str) -> Message {
+    let (_, raw) = corpus().into_iter().find(|(n, _)| n == name).unwrap();
+    Message::parse(&raw)
+}
+
+/// Checks invariants that must hold for any parse result.
+fn check_invariants(message: &Message) {
+    for attachment in message.attachments.iter().chain(&message.inlines) {
+        let part = message
+            .part(&attachment.section)
+            .unwrap_or_else(|| panic!("section {:?} does not resolve", attachment.section));
+        assert_eq!(part.data().len(), attachment.size);
+    }
+    for inline in &message.inlines {
+        let cid = inline.content_id.as_deref().unwrap();
+        assert!(message.inline_part(cid).is_some());
+    }
+    if let Body::Message { message, .. } = &message.root.body {
+        check_invariants(message);
+    }
+}
+
+#[test]
+fn multipart_alternative_with_encoded_headers() {
+    let message = load("alternative.eml");
+    assert_eq!(message.subject.as_deref(), Some("Password reset 🔑"));
+    assert_eq!(message.from[0].name.as_deref(), Some("Alice Example"));
+    assert_eq!(message.from[0].email, "alice@example.test");
+    let to: Vec<_> = message.to.iter().map(ToString::to_string).collect();
+    assert_eq!(to, ["bob@example.test", "Renée <renee@example.test>"]);
+    assert_eq!(message.cc.len(), 2);
+    assert_eq!(message.message_id.as_deref(), Some("reset-1@example.test"));
+    assert_eq!(
+        message.date.unwrap().to_rfc3339(),
+        "2025-02-13T09:30:00+00:00"
+    );
+    assert_eq!(
+        message.text.as_deref(),
+        Some("Hi Bob,\r\nreset your password here: https://app.example.test/reset?token=abc (valid for 1 hour)")
+    );
+    assert!(message
+        .html
+        .as_deref()
+        .unwrap()
+        .contains("href=\"https://app.example.test/reset?token=abc\""));
+    assert!(message.attachments.is_empty());
+}
+
+#[test]
+fn related_inline_image_and_attachments() {
+    let message = load("related_attachments.eml");
+    assert_eq!(
+        message.html.as_deref(),
+        Some("<p>Merci pour votre achat, café offert.</p><img src=\"cid:logo@example.test\">")
+    );
+    assert!(message.text.is_none());
+
+    assert_eq!(message.inlines.len(), 1);
+    let logo = message.inline_part("cid:logo@example.test").unwrap();
+    assert_eq!(logo.content_type.mime_type, "image/png");
+    assert_eq!(logo.data(), b"\x89PNG\r\n\x1a\n");
+    assert_eq!(message.inlines[0].section, [1, 2]);
+
+    let names: Vec<_> = message
+        .attachments
+        .iter()
+        .map(|a| (a.filename.as_deref().unwrap(), a.content_type.as_str()))
+        .collect();
+    assert_eq!(
+        names,
+        [
+            ("facture n°42.pdf", "application/pdf"),
+            ("items.csv", "text/csv")
+        ]
+    );
+    assert_eq!(
+        message.attachments[0].disposition.as_deref(),
+        Some("attachment")
+    );
+    let pdf = message.part(&message.attachments[0].section).unwrap();
+    assert_eq!(pdf.data(), b"%PDF-1.4\n");
+}
+
+#[test]
+fn bare_lf_line_endings() {
+    let message = load("bare_lf.eml");
+    assert_eq!(message.subject.as_deref(), Some("bare LF"));
+    assert_eq!(message.text.as_deref(), Some("line one\nline two"));
+    assert_eq!(message.html.as_deref(), Some("<b>bold</b>"));
+}
+
+#[test]
+fn unterminated_boundary_keeps_last_part() {
+    let message = load("unterminated_boundary.eml");
+    assert_eq!(message.text.as_deref(), Some("first part"));
+    assert_eq!(message.attachments.len(), 1);
+    assert_eq!(message.attachments[0].filename.as_deref(), Some("data.bin"));
+    assert_eq!(message.attachments[0].size, 13);
+}
+
+#[test]
+fn missing_boundary_parameter_is_sniffed() {
+    let message = load("missing_boundary_param.eml");
+    assert_eq!(message.text.as_deref(), Some("sniffed text"));
+    assert_eq!(message.html.as_deref(), Some("<i>sniffed html</i>"));
+}
+
+#[test]
+fn multipart_without_delimiters_falls_back_to_text() {
+    let message = load("no_delimiters.eml");
+    assert_eq!(
+        message.text.as_deref(),
+        Some("There are no delimiter lines at all.\r\n")
+    );
+}
+
+#[test]
+fn unknown_charset_falls_back() {
+    let message = load("unknown_charset.eml");
+    assert_eq!(message.subject.as_deref(), Some("é unknown"));
+    assert_eq!(message.from[0].name.as_deref(), Some("Qaplaé"));
+    assert_eq!(
+        message.text.as_deref(),
+        Some("café latin1 bytes under an unknown label\r\n")
+    );
+}
+
+#[test]
+fn forwarded_message_is_an_attachment_with_parsed_content() {
+    let message = load("forwarded.eml");
+    assert_eq!(message.text.as_deref(), Some("See below."));
+    assert_eq!(message.attachments.len(), 1);
+    assert_eq!(message.attachments[0].content_type, "message/rfc822");
+    let Body::Message { message: inner, .. } = &message.part(&[2]).unwrap().body else {
+        panic!("expected an encapsulated message");
+    };
+    assert_eq!(inner.subject.as_deref(), Some("original"));
+    assert_eq!(inner.text.as_deref(), Some("inner text"));
+    assert_eq!(message.part(&[2, 1]).unwrap().text(), Some("inner text"));
+}
+
+#[test]
+fn eight_bit_headers_and_junk_lines() {
+    let message = load("raw_8bit_headers.eml");
+    assert_eq!(message.subject.as_deref(), Some("8-bit header café"));
+    assert_eq!(message.from[0].name.as_deref(), Some("José"));
+    assert_eq!(message.headers().get("X-Folded"), Some("first\tsecond"));
+    assert_eq!(message.headers().len(), 3);
+    assert!(message.text.as_deref().unwrap().starts_with("Body with"));
+}
+
+#[test]
+fn whole_corpus_satisfies_invariants() {
+    for (name, raw) in corpus() {
+        let message = Message::parse(&raw);
+        assert!(
+            message.text.is_some() || message.html.is_some(),
+            "{name}: no body found"
+        );
+        check_invariants(&message);
+    }
+}
+
+/// Small deterministic PRNG so mutation runs are reproducible.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+const INTERESTING: &[&[u8]] = &[
+    b"\r\n",
+    b"\n",
+    b"\r",
+    b"--",
+    b"=?",
+    b"?=",
+    b"=?utf-8?B?",
+    b"=?x?Q?=",
+    b"=",
+    b"=\r\n",
+    b";",
+    b"*0*=",
+    b"\"",
+    b"<",
+    b">",
+    b"(",
+    b"\\",
+    b":",
+    b"\xff",
+    b"\xc3",
+    b"Content-Type: multipart/mixed; boundary=x\r\n\r\n--x\r\n",
+    b"Content-Type: message/rfc822\r\n\r\n",
+    b"Content-Transfer-Encoding: base64\r\n",
+];
+
+fn mutate(rng: &mut XorShift, input: &[u8]) -> Vec<u8> {
+    let mut data = input.to_vec();
+    for _ in 0..1 + rng.below(4) {
+        let at = rng.below(data.len() + 1);
+        match rng.below(5) {
+            0 => data.truncate(at),
+            1 if at < data.len() => data[at] = rng.next() as u8,
+            2 => {
+                let token = INTERESTING[rng.below(INTERESTING.len())];
+                data.splice(at..at, token.iter().copied());
+            }
+            3 if at < data.len() => {
+                let end = (at + rng.below(32)).min(data.len());
+                data.drain(at..end);
+            }
+            _ => {
+                // Duplicate a slice, which tends to repeat boundaries and headers.
+                let end = (at + rng.below(64)).min(data.len());
+                let copy = data[at..end].to_vec();
+                data.splice(at..at, copy);
+            }
+        }
+    }
+    data
+}
+
+#[test]
+fn mutated_corpus_never_panics() {
+    let mut rng = XorShift(0x5eed_1234_abcd_9876);
+    for (_, raw) in corpus() {
+        for _ in 0..400 {
+            let mutated = mutate(&mut rng, &raw);
+            check_invariants(&Message::parse(&mutated));
+        }
+        for len in 0..raw.len() {
+            check_invariants(&Message::parse(&raw[..len]));
+        }
+    }
+}
+
+#[test]
+fn deep_nesting_is_bounded() {
+    let mut raw = Vec::new();
+    for i in 0..200 {
+        raw.extend_from_slice(
+            format!("Content-Type: multipart/mixed; boundary=b{i}\r\n\r\n--b{i}\r\n").as_bytes(),
+        );
+    }
+    raw.extend_from_slice(b"\r\ninnermost\r\n");
+    for _ in 0..200 {
+        raw.extend_from_slice(b"Content-Type: message/rfc822\r\n\r\n");
+    }
+    check_invariants(&Message::parse(&raw));
+}
@@ -0,0 +1,282 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{tls_connector, TestApp};
+use rubbermail::auth::AuthPolicy;
+use rubbermail::mailbox::Mailboxes;
+use rubbermail::pop3::{Pop3Config, Pop3Server};
+use rubbermail::sessions::SessionLog;
+use rubbermail::smtp::SmtpConfig;
+use rubbermail::tls::{Identity, TlsMode};
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// A scripted POP3 client.
+struct Pop3Client<S = TcpStream> {
+    stream: BufReader<S>,
+}
+
+impl Pop3Client {
+    /// Connects and consumes the greeting.
+    async fn connect(addr: SocketAddr) -> Pop3Client {
+        let mut client = Pop3Client {
+            stream: BufReader::new(TcpStream::connect(addr).await.unwrap()),
+        };
+        let greeting = client.read_line().await;
+        assert!(greeting.starts_with("+OK "), "{greeting}");
+        client
+    }
+
+    /// Runs the TLS handshake after a successful `STLS`.
+    async fn upgrade(self, connector: &TlsConnector) -> Pop3Client<TlsStream<TcpStream>> {
+        let stream = connector
+            .connect(
+                ServerName::try_from("localhost").unwrap(),
+                self.stream.into_inner(),
+            )
+            .await
+            .unwrap();
+        Pop3Client {
+            stream: BufReader::new(stream),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Pop3Client<S> {
+    async fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        let n = self.stream.read_line(&mut line).await.unwrap();
+        assert!(n > 0, "connection closed while waiting for a response");
+        assert!(line.ends_with("\r\n"), "{line:?}");
+        line.trim_end_matches("\r\n").to_string()
+    }
+
+    /// Sends a command and returns the status line.
+    async fn cmd(&mut self, line: &str) -> String {
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(format!("{line}\r\n").as_bytes())
+            .await
+            .unwrap();
+        self.read_line().await
+    }
+
+    /// Sends a command with a multi-line response and returns the status
+    /// line and the unstuffed body lines.
+    async fn multi(&mut self, line: &str) -> (String, Vec<String>) {
+        let status = self.cmd(line).await;
+        assert!(status.starts_with("+OK"), "{line}: {status}");
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line().await;
+            if line == "." {
+                return (status, lines);
+            }
+            lines.push(line.strip_prefix('.').unwrap_or(&line).to_string());
+        }
+    }
+
+    async fn login(&mut self, user: &str, password: &str) -> String {
+        assert_eq!(self.cmd(&format!("USER {user}")).await, "+OK send PASS");
+        self.cmd(&format!("PASS {password}")).await
+    }
+}
+
+/// Starts SMTP and HTTP listeners, plus a POP3 listener on the same store.
+async fn start(mailboxes: Mailboxes, config: Pop3Config) -> (TestApp, SocketAddr) {
+    let app = TestApp::start_with(SmtpConfig {
+        mailboxes: mailboxes.clone(),
+        ..SmtpConfig::default()
+    })
+    .await;
+    let config = Pop3Config {
+        mailboxes,
+        ..config
+    };
+    let server = Pop3Server::bind("127.0.0.1:0".parse().unwrap(), config, app.store.clone())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.serve());
+    (app, addr)
+}
+
+fn team_mailboxes() -> Mailboxes {
+    Mailboxes::new(vec![
+        "team-a:rcpt=*@team-a.test".parse().unwrap(),
+        "billing:user=billing-app".parse().unwrap(),
+    ])
+}
+
+#[tokio::test]
+async fn reads_and_deletes_the_users_mailbox() {
+    let (app, addr) = start(team_mailboxes(), Pop3Config::default()).await;
+    let first = "Subject: first\r\n\r\nhello\r\n";
+    let second = "Subject: second\r\nX-Test: 1\r\n\r\n..hidden\r\nline 2\r\nline 3\r\n";
+    app.send("a@example.test", &["x@team-a.test"], first).await;
+    app.send("a@example.test", &["y@team-a.test"], second).await;
+    app.send("a@example.test", &["z@example.test"], first).await;
+    let ids: Vec<u64> = app
+        .store
+        .list_mailbox("team-a")
+        .iter()
+        .rev()
+        .map(|message| message.id)
+        .collect();
+    let sizes: Vec<usize> = app
+        .store
+        .list_mailbox("team-a")
+        .iter()
+        .rev()
+        .map(|message| message.raw.len())
+        .collect();
+    let total = sizes[0] + sizes[1];
+
+    let mut client = Pop3Client::connect(addr).await;
+    assert_eq!(client.cmd("STAT").await, "-ERR log in first");
+    assert_eq!(
+        client.login("team-a", "anything").await,
+        format!("+OK team-a has 2 messages ({total} octets) in mailbox team-a")
+    );
+    assert_eq!(client.cmd("STAT").await, format!("+OK 2 {total}"));
+    assert_eq!(
+        client.multi("LIST").await.1,
+        [format!("1 {}", sizes[0]), format!("2 {}", sizes[1])]
+    );
+    assert_eq!(client.cmd("LIST 2").await, format!("+OK 2 {}", sizes[1]));
+    assert_eq!(client.cmd("LIST 3").await, "-ERR no such message");
+    assert_eq!(
+        client.multi("UIDL").await.1,
+        [format!("1 {}", ids[0]), format!("2 {}", ids[1])]
+    );
+
+    let (_, top) = client.multi("TOP 2 1").await;
+    assert_eq!(top, ["Subject: second", "X-Test: 1", "", ".hidden"]);
+    let (status, lines) = client.multi("RETR 2").await;
+    assert_eq!(status, format!("+OK {} octets", sizes[1]));
+    assert_eq!(lines.join("\r\n") + "\r\n", second.replace("..", "."));
+
+    assert_eq!(client.cmd("DELE 1").await, "+OK message 1 deleted");
+    assert_eq!(client.cmd("RETR 1").await, "-ERR message already deleted");
+    assert_eq!(client.cmd("STAT").await, format!("+OK 1 {}", sizes[1]));
+    assert_eq!(
+        client.cmd("RSET").await,
+        format!("+OK maildrop has 2 messages ({total} octets)")
+    );
+    assert_eq!(client.cmd("DELE 2").await, "+OK message 2 deleted");
+    // Deletions only happen once the client quits.
+    assert!(app.store.get(ids[1]).is_some());
+    assert_eq!(client.cmd("QUIT").await, "+OK bye, 1 messages deleted");
+    assert!(app.store.get(ids[1]).is_none());
+    assert!(app.store.get(ids[0]).is_some());
+    assert_eq!(app.store.len(), 2);
+}
+
+#[tokio::test]
+async fn dropped_connections_keep_marked_messages() {
+    let (app, addr) = start(Mailboxes::default(), Pop3Config::default()).await;
+    app.send(
+        "a@example.test",
+        &["b@example.test"],
+        "Subject: x\r\n\r\nx\r\n",
+    )
+    .await;
+    let mut client = Pop3Client::connect(addr).await;
+    assert!(client.login("anyone", "x").await.starts_with("+OK "));
+    assert_eq!(client.cmd("DELE 1").await, "+OK message 1 deleted");
+    drop(client);
+
+    let mut client = Pop3Client::connect(addr).await;
+    assert!(client
+        .login("anyone", "x")
+        .await
+        .starts_with("+OK anyone has 1 messages"));
+    assert_eq!(client.cmd("QUIT").await, "+OK bye, 0 messages deleted");
+    assert_eq!(app.store.len(), 1);
+}
+
+#[tokio::test]
+async fn maps_users_to_mailboxes() {
+    let (app, addr) = start(team_mailboxes(), Pop3Config::default()).await;
+    app.send(
+        "a@example.test",
+        &["x@team-a.test"],
+        "Subject: a\r\n\r\na\r\n",
+    )
+    .await;
+    app.send(
+        "a@example.test",
+        &["x@example.test"],
+        "Subject: b\r\n\r\nb\r\n",
+    )
+    .await;
+
+    for (user, expected) in [
+        ("billing-app", "0 messages (0 octets) in mailbox billing"),
+        ("team-a", "1 messages"),
+        ("someone", "in mailbox default"),
+    ] {
+        let mut client = Pop3Client::connect(addr).await;
+        let status = client.login(user, "x").await;
+        assert!(status.contains(expected), "{user}: {status}");
+        client.cmd("QUIT").await;
+    }
+}
+
+#[tokio::test]
+async fn strict_auth_rejects_bad_passwords() {
+    let sessions = SessionLog::new();
+    let config = Pop3Config {
+        auth: AuthPolicy::from_users(&["alice:s3cret pass".parse().unwrap()]),
+        sessions: sessions.clone(),
+        ..Pop3Config::default()
+    };
+    let (_app, addr) = start(Mailboxes::default(), config).await;
+    let mut client = Pop3Client::connect(addr).await;
+    assert_eq!(client.cmd("PASS x").await, "-ERR send USER first");
+    assert_eq!(
+        client.login("alice", "wrong").await,
+        "-ERR [AUTH] invalid credentials"
+    );
+    assert_eq!(client.cmd("PASS s3cret pass").await, "-ERR send USER first");
+    assert!(client
+        .login("alice", "s3cret pass")
+        .await
+        .starts_with("+OK alice has 0 messages"));
+    assert_eq!(client.cmd("USER bob").await, "-ERR already logged in");
+    assert_eq!(client.cmd("FOO").await, "-ERR unknown command");
+    client.cmd("QUIT").await;
+
+    let session = &sessions.list()[0];
+    assert_eq!(session.protocol, "pop3");
+    let entries: Vec<&str> = session.entries.iter().map(|e| e.text.as_str()).collect();
+    assert!(entries.contains(&"PASS <credentials>"));
+    assert!(!entries.iter().any(|text| text.contains("s3cret")));
+}
+
+#[tokio::test]
+async fn stls_upgrades_before_login() {
+    let identity = Identity::self_signed("rubbermail").unwrap();
+    let cert = identity.certs[0].clone();
+    let config = Pop3Config {
+        tls: TlsMode::StartTls(identity.acceptor().unwrap()),
+        ..Pop3Config::default()
+    };
+    let (_app, addr) = start(Mailboxes::default(), config).await;
+    let mut client = Pop3Client::connect(addr).await;
+    let (_, capabilities) = client.multi("CAPA").await;
+    assert!(capabilities.contains(&"STLS".to_string()));
+    assert!(capabilities.contains(&"UIDL".to_string()));
+    assert_eq!(client.cmd("STLS").await, "+OK begin TLS negotiation");
+
+    let mut client = client.upgrade(&tls_connector(cert)).await;
+    let (_, capabilities) = client.multi("CAPA").await;
+    assert!(!capabilities.contains(&"STLS".to_string()));
+    assert_eq!(client.cmd("STLS").await, "-ERR STLS not available");
+    assert!(client.login("alice", "x").await.starts_with("+OK "));
+    assert_eq!(client.cmd("QUIT").await, "+OK bye, 0 messages deleted");
+}
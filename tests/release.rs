@@ -0,0 +1,282 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{start_smtp, TestApp};
+use reqwest::StatusCode;
+use rubbermail::auth::AuthPolicy;
+use rubbermail::faults::{FaultAction, FaultRule, Faults, Stage};
+use rubbermail::release::{Relay, ReleaseConfig, UpstreamTls};
+use rubbermail::smtp::SmtpConfig;
+use rubbermail::store::{Envelope, NewMessage, SessionInfo};
+use rubbermail::tls::{Identity, TlsMode};
+use rubbermail::MessageStore;
+use rustls::pki_types::CertificateDer;
+use serde_json::{json, Value};
+
+const MESSAGE: &str = "From: app@example.test\r\n\
+To: user@example.test\r\n\
+Subject: Your receipt\r\n\
+\r\n\
+Thanks!\r\n\
+..and a line starting with a dot\r\n";
+
+/// Starts a second instance to act as the upstream server.
+async fn upstream(config: SmtpConfig) -> (SocketAddr, MessageStore) {
+    start_smtp(config).await
+}
+
+/// An upstream with a self-signed certificate, returned so it can be trusted.
+fn secured(
+    mode: fn(rubbermail::tls::TlsAcceptor) -> TlsMode,
+) -> (SmtpConfig, CertificateDer<'static>) {
+    let identity = Identity::self_signed("localhost").unwrap();
+    let cert = identity.certs[0].clone();
+    let config = SmtpConfig {
+        tls: mode(identity.acceptor().unwrap()),
+        ..SmtpConfig::default()
+    };
+    (config, cert)
+}
+
+async fn release(app: &TestApp, id: u64, body: Option<Value>) -> (StatusCode, Value) {
+    let mut request = reqwest::Client::new().post(app.url(&format!("/api/messages/{id}/release")));
+    if let Some(body) = body {
+        request = request.json(&body);
+    }
+    let response = request.send().await.unwrap();
+    let status = response.status();
+    (status, response.json().await.unwrap())
+}
+
+#[tokio::test]
+async fn releases_over_starttls_with_auth_and_rewritten_recipient() {
+    let (config, cert) = secured(TlsMode::StartTls);
+    let (addr, upstream_store) = upstream(SmtpConfig {
+        auth: AuthPolicy::from_users(&["relay:hunter2".parse().unwrap()]),
+        ..config
+    })
+    .await;
+    let relay = Relay::new(ReleaseConfig {
+        tls: UpstreamTls::Starttls,
+        credentials: Some("relay:hunter2".parse().unwrap()),
+        ca_certs: vec![cert],
+        ..ReleaseConfig::new("localhost", addr.port())
+    })
+    .unwrap();
+    let app = TestApp::start_with_relay(relay).await;
+    app.send("app@example.test", &["user@example.test"], MESSAGE)
+        .await;
+
+    let (status, body) = release(&app, 1, Some(json!({"to": ["qa@inbox.test"]}))).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(body["to"], json!(["qa@inbox.test"]));
+    assert!(body["reply"].as_str().unwrap().starts_with("250 "));
+
+    let released = upstream_store.get(1).unwrap();
+    assert_eq!(released.envelope.mail_from, "app@example.test");
+    assert_eq!(released.envelope.rcpt_to, ["qa@inbox.test"]);
+    assert_eq!(released.raw, app.store.get(1).unwrap().raw);
+    assert_eq!(released.session.username.as_deref(), Some("relay"));
+    assert!(released.session.tls.is_some());
+}
+
+#[tokio::test]
+async fn releases_to_the_captured_recipients_by_default() {
+    let (addr, upstream_store) = upstream(SmtpConfig::default()).await;
+    let relay = Relay::new(ReleaseConfig {
+        mail_from: Some("rubbermail@qa.test".to_string()),
+        ..ReleaseConfig::new("127.0.0.1", addr.port())
+    })
+    .unwrap();
+    let app = TestApp::start_with_relay(relay).await;
+    app.send(
+        "app@example.test",
+        &["user@example.test", "other@example.test"],
+        MESSAGE,
+    )
+    .await;
+
+    let (status, body) = release(&app, 1, None).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    let released = upstream_store.get(1).unwrap();
+    assert_eq!(released.envelope.mail_from, "rubbermail@qa.test");
+    assert_eq!(
+        released.envelope.rcpt_to,
+        ["user@example.test", "other@example.test"]
+    );
+    // The captured copy has a line starting with a dot, which must survive
+    // dot-stuffing on the way out.
+    let captured = app.store.get(1).unwrap();
+    assert!(captured.raw.windows(6).any(|w| w == b"\r\n.and"));
+    assert_eq!(released.raw, captured.raw);
+}
+
+#[tokio::test]
+async fn releases_bare_line_endings_as_crlf() {
+    let (addr, upstream_store) = upstream(SmtpConfig::default()).await;
+    let relay = Relay::new(ReleaseConfig::new("127.0.0.1", addr.port())).unwrap();
+    let app = TestApp::start_with_relay(relay).await;
+    // Imported mail may use bare LF, including around a lone dot that would
+    // end the data early if it were sent unchanged.
+    let raw = "Subject: LF only\n\nfirst\n.second\n.\nafter\rlast";
+    app.store
+        .insert(NewMessage {
+            envelope: Envelope {
+                mail_from: "app@example.test".to_string(),
+                rcpt_to: vec!["user@example.test".to_string()],
+            },
+            session: SessionInfo {
+                peer: "127.0.0.1:1".parse().unwrap(),
+                helo: String::new(),
+                tls: None,
+                username: None,
+            },
+            raw: raw.as_bytes().to_vec(),
+            authentication: None,
+        })
+        .unwrap();
+
+    let (status, body) = release(&app, 1, None).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(
+        String::from_utf8_lossy(&upstream_store.get(1).unwrap().raw),
+        "Subject: LF only\r\n\r\nfirst\r\n.second\r\n.\r\nafter\r\nlast\r\n"
+    );
+}
+
+#[tokio::test]
+async fn releases_over_implicit_tls() {
+    let (config, cert) = secured(TlsMode::Implicit);
+    let (addr, upstream_store) = upstream(config).await;
+    let relay = Relay::new(ReleaseConfig {
+        tls: UpstreamTls::Tls,
+        ca_certs: vec![cert],
+        ..ReleaseConfig::new("localhost", addr.port())
+    })
+    .unwrap();
+    let app = TestApp::start_with_relay(relay).await;
+    app.send("app@example.test", &["user@example.test"], MESSAGE)
+        .await;
+
+    assert_eq!(release(&app, 1, None).await.0, StatusCode::OK);
+    assert!(upstream_store.get(1).unwrap().session.tls.is_some());
+}
+
+#[tokio::test]
+async fn untrusted_certificates_are_refused() {
+    let (config, _) = secured(TlsMode::StartTls);
+    let (addr, upstream_store) = upstream(config).await;
+    let relay = Relay::new(ReleaseConfig {
+        tls: UpstreamTls::Starttls,
+        ..ReleaseConfig::new("localhost", addr.port())
+    })
+    .unwrap();
+    let app = TestApp::start_with_relay(relay).await;
+    app.send("app@example.test", &["user@example.test"], MESSAGE)
+        .await;
+
+    let (status, body) = release(&app, 1, None).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert!(
+        body["error"].as_str().unwrap().contains("certificate"),
+        "{body}"
+    );
+    assert!(upstream_store.is_empty());
+}
+
+#[tokio::test]
+async fn malformed_recipients_are_refused_before_connecting() {
+    let (addr, upstream_store) = upstream(SmtpConfig::default()).await;
+    let relay = Relay::new(ReleaseConfig::new("127.0.0.1", addr.port())).unwrap();
+    let app = TestApp::start_with_relay(relay).await;
+    app.send("app@example.test", &["user@example.test"], MESSAGE)
+        .await;
+
+    for to in [
+        "a@x>\r\nRCPT TO:<victim@y",
+        "qa@example.com\r\nDATA",
+        "q a@example.com",
+        "qa.example.com",
+        "@example.com",
+    ] {
+        let (status, body) = release(&app, 1, Some(json!({ "to": ["ok@example.test", to] }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{to:?}: {body}");
+        assert_eq!(
+            body["error"],
+            format!("invalid recipient {to:?}: expected local@domain")
+        );
+    }
+    assert!(upstream_store.is_empty());
+}
+
+#[tokio::test]
+async fn upstream_rejections_are_reported() {
+    let rejecting = FaultRule {
+        stage: Stage::Rcpt,
+        action: FaultAction::Reply {
+            code: 550,
+            message: Some("5.1.1 No such user".to_string()),
+        },
+        probability: 1.0,
+        matching: None,
+        times: None,
+    };
+    let (addr, upstream_store) = upstream(SmtpConfig {
+        faults: Faults::new(vec![rejecting]).unwrap(),
+        ..SmtpConfig::default()
+    })
+    .await;
+    let relay = Relay::new(ReleaseConfig::new("127.0.0.1", addr.port())).unwrap();
+    let app = TestApp::start_with_relay(relay).await;
+    app.send("app@example.test", &["user@example.test"], MESSAGE)
+        .await;
+
+    let (status, body) = release(&app, 1, None).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(
+        body["error"],
+        "upstream rejected RCPT TO:<user@example.test>: 550 5.1.1 No such user"
+    );
+    assert!(upstream_store.is_empty());
+}
+
+#[tokio::test]
+async fn release_status_and_errors() {
+    let app = TestApp::start().await;
+    app.send("app@example.test", &["user@example.test"], MESSAGE)
+        .await;
+    let status: Value = reqwest::get(app.url("/api/release"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(status, json!({"upstream": null}));
+    assert_eq!(release(&app, 1, None).await.0, StatusCode::CONFLICT);
+
+    let relay = Relay::new(ReleaseConfig {
+        credentials: Some("relay:hunter2".parse().unwrap()),
+        ..ReleaseConfig::new("smtp.example.test", 587)
+    })
+    .unwrap();
+    let app = TestApp::start_with_relay(relay).await;
+    let status: Value = reqwest::get(app.url("/api/release"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        status["upstream"],
+        json!({
+            "host": "smtp.example.test",
+            "port": 587,
+            "tls": "none",
+            "auth": true,
+            "mail_from": null,
+            "to": [],
+        })
+    );
+    assert_eq!(release(&app, 1, None).await.0, StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,153 @@
+mod common;
+
+use std::time::Duration;
+
+use chrono::{TimeDelta, Utc};
+use common::TestApp;
+use rubbermail::http::AppState;
+use rubbermail::mailbox::Mailboxes;
+use rubbermail::retention::Retention;
+use rubbermail::smtp::SmtpConfig;
+use rubbermail::store::{Envelope, NewMessage, SessionInfo};
+use rubbermail::MessageStore;
+use serde_json::Value;
+
+fn retention(rules: &[&str]) -> Retention {
+    Retention::new(rules.iter().map(|rule| rule.parse().unwrap()).collect())
+}
+
+async fn start(mailbox_rules: &[&str], retention_rules: &[&str]) -> TestApp {
+    let config = SmtpConfig {
+        mailboxes: Mailboxes::new(
+            mailbox_rules
+                .iter()
+                .map(|rule| rule.parse().unwrap())
+                .collect(),
+        ),
+        ..SmtpConfig::default()
+    };
+    let retention = retention(retention_rules);
+    let state = AppState {
+        retention: retention.clone(),
+        ..AppState::default()
+    };
+    let app = TestApp::start_with_state(config, state).await;
+    retention.watch(app.store.clone());
+    app
+}
+
+/// Waits for the background task to bring `mailbox` down to `ids`, oldest
+/// first.
+async fn wait_for_ids(store: &MessageStore, mailbox: &str, ids: &[u64]) {
+    let current = || -> Vec<u64> {
+        let mut current: Vec<u64> = store.list_mailbox(mailbox).iter().map(|m| m.id).collect();
+        current.reverse();
+        current
+    };
+    for _ in 0..100 {
+        if current() == ids {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(current(), ids);
+}
+
+async fn pin(app: &TestApp, id: u64, pinned: bool) -> Value {
+    let client = reqwest::Client::new();
+    let url = app.url(&format!("/api/messages/{id}/pin"));
+    let request = if pinned {
+        client.put(url)
+    } else {
+        client.delete(url)
+    };
+    let response = request.send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    response.json().await.unwrap()
+}
+
+fn message(subject: &str) -> String {
+    format!("Subject: {subject}\r\n\r\n{subject}\r\n")
+}
+
+#[tokio::test]
+async fn evicts_the_oldest_unpinned_messages_over_the_count() {
+    let app = start(&[], &["*:messages=2"]).await;
+    app.send("a@example.test", &["b@example.test"], &message("one"))
+        .await;
+    let pinned = pin(&app, 1, true).await;
+    assert_eq!(pinned["pinned"], true);
+    for subject in ["two", "three", "four"] {
+        app.send("a@example.test", &["b@example.test"], &message(subject))
+            .await;
+    }
+    // The pinned message counts towards the limit but is kept.
+    wait_for_ids(&app.store, "default", &[1, 4]).await;
+
+    assert_eq!(pin(&app, 1, false).await["pinned"], false);
+    app.send("a@example.test", &["b@example.test"], &message("five"))
+        .await;
+    wait_for_ids(&app.store, "default", &[4, 5]).await;
+
+    let mailboxes: Value = reqwest::get(app.url("/api/mailboxes"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(mailboxes[0]["evicted"], 3);
+    assert_eq!(
+        mailboxes[0]["retention"],
+        serde_json::json!({ "max_messages": 2, "max_age_secs": null, "max_bytes": null })
+    );
+}
+
+#[tokio::test]
+async fn limits_apply_per_mailbox() {
+    let app = start(&["ci:rcpt=*@ci.test"], &["*:messages=10", "ci:bytes=100"]).await;
+    let big = format!("Subject: big\r\n\r\n{}\r\n", "x".repeat(40));
+    for _ in 0..3 {
+        app.send("a@example.test", &["build@ci.test"], &big).await;
+        app.send("a@example.test", &["b@example.test"], &big).await;
+    }
+    // Two 58-byte messages do not fit in 100 bytes, so only the newest is
+    // left.
+    wait_for_ids(&app.store, "ci", &[5]).await;
+    assert_eq!(app.store.list_mailbox("default").len(), 3);
+}
+
+#[tokio::test]
+async fn evicts_expired_messages() {
+    let store = MessageStore::new();
+    let now = Utc::now();
+    let retention = retention(&["*:age=1h"]);
+    for hours in [3, 2, 0] {
+        let message = NewMessage {
+            envelope: Envelope::default(),
+            session: SessionInfo {
+                peer: "127.0.0.1:1".parse().unwrap(),
+                helo: String::new(),
+                tls: None,
+                username: None,
+            },
+            raw: message("old").into_bytes(),
+            authentication: None,
+        };
+        store
+            .insert_at("default", message, now - TimeDelta::hours(hours))
+            .unwrap();
+    }
+    store.update(1, |message| message.pinned = true).unwrap();
+
+    assert_eq!(retention.enforce(&store, now).unwrap(), 1);
+    let ids: Vec<u64> = store.list().iter().map(|m| m.id).collect();
+    assert_eq!(ids, [3, 1]);
+    assert_eq!(retention.evicted("default"), 1);
+    assert_eq!(
+        retention
+            .enforce(&store, now + TimeDelta::hours(2))
+            .unwrap(),
+        1
+    );
+    assert_eq!(store.len(), 1);
+}
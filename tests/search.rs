@@ -0,0 +1,168 @@
+use std::net::SocketAddr;
+
+use chrono::{Duration, Utc};
+use rubbermail::search::Query;
+use rubbermail::store::{Envelope, NewMessage, SessionInfo};
+use rubbermail::MessageStore;
+
+fn message(from: &str, to: &str, raw: &str) -> NewMessage {
+    NewMessage {
+        envelope: Envelope {
+            mail_from: from.into(),
+            rcpt_to: vec![to.into()],
+        },
+        session: SessionInfo {
+            peer: "127.0.0.1:40000".parse::<SocketAddr>().unwrap(),
+            helo: "client.test".into(),
+            tls: None,
+            username: None,
+        },
+        raw: raw.into(),
+        authentication: None,
+    }
+}
+
+/// Three messages: ids 1 (welcome), 2 (password reset, HTML with an
+/// attachment) and 3 (newsletter with a Cc).
+fn sample_store() -> MessageStore {
+    let store = MessageStore::new();
+    store
+        .insert(message(
+            "app@example.test",
+            "alice@example.test",
+            "From: App <app@example.test>\r\nTo: alice@example.test\r\nSubject: Welcome aboard\r\n\r\nHello Alice, your account is ready.\r\n",
+        ))
+        .unwrap();
+    store
+        .insert(message(
+            "app@example.test",
+            "bob@example.test",
+            "From: App <app@example.test>\r\n\
+To: Bob <bob@example.test>\r\n\
+Subject: =?utf-8?Q?Password_reset_=F0=9F=94=91?=\r\n\
+Content-Type: multipart/mixed; boundary=b\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/html\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+<p>Use this <b>reset</b> token: ZX=\r\n\
+-42 (expires soon)</p>\r\n\
+--b\r\n\
+Content-Type: application/pdf\r\n\
+Content-Disposition: attachment; filename=help.pdf\r\n\
+\r\n\
+%PDF\r\n\
+--b--\r\n",
+        ))
+        .unwrap();
+    store
+        .insert(message(
+            "news@example.test",
+            "bob@example.test",
+            "From: Newsletter <news@example.test>\r\nTo: list@example.test\r\nCc: Carol <carol@example.test>\r\nSubject: Weekly digest\r\n\r\nThis week: password managers\r\nand   token   rotation.\r\n",
+        ))
+        .unwrap();
+    store
+}
+
+fn ids(store: &MessageStore, query: &str) -> Vec<u64> {
+    let query = Query::parse(query).unwrap();
+    store.search(&query).iter().map(|m| m.id).collect()
+}
+
+#[test]
+fn structured_terms() {
+    let store = sample_store();
+    assert_eq!(ids(&store, "from:news"), [3]);
+    assert_eq!(ids(&store, "from:App"), [2, 1]);
+    assert_eq!(ids(&store, "to:bob@example.test"), [3, 2]);
+    assert_eq!(ids(&store, "to:carol"), [3]);
+    assert_eq!(ids(&store, "subject:password"), [2]);
+    assert_eq!(ids(&store, "subject:\"weekly digest\""), [3]);
+    assert_eq!(ids(&store, "has:attachment"), [2]);
+    assert_eq!(ids(&store, "to:bob subject:reset"), [2]);
+}
+
+#[test]
+fn free_text_searches_decoded_bodies() {
+    let store = sample_store();
+    // Quoted-printable soft break and HTML tags are gone after decoding.
+    assert_eq!(ids(&store, "zx-42"), [2]);
+    assert_eq!(ids(&store, "\"reset token\""), [2]);
+    assert_eq!(ids(&store, "token"), [3, 2]);
+    // Phrases match across line breaks and repeated spaces.
+    assert_eq!(ids(&store, "\"managers and token rotation\""), [3]);
+    // Words match by prefix.
+    assert_eq!(ids(&store, "acc"), [1]);
+    assert_eq!(ids(&store, "password -from:news"), [2]);
+    assert!(ids(&store, "nonexistent").is_empty());
+}
+
+#[test]
+fn single_messages_match_like_the_index() {
+    let store = sample_store();
+    for query in [
+        "subject:set",
+        "subject:reset",
+        "subject:\"word reset\"",
+        "ccount",
+        "\"reset token\"",
+        "\"et tok\"",
+        "to:example.test",
+        "from:pp",
+    ] {
+        let parsed = Query::parse(query).unwrap();
+        let matching: Vec<u64> = store
+            .list()
+            .iter()
+            .filter(|message| parsed.matches(message))
+            .map(|message| message.id)
+            .collect();
+        assert_eq!(matching, ids(&store, query), "{query}");
+    }
+    assert!(ids(&store, "subject:set").is_empty());
+    assert!(ids(&store, "\"et tok\"").is_empty());
+}
+
+#[test]
+fn received_date_bounds() {
+    let store = sample_store();
+    assert_eq!(ids(&store, "after:2000-01-01"), [3, 2, 1]);
+    assert!(ids(&store, "before:2000-01-01").is_empty());
+    let soon = (Utc::now() + Duration::hours(1)).to_rfc3339();
+    assert_eq!(ids(&store, &format!("before:{soon} from:news")), [3]);
+    assert!(ids(&store, &format!("after:{soon}")).is_empty());
+}
+
+#[test]
+fn index_follows_deletes() {
+    let store = sample_store();
+    store.delete(2).unwrap();
+    assert_eq!(ids(&store, "token"), [3]);
+    assert!(ids(&store, "has:attachment").is_empty());
+    store.clear().unwrap();
+    assert!(ids(&store, "from:app").is_empty());
+    store
+        .insert(message(
+            "app@example.test",
+            "dave@example.test",
+            "Subject: again\r\n\r\nreset\r\n",
+        ))
+        .unwrap();
+    assert_eq!(ids(&store, "reset"), [4]);
+}
+
+#[test]
+fn finds_one_message_among_thousands() {
+    let store = MessageStore::new();
+    for i in 0..3000 {
+        let to = format!("user{i}@example.test");
+        let raw = format!("To: {to}\r\nSubject: Notification {i}\r\n\r\nBody number {i}\r\n");
+        store
+            .insert(message("app@example.test", &to, &raw))
+            .unwrap();
+    }
+    assert_eq!(ids(&store, "to:user1234@example.test"), [1235]);
+    assert_eq!(ids(&store, "subject:notification \"number 2999\""), [3000]);
+}
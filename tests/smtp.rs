@@ -0,0 +1,205 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{start_smtp, SmtpClient};
+use rubbermail::smtp::SmtpConfig;
+
+#[tokio::test]
+async fn captures_a_message_and_never_relays() {
+    let (addr, store) = start_smtp(SmtpConfig::default()).await;
+    let mut client = SmtpClient::connect(addr).await;
+
+    let code = client
+        .send_mail(
+            "sender@example.test",
+            &["bob@example.test", "carol@example.test"],
+            "Subject: Hello\r\n\r\nHi Bob\r\n",
+        )
+        .await;
+    assert_eq!(code, 250);
+    assert_eq!(client.cmd("QUIT").await.0, 221);
+
+    let messages = store.list();
+    assert_eq!(messages.len(), 1);
+    let message = &messages[0];
+    assert_eq!(message.envelope.mail_from, "sender@example.test");
+    assert_eq!(
+        message.envelope.rcpt_to,
+        ["bob@example.test", "carol@example.test"]
+    );
+    assert_eq!(message.session.helo, "client.test");
+    assert_eq!(message.raw, b"Subject: Hello\r\n\r\nHi Bob\r\n");
+}
+
+#[tokio::test]
+async fn ehlo_advertises_extensions() {
+    let (addr, _) = start_smtp(SmtpConfig::default()).await;
+    let mut client = SmtpClient::connect(addr).await;
+
+    let (code, lines) = client.cmd("EHLO client.test").await;
+    assert_eq!(code, 250);
+    assert!(lines[0].starts_with("rubbermail greets client.test"));
+    for ext in ["PIPELINING", "8BITMIME", "SMTPUTF8", "SIZE 26214400"] {
+        assert!(lines.iter().any(|l| l == ext), "missing {ext} in {lines:?}");
+    }
+    assert_eq!(
+        client.cmd("HELO client.test").await,
+        (250, vec!["rubbermail".to_string()])
+    );
+}
+
+#[tokio::test]
+async fn enforces_command_order() {
+    let (addr, store) = start_smtp(SmtpConfig::default()).await;
+    let mut client = SmtpClient::connect(addr).await;
+
+    assert_eq!(client.cmd("MAIL FROM:<a@example.test>").await.0, 503);
+    assert_eq!(client.cmd("HELO client.test").await.0, 250);
+    assert_eq!(client.cmd("RCPT TO:<b@example.test>").await.0, 503);
+    assert_eq!(client.cmd("DATA").await.0, 503);
+    assert_eq!(client.cmd("MAIL FROM:<a@example.test>").await.0, 250);
+    assert_eq!(client.cmd("MAIL FROM:<a@example.test>").await.0, 503);
+    assert_eq!(client.cmd("DATA").await.0, 503);
+    assert_eq!(client.cmd("RSET").await.0, 250);
+    assert_eq!(client.cmd("RCPT TO:<b@example.test>").await.0, 503);
+    assert!(store.is_empty());
+}
+
+#[tokio::test]
+async fn noop_rset_and_unknown_commands() {
+    let (addr, store) = start_smtp(SmtpConfig::default()).await;
+    let mut client = SmtpClient::connect(addr).await;
+
+    assert_eq!(client.cmd("NOOP").await.0, 250);
+    assert_eq!(client.cmd("EXPN staff").await.0, 500);
+    assert_eq!(client.cmd("HELO").await.0, 501);
+    assert_eq!(client.cmd("EHLO client.test").await.0, 250);
+    assert_eq!(client.cmd("MAIL FROM:<a@example.test>").await.0, 250);
+    assert_eq!(client.cmd("RCPT TO:<b@example.test>").await.0, 250);
+    assert_eq!(client.cmd("RSET").await.0, 250);
+    assert_eq!(client.cmd("DATA").await.0, 503);
+    assert_eq!(client.cmd("QUIT").await.0, 221);
+    assert!(client.is_closed().await);
+    assert!(store.is_empty());
+}
+
+#[tokio::test]
+async fn removes_dot_stuffing() {
+    let (addr, store) = start_smtp(SmtpConfig::default()).await;
+    let mut client = SmtpClient::connect(addr).await;
+
+    let code = client
+        .send_mail(
+            "a@example.test",
+            &["b@example.test"],
+            "Subject: dots\r\n\r\n..leading\r\n..\r\n",
+        )
+        .await;
+    assert_eq!(code, 250);
+    assert_eq!(
+        store.list()[0].raw,
+        b"Subject: dots\r\n\r\n.leading\r\n.\r\n"
+    );
+}
+
+#[tokio::test]
+async fn accepts_pipelined_transaction() {
+    let (addr, store) = start_smtp(SmtpConfig::default()).await;
+    let mut client = SmtpClient::connect(addr).await;
+
+    client
+        .send_raw(
+            b"EHLO client.test\r\nMAIL FROM:<a@example.test>\r\nRCPT TO:<b@example.test>\r\nDATA\r\n",
+        )
+        .await;
+    for expected in [250, 250, 250, 354] {
+        assert_eq!(client.read_reply().await.0, expected);
+    }
+    client
+        .send_raw(b"Subject: piped\r\n\r\nbody\r\n.\r\nQUIT\r\n")
+        .await;
+    assert_eq!(client.read_reply().await.0, 250);
+    assert_eq!(client.read_reply().await.0, 221);
+    assert_eq!(store.len(), 1);
+}
+
+#[tokio::test]
+async fn several_messages_on_one_connection() {
+    let (addr, store) = start_smtp(SmtpConfig::default()).await;
+    let mut client = SmtpClient::connect(addr).await;
+
+    for i in 0..3 {
+        let body = format!("Subject: {i}\r\n\r\nbody\r\n");
+        assert_eq!(
+            client
+                .send_mail("a@example.test", &["b@example.test"], &body)
+                .await,
+            250
+        );
+    }
+    let ids: Vec<_> = store.list().iter().map(|m| m.id).collect();
+    assert_eq!(ids, [3, 2, 1]);
+}
+
+#[tokio::test]
+async fn rejects_oversized_messages() {
+    let config = SmtpConfig {
+        max_message_size: 64,
+        ..SmtpConfig::default()
+    };
+    let (addr, store) = start_smtp(config).await;
+    let mut client = SmtpClient::connect(addr).await;
+
+    assert_eq!(client.cmd("EHLO client.test").await.0, 250);
+    assert_eq!(
+        client.cmd("MAIL FROM:<a@example.test> SIZE=1000").await.0,
+        552
+    );
+
+    let body = format!("Subject: big\r\n\r\n{}\r\n", "x".repeat(200));
+    assert_eq!(
+        client
+            .send_mail("a@example.test", &["b@example.test"], &body)
+            .await,
+        552
+    );
+    assert!(store.is_empty());
+
+    // The session is still usable after the rejection.
+    assert_eq!(
+        client
+            .send_mail("a@example.test", &["b@example.test"], "hi\r\n")
+            .await,
+        250
+    );
+}
+
+#[tokio::test]
+async fn tolerates_bare_lf() {
+    let (addr, store) = start_smtp(SmtpConfig::default()).await;
+    let mut client = SmtpClient::connect(addr).await;
+
+    client
+        .send_raw(b"HELO lf.test\nMAIL FROM:<a@example.test>\nRCPT TO:<b@example.test>\nDATA\n")
+        .await;
+    for expected in [250, 250, 250, 354] {
+        assert_eq!(client.read_reply().await.0, expected);
+    }
+    client.send_raw(b"Subject: lf\n\nbody\n.\n").await;
+    assert_eq!(client.read_reply().await.0, 250);
+    assert_eq!(store.list()[0].raw, b"Subject: lf\n\nbody\n");
+}
+
+#[tokio::test]
+async fn disconnects_idle_clients() {
+    let config = SmtpConfig {
+        idle_timeout: Duration::from_millis(100),
+        ..SmtpConfig::default()
+    };
+    let (addr, _) = start_smtp(config).await;
+    let mut client = SmtpClient::connect(addr).await;
+
+    assert_eq!(client.read_reply().await.0, 421);
+    assert!(client.is_closed().await);
+}
@@ -0,0 +1,251 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::TestApp;
+use reqwest::StatusCode;
+use rubbermail::http::AppState;
+use rubbermail::smtp::SmtpConfig;
+use rubbermail::spam::{Spamd, SpamdConfig};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// The GTUBE test string, which SpamAssassin always scores as spam.
+const GTUBE: &str = "XJS*C4JDBQADN1.NSBN3*2IDNEN*GTUBE-STANDARD-ANTI-UBE-TEST-EMAIL*C.34X";
+
+/// A request as the stub spamd received it.
+#[derive(Debug, Clone)]
+struct Request {
+    command: String,
+    headers: Vec<String>,
+    body: Vec<u8>,
+}
+
+/// A stand-in for spamd that scores GTUBE as spam and everything else as
+/// clean, or refuses everything.
+async fn start_spamd(refuse: bool) -> (SocketAddr, Arc<Mutex<Vec<Request>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let seen = seen.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut command = String::new();
+                stream.read_line(&mut command).await.unwrap();
+                let mut headers = Vec::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).await.unwrap();
+                    let line = line.trim_end().to_string();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-length: ") {
+                        length = value.parse().unwrap();
+                    }
+                    headers.push(line);
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).await.unwrap();
+                let spam = String::from_utf8_lossy(&body).contains(GTUBE);
+                seen.lock().unwrap().push(Request {
+                    command: command.trim_end().to_string(),
+                    headers,
+                    body,
+                });
+
+                let response = if refuse {
+                    "SPAMD/1.0 76 Bad header line: (EOF)\r\n".to_string()
+                } else {
+                    let (verdict, score, rules) = if spam {
+                        (
+                            "True",
+                            "1001.4",
+                            " 1.4 MISSING_DATE           Missing Date: header\n\
+                             1000 GTUBE                  BODY: Generic Test for Unsolicited Bulk Email\n",
+                        )
+                    } else {
+                        (
+                            "False",
+                            "1.4",
+                            " 1.4 MISSING_DATE           Missing Date: header\n",
+                        )
+                    };
+                    let report = format!(
+                        "Content analysis details:   ({score} points, 5.0 required)\n\n \
+                         pts rule name              description\n\
+                         ---- ---------------------- ------------------------------\n{rules}"
+                    );
+                    format!(
+                        "SPAMD/1.1 0 EX_OK\r\nContent-length: {}\r\nSpam: {verdict} ; {score} / 5.0\r\n\r\n{report}",
+                        report.len()
+                    )
+                };
+                stream.write_all(response.as_bytes()).await.unwrap();
+            });
+        }
+    });
+    (addr, requests)
+}
+
+fn spamd(addr: SocketAddr) -> Spamd {
+    Spamd::new(SpamdConfig {
+        user: Some("qa".to_string()),
+        ..SpamdConfig::new(addr.ip().to_string(), addr.port())
+    })
+}
+
+async fn get_json(url: &str) -> (StatusCode, Value) {
+    let response = reqwest::get(url).await.unwrap();
+    (response.status(), response.json().await.unwrap())
+}
+
+/// Starts an instance that scores every received message with `spamd`.
+async fn start(spamd: Spamd) -> TestApp {
+    let spamd = Arc::new(spamd);
+    let state = AppState {
+        spamd: Some(spamd.clone()),
+        ..AppState::default()
+    };
+    let app = TestApp::start_with_state(SmtpConfig::default(), state).await;
+    spamd.watch(app.store.clone());
+    app
+}
+
+/// Polls until the message has been scored.
+async fn wait_for_score(app: &TestApp, id: u64) -> Value {
+    for _ in 0..100 {
+        let (_, message) = get_json(&app.url(&format!("/api/messages/{id}"))).await;
+        if !message["spam"].is_null() {
+            return message;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("message {id} was never scored");
+}
+
+#[tokio::test]
+async fn scores_received_messages() {
+    let (addr, requests) = start_spamd(false).await;
+    let app = start(spamd(addr)).await;
+    app.send(
+        "news@example.test",
+        &["bob@example.test"],
+        "Subject: Hello\r\n\r\nJust saying hi.\r\n",
+    )
+    .await;
+    app.send(
+        "news@example.test",
+        &["bob@example.test"],
+        &format!("Subject: Offer\r\n\r\n{GTUBE}\r\n"),
+    )
+    .await;
+
+    let clean = wait_for_score(&app, 1).await;
+    assert_eq!(clean["spam"]["score"], 1.4);
+    assert_eq!(clean["spam"]["threshold"], 5.0);
+    assert_eq!(clean["spam"]["is_spam"], false);
+    assert_eq!(clean["spam"]["rules"][0]["name"], "MISSING_DATE");
+    assert_eq!(
+        clean["spam"]["rules"][0]["description"],
+        "Missing Date: header"
+    );
+
+    let spam = wait_for_score(&app, 2).await;
+    assert_eq!(spam["spam"]["is_spam"], true);
+    assert_eq!(spam["spam"]["rules"][1]["name"], "GTUBE");
+    assert_eq!(spam["spam"]["rules"][1]["score"], 1000.0);
+
+    let (_, list) = get_json(&app.url("/api/messages")).await;
+    assert_eq!(list["messages"][0]["spam_score"], 1001.4);
+    assert_eq!(list["messages"][1]["spam_score"], 1.4);
+
+    let requests = requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    let request = requests
+        .iter()
+        .find(|request| !String::from_utf8_lossy(&request.body).contains(GTUBE))
+        .unwrap();
+    assert_eq!(request.command, "REPORT SPAMC/1.5");
+    assert!(request.headers.contains(&"User: qa".to_string()));
+    assert_eq!(request.body, app.store.get(1).unwrap().raw);
+}
+
+#[tokio::test]
+async fn rescores_on_request() {
+    let (addr, requests) = start_spamd(false).await;
+    // Scoring on request needs a spamd too.
+    let app = TestApp::start().await;
+    app.send(
+        "news@example.test",
+        &["bob@example.test"],
+        "Subject: Hello\r\n\r\nhi\r\n",
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let response = client
+        .post(app.url("/api/messages/1/spam"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert!(requests.lock().unwrap().is_empty());
+
+    let app = start(spamd(addr)).await;
+    app.send(
+        "news@example.test",
+        &["bob@example.test"],
+        "Subject: Hello\r\n\r\nhi\r\n",
+    )
+    .await;
+    wait_for_score(&app, 1).await;
+    let response = client
+        .post(app.url("/api/messages/1/spam"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let report: Value = response.json().await.unwrap();
+    assert_eq!(report["score"], 1.4);
+    assert_eq!(requests.lock().unwrap().len(), 2);
+
+    let response = client
+        .post(app.url("/api/messages/9/spam"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn spamd_errors_are_reported() {
+    let (addr, _) = start_spamd(true).await;
+    let app = start(spamd(addr)).await;
+    app.send(
+        "news@example.test",
+        &["bob@example.test"],
+        "Subject: Hello\r\n\r\nhi\r\n",
+    )
+    .await;
+    let response = reqwest::Client::new()
+        .post(app.url("/api/messages/1/spam"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(
+        body["error"],
+        "spamd refused the message: 76 Bad header line: (EOF)"
+    );
+    let (_, message) = get_json(&app.url("/api/messages/1")).await;
+    assert!(message["spam"].is_null());
+}
@@ -0,0 +1,334 @@
+//! Conformance suite run against every storage backend.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+use rubbermail::dmarc::Authentication;
+use rubbermail::dns::Records;
+use rubbermail::spam::{SpamReport, SpamRule};
+use rubbermail::storage::{EmlDirStorage, MemoryStorage, SqliteStorage, Storage};
+use rubbermail::store::{CapturedMessage, Envelope, NewMessage, SessionInfo};
+use rubbermail::tls::TlsInfo;
+use rubbermail::MessageStore;
+use tempfile::TempDir;
+
+fn message(id: u64, subject: &str) -> Arc<CapturedMessage> {
+    Arc::new(CapturedMessage::new(
+        id,
+        Utc.with_ymd_and_hms(2025, 3, 1, 12, 0, id as u32).unwrap(),
+        Envelope {
+            mail_from: "sender@example.test".into(),
+            rcpt_to: vec!["a@example.test".into(), "b@example.test".into()],
+        },
+        SessionInfo {
+            peer: "127.0.0.1:40000".parse().unwrap(),
+            helo: "client.test".into(),
+            tls: Some(TlsInfo {
+                version: "TLSv1.3".into(),
+                cipher: "TLS13_AES_128_GCM_SHA256".into(),
+            }),
+            username: Some("mailer".into()),
+        },
+        format!("Subject: {subject}\r\n\r\nbody {id}\r\n").into_bytes(),
+    ))
+}
+
+fn new_message(subject: &str) -> NewMessage {
+    NewMessage {
+        envelope: Envelope {
+            mail_from: "sender@example.test".into(),
+            rcpt_to: vec!["a@example.test".into()],
+        },
+        session: SessionInfo {
+            peer: "127.0.0.1:40000".parse::<SocketAddr>().unwrap(),
+            helo: "client.test".into(),
+            tls: None,
+            username: None,
+        },
+        raw: format!("Subject: {subject}\r\n\r\nbody\r\n").into_bytes(),
+        authentication: None,
+    }
+}
+
+fn sorted_ids(storage: &dyn Storage) -> Vec<u64> {
+    let mut ids: Vec<_> = storage.load().unwrap().iter().map(|m| m.id).collect();
+    ids.sort();
+    ids
+}
+
+/// Opens a backend at a location that survives re-opening.
+trait Backend {
+    fn open(&self) -> Arc<dyn Storage>;
+    /// Whether messages survive re-opening.
+    fn durable(&self) -> bool;
+}
+
+struct Memory(Arc<MemoryStorage>);
+
+impl Backend for Memory {
+    fn open(&self) -> Arc<dyn Storage> {
+        self.0.clone()
+    }
+    fn durable(&self) -> bool {
+        false
+    }
+}
+
+struct Eml(TempDir);
+
+impl Backend for Eml {
+    fn open(&self) -> Arc<dyn Storage> {
+        Arc::new(EmlDirStorage::open(self.0.path().join("mail")).unwrap())
+    }
+    fn durable(&self) -> bool {
+        true
+    }
+}
+
+struct Sqlite(TempDir);
+
+impl Backend for Sqlite {
+    fn open(&self) -> Arc<dyn Storage> {
+        Arc::new(SqliteStorage::open(self.0.path().join("mail.db")).unwrap())
+    }
+    fn durable(&self) -> bool {
+        true
+    }
+}
+
+fn round_trips_every_field(backend: &dyn Backend) {
+    let storage = backend.open();
+    let mut original = CapturedMessage::clone(&message(7, "Grüße"));
+    original.mailbox = "team-a".into();
+    original.authentication = Some(Authentication::check(
+        &original.raw,
+        &original.envelope,
+        &original.session,
+        &Records::default(),
+    ));
+    storage.insert(&Arc::new(original.clone())).unwrap();
+
+    let loaded = storage.load().unwrap();
+    assert_eq!(loaded.len(), 1);
+    let loaded = &loaded[0];
+    assert_eq!(loaded.id, original.id);
+    assert_eq!(loaded.mailbox, "team-a");
+    assert_eq!(loaded.received_at, original.received_at);
+    assert_eq!(loaded.envelope, original.envelope);
+    assert_eq!(loaded.session, original.session);
+    assert_eq!(loaded.raw, original.raw);
+    assert_eq!(loaded.summary.subject.as_deref(), Some("Grüße"));
+    assert_eq!(loaded.authentication, original.authentication);
+}
+
+fn updates_metadata(backend: &dyn Backend) {
+    let storage = backend.open();
+    storage.insert(&message(1, "x")).unwrap();
+    storage.insert(&message(2, "y")).unwrap();
+    let mut scored = CapturedMessage::clone(&message(1, "x"));
+    scored.spam = Some(SpamReport {
+        score: 2.5,
+        threshold: 5.0,
+        is_spam: false,
+        rules: vec![SpamRule {
+            name: "MISSING_DATE".into(),
+            score: 1.4,
+            description: "Missing Date: header".into(),
+        }],
+        checked_at: Utc.with_ymd_and_hms(2025, 3, 1, 13, 0, 0).unwrap(),
+    });
+    storage.update(&Arc::new(scored.clone())).unwrap();
+    // Updating a deleted message must not bring it back.
+    storage.delete(2).unwrap();
+    storage.update(&message(2, "y")).unwrap();
+
+    let storage = if backend.durable() {
+        backend.open()
+    } else {
+        storage
+    };
+    let loaded = storage.load().unwrap();
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded[0].spam, scored.spam);
+    assert_eq!(loaded[0].raw, scored.raw);
+}
+
+fn deletes_single_messages(backend: &dyn Backend) {
+    let storage = backend.open();
+    for id in 1..=3 {
+        storage.insert(&message(id, "x")).unwrap();
+    }
+    storage.delete(2).unwrap();
+    storage.delete(99).unwrap();
+    assert_eq!(sorted_ids(&*storage), [1, 3]);
+}
+
+fn clears_everything(backend: &dyn Backend) {
+    let storage = backend.open();
+    for id in 1..=3 {
+        storage.insert(&message(id, "x")).unwrap();
+    }
+    storage.clear().unwrap();
+    assert!(storage.load().unwrap().is_empty());
+    storage.insert(&message(4, "after clear")).unwrap();
+    assert_eq!(sorted_ids(&*storage), [4]);
+}
+
+fn survives_reopening(backend: &dyn Backend) {
+    if !backend.durable() {
+        return;
+    }
+    {
+        let storage = backend.open();
+        for id in 1..=4 {
+            storage.insert(&message(id, "x")).unwrap();
+        }
+        storage.delete(1).unwrap();
+    }
+    assert_eq!(sorted_ids(&*backend.open()), [2, 3, 4]);
+    // Opening again (and compacting) must not lose or duplicate anything.
+    assert_eq!(sorted_ids(&*backend.open()), [2, 3, 4]);
+}
+
+fn store_continues_ids_after_restart(backend: &dyn Backend) {
+    let store = MessageStore::open(backend.open()).unwrap();
+    store.insert(new_message("one")).unwrap();
+    store.insert(new_message("two")).unwrap();
+    store.delete(1).unwrap();
+
+    let store = MessageStore::open(backend.open()).unwrap();
+    let next = store.insert(new_message("three")).unwrap();
+    assert_eq!(next.id, 3);
+    let subjects: Vec<_> = store
+        .list()
+        .iter()
+        .map(|m| m.summary.subject.clone().unwrap())
+        .collect();
+    assert_eq!(subjects, ["three", "two"]);
+}
+
+fn store_never_reuses_ids_of_deleted_messages(backend: &dyn Backend) {
+    let store = MessageStore::open(backend.open()).unwrap();
+    store.insert(new_message("one")).unwrap();
+    store.insert(new_message("two")).unwrap();
+    store.delete(2).unwrap();
+
+    let store = MessageStore::open(backend.open()).unwrap();
+    assert_eq!(store.insert(new_message("three")).unwrap().id, 3);
+    store.clear().unwrap();
+
+    // Opening twice also covers the eml journal after compaction.
+    MessageStore::open(backend.open()).unwrap();
+    let store = MessageStore::open(backend.open()).unwrap();
+    assert_eq!(store.insert(new_message("four")).unwrap().id, 4);
+}
+
+fn conformance(make: impl Fn() -> Box<dyn Backend>) {
+    round_trips_every_field(&*make());
+    updates_metadata(&*make());
+    deletes_single_messages(&*make());
+    clears_everything(&*make());
+    survives_reopening(&*make());
+    store_continues_ids_after_restart(&*make());
+    store_never_reuses_ids_of_deleted_messages(&*make());
+}
+
+#[test]
+fn memory_backend() {
+    conformance(|| Box::new(Memory(Arc::new(MemoryStorage::new()))));
+}
+
+#[test]
+fn eml_directory_backend() {
+    conformance(|| Box::new(Eml(TempDir::new().unwrap())));
+}
+
+#[test]
+fn sqlite_backend() {
+    conformance(|| Box::new(Sqlite(TempDir::new().unwrap())));
+}
+
+#[test]
+fn eml_directory_layout() {
+    let dir = TempDir::new().unwrap();
+    let storage = EmlDirStorage::open(dir.path()).unwrap();
+    storage.insert(&message(5, "on disk")).unwrap();
+
+    let eml = std::fs::read(dir.path().join("5.eml")).unwrap();
+    assert_eq!(eml, b"Subject: on disk\r\n\r\nbody 5\r\n");
+    let index = std::fs::read_to_string(dir.path().join("index.jsonl")).unwrap();
+    assert!(index.contains("\"op\":\"insert\""));
+
+    storage.delete(5).unwrap();
+    assert!(!dir.path().join("5.eml").exists());
+}
+
+#[test]
+fn eml_directory_skips_damaged_index_lines() {
+    let dir = TempDir::new().unwrap();
+    {
+        let storage = EmlDirStorage::open(dir.path()).unwrap();
+        storage.insert(&message(1, "kept")).unwrap();
+    }
+    let index = dir.path().join("index.jsonl");
+    let mut contents = std::fs::read_to_string(&index).unwrap();
+    contents.push_str("{\"op\":\"insert\",\"id\":2,\"rec");
+    std::fs::write(&index, contents).unwrap();
+
+    let storage = EmlDirStorage::open(dir.path()).unwrap();
+    assert_eq!(sorted_ids(&storage), [1]);
+}
+
+/// Memory storage that refuses every insert after the first `limit`.
+#[derive(Debug)]
+struct FullStorage {
+    inner: MemoryStorage,
+    limit: usize,
+    inserted: AtomicUsize,
+}
+
+impl Storage for FullStorage {
+    fn load(&self) -> rubbermail::Result<Vec<Arc<CapturedMessage>>> {
+        self.inner.load()
+    }
+    fn insert(&self, message: &Arc<CapturedMessage>) -> rubbermail::Result<()> {
+        if self.inserted.fetch_add(1, Ordering::SeqCst) >= self.limit {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, "disk full").into());
+        }
+        self.inner.insert(message)
+    }
+    fn update(&self, message: &Arc<CapturedMessage>) -> rubbermail::Result<()> {
+        self.inner.update(message)
+    }
+    fn delete(&self, id: u64) -> rubbermail::Result<()> {
+        self.inner.delete(id)
+    }
+    fn clear(&self) -> rubbermail::Result<()> {
+        self.inner.clear()
+    }
+    fn last_id(&self) -> rubbermail::Result<u64> {
+        self.inner.last_id()
+    }
+}
+
+#[test]
+fn failed_copies_leave_nothing_behind() {
+    let storage = Arc::new(FullStorage {
+        inner: MemoryStorage::new(),
+        limit: 2,
+        inserted: AtomicUsize::new(0),
+    });
+    let store = MessageStore::open(storage.clone()).unwrap();
+    let mut events = store.subscribe();
+    let mailboxes = ["a".to_string(), "b".to_string(), "c".to_string()];
+
+    assert!(store
+        .insert_copies(&mailboxes, new_message("copied"))
+        .is_err());
+    assert!(store.list().is_empty());
+    assert!(storage.load().unwrap().is_empty());
+    assert!(events.try_recv().is_err());
+}
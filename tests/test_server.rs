@@ -0,0 +1,78 @@
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+use common::SmtpClient;
+use rubbermail::TestServer;
+use serde_json::Value;
+
+#[tokio::test]
+async fn captures_mail_and_serves_the_api() {
+    let server = TestServer::start().unwrap();
+    let mut client = SmtpClient::connect(server.smtp_addr()).await;
+    let status = client
+        .send_mail(
+            "app@example.test",
+            &["bob@example.test"],
+            "Subject: Welcome\r\n\r\nhi\r\n",
+        )
+        .await;
+    assert_eq!(status, 250);
+
+    let messages = server.messages();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].summary.subject.as_deref(), Some("Welcome"));
+    let list: Value = reqwest::get(format!("{}/api/messages", server.http_url()))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(list["total"], 1);
+    assert_eq!(
+        server.http_url(),
+        format!("http://127.0.0.1:{}", server.http_port())
+    );
+}
+
+#[tokio::test]
+async fn instances_are_isolated() {
+    let (first, second) = (TestServer::start().unwrap(), TestServer::start().unwrap());
+    assert_ne!(first.smtp_port(), second.smtp_port());
+    assert_ne!(first.http_port(), second.http_port());
+
+    let mut client = SmtpClient::connect(first.smtp_addr()).await;
+    client
+        .send_mail(
+            "app@example.test",
+            &["bob@example.test"],
+            "Subject: x\r\n\r\nx\r\n",
+        )
+        .await;
+    assert_eq!(first.store().len(), 1);
+    assert!(second.store().is_empty());
+}
+
+/// Works without an async runtime, and closes its ports when dropped.
+#[test]
+fn shuts_down_on_drop() {
+    let server = TestServer::start().unwrap();
+    let (smtp, http) = (server.smtp_addr(), server.http_addr());
+    let stream = TcpStream::connect(smtp).unwrap();
+    let mut greeting = String::new();
+    BufReader::new(&stream).read_line(&mut greeting).unwrap();
+    assert!(greeting.starts_with("220 "));
+
+    drop(server);
+    assert!(TcpStream::connect(smtp).is_err());
+    assert!(TcpStream::connect(http).is_err());
+    // The open session was ended too.
+    let mut stream = stream;
+    let _ = stream.write_all(b"NOOP\r\n");
+    let mut reply = String::new();
+    assert_eq!(
+        BufReader::new(&stream).read_line(&mut reply).unwrap_or(0),
+        0
+    );
+}
@@ -0,0 +1,141 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{start_smtp, tls_connector, SmtpClient};
+use rubbermail::smtp::SmtpConfig;
+use rubbermail::tls::{Identity, TlsMode};
+use rubbermail::MessageStore;
+use rustls::pki_types::CertificateDer;
+use tempfile::TempDir;
+
+/// Starts a listener with a fresh self-signed certificate and returns the
+/// certificate so clients can trust it.
+async fn start(
+    mode: fn(rubbermail::tls::TlsAcceptor) -> TlsMode,
+) -> (SocketAddr, MessageStore, CertificateDer<'static>) {
+    let identity = Identity::self_signed("rubbermail").unwrap();
+    let cert = identity.certs[0].clone();
+    let config = SmtpConfig {
+        tls: mode(identity.acceptor().unwrap()),
+        ..SmtpConfig::default()
+    };
+    let (addr, store) = start_smtp(config).await;
+    (addr, store, cert)
+}
+
+#[tokio::test]
+async fn starttls_upgrades_and_records_the_session() {
+    let (addr, store, cert) = start(TlsMode::StartTls).await;
+    let mut client = SmtpClient::connect(addr).await;
+    let (code, lines) = client.cmd("EHLO client.test").await;
+    assert_eq!(code, 250);
+    assert!(lines.contains(&"STARTTLS".to_string()));
+    assert_eq!(
+        client.cmd("STARTTLS").await,
+        (220, vec!["2.0.0 Ready to start TLS".to_string()])
+    );
+
+    let mut client = client.upgrade(&tls_connector(cert)).await;
+    // The session starts over: MAIL needs a fresh EHLO, which no longer
+    // offers STARTTLS.
+    assert_eq!(client.cmd("MAIL FROM:<a@example.test>").await.0, 503);
+    let (_, lines) = client.cmd("EHLO client.test").await;
+    assert!(!lines.contains(&"STARTTLS".to_string()));
+    assert_eq!(client.cmd("STARTTLS").await.0, 503);
+    assert_eq!(
+        client
+            .send_mail(
+                "a@example.test",
+                &["b@example.test"],
+                "Subject: secret\r\n\r\nhi\r\n"
+            )
+            .await,
+        250
+    );
+
+    let message = store.get(1).unwrap();
+    let tls = message.session.tls.as_ref().unwrap();
+    assert_eq!(tls.version, "TLSv1.3");
+    assert!(tls.cipher.starts_with("TLS13_"), "{}", tls.cipher);
+}
+
+#[tokio::test]
+async fn implicit_tls_listener() {
+    let (addr, store, cert) = start(TlsMode::Implicit).await;
+    let mut client = SmtpClient::connect_tls(addr, &tls_connector(cert)).await;
+    let (_, lines) = client.cmd("EHLO client.test").await;
+    assert!(!lines.contains(&"STARTTLS".to_string()));
+    assert_eq!(
+        client
+            .send_mail(
+                "a@example.test",
+                &["b@example.test"],
+                "Subject: smtps\r\n\r\nhi\r\n"
+            )
+            .await,
+        250
+    );
+    assert!(store.get(1).unwrap().session.tls.is_some());
+}
+
+#[tokio::test]
+async fn plaintext_messages_have_no_tls_info() {
+    let (addr, store) = start_smtp(SmtpConfig::default()).await;
+    let mut client = SmtpClient::connect(addr).await;
+    let (_, lines) = client.cmd("EHLO client.test").await;
+    assert!(!lines.contains(&"STARTTLS".to_string()));
+    assert_eq!(client.cmd("STARTTLS").await.0, 502);
+    assert_eq!(
+        client
+            .send_mail(
+                "a@example.test",
+                &["b@example.test"],
+                "Subject: plain\r\n\r\nhi\r\n"
+            )
+            .await,
+        250
+    );
+    assert!(store.get(1).unwrap().session.tls.is_none());
+}
+
+#[tokio::test]
+async fn commands_pipelined_with_starttls_are_discarded() {
+    let (addr, _store, cert) = start(TlsMode::StartTls).await;
+    let mut client = SmtpClient::connect(addr).await;
+    client.cmd("EHLO client.test").await;
+    client.send_raw(b"STARTTLS\r\nNOOP\r\n").await;
+    assert_eq!(client.read_reply().await.0, 220);
+
+    let mut client = client.upgrade(&tls_connector(cert)).await;
+    // Had the injected NOOP survived, its 250 would arrive first.
+    let (code, lines) = client.cmd("EHLO client.test").await;
+    assert_eq!(code, 250);
+    assert!(lines[0].contains("greets client.test"));
+}
+
+#[tokio::test]
+async fn loads_certificate_and_key_from_pem_files() {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = TempDir::new().unwrap();
+    let cert_path = dir.path().join("cert.pem");
+    let key_path = dir.path().join("key.pem");
+    std::fs::write(&cert_path, generated.cert.pem()).unwrap();
+    std::fs::write(&key_path, generated.key_pair.serialize_pem()).unwrap();
+
+    let identity = Identity::load(&cert_path, &key_path).unwrap();
+    let config = SmtpConfig {
+        tls: TlsMode::Implicit(identity.acceptor().unwrap()),
+        ..SmtpConfig::default()
+    };
+    let (addr, _store) = start_smtp(config).await;
+    let connector = tls_connector(generated.cert.der().clone());
+    let mut client = SmtpClient::connect_tls(addr, &connector).await;
+    assert_eq!(client.cmd("NOOP").await.0, 250);
+
+    let missing = dir.path().join("missing.pem");
+    let err = Identity::load(&missing, &key_path).err().unwrap();
+    assert!(err.to_string().contains("missing.pem"), "{err}");
+    let err = Identity::load(&key_path, &key_path).err().unwrap();
+    assert!(err.to_string().contains("no certificate found"), "{err}");
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::TestApp;
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn serves_embedded_inbox() {
+    let app = TestApp::start().await;
+
+    let response = reqwest::get(app.url("/")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let html = response.text().await.unwrap();
+    assert!(html.contains("/assets/app.js"));
+    assert!(html.contains("/assets/style.css"));
+    for tab in ["html", "text", "raw", "headers", "compat", "spam"] {
+        assert!(
+            html.contains(&format!("data-tab=\"{tab}\"")),
+            "missing {tab} tab"
+        );
+    }
+
+    for (path, content_type) in [
+        ("/assets/app.js", "text/javascript; charset=utf-8"),
+        ("/assets/style.css", "text/css; charset=utf-8"),
+    ] {
+        let response = reqwest::get(app.url(path)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], content_type);
+        assert!(!response.text().await.unwrap().is_empty());
+    }
+}
+
+#[tokio::test]
+async fn unknown_paths_are_not_found() {
+    let app = TestApp::start().await;
+    let response = reqwest::get(app.url("/assets/missing.js")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
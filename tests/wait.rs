@@ -0,0 +1,109 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::TestApp;
+use reqwest::StatusCode;
+use serde_json::Value;
+
+async fn wait(app: &TestApp, query: &str) -> (StatusCode, Value) {
+    let response = reqwest::Client::new()
+        .get(app.url("/api/messages/wait"))
+        .query(&[("query", query), ("timeout", "5")])
+        .send()
+        .await
+        .unwrap();
+    (response.status(), response.json().await.unwrap())
+}
+
+#[tokio::test]
+async fn returns_a_message_stored_before_the_call() {
+    let app = TestApp::start().await;
+    app.send(
+        "app@example.test",
+        &["bob@example.test"],
+        "Subject: Password reset\r\n\r\nclick\r\n",
+    )
+    .await;
+
+    let started = Instant::now();
+    let (status, message) = wait(&app, "to:bob subject:reset").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(message["id"], 1);
+    assert_eq!(message["subject"], "Password reset");
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn returns_a_message_arriving_during_the_call() {
+    let app = TestApp::start().await;
+    let waiting = tokio::spawn({
+        let url = app.url("/api/messages/wait?query=to:bob%20reset&timeout=5");
+        async move {
+            let response = reqwest::get(url).await.unwrap();
+            (response.status(), response.json::<Value>().await.unwrap())
+        }
+    });
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    // Neither of these matches: wrong recipient, then wrong subject.
+    app.send(
+        "app@example.test",
+        &["alice@example.test"],
+        "Subject: Password reset\r\n\r\nclick\r\n",
+    )
+    .await;
+    app.send(
+        "app@example.test",
+        &["bob@example.test"],
+        "Subject: Welcome\r\n\r\nhi\r\n",
+    )
+    .await;
+    assert!(!waiting.is_finished());
+    app.send(
+        "app@example.test",
+        &["bob@example.test"],
+        "Subject: Password reset\r\n\r\nclick\r\n",
+    )
+    .await;
+
+    let (status, message) = waiting.await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(message["id"], 3);
+}
+
+#[tokio::test]
+async fn times_out_when_nothing_matches() {
+    let app = TestApp::start().await;
+    app.send(
+        "app@example.test",
+        &["bob@example.test"],
+        "Subject: Welcome\r\n\r\nhi\r\n",
+    )
+    .await;
+
+    let started = Instant::now();
+    let response = reqwest::get(app.url("/api/messages/wait?query=subject:reset&timeout=0.3"))
+        .await
+        .unwrap();
+    let elapsed = started.elapsed();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "no matching message arrived within 0.3s");
+    assert_eq!(body["timed_out"], true);
+    assert!(elapsed >= Duration::from_millis(300));
+    assert!(elapsed < Duration::from_secs(3));
+}
+
+#[tokio::test]
+async fn rejects_bad_parameters() {
+    let app = TestApp::start().await;
+    for path in [
+        "/api/messages/wait?timeout=-1",
+        "/api/messages/wait?timeout=100000",
+        "/api/messages/wait?query=before:soon",
+    ] {
+        let response = reqwest::get(app.url(path)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{path}");
+    }
+}
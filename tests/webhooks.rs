@@ -0,0 +1,326 @@
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use axum::Router;
+use common::{start_smtp, TestApp};
+use rubbermail::release::{Relay, ReleaseConfig};
+use rubbermail::smtp::SmtpConfig;
+use rubbermail::webhooks::{sign, DELIVERY_HEADER, EVENT_HEADER, SIGNATURE_HEADER};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// One request the receiver got.
+struct Delivery {
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Delivery {
+    fn header(&self, name: &str) -> &str {
+        self.headers[name].to_str().unwrap()
+    }
+
+    fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+#[derive(Clone)]
+struct Receiver {
+    deliveries: mpsc::UnboundedSender<Delivery>,
+    /// Requests to answer with 503 before accepting.
+    failures: Arc<AtomicUsize>,
+}
+
+/// Starts an HTTP receiver that fails its first `failures` requests, and
+/// returns its URL and the requests it gets.
+async fn start_receiver(failures: usize) -> (String, mpsc::UnboundedReceiver<Delivery>) {
+    let (deliveries, received) = mpsc::unbounded_channel();
+    let receiver = Receiver {
+        deliveries,
+        failures: Arc::new(AtomicUsize::new(failures)),
+    };
+    let app = Router::new()
+        .route(
+            "/hook",
+            post(
+                |State(receiver): State<Receiver>, headers: HeaderMap, body: Bytes| async move {
+                    receiver
+                        .deliveries
+                        .send(Delivery { headers, body })
+                        .unwrap();
+                    let failing = receiver
+                        .failures
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                    if failing {
+                        StatusCode::SERVICE_UNAVAILABLE
+                    } else {
+                        StatusCode::NO_CONTENT
+                    }
+                },
+            ),
+        )
+        .with_state(receiver);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (format!("http://{addr}/hook"), received)
+}
+
+async fn next(received: &mut mpsc::UnboundedReceiver<Delivery>) -> Delivery {
+    tokio::time::timeout(Duration::from_secs(10), received.recv())
+        .await
+        .expect("no webhook delivery")
+        .unwrap()
+}
+
+async fn put_webhooks(app: &TestApp, body: Value) -> reqwest::Response {
+    reqwest::Client::new()
+        .put(app.url("/api/webhooks"))
+        .json(&body)
+        .send()
+        .await
+        .unwrap()
+}
+
+fn message(subject: &str) -> String {
+    format!("Subject: {subject}\r\n\r\n{subject}\r\n")
+}
+
+#[tokio::test]
+async fn delivers_signed_events_matching_the_filter() {
+    let (url, mut received) = start_receiver(0).await;
+    let app = TestApp::start().await;
+    let response = put_webhooks(
+        &app,
+        json!({ "webhooks": [{
+            "url": url,
+            "events": ["received"],
+            "query": "subject:invoice",
+            "secret": "s3cret",
+        }] }),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    // The secret is never shown again.
+    let set: Value = response.json().await.unwrap();
+    assert_eq!(set["webhooks"][0].get("secret"), None);
+    assert_eq!(set["webhooks"][0]["has_secret"], true);
+
+    app.send("a@example.test", &["b@example.test"], &message("Welcome"))
+        .await;
+    app.send(
+        "a@example.test",
+        &["b@example.test"],
+        &message("Invoice 42"),
+    )
+    .await;
+    reqwest::Client::new()
+        .delete(app.url("/api/messages/2"))
+        .send()
+        .await
+        .unwrap();
+
+    let delivery = next(&mut received).await;
+    assert_eq!(delivery.header("content-type"), "application/json");
+    assert_eq!(delivery.header(EVENT_HEADER), "message_received");
+    assert_eq!(
+        delivery.header(SIGNATURE_HEADER),
+        sign("s3cret", &delivery.body)
+    );
+    let payload = delivery.json();
+    assert_eq!(payload["type"], "message_received");
+    assert_eq!(payload["message"]["id"], 2);
+    assert_eq!(payload["message"]["subject"], "Invoice 42");
+
+    // Neither the other message nor the deletion is delivered.
+    let more = tokio::time::timeout(Duration::from_millis(300), received.recv()).await;
+    assert!(more.is_err(), "unexpected delivery");
+}
+
+#[tokio::test]
+async fn keeps_secrets_when_webhooks_are_sent_back() {
+    let (url, mut received) = start_receiver(0).await;
+    let app = TestApp::start().await;
+    let response = put_webhooks(
+        &app,
+        json!({ "webhooks": [{ "url": url, "secret": "s3cret" }] }),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+
+    // Edit what GET returns and send it back.
+    let mut set: Value = reqwest::get(app.url("/api/webhooks"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    set["webhooks"][0]["events"] = json!(["received"]);
+    let response = put_webhooks(&app, set).await;
+    assert_eq!(response.status(), 200);
+    let set: Value = response.json().await.unwrap();
+    assert_eq!(set["webhooks"][0]["has_secret"], true);
+
+    app.send("a@example.test", &["b@example.test"], &message("Signed"))
+        .await;
+    let delivery = next(&mut received).await;
+    assert_eq!(
+        delivery.header(SIGNATURE_HEADER),
+        sign("s3cret", &delivery.body)
+    );
+}
+
+#[tokio::test]
+async fn retries_failed_deliveries() {
+    let (url, mut received) = start_receiver(2).await;
+    let app = TestApp::start().await;
+    let response = put_webhooks(&app, json!({ "webhooks": [{ "url": url }] })).await;
+    assert_eq!(response.status(), 200);
+    app.send("a@example.test", &["b@example.test"], &message("Retry"))
+        .await;
+
+    let first = next(&mut received).await;
+    let second = next(&mut received).await;
+    let third = next(&mut received).await;
+    // Without a secret nothing is signed.
+    assert!(!first.headers.contains_key(SIGNATURE_HEADER));
+    for retry in [&second, &third] {
+        assert_eq!(retry.header(DELIVERY_HEADER), first.header(DELIVERY_HEADER));
+        assert_eq!(retry.body, first.body);
+    }
+    let more = tokio::time::timeout(Duration::from_millis(300), received.recv()).await;
+    assert!(more.is_err(), "delivered again after success");
+}
+
+#[tokio::test]
+async fn delivers_deletions_and_releases() {
+    let (url, mut received) = start_receiver(0).await;
+    let (upstream, _) = start_smtp(SmtpConfig::default()).await;
+    let relay = Relay::new(ReleaseConfig::new("127.0.0.1", upstream.port())).unwrap();
+    let app = TestApp::start_with_relay(relay).await;
+    let response = put_webhooks(
+        &app,
+        json!({ "webhooks": [{ "url": url, "events": ["deleted", "released"] }] }),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    app.send("a@example.test", &["b@example.test"], &message("Hello"))
+        .await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post(app.url("/api/messages/1/release"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let released = next(&mut received).await.json();
+    assert_eq!(released["type"], "message_released");
+    assert_eq!(released["release"]["to"], json!(["b@example.test"]));
+    assert!(released["release"]["reply"]
+        .as_str()
+        .unwrap()
+        .starts_with("250 "));
+
+    client
+        .delete(app.url("/api/messages/1"))
+        .send()
+        .await
+        .unwrap();
+    let deleted = next(&mut received).await.json();
+    assert_eq!(deleted["type"], "message_deleted");
+    assert_eq!(deleted["message"]["subject"], "Hello");
+}
+
+#[tokio::test]
+async fn delivers_a_deletion_for_each_cleared_message() {
+    let (url, mut received) = start_receiver(0).await;
+    let app = TestApp::start().await;
+    let response = put_webhooks(
+        &app,
+        json!({ "webhooks": [{ "url": url, "events": ["deleted"] }] }),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    app.send("a@example.test", &["b@example.test"], &message("One"))
+        .await;
+    app.send("a@example.test", &["b@example.test"], &message("Two"))
+        .await;
+
+    let response = reqwest::Client::new()
+        .delete(app.url("/api/messages"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let mut deleted = Vec::new();
+    for _ in 0..2 {
+        let payload = next(&mut received).await.json();
+        assert_eq!(payload["type"], "message_deleted");
+        deleted.push(payload["message"]["subject"].as_str().unwrap().to_string());
+    }
+    deleted.sort();
+    assert_eq!(deleted, ["One", "Two"]);
+}
+
+#[tokio::test]
+async fn imported_messages_are_not_delivered() {
+    let (url, mut received) = start_receiver(0).await;
+    let app = TestApp::start().await;
+    let response = put_webhooks(&app, json!({ "webhooks": [{ "url": url }] })).await;
+    assert_eq!(response.status(), 200);
+    app.send("a@example.test", &["b@example.test"], &message("Original"))
+        .await;
+    assert_eq!(next(&mut received).await.json()["message"]["id"], 1);
+
+    let archive = reqwest::get(app.url("/api/messages/export?format=mbox"))
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+    let response = reqwest::Client::new()
+        .post(app.url("/api/messages/import"))
+        .body(archive)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(app.store.len(), 2);
+
+    let more = tokio::time::timeout(Duration::from_millis(300), received.recv()).await;
+    assert!(more.is_err(), "import was delivered");
+}
+
+#[tokio::test]
+async fn rejects_invalid_webhooks() {
+    let app = TestApp::start().await;
+    let response = put_webhooks(
+        &app,
+        json!({ "webhooks": [{ "url": "http://127.0.0.1:9/", "query": "before:soon" }] }),
+    )
+    .await;
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.unwrap();
+    assert!(body["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("webhook 0: invalid query"));
+    let set: Value = reqwest::get(app.url("/api/webhooks"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(set, json!({ "webhooks": [] }));
+}
@@ -0,0 +1,454 @@
+"use strict";
+
+const RECONNECT_DELAY_MS = 2000;
+const SEARCH_DELAY_MS = 250;
+
+const state = {
+  messages: [],
+  selectedId: null,
+  detail: null,
+  tab: "html",
+  query: "",
+  upstream: null,
+  mailbox: new URLSearchParams(location.search).get("mailbox") || "default",
+};
+
+const $ = (selector, root = document) => root.querySelector(selector);
+
+function el(tag, className, text) {
+  const node = document.createElement(tag);
+  if (className) node.className = className;
+  if (text !== undefined && text !== null) node.textContent = text;
+  return node;
+}
+
+// Prefix of the API paths scoped to the selected mailbox.
+function base() {
+  return `/api/mailboxes/${encodeURIComponent(state.mailbox)}`;
+}
+
+async function api(path, options = {}) {
+  const response = await fetch(path, options);
+  if (!response.ok && response.status !== 404) {
+    throw new Error(`${options.method || "GET"} ${path}: ${response.status}`);
+  }
+  return response;
+}
+
+function formatAddress(address) {
+  if (!address) return "";
+  return address.name ? `${address.name} <${address.address}>` : address.address;
+}
+
+function formatAddresses(list) {
+  return (list || []).map(formatAddress).join(", ");
+}
+
+function formatTime(iso) {
+  const date = new Date(iso);
+  const now = new Date();
+  if (date.toDateString() === now.toDateString()) {
+    return date.toLocaleTimeString();
+  }
+  return date.toLocaleString();
+}
+
+async function loadMessages() {
+  const params = new URLSearchParams({ limit: 1000 });
+  if (state.query) params.set("query", state.query);
+  const response = await fetch(`${base()}/messages?${params}`);
+  const search = $("#search");
+  if (response.status === 400) {
+    search.classList.add("invalid");
+    search.title = (await response.json()).error;
+    return;
+  }
+  if (!response.ok) throw new Error(`GET ${base()}/messages: ${response.status}`);
+  search.classList.remove("invalid");
+  search.title = "";
+  const page = await response.json();
+  state.messages = page.messages;
+  const noun = `message${page.total === 1 ? "" : "s"}`;
+  $("#count").textContent = state.query ? `${page.total} matching ${noun}` : `${page.total} ${noun}`;
+  renderList();
+  if (state.selectedId !== null && !state.messages.some((m) => m.id === state.selectedId)) {
+    select(null);
+  }
+}
+
+function renderList() {
+  const list = $("#list");
+  list.replaceChildren();
+  if (state.messages.length === 0) {
+    list.append(el("p", "empty muted", state.query ? "No matching messages." : "No messages yet."));
+    return;
+  }
+  for (const message of state.messages) {
+    const item = el("button", "item");
+    item.type = "button";
+    if (message.id === state.selectedId) item.classList.add("selected");
+
+    const row = el("div", "row");
+    row.append(
+      el("span", "from", formatAddress(message.from) || message.envelope.mail_from || "(no sender)"),
+      el("span", "muted", formatTime(message.received_at)),
+    );
+    if (message.spam_score !== null) {
+      const badge = el("span", "spam-badge", message.spam_score.toFixed(1));
+      badge.title = "SpamAssassin score";
+      row.insertBefore(badge, row.lastChild);
+    }
+    item.append(
+      row,
+      el("div", "subject", message.subject || "(no subject)"),
+      el("div", "snippet", message.snippet),
+    );
+    item.addEventListener("click", () => select(message.id));
+    list.append(item);
+  }
+}
+
+async function select(id) {
+  state.selectedId = id;
+  state.detail = null;
+  renderList();
+  if (id === null) {
+    renderDetail();
+    return;
+  }
+  const response = await api(`${base()}/messages/${id}`);
+  if (response.status === 404) {
+    state.selectedId = null;
+  } else if (state.selectedId === id) {
+    state.detail = await response.json();
+    if (state.tab === "html" && !state.detail.html) state.tab = "text";
+  }
+  renderDetail();
+}
+
+function renderDetail() {
+  const container = $("#detail");
+  container.replaceChildren();
+  const message = state.detail;
+  if (!message) {
+    container.append(el("p", "empty muted", "Select a message."));
+    return;
+  }
+
+  const view = $("#detail-template").content.cloneNode(true);
+  const field = (name) => $(`[data-field="${name}"]`, view);
+  field("subject").textContent = message.subject || "(no subject)";
+  field("from").textContent = formatAddress(message.from);
+  field("to").textContent = formatAddresses(message.to);
+  field("cc").textContent = formatAddresses(message.cc);
+  field("date").textContent = message.date ? new Date(message.date).toLocaleString() : "";
+  field("envelope").textContent =
+    `${message.envelope.mail_from || "<>"} → ${message.envelope.rcpt_to.join(", ")}`;
+  const tls = message.session.tls;
+  field("tls").textContent = tls ? `${tls.version}, ${tls.cipher}` : "none";
+  field("dkim").textContent = message.dkim.length ? message.dkim.map(formatDkim).join("; ") : "none";
+  field("spf").textContent = formatSpf(message.spf);
+  field("dmarc").textContent = formatDmarc(message.dmarc);
+  if (message.spam) {
+    field("spam-label").hidden = false;
+    field("spam").hidden = false;
+    field("spam").textContent = formatSpam(message.spam);
+  }
+
+  for (const attachment of message.attachments) {
+    const item = el("li");
+    const link = el("a", null, `${attachment.filename || attachment.part_id} (${formatSize(attachment.size)})`);
+    link.href = `${base()}/messages/${message.id}/parts/${attachment.part_id}?download=true`;
+    item.append(link);
+    field("attachments").append(item);
+  }
+
+  for (const tab of view.querySelectorAll("[data-tab]")) {
+    tab.classList.toggle("active", tab.dataset.tab === state.tab);
+    tab.addEventListener("click", () => {
+      state.tab = tab.dataset.tab;
+      renderDetail();
+    });
+  }
+  $('[data-action="delete"]', view).addEventListener("click", () => deleteMessage(message.id));
+  const release = $('[data-action="release"]', view);
+  if (state.upstream) {
+    release.hidden = false;
+    release.title = `Send to ${state.upstream.host}:${state.upstream.port}`;
+    release.addEventListener("click", () => releaseMessage(message));
+  }
+
+  renderTab(field("body"), message);
+  container.append(view);
+}
+
+function formatSpam(spam) {
+  const verdict = spam.is_spam ? "spam" : "not spam";
+  return `${spam.score.toFixed(1)} of ${spam.threshold.toFixed(1)} (${verdict})`;
+}
+
+function formatDkim(result) {
+  const outcome = result.reason ? `${result.status}, ${result.reason}` : result.status;
+  return `${result.domain} (s=${result.selector}, ${result.algorithm}): ${outcome}`;
+}
+
+function formatSpf(spf) {
+  const detail = spf.mechanism ? `matched ${spf.mechanism}` : spf.reason;
+  const outcome = detail ? `${spf.status}, ${detail}` : spf.status;
+  return `${spf.domain} from ${spf.ip}: ${outcome}`;
+}
+
+function formatDmarc(dmarc) {
+  if (!dmarc.policy) return dmarc.reason ? `${dmarc.status}, ${dmarc.reason}` : dmarc.status;
+  const outcome = dmarc.status === "pass" ? "pass" : `${dmarc.status} (${dmarc.disposition}), ${dmarc.reason}`;
+  return `${dmarc.from_domain} p=${dmarc.policy}: ${outcome}`;
+}
+
+function formatSize(bytes) {
+  if (bytes < 1024) return `${bytes} B`;
+  if (bytes < 1024 * 1024) return `${(bytes / 1024).toFixed(1)} KB`;
+  return `${(bytes / 1024 / 1024).toFixed(1)} MB`;
+}
+
+function renderTab(body, message) {
+  switch (state.tab) {
+    case "html": {
+      if (!message.html) {
+        body.append(el("p", "empty muted", "This message has no HTML body."));
+        return;
+      }
+      const frame = document.createElement("iframe");
+      // No allow-scripts: captured HTML must never run in the UI's origin.
+      frame.sandbox = "allow-popups allow-popups-to-escape-sandbox";
+      frame.srcdoc = resolveContentIds(message);
+      body.append(frame);
+      return;
+    }
+    case "text":
+      if (!message.text) {
+        body.append(el("p", "empty muted", "This message has no text body."));
+        return;
+      }
+      body.append(el("pre", null, message.text));
+      return;
+    case "raw": {
+      const pre = el("pre", null, "Loading…");
+      body.append(pre);
+      api(`${base()}/messages/${message.id}/raw`)
+        .then((response) => response.text())
+        .then((text) => { pre.textContent = text; });
+      return;
+    }
+    case "headers": {
+      const table = el("table");
+      for (const header of message.headers) {
+        const row = el("tr");
+        row.append(el("td", null, header.name), el("td", null, header.value));
+        table.append(row);
+      }
+      body.append(table);
+      return;
+    }
+    case "compat": {
+      if (!message.html) {
+        body.append(el("p", "empty muted", "This message has no HTML body."));
+        return;
+      }
+      const container = el("div", "compat", "Loading…");
+      body.append(container);
+      api(`${base()}/messages/${message.id}/compatibility`)
+        .then((response) => response.json())
+        .then((report) => renderCompat(container, report));
+      return;
+    }
+    case "spam": {
+      if (!message.spam) {
+        body.append(el("p", "empty muted", "This message has not been scored by SpamAssassin."));
+        return;
+      }
+      const container = el("div", "spam");
+      container.append(el("p", message.spam.is_spam ? "verdict spam" : "verdict", formatSpam(message.spam)));
+      const table = el("table");
+      for (const rule of message.spam.rules) {
+        const row = el("tr");
+        row.append(
+          el("td", null, rule.score.toFixed(1)),
+          el("td", null, rule.name),
+          el("td", null, rule.description),
+        );
+        table.append(row);
+      }
+      container.append(table);
+      body.append(container);
+      return;
+    }
+  }
+}
+
+const SUPPORT_MARKS = { yes: "✓", partial: "◐", no: "✗" };
+
+function renderCompat(container, report) {
+  container.replaceChildren();
+  const scores = el("div", "scores");
+  for (const client of report.clients) {
+    const grade = client.score >= 90 ? "good" : client.score >= 60 ? "fair" : "poor";
+    const card = el("div", `score ${grade}`);
+    card.append(el("strong", null, `${client.score}%`), el("span", null, client.name));
+    card.title = `${client.supported} supported, ${client.partial} partial, ${client.unsupported} unsupported`;
+    scores.append(card);
+  }
+  container.append(scores);
+
+  container.append(el("h3", null, "Features"));
+  if (report.features.length === 0) {
+    container.append(el("p", "muted", "No features with known compatibility issues."));
+  } else {
+    const table = el("table");
+    const head = el("tr");
+    head.append(el("th", null, "Feature"), el("th", null, "Uses"));
+    for (const client of report.clients) head.append(el("th", null, client.name));
+    table.append(head);
+    for (const feature of report.features) {
+      const row = el("tr");
+      row.append(el("td", null, feature.title), el("td", null, String(feature.count)));
+      for (const client of report.clients) {
+        const support = feature.support[client.id] || "no";
+        const cell = el("td", `support-${support}`, SUPPORT_MARKS[support]);
+        const note = feature.notes && feature.notes[client.id];
+        if (note) cell.title = note;
+        row.append(cell);
+      }
+      table.append(row);
+    }
+    container.append(table);
+  }
+
+  const usage = (list) => list.map((item) => `${item.name} ×${item.count}`).join(", ") || "none";
+  container.append(
+    el("h3", null, "Elements"),
+    el("p", "usage", usage(report.elements)),
+    el("h3", null, "CSS properties"),
+    el("p", "usage", usage(report.properties)),
+  );
+}
+
+// Points `cid:` references in the HTML body at the matching inline parts.
+function resolveContentIds(message) {
+  let html = message.html;
+  for (const inline of message.inlines) {
+    if (!inline.content_id) continue;
+    const url = `${base()}/messages/${message.id}/parts/${inline.part_id}`;
+    html = html.split(`cid:${inline.content_id}`).join(url);
+  }
+  return html;
+}
+
+async function deleteMessage(id) {
+  await api(`${base()}/messages/${id}`, { method: "DELETE" });
+  if (state.selectedId === id) select(null);
+  await loadMessages();
+}
+
+async function loadUpstream() {
+  const response = await api("/api/release");
+  state.upstream = (await response.json()).upstream;
+}
+
+// Sends the message to the configured upstream server, to the recipients
+// the user confirms.
+async function releaseMessage(message) {
+  const defaults = state.upstream.to.length ? state.upstream.to : message.envelope.rcpt_to;
+  const answer = prompt("Release to (comma-separated):", defaults.join(", "));
+  if (answer === null) return;
+  const to = answer.split(",").map((address) => address.trim()).filter(Boolean);
+  const response = await fetch(`${base()}/messages/${message.id}/release`, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ to }),
+  });
+  const result = await response.json();
+  if (response.ok) {
+    alert(`Released to ${result.to.join(", ")}.\nUpstream replied: ${result.reply}`);
+  } else {
+    alert(`Release failed: ${result.error}`);
+  }
+}
+
+async function deleteAll() {
+  if (!confirm(`Delete all messages in ${state.mailbox}?`)) return;
+  await api(`${base()}/messages`, { method: "DELETE" });
+  select(null);
+  await loadMessages();
+}
+
+// Fills the mailbox picker, which stays hidden unless mailboxes are
+// configured.
+async function loadMailboxes() {
+  const response = await api("/api/mailboxes");
+  const mailboxes = await response.json();
+  const picker = $("#mailbox");
+  picker.replaceChildren();
+  for (const mailbox of mailboxes) {
+    const option = el("option", null, `${mailbox.name} (${mailbox.total})`);
+    option.value = mailbox.name;
+    option.selected = mailbox.name === state.mailbox;
+    picker.append(option);
+  }
+  picker.hidden = mailboxes.length < 2;
+}
+
+function switchMailbox(name) {
+  state.mailbox = name;
+  const url = new URL(location.href);
+  url.searchParams.set("mailbox", name);
+  history.replaceState(null, "", url);
+  select(null);
+  const previous = socket;
+  connectEvents();
+  previous.close();
+}
+
+$("#mailbox").addEventListener("change", (event) => switchMailbox(event.target.value));
+$("#refresh").addEventListener("click", loadMessages);
+$("#delete-all").addEventListener("click", deleteAll);
+
+let searchTimer;
+$("#search").addEventListener("input", (event) => {
+  clearTimeout(searchTimer);
+  searchTimer = setTimeout(() => {
+    state.query = event.target.value.trim();
+    loadMessages();
+  }, SEARCH_DELAY_MS);
+});
+
+// Reloads the list whenever the server reports a change in the selected
+// mailbox. While the socket is down the list is refreshed on every
+// reconnect attempt instead.
+let socket = null;
+function connectEvents() {
+  const scheme = location.protocol === "https:" ? "wss" : "ws";
+  const current = new WebSocket(`${scheme}://${location.host}${base()}/ws`);
+  socket = current;
+  current.addEventListener("open", loadMessages);
+  current.addEventListener("message", (message) => {
+    const event = JSON.parse(message.data);
+    if (event.type === "message_deleted" && event.message.id === state.selectedId) {
+      select(null);
+    } else if (event.type === "mailbox_cleared") {
+      select(null);
+    } else if (event.type === "message_updated" && event.message.id === state.selectedId) {
+      select(state.selectedId);
+    }
+    loadMessages();
+    loadMailboxes();
+  });
+  current.addEventListener("close", () => {
+    // A socket closed by switching mailboxes has already been replaced.
+    if (socket === current) setTimeout(connectEvents, RECONNECT_DELAY_MS);
+  });
+}
+
+loadUpstream().then(renderDetail);
+loadMailboxes();
+loadMessages();
+connectEvents();